@@ -0,0 +1,220 @@
+#![allow(non_snake_case)]
+
+use a2l::transcript::{SessionId, Transcript};
+use a2l::{dleq, hsm_cl, pedersen, pointcheval_sanders, secp256k1};
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use rand::{thread_rng, Rng};
+
+const BATCH_SIZE: usize = 32;
+
+/// CL-DL proofs take milliseconds to verify, hence the batch is smaller.
+const HSM_CL_BATCH_SIZE: usize = 8;
+
+fn random_bls12_381_scalar() -> bls12_381::Scalar {
+    let mut bytes = [0u8; 64];
+    thread_rng().fill(&mut bytes[..]);
+    bls12_381::Scalar::from_bytes_wide(&bytes)
+}
+
+fn pointcheval_sanders_benchmark(c: &mut Criterion) {
+    let keypair = pointcheval_sanders::keygen(a2l::TOKEN_MESSAGES, &mut thread_rng());
+    let batch = (0..BATCH_SIZE)
+        .map(|_| {
+            let messages = (0..a2l::TOKEN_MESSAGES)
+                .map(|_| random_bls12_381_scalar())
+                .collect::<Vec<_>>();
+            let (commitment, decommitment) = pedersen::commit(
+                &bls12_381::G1Affine::generator(),
+                &keypair.public_key.Y1[..1],
+                &messages[..1],
+                &mut thread_rng(),
+            );
+            let blinded =
+                pointcheval_sanders::sign(&keypair, commitment, &messages[1..], &mut thread_rng())
+                    .unwrap();
+
+            (
+                messages,
+                pointcheval_sanders::unblind(blinded, decommitment.r.expose()),
+            )
+        })
+        .collect::<Vec<_>>();
+    let items = batch
+        .iter()
+        .map(|(m, sig)| (&m[..], sig))
+        .collect::<Vec<_>>();
+
+    let mut group = c.benchmark_group("pointcheval_sanders");
+    group.bench_function(BenchmarkId::new("individual", BATCH_SIZE), |b| {
+        b.iter(|| {
+            for (m, sig) in &items {
+                pointcheval_sanders::verify(black_box(&keypair.public_key), m, sig).unwrap();
+            }
+        })
+    });
+    group.bench_function(BenchmarkId::new("batch", BATCH_SIZE), |b| {
+        b.iter(|| {
+            pointcheval_sanders::verify_batch(
+                black_box(&keypair.public_key),
+                black_box(&items),
+                &mut thread_rng(),
+            )
+            .unwrap()
+        })
+    });
+    group.finish();
+}
+
+fn pedersen_benchmark(c: &mut Criterion) {
+    let G = bls12_381::G1Affine::generator();
+    let H = vec![bls12_381::G1Affine::from(G * random_bls12_381_scalar())];
+    let batch = (0..BATCH_SIZE)
+        .map(|_| {
+            let session_id = SessionId::random(&mut thread_rng());
+            let (C, D) = pedersen::commit(&G, &H, &[random_bls12_381_scalar()], &mut thread_rng());
+            let proof = pedersen::prove(
+                &mut Transcript::new(&session_id),
+                &G,
+                &H,
+                &C,
+                &D,
+                &mut thread_rng(),
+            );
+
+            (session_id, C, proof)
+        })
+        .collect::<Vec<_>>();
+    let items = batch
+        .iter()
+        .map(|(session_id, C, proof)| (Transcript::new(session_id), C, proof))
+        .collect::<Vec<_>>();
+
+    let mut group = c.benchmark_group("pedersen");
+    group.bench_function(BenchmarkId::new("individual", BATCH_SIZE), |b| {
+        b.iter(|| {
+            for (transcript, C, proof) in &items {
+                pedersen::verify(
+                    &mut transcript.clone(),
+                    black_box(&G),
+                    black_box(&H),
+                    C,
+                    (*proof).clone(),
+                )
+                .unwrap();
+            }
+        })
+    });
+    group.bench_function(BenchmarkId::new("batch", BATCH_SIZE), |b| {
+        b.iter(|| {
+            pedersen::verify_batch(
+                black_box(&G),
+                black_box(&H),
+                black_box(&items),
+                &mut thread_rng(),
+            )
+            .unwrap()
+        })
+    });
+    group.finish();
+}
+
+fn dleq_benchmark(c: &mut Criterion) {
+    let session_id = SessionId::random(&mut thread_rng());
+    let H = secp256k1::KeyPair::random(&mut thread_rng()).to_pk();
+    let batch = (0..BATCH_SIZE)
+        .map(|_| {
+            let x = secp256k1::KeyPair::random(&mut thread_rng());
+
+            let mut Hx = H.clone();
+            Hx.tweak_mul_assign(x.as_sk()).unwrap();
+
+            let proof = dleq::prove(
+                &mut Transcript::new(&session_id),
+                &mut thread_rng(),
+                &secp256k1::G,
+                &x.to_pk(),
+                &H,
+                &Hx,
+                x.to_scalar().expose(),
+            );
+
+            (x.to_pk(), Hx, proof)
+        })
+        .collect::<Vec<_>>();
+    let items = batch
+        .iter()
+        .map(|(Gx, Hx, proof)| {
+            (
+                Transcript::new(&session_id),
+                (&*secp256k1::G, Gx, &H, Hx),
+                proof,
+            )
+        })
+        .collect::<Vec<_>>();
+
+    let mut group = c.benchmark_group("dleq");
+    group.bench_function(BenchmarkId::new("individual", BATCH_SIZE), |b| {
+        b.iter(|| {
+            for (transcript, (G, Gx, H, Hx), proof) in &items {
+                dleq::verify(&mut transcript.clone(), G, Gx, H, Hx, black_box(proof)).unwrap();
+            }
+        })
+    });
+    group.bench_function(BenchmarkId::new("batch", BATCH_SIZE), |b| {
+        b.iter(|| dleq::verify_batch(black_box(&items)).unwrap())
+    });
+    group.finish();
+}
+
+fn hsm_cl_benchmark(c: &mut Criterion) {
+    let class_group = hsm_cl::ClassGroupParams::from_seed(hsm_cl::DEFAULT_CLASS_GROUP_SEED);
+    let public_key = hsm_cl::keygen(&class_group).to_pk();
+    let batch = (0..HSM_CL_BATCH_SIZE)
+        .map(|_| {
+            let msg = secp256k1::KeyPair::random(&mut thread_rng());
+            let (ciphertext, proof) = hsm_cl::encrypt(&class_group, &public_key, &msg);
+
+            (ciphertext, proof, msg.to_pk())
+        })
+        .collect::<Vec<_>>();
+    let items = batch
+        .iter()
+        .map(|(ciphertext, proof, X)| (proof, (ciphertext, X)))
+        .collect::<Vec<_>>();
+
+    let mut group = c.benchmark_group("hsm_cl");
+    group.sample_size(10);
+    group.bench_function(BenchmarkId::new("individual", HSM_CL_BATCH_SIZE), |b| {
+        b.iter(|| {
+            for &(proof, statement) in &items {
+                hsm_cl::verify(
+                    black_box(&class_group),
+                    black_box(&public_key),
+                    proof,
+                    statement,
+                )
+                .unwrap();
+            }
+        })
+    });
+    group.bench_function(BenchmarkId::new("batch", HSM_CL_BATCH_SIZE), |b| {
+        b.iter(|| {
+            hsm_cl::verify_batch(
+                black_box(&class_group),
+                black_box(&public_key),
+                black_box(&items),
+            )
+            .unwrap()
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    pointcheval_sanders_benchmark,
+    pedersen_benchmark,
+    dleq_benchmark,
+    hsm_cl_benchmark
+);
+criterion_main!(benches);
@@ -3,23 +3,32 @@ use criterion::{black_box, criterion_group, criterion_main, Criterion};
 use rand::thread_rng;
 
 fn encrypt_benchmark(c: &mut Criterion) {
-    let public_key = hsm_cl::keygen().to_pk();
+    let class_group = hsm_cl::ClassGroupParams::from_seed(hsm_cl::DEFAULT_CLASS_GROUP_SEED);
+    let public_key = hsm_cl::keygen(&class_group).to_pk();
     let msg = a2l::secp256k1::KeyPair::random(&mut thread_rng());
 
     c.bench_function("encrypt", |b| {
-        b.iter(|| hsm_cl::encrypt(black_box(&public_key), black_box(&msg)))
+        b.iter(|| {
+            hsm_cl::encrypt(
+                black_box(&class_group),
+                black_box(&public_key),
+                black_box(&msg),
+            )
+        })
     });
 }
 
 fn verify_benchmark(c: &mut Criterion) {
-    let public_key = hsm_cl::keygen().to_pk();
+    let class_group = hsm_cl::ClassGroupParams::from_seed(hsm_cl::DEFAULT_CLASS_GROUP_SEED);
+    let public_key = hsm_cl::keygen(&class_group).to_pk();
     let msg = a2l::secp256k1::KeyPair::random(&mut thread_rng());
 
-    let (ciphertext, proof) = hsm_cl::encrypt(&public_key, &msg);
+    let (ciphertext, proof) = hsm_cl::encrypt(&class_group, &public_key, &msg);
 
     c.bench_function("verify", |b| {
         b.iter(|| {
             hsm_cl::verify(
+                black_box(&class_group),
                 black_box(&public_key),
                 black_box(&proof),
                 black_box((&ciphertext, &msg.to_pk())),
@@ -29,21 +38,29 @@ fn verify_benchmark(c: &mut Criterion) {
 }
 
 fn decrypt_benchmark(c: &mut Criterion) {
-    let keypair = hsm_cl::keygen();
+    let class_group = hsm_cl::ClassGroupParams::from_seed(hsm_cl::DEFAULT_CLASS_GROUP_SEED);
+    let keypair = hsm_cl::keygen(&class_group);
     let msg = a2l::secp256k1::KeyPair::random(&mut thread_rng());
 
-    let (ciphertext, _) = hsm_cl::encrypt(&keypair.to_pk(), &msg);
+    let (ciphertext, _) = hsm_cl::encrypt(&class_group, &keypair.to_pk(), &msg);
 
     c.bench_function("decrypt", |b| {
-        b.iter(|| hsm_cl::decrypt(black_box(&keypair), black_box(&ciphertext)))
+        b.iter(|| {
+            hsm_cl::decrypt(
+                black_box(&class_group),
+                black_box(&keypair),
+                black_box(&ciphertext),
+            )
+        })
     });
 }
 
 fn multiply_benchmark(c: &mut Criterion) {
-    let keypair = hsm_cl::keygen();
+    let class_group = hsm_cl::ClassGroupParams::from_seed(hsm_cl::DEFAULT_CLASS_GROUP_SEED);
+    let keypair = hsm_cl::keygen(&class_group);
     let msg = a2l::secp256k1::KeyPair::random(&mut thread_rng());
 
-    let (ciphertext, _) = hsm_cl::encrypt(&keypair.to_pk(), &msg);
+    let (ciphertext, _) = hsm_cl::encrypt(&class_group, &keypair.to_pk(), &msg);
 
     c.bench_function("multiply", |b| {
         b.iter(|| black_box(&ciphertext) * black_box(&msg))
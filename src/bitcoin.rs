@@ -1,127 +1,964 @@
 use crate::secp256k1;
-use crate::secp256k1::ToMessage;
+use crate::secp256k1::{tagged_hash, ToMessage};
 use anyhow::{bail, Context};
+use bitcoin::blockdata::opcodes;
+use bitcoin::blockdata::script::{Builder, Script};
+use bitcoin::consensus::encode::{serialize, Encodable};
 pub use bitcoin::hash_types::SigHash;
-use bitcoin::hashes::Hash;
+use bitcoin::hashes::{hash160, sha256, Hash};
 use bitcoin::util::bip143::SighashComponents;
+use bitcoin::util::psbt;
+pub use bitcoin::util::psbt::PartiallySignedTransaction as Psbt;
 pub use bitcoin::Transaction;
 pub use bitcoin::TxIn;
 pub use bitcoin::Txid;
 pub use bitcoin::{Address, Amount, OutPoint, SigHashType, TxOut};
-use std::{collections::HashMap, str::FromStr};
+use sha2::{Digest, Sha256};
+use std::{cmp, collections::HashMap, fmt, str::FromStr};
 
-const MAX_SATISFACTION_WEIGHT: u64 = 222;
+/// The maximum weight of the witness that satisfies the 2-of-2 `MINISCRIPT_TEMPLATE` of a
+/// [`JointOutput::P2wsh`] output with two compressed keys, which does not depend on the keys.
+pub const P2WSH_SATISFACTION_WEIGHT: u64 = 222;
+/// A key path spend of a P2TR output only pushes a single 64-byte signature onto the witness
+/// stack.
+pub const TAPROOT_KEY_SPEND_SATISFACTION_WEIGHT: u64 = 66;
+/// A P2WPKH spend pushes a DER-encoded signature of at most 72 bytes plus the sighash type and a
+/// compressed public key onto the witness stack.
+pub const P2WPKH_SATISFACTION_WEIGHT: u64 = 1 + 1 + 73 + 1 + 33;
 const MINISCRIPT_TEMPLATE: &str = "and_v(vc:pk(X_from),c:pk(X_to))";
 
-pub fn spend_tx_miner_fee(sats_per_wu: bitcoin::Amount) -> bitcoin::Amount {
-    sats_per_wu * MAX_SATISFACTION_WEIGHT
+/// nLockTime values below this threshold are interpreted as block heights, values at or above as
+/// unix timestamps.
+const LOCKTIME_THRESHOLD: u32 = 500_000_000;
+
+/// The sequence number of an input that opts into nLockTime enforcement.
+///
+/// A sequence of `0xFFFF_FFFF` marks the input as final which disables nLockTime. This value also
+/// has the BIP68 disable flag set, i.e. it does not impose a relative timelock.
+const ENABLE_LOCKTIME_NO_RBF: u32 = 0xFFFF_FFFE;
+
+/// The default dust relay fee of Bitcoin Core in satoshis per virtual byte.
+const DUST_RELAY_FEE_PER_VBYTE: u64 = 3;
+
+/// The value of the anchor output of the redeem and refund transactions.
+///
+/// This is the dust threshold of a P2WSH output, the joint output has to cover it on top of the
+/// miner fee of the spend transaction.
+pub const ANCHOR_OUTPUT_VALUE: Amount = Amount::from_sat(330);
+/// After this many confirmations of its transaction, anyone can sweep an anchor output.
+const ANCHOR_CSV_DELAY: i64 = 16;
+/// Witness data of a keyed anchor spend: the number of items, a signature and the witness script.
+const ANCHOR_SATISFACTION_WEIGHT: u64 = 1 + 1 + 73 + 1 + 40;
+/// The index of the anchor output in the redeem and refund transactions.
+const ANCHOR_OUTPUT_INDEX: u32 = 1;
+
+/// Multiples of the fee of the refund transaction at which bumped refund transactions are signed.
+const REFUND_FEE_LADDER: [u64; 4] = [2, 4, 8, 16];
+
+const TAP_SIGHASH_TAG: &[u8] = b"TapSighash";
+const TAP_SIGHASH_EPOCH: u8 = 0x00;
+const SIGHASH_DEFAULT: u8 = 0x00;
+
+/// The point in time after which a refund transaction can be included in the blockchain.
+///
+/// An expiry is checked against `LOCKTIME_THRESHOLD` whenever it is deserialized, hence its
+/// nLockTime is always interpreted as the kind it claims to be.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(try_from = "UncheckedExpiry")]
+pub enum Expiry {
+    /// The refund can be included in the block following the block at this height.
+    BlockHeight(u32),
+    /// The refund can be included once the median time past exceeds this unix timestamp.
+    Timestamp(u32),
 }
 
-#[derive(Debug, Clone)]
+/// The serialized form of an [`Expiry`] before it has been checked.
+#[derive(serde::Deserialize)]
+enum UncheckedExpiry {
+    BlockHeight(u32),
+    Timestamp(u32),
+}
+
+impl std::convert::TryFrom<UncheckedExpiry> for Expiry {
+    type Error = InvalidExpiry;
+
+    fn try_from(expiry: UncheckedExpiry) -> Result<Self, Self::Error> {
+        match expiry {
+            UncheckedExpiry::BlockHeight(height) => Self::block_height(height),
+            UncheckedExpiry::Timestamp(timestamp) => Self::timestamp(timestamp),
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+#[error("{0} is not a valid {1}")]
+pub struct InvalidExpiry(u32, &'static str);
+
+impl Expiry {
+    pub fn block_height(height: u32) -> Result<Self, InvalidExpiry> {
+        if height >= LOCKTIME_THRESHOLD {
+            return Err(InvalidExpiry(height, "block height"));
+        }
+
+        Ok(Expiry::BlockHeight(height))
+    }
+
+    pub fn timestamp(timestamp: u32) -> Result<Self, InvalidExpiry> {
+        if timestamp < LOCKTIME_THRESHOLD {
+            return Err(InvalidExpiry(timestamp, "timestamp"));
+        }
+
+        Ok(Expiry::Timestamp(timestamp))
+    }
+
+    /// Checks that the lock time of the expiry is interpreted as the kind of the expiry.
+    pub fn validate(self) -> Result<(), InvalidExpiry> {
+        match self {
+            Expiry::BlockHeight(height) => Self::block_height(height).map(|_| ()),
+            Expiry::Timestamp(timestamp) => Self::timestamp(timestamp).map(|_| ()),
+        }
+    }
+
+    pub fn from_lock_time(lock_time: u32) -> Self {
+        if lock_time < LOCKTIME_THRESHOLD {
+            Expiry::BlockHeight(lock_time)
+        } else {
+            Expiry::Timestamp(lock_time)
+        }
+    }
+
+    pub fn lock_time(self) -> u32 {
+        match self {
+            Expiry::BlockHeight(height) => height,
+            Expiry::Timestamp(timestamp) => timestamp,
+        }
+    }
+}
+
+/// The expiries of the refund transactions of both legs of the protocol.
+///
+/// Once the receiver redeems the tumbler's funds, the tumbler learns the secret it needs to redeem
+/// the sender's funds. The receiver can redeem right up to `promise_expiry`, hence the sender must
+/// not be able to refund before the tumbler had `safety_margin` blocks (or seconds, for
+/// timestamps) to redeem.
+///
+/// A policy and both of its expiries are validated whenever they are deserialized, hence `Params`
+/// restored from a state store or received from a counterparty cannot carry unsafe timelocks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(try_from = "UncheckedTimelockPolicy")]
+pub struct TimelockPolicy {
+    /// Expiry of the tumbler's refund in the puzzle-promise protocol.
+    pub promise_expiry: Expiry,
+    /// Expiry of the sender's refund in the puzzle-solver protocol.
+    pub solver_expiry: Expiry,
+    pub safety_margin: u32,
+}
+
+/// The serialized form of a [`TimelockPolicy`] before it has been validated.
+#[derive(serde::Deserialize)]
+struct UncheckedTimelockPolicy {
+    promise_expiry: Expiry,
+    solver_expiry: Expiry,
+    safety_margin: u32,
+}
+
+impl std::convert::TryFrom<UncheckedTimelockPolicy> for TimelockPolicy {
+    type Error = UnsafeTimelocks;
+
+    fn try_from(policy: UncheckedTimelockPolicy) -> Result<Self, Self::Error> {
+        Self::new(
+            policy.promise_expiry,
+            policy.solver_expiry,
+            policy.safety_margin,
+        )
+    }
+}
+
+#[derive(thiserror::Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnsafeTimelocks {
+    #[error("promise and solver expiry must both be block heights or both be timestamps")]
+    MixedExpiryKinds,
+    #[error("safety margin must not be zero")]
+    ZeroSafetyMargin,
+    #[error("solver expiry {solver} is less than {safety_margin} after promise expiry {promise}")]
+    InsufficientSafetyMargin {
+        promise: u32,
+        solver: u32,
+        safety_margin: u32,
+    },
+    #[error("solver expiry overflows the range of its kind")]
+    SolverExpiryOverflow,
+    #[error("expiry {0} is out of the range of its kind")]
+    ExpiryOutOfRange(u32),
+}
+
+impl TimelockPolicy {
+    pub fn new(
+        promise_expiry: Expiry,
+        solver_expiry: Expiry,
+        safety_margin: u32,
+    ) -> Result<Self, UnsafeTimelocks> {
+        let policy = Self {
+            promise_expiry,
+            solver_expiry,
+            safety_margin,
+        };
+        policy.validate()?;
+
+        Ok(policy)
+    }
+
+    /// Computes the earliest safe solver expiry given the promise expiry.
+    pub fn from_safety_margin(
+        promise_expiry: Expiry,
+        safety_margin: u32,
+    ) -> Result<Self, UnsafeTimelocks> {
+        let solver_lock_time = promise_expiry
+            .lock_time()
+            .checked_add(safety_margin)
+            .ok_or(UnsafeTimelocks::SolverExpiryOverflow)?;
+
+        let solver_expiry = match promise_expiry {
+            Expiry::BlockHeight(_) => Expiry::block_height(solver_lock_time),
+            Expiry::Timestamp(_) => Expiry::timestamp(solver_lock_time),
+        }
+        .map_err(|_| UnsafeTimelocks::SolverExpiryOverflow)?;
+
+        Self::new(promise_expiry, solver_expiry, safety_margin)
+    }
+
+    pub fn validate(&self) -> Result<(), UnsafeTimelocks> {
+        for expiry in &[self.promise_expiry, self.solver_expiry] {
+            expiry
+                .validate()
+                .map_err(|_| UnsafeTimelocks::ExpiryOutOfRange(expiry.lock_time()))?;
+        }
+
+        let (promise, solver) = match (self.promise_expiry, self.solver_expiry) {
+            (Expiry::BlockHeight(promise), Expiry::BlockHeight(solver))
+            | (Expiry::Timestamp(promise), Expiry::Timestamp(solver)) => (promise, solver),
+            _ => return Err(UnsafeTimelocks::MixedExpiryKinds),
+        };
+
+        if self.safety_margin == 0 {
+            return Err(UnsafeTimelocks::ZeroSafetyMargin);
+        }
+
+        if solver < promise || solver - promise < self.safety_margin {
+            return Err(UnsafeTimelocks::InsufficientSafetyMargin {
+                promise,
+                solver,
+                safety_margin: self.safety_margin,
+            });
+        }
+
+        Ok(())
+    }
+}
+
+/// The feerate a transaction pays, either per weight unit or per virtual byte.
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum FeeRate {
+    PerWeightUnit(#[serde(with = "crate::serde::bitcoin_amount")] Amount),
+    PerVbyte(#[serde(with = "crate::serde::bitcoin_amount")] Amount),
+}
+
+impl FeeRate {
+    /// Computes the fee of a transaction of the given weight.
+    ///
+    /// Like Bitcoin Core, fees per virtual byte are charged on the weight rounded up to the next
+    /// virtual byte.
+    pub fn fee(&self, weight: u64) -> Amount {
+        match self {
+            FeeRate::PerWeightUnit(sats) => *sats * weight,
+            FeeRate::PerVbyte(sats) => *sats * vsize(weight),
+        }
+    }
+}
+
+impl fmt::Display for FeeRate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FeeRate::PerWeightUnit(sats) => write!(f, "{} sat/WU", sats.as_sat()),
+            FeeRate::PerVbyte(sats) => write!(f, "{} sat/vB", sats.as_sat()),
+        }
+    }
+}
+
+/// Computes the miner fee of the redeem and refund transactions that spend a joint output whose
+/// witness weighs at most `satisfaction_weight`, i.e. `JointOutputKind::max_satisfaction_weight`
+/// of the joint output the parties agreed on or `JointOutput::max_satisfaction_weight`.
+///
+/// The fee covers whichever of the two spend transactions is heavier, i.e. it depends on the
+/// kinds of `X_redeem` and `X_refund` but not on their keys.
+pub fn spend_tx_miner_fee(
+    feerate: FeeRate,
+    satisfaction_weight: u64,
+    X_redeem: &bitcoin::Address,
+    X_refund: &bitcoin::Address,
+) -> bitcoin::Amount {
+    let weight = cmp::max(
+        spend_tx_weight(satisfaction_weight, &X_redeem.script_pubkey()),
+        spend_tx_weight(satisfaction_weight, &X_refund.script_pubkey()),
+    );
+
+    feerate.fee(weight)
+}
+
+/// Computes an upper bound for the weight of a signed transaction that spends a joint output to
+/// `destination` alongside the anchor output.
+fn spend_tx_weight(satisfaction_weight: u64, destination: &Script) -> u64 {
+    // every anchor output is a P2WSH output, its size does not depend on the key it is locked to
+    let anchor_output = TxOut {
+        value: ANCHOR_OUTPUT_VALUE.as_sat(),
+        script_pubkey: v0_p2wsh(&Script::new()),
+    };
+    let unsigned = Transaction {
+        version: 2,
+        lock_time: 0,
+        input: vec![TxIn {
+            previous_output: OutPoint::default(),
+            script_sig: Script::new(),
+            sequence: 0xFFFF_FFFF,
+            witness: Vec::new(),
+        }],
+        output: vec![
+            TxOut {
+                value: 0,
+                script_pubkey: destination.clone(),
+            },
+            anchor_output,
+        ],
+    };
+
+    // the segwit marker and flag are only serialized once the input has a witness
+    unsigned.get_weight() as u64 + 2 + satisfaction_weight
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct Transactions {
-    pub fund: Transaction,
+    /// The fund transaction along with the metadata a wallet needs to sign it.
+    #[serde(with = "crate::serde::bitcoin_psbt")]
+    pub fund_psbt: Psbt,
+    #[serde(with = "crate::serde::bitcoin_transaction")]
     pub redeem: Transaction,
+    #[serde(with = "crate::serde::bitcoin_sighash")]
     pub redeem_tx_digest: SigHash,
+    #[serde(with = "crate::serde::bitcoin_transaction")]
     pub refund: Transaction,
+    #[serde(with = "crate::serde::bitcoin_sighash")]
     pub refund_tx_digest: SigHash,
+    #[serde(with = "crate::serde::bitcoin_amount")]
+    pub refund_fee: Amount,
+    /// Variants of the refund transaction with the same locktime that pay higher fees, ordered by
+    /// increasing fee. Rungs whose output would be dust are left out.
+    pub bumped_refunds: Vec<BumpedRefund>,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct BumpedRefund {
+    #[serde(with = "crate::serde::bitcoin_amount")]
+    pub fee: Amount,
+    #[serde(with = "crate::serde::bitcoin_transaction")]
+    pub transaction: Transaction,
+    #[serde(with = "crate::serde::bitcoin_sighash")]
+    pub digest: SigHash,
 }
 
+#[derive(thiserror::Error, Debug, Clone, Copy, PartialEq)]
+#[error("expected {expected} signatures on refund transactions, received {actual}")]
+pub struct WrongNumberOfRefundSignatures {
+    expected: usize,
+    actual: usize,
+}
+
+impl Transactions {
+    /// The unsigned fund transaction.
+    pub fn fund(&self) -> &Transaction {
+        &self.fund_psbt.global.unsigned_tx
+    }
+
+    /// The digests both parties sign to complete the refund transaction and the bumped refund
+    /// transactions, in this order.
+    pub fn refund_digests(&self) -> Vec<SigHash> {
+        self.refunds().map(|(_, _, digest)| digest).collect()
+    }
+
+    /// Completes the refund transaction and all bumped refund transactions of a
+    /// [`JointOutput::P2wsh`] output with the signatures of both parties.
+    ///
+    /// The signatures are given in the order of `refund_digests`. They are expected to have been
+    /// verified already.
+    pub fn complete_refunds(
+        &self,
+        (X_from, sigs_from): (&secp256k1::PublicKey, Vec<secp256k1::Signature>),
+        (X_to, sigs_to): (&secp256k1::PublicKey, Vec<secp256k1::Signature>),
+    ) -> anyhow::Result<SignedRefunds> {
+        self.ensure_refund_signatures(sigs_from.len())?;
+        self.ensure_refund_signatures(sigs_to.len())?;
+
+        let refunds = self
+            .refunds()
+            .zip(sigs_from.into_iter().zip(sigs_to))
+            .map(|((fee, transaction, _), (sig_from, sig_to))| {
+                Ok(SignedRefund {
+                    fee,
+                    transaction: complete_spend_transaction(
+                        transaction.clone(),
+                        (X_from.clone(), sig_from),
+                        (X_to.clone(), sig_to),
+                    )?,
+                })
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        Ok(SignedRefunds { refunds })
+    }
+
+    /// Completes the refund transaction and all bumped refund transactions of a
+    /// [`JointOutput::P2tr`] output with the aggregated signatures of both parties.
+    ///
+    /// The signatures are given in the order of `refund_digests`. They are expected to have been
+    /// verified already.
+    pub fn complete_taproot_refunds(
+        &self,
+        signatures: Vec<secp256k1::schnorr::Signature>,
+    ) -> Result<SignedRefunds, WrongNumberOfRefundSignatures> {
+        self.ensure_refund_signatures(signatures.len())?;
+
+        let refunds = self
+            .refunds()
+            .zip(signatures)
+            .map(|((fee, transaction, _), signature)| SignedRefund {
+                fee,
+                transaction: complete_taproot_spend_transaction(transaction.clone(), &signature),
+            })
+            .collect();
+
+        Ok(SignedRefunds { refunds })
+    }
+
+    /// The refund transaction and the bumped refund transactions along with their fees and
+    /// digests, in order of increasing fee.
+    fn refunds(&self) -> impl Iterator<Item = (Amount, &Transaction, SigHash)> {
+        std::iter::once((self.refund_fee, &self.refund, self.refund_tx_digest)).chain(
+            self.bumped_refunds
+                .iter()
+                .map(|refund| (refund.fee, &refund.transaction, refund.digest)),
+        )
+    }
+
+    fn ensure_refund_signatures(&self, actual: usize) -> Result<(), WrongNumberOfRefundSignatures> {
+        let expected = 1 + self.bumped_refunds.len();
+        if actual != expected {
+            return Err(WrongNumberOfRefundSignatures { expected, actual });
+        }
+
+        Ok(())
+    }
+}
+
+/// The refund transaction and its bumped variants, signed by both parties.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct SignedRefunds {
+    /// Ordered by increasing fee, starting with the refund transaction.
+    refunds: Vec<SignedRefund>,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+struct SignedRefund {
+    #[serde(with = "crate::serde::bitcoin_amount")]
+    fee: Amount,
+    #[serde(with = "crate::serde::bitcoin_transaction")]
+    transaction: Transaction,
+}
+
+#[derive(thiserror::Error, Debug, Clone, Copy, PartialEq)]
+#[error("no refund transaction pays {0}")]
+pub struct NoRefundForFeerate(FeeRate);
+
+impl SignedRefunds {
+    /// The refund transaction at the fee both parties agreed on in `Params`.
+    pub fn refund(&self) -> &Transaction {
+        &self.refunds[0].transaction
+    }
+
+    /// All refund transactions, starting with the one returned by `refund`.
+    ///
+    /// They all spend the same joint output, hence at most one of them can ever be mined.
+    pub fn transactions(&self) -> impl Iterator<Item = &Transaction> {
+        self.refunds.iter().map(|refund| &refund.transaction)
+    }
+
+    /// Picks the cheapest refund transaction that pays at least `feerate`.
+    ///
+    /// The refund transactions are fully signed, hence their fee is checked against their actual
+    /// weight.
+    pub fn for_feerate(&self, feerate: FeeRate) -> Result<&Transaction, NoRefundForFeerate> {
+        self.refunds
+            .iter()
+            .find(|refund| refund.fee >= feerate.fee(refund.transaction.get_weight() as u64))
+            .map(|refund| &refund.transaction)
+            .ok_or(NoRefundForFeerate(feerate))
+    }
+
+    #[cfg(test)]
+    pub(crate) fn from_transactions(refunds: Vec<(Amount, Transaction)>) -> Self {
+        Self {
+            refunds: refunds
+                .into_iter()
+                .map(|(fee, transaction)| SignedRefund { fee, transaction })
+                .collect(),
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq)]
+pub enum InvalidPartialFundTransaction {
+    #[error("partial fund transaction does not spend anything")]
+    NoInputs,
+    #[error("PSBT describes {metadata} inputs but the partial fund transaction has {inputs}")]
+    InputCountMismatch { inputs: usize, metadata: usize },
+    #[error("PSBT describes {metadata} outputs but the partial fund transaction has {outputs}")]
+    OutputCountMismatch { outputs: usize, metadata: usize },
+    #[error("the output spent by input {0} is not part of the PSBT")]
+    UnknownPrevout(usize),
+    #[error(
+        "inputs of {inputs} do not cover the joint output of {joint_output} and change of {change}"
+    )]
+    InsufficientValue {
+        inputs: Amount,
+        joint_output: Amount,
+        change: Amount,
+    },
+    #[error("change output {index} of {value} is below the dust threshold of {threshold}")]
+    DustChange {
+        index: usize,
+        value: Amount,
+        threshold: Amount,
+    },
+    #[error("input {0} is already signed, inserting the joint output would invalidate it")]
+    AlreadySigned(usize),
+}
+
+/// Checks that the partial fund transaction can pay for a joint output of the given value.
+///
+/// The values of the outputs it spends are taken from the `witness_utxo` or `non_witness_utxo` of
+/// each input, hence the PSBT has to include them.
+pub fn validate_partial_fund_psbt(
+    psbt: &Psbt,
+    joint_output_value: Amount,
+) -> Result<(), InvalidPartialFundTransaction> {
+    let transaction = &psbt.global.unsigned_tx;
+
+    if transaction.input.is_empty() {
+        return Err(InvalidPartialFundTransaction::NoInputs);
+    }
+
+    // the metadata is matched with inputs and outputs by position
+    if transaction.input.len() != psbt.inputs.len() {
+        return Err(InvalidPartialFundTransaction::InputCountMismatch {
+            inputs: transaction.input.len(),
+            metadata: psbt.inputs.len(),
+        });
+    }
+    if transaction.output.len() != psbt.outputs.len() {
+        return Err(InvalidPartialFundTransaction::OutputCountMismatch {
+            outputs: transaction.output.len(),
+            metadata: psbt.outputs.len(),
+        });
+    }
+
+    let mut inputs = Amount::from_sat(0);
+    for (index, (txin, input)) in transaction.input.iter().zip(&psbt.inputs).enumerate() {
+        if !input.partial_sigs.is_empty()
+            || input.final_script_sig.is_some()
+            || input.final_script_witness.is_some()
+        {
+            return Err(InvalidPartialFundTransaction::AlreadySigned(index));
+        }
+
+        let prevout = match (&input.witness_utxo, &input.non_witness_utxo) {
+            (Some(prevout), _) => Some(prevout),
+            (None, Some(previous_transaction))
+                if previous_transaction.txid() == txin.previous_output.txid =>
+            {
+                previous_transaction
+                    .output
+                    .get(txin.previous_output.vout as usize)
+            }
+            _ => None,
+        }
+        .ok_or(InvalidPartialFundTransaction::UnknownPrevout(index))?;
+
+        inputs += Amount::from_sat(prevout.value);
+    }
+
+    let mut change = Amount::from_sat(0);
+    for (index, output) in transaction.output.iter().enumerate() {
+        let value = Amount::from_sat(output.value);
+        let threshold = dust_threshold(&output.script_pubkey);
+
+        if value < threshold {
+            return Err(InvalidPartialFundTransaction::DustChange {
+                index,
+                value,
+                threshold,
+            });
+        }
+
+        change += value;
+    }
+
+    if inputs < joint_output_value + change {
+        return Err(InvalidPartialFundTransaction::InsufficientValue {
+            inputs,
+            joint_output: joint_output_value,
+            change,
+        });
+    }
+
+    Ok(())
+}
+
+/// Computes the value below which Bitcoin Core does not relay an output.
+///
+/// Follows `GetDustThreshold` at the default dust relay fee.
+fn dust_threshold(script_pubkey: &Script) -> Amount {
+    if script_pubkey.is_provably_unspendable() {
+        return Amount::from_sat(0);
+    }
+
+    let output_size = serialize(&TxOut {
+        value: 0,
+        script_pubkey: script_pubkey.clone(),
+    })
+    .len() as u64;
+    // the size of the input that spends the output, witness data is discounted
+    let input_size = if is_witness_program(script_pubkey) {
+        32 + 4 + 1 + 107 / 4 + 4
+    } else {
+        32 + 4 + 1 + 107 + 4
+    };
+
+    Amount::from_sat((output_size + input_size) * DUST_RELAY_FEE_PER_VBYTE)
+}
+
+/// A witness program is a version opcode followed by a single push of 2 to 40 bytes.
+fn is_witness_program(script_pubkey: &Script) -> bool {
+    let bytes = script_pubkey.as_bytes();
+
+    (4..=42).contains(&bytes.len())
+        && (bytes[0] == opcodes::all::OP_PUSHBYTES_0.into_u8()
+            || (opcodes::all::OP_PUSHNUM_1.into_u8()..=opcodes::all::OP_PUSHNUM_16.into_u8())
+                .contains(&bytes[0]))
+        && bytes[1] as usize == bytes.len() - 2
+}
+
+/// The output the fund transaction locks the funds of both parties in.
+#[derive(Debug, Clone, PartialEq)]
+pub enum JointOutput {
+    /// A 2-of-2 P2WSH output which is spent with one ECDSA signature per party.
+    P2wsh {
+        X_from: secp256k1::PublicKey,
+        X_to: secp256k1::PublicKey,
+    },
+    /// A P2TR output which is spent through the key path with a single BIP340 signature.
+    ///
+    /// The output key is the MuSig2 aggregate of both parties' keys computed with
+    /// [`taproot_key_agg_context`]. On the blockchain such an output is
+    /// indistinguishable from any other single-key taproot output.
+    P2tr { output_key: [u8; 32] },
+    /// A P2WPKH output which is spent with a single ECDSA signature.
+    ///
+    /// The key is the joint public key of both parties computed with `two_party_ecdsa`.
+    P2wpkh { X: secp256k1::PublicKey },
+}
+
+impl JointOutput {
+    pub fn max_satisfaction_weight(&self) -> u64 {
+        match self {
+            JointOutput::P2wsh { X_from, X_to } => {
+                descriptor(X_from, X_to).max_satisfaction_weight() as u64
+            }
+            JointOutput::P2tr { .. } => TAPROOT_KEY_SPEND_SATISFACTION_WEIGHT,
+            JointOutput::P2wpkh { .. } => P2WPKH_SATISFACTION_WEIGHT,
+        }
+    }
+
+    /// Computes an upper bound for the weight of a signed transaction that spends this output to
+    /// `destination` alongside the anchor output.
+    pub fn spend_tx_weight(&self, destination: &Script) -> u64 {
+        spend_tx_weight(self.max_satisfaction_weight(), destination)
+    }
+
+    /// Computes the miner fee of the redeem and refund transactions, which pay the same fee
+    /// regardless of which one of them is heavier.
+    pub fn spend_tx_miner_fee(
+        &self,
+        feerate: FeeRate,
+        X_redeem: &bitcoin::Address,
+        X_refund: &bitcoin::Address,
+    ) -> bitcoin::Amount {
+        spend_tx_miner_fee(feerate, self.max_satisfaction_weight(), X_redeem, X_refund)
+    }
+
+    /// Describes the joint output to the wallet that signs the fund transaction.
+    ///
+    /// The keys of the joint output are not derived from any wallet, hence the output carries no
+    /// key origins, only the witness script if there is one.
+    fn psbt_output(&self) -> psbt::Output {
+        let witness_script = match self {
+            JointOutput::P2wsh { X_from, X_to } => Some(descriptor(X_from, X_to).witness_script()),
+            JointOutput::P2tr { .. } | JointOutput::P2wpkh { .. } => None,
+        };
+
+        psbt::Output {
+            witness_script,
+            ..Default::default()
+        }
+    }
+
+    fn script_pubkey(&self) -> Script {
+        match self {
+            JointOutput::P2wsh { X_from, X_to } => descriptor(X_from, X_to).script_pubkey(),
+            JointOutput::P2tr { output_key } => Builder::new()
+                .push_int(1)
+                .push_slice(output_key)
+                .into_script(),
+            JointOutput::P2wpkh { X } => Builder::new()
+                .push_int(0)
+                .push_slice(&hash160::Hash::hash(&X.serialize_compressed())[..])
+                .into_script(),
+        }
+    }
+
+    /// Computes the digest the parties sign to spend the joint output with the only input of
+    /// the transaction.
+    fn sighash(&self, transaction: &Transaction, fund_amount: bitcoin::Amount) -> SigHash {
+        match self {
+            JointOutput::P2wsh { X_from, X_to } => SighashComponents::new(transaction).sighash_all(
+                &transaction.input[0],
+                &descriptor(X_from, X_to).witness_script(),
+                fund_amount.as_sat(),
+            ),
+            JointOutput::P2tr { .. } => taproot_key_spend_sighash(
+                transaction,
+                &TxOut {
+                    value: fund_amount.as_sat(),
+                    script_pubkey: self.script_pubkey(),
+                },
+            ),
+            JointOutput::P2wpkh { X } => SighashComponents::new(transaction).sighash_all(
+                &transaction.input[0],
+                &p2wpkh_script_code(X),
+                fund_amount.as_sat(),
+            ),
+        }
+    }
+}
+
+/// The kinds of joint output the parties can agree on in `Params`.
+///
+/// The protocol actors sign the spend transactions of a [`JointOutput::P2wsh`] output with one
+/// ECDSA adaptor signature per party and those of a [`JointOutput::P2tr`] output with a single
+/// MuSig2 adaptor signature.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum JointOutputKind {
+    P2wsh,
+    P2tr,
+}
+
+impl JointOutputKind {
+    /// The maximum weight of the witness that spends a joint output of this kind, which does not
+    /// depend on the keys.
+    pub fn max_satisfaction_weight(self) -> u64 {
+        match self {
+            JointOutputKind::P2wsh => P2WSH_SATISFACTION_WEIGHT,
+            JointOutputKind::P2tr => TAPROOT_KEY_SPEND_SATISFACTION_WEIGHT,
+        }
+    }
+
+    /// Locks the joint output to the keys of the funding and the redeeming party.
+    pub fn joint_output(
+        self,
+        X_from: &secp256k1::PublicKey,
+        X_to: &secp256k1::PublicKey,
+    ) -> Result<JointOutput, secp256k1::musig::PointAtInfinity> {
+        let joint_output = match self {
+            JointOutputKind::P2wsh => JointOutput::P2wsh {
+                X_from: X_from.clone(),
+                X_to: X_to.clone(),
+            },
+            JointOutputKind::P2tr => JointOutput::P2tr {
+                output_key: taproot_key_agg_context(X_from, X_to)?.aggregate_key(),
+            },
+        };
+
+        Ok(joint_output)
+    }
+}
+
+/// Aggregates the keys of the funding and the redeeming party, in this order, into the output key
+/// of a [`JointOutput::P2tr`] output.
+pub fn taproot_key_agg_context(
+    X_from: &secp256k1::PublicKey,
+    X_to: &secp256k1::PublicKey,
+) -> Result<secp256k1::musig::KeyAggContext, secp256k1::musig::PointAtInfinity> {
+    secp256k1::musig::KeyAggContext::new(vec![X_from.clone(), X_to.clone()])?.with_taproot_tweak()
+}
+
+/// Builds the fund transaction that locks `fund_amount` in the joint output, the redeem
+/// transaction and the refund transactions that spend it.
+///
+/// The anchor output of the redeem transaction is locked to `X_redeem_anchor`, the one of the
+/// refund transactions to `X_refund_anchor`, i.e. the keys of the redeeming and the funding party.
 #[allow(clippy::too_many_arguments)]
 pub fn make_transactions(
-    partial_fund_transaction: Transaction,
+    partial_fund_psbt: Psbt,
     fund_amount: bitcoin::Amount,
     spend_amount: bitcoin::Amount,
-    X_fund_from: &secp256k1::PublicKey,
-    X_fund_to: &secp256k1::PublicKey,
-    refund_locktime: u32,
+    joint_output: &JointOutput,
+    X_redeem_anchor: &secp256k1::PublicKey,
+    X_refund_anchor: &secp256k1::PublicKey,
+    refund_expiry: Expiry,
     X_redeem: &bitcoin::Address,
     X_refund: &bitcoin::Address,
 ) -> Transactions {
-    let descriptor = descriptor(&X_fund_from, &X_fund_to);
-
     let fund_output = bitcoin::TxOut {
         value: fund_amount.as_sat(),
-        script_pubkey: descriptor.script_pubkey(),
+        script_pubkey: joint_output.script_pubkey(),
     };
 
-    let Transaction {
-        input,
-        output: existing_outputs,
-        lock_time,
-        version,
-    } = partial_fund_transaction;
-
     let joint_output_index = 0;
 
-    let mut outputs = Vec::with_capacity(existing_outputs.len() + 1);
+    // the metadata of the outputs is kept in the same order as the outputs themselves
+    let mut fund_psbt = partial_fund_psbt;
+    fund_psbt
+        .global
+        .unsigned_tx
+        .output
+        .insert(joint_output_index, fund_output);
+    fund_psbt
+        .outputs
+        .insert(joint_output_index, joint_output.psbt_output());
 
-    outputs.insert(joint_output_index, fund_output);
-    outputs.extend(existing_outputs);
-
-    let fund_transaction = bitcoin::Transaction {
-        input,
-        lock_time,
-        version,
-        output: outputs,
-    };
-
-    let input = TxIn {
+    // the script_sig of a native segwit spend is always empty
+    let redeem_input = TxIn {
         previous_output: bitcoin::OutPoint {
-            txid: fund_transaction.txid(),
+            txid: fund_psbt.global.unsigned_tx.txid(),
             vout: joint_output_index as u32,
         },
-        script_sig: descriptor.unsigned_script_sig(),
+        script_sig: Script::new(),
         sequence: 0xFFFF_FFFF,
         witness: Vec::new(),
     };
 
     let (redeem_transaction, redeem_tx_digest) = {
+        let input = redeem_input.clone();
         let output = make_spend_output(spend_amount, &X_redeem);
 
         let transaction = bitcoin::Transaction {
             version: 2,
             lock_time: 0,
-            input: vec![input.clone()],
-            output: vec![output],
+            input: vec![input],
+            output: vec![output, anchor_output(X_redeem_anchor)],
         };
 
-        let digest = SighashComponents::new(&transaction).sighash_all(
-            &input,
-            &descriptor.witness_script(),
-            fund_amount.as_sat(),
-        );
+        let digest = joint_output.sighash(&transaction, fund_amount);
 
         (transaction, digest)
     };
 
-    let (refund_transaction, refund_tx_digest) = {
-        let output = make_spend_output(spend_amount, &X_refund);
+    let make_refund = |amount: bitcoin::Amount| {
+        // a final input would disable the locktime and make the refund spendable immediately
+        let input = TxIn {
+            sequence: ENABLE_LOCKTIME_NO_RBF,
+            ..redeem_input.clone()
+        };
+        let output = make_spend_output(amount, &X_refund);
 
         let transaction = bitcoin::Transaction {
             version: 2,
-            lock_time: refund_locktime,
-            input: vec![input.clone()],
-            output: vec![output],
+            lock_time: refund_expiry.lock_time(),
+            input: vec![input],
+            output: vec![output, anchor_output(X_refund_anchor)],
         };
 
-        let digest = SighashComponents::new(&transaction).sighash_all(
-            &input,
-            &descriptor.witness_script(),
-            fund_amount.as_sat(),
-        );
+        let digest = joint_output.sighash(&transaction, fund_amount);
 
         (transaction, digest)
     };
 
+    let (refund_transaction, refund_tx_digest) = make_refund(spend_amount);
+
+    let (refundable, refund_fee) = refund_fee(fund_amount, spend_amount);
+    let bumped_refunds = bumped_refund_fees(fund_amount, spend_amount, X_refund)
+        .into_iter()
+        .map(|fee| {
+            let (transaction, digest) = make_refund(refundable - fee);
+
+            BumpedRefund {
+                fee,
+                transaction,
+                digest,
+            }
+        })
+        .collect();
+
     Transactions {
-        fund: fund_transaction,
+        fund_psbt,
         redeem: redeem_transaction,
         redeem_tx_digest,
         refund: refund_transaction,
         refund_tx_digest,
+        refund_fee,
+        bumped_refunds,
     }
 }
 
+/// Returns how many refund transactions `make_transactions` builds for these amounts, the refund
+/// transaction and the bumped refund transactions.
+///
+/// The count does not depend on the joint output, which lets a party commit to one MuSig2 nonce
+/// per refund transaction before it knows the key of its counterparty.
+pub fn refund_transaction_count(
+    fund_amount: bitcoin::Amount,
+    spend_amount: bitcoin::Amount,
+    X_refund: &bitcoin::Address,
+) -> usize {
+    1 + bumped_refund_fees(fund_amount, spend_amount, X_refund).len()
+}
+
+/// Computes the amount the refund transactions can pay out and the fee of the refund transaction.
+fn refund_fee(fund_amount: bitcoin::Amount, spend_amount: bitcoin::Amount) -> (Amount, Amount) {
+    let refundable = fund_amount
+        .checked_sub(ANCHOR_OUTPUT_VALUE)
+        .unwrap_or_else(|| Amount::from_sat(0));
+    let refund_fee = refundable
+        .checked_sub(spend_amount)
+        .unwrap_or_else(|| Amount::from_sat(0));
+
+    (refundable, refund_fee)
+}
+
+/// Computes the fees of the bumped refund transactions, which pay their additional fee out of the
+/// refunded amount. Rungs whose output would be dust are left out.
+fn bumped_refund_fees(
+    fund_amount: bitcoin::Amount,
+    spend_amount: bitcoin::Amount,
+    X_refund: &bitcoin::Address,
+) -> Vec<Amount> {
+    let (refundable, refund_fee) = refund_fee(fund_amount, spend_amount);
+    let dust = dust_threshold(&X_refund.script_pubkey());
+
+    REFUND_FEE_LADDER
+        .iter()
+        .map(|multiple| refund_fee * *multiple)
+        .take_while(|fee| refund_fee > Amount::from_sat(0) && *fee + dust <= refundable)
+        .collect()
+}
+
 pub fn complete_spend_transaction(
     mut transaction: Transaction,
     (X_from, mut sig_from): (secp256k1::PublicKey, secp256k1::Signature),
@@ -150,6 +987,33 @@ pub fn complete_spend_transaction(
     Ok(transaction)
 }
 
+/// Completes the key path spend of a [`JointOutput::P2tr`] output.
+pub fn complete_taproot_spend_transaction(
+    mut transaction: Transaction,
+    signature: &secp256k1::schnorr::Signature,
+) -> Transaction {
+    // a 64-byte signature implies SIGHASH_DEFAULT which commits to the whole transaction
+    transaction.input[0].witness = vec![signature.serialize().to_vec()];
+
+    transaction
+}
+
+/// Completes the spend of a [`JointOutput::P2wpkh`] output.
+pub fn complete_p2wpkh_spend_transaction(
+    mut transaction: Transaction,
+    X: &secp256k1::PublicKey,
+    mut signature: secp256k1::Signature,
+) -> Transaction {
+    signature.normalize_s();
+
+    let mut signature = signature.serialize_der().as_ref().to_vec();
+    signature.push(SigHashType::All as u8);
+
+    transaction.input[0].witness = vec![signature, X.serialize_compressed().to_vec()];
+
+    transaction
+}
+
 #[derive(thiserror::Error, Debug)]
 #[error("transaction does not spend anything")]
 pub struct NoInputs;
@@ -166,6 +1030,10 @@ pub struct EmptyWitnessStack;
 #[error("input has {0} witnesses, expected 3")]
 pub struct NotThreeWitnesses(usize);
 
+#[derive(thiserror::Error, Debug)]
+#[error("input has {0} witnesses, expected 1")]
+pub struct NotOneWitness(usize);
+
 pub fn extract_signature_by_key(
     spend_transaction: Transaction,
     digest: SigHash,
@@ -198,6 +1066,44 @@ pub fn extract_signature_by_key(
     Ok(sig_from)
 }
 
+/// Extracts the signature from the key path spend of a [`JointOutput::P2tr`] output.
+pub fn extract_taproot_signature(
+    spend_transaction: Transaction,
+    digest: SigHash,
+    output_key: &[u8; 32],
+) -> anyhow::Result<secp256k1::schnorr::Signature> {
+    let input = match spend_transaction.input.as_slice() {
+        [input] => input,
+        [] => bail!(NoInputs),
+        [inputs @ ..] => bail!(TooManyInputs(inputs.len())),
+    };
+
+    let signature = match input
+        .witness
+        .iter()
+        .map(|vec| vec.as_slice())
+        .collect::<Vec<_>>()
+        .as_slice()
+    {
+        [signature] => {
+            let mut bytes = [0u8; 64];
+            if signature.len() != bytes.len() {
+                bail!("unknown witness layout")
+            }
+            bytes.copy_from_slice(signature);
+
+            secp256k1::schnorr::Signature::parse(&bytes).context("unknown witness layout")?
+        }
+        [] => bail!(EmptyWitnessStack),
+        [witnesses @ ..] => bail!(NotOneWitness(witnesses.len())),
+    };
+
+    secp256k1::schnorr::verify(output_key, &digest.into_inner(), &signature)
+        .context("signature on witness stack does not verify against the output key")?;
+
+    Ok(signature)
+}
+
 fn descriptor(
     X_from: &secp256k1::PublicKey,
     X_to: &secp256k1::PublicKey,
@@ -215,6 +1121,36 @@ fn descriptor(
     miniscript::Descriptor::Wsh(miniscript)
 }
 
+/// Computes the BIP341 signature hash of a key path spend with `SIGHASH_DEFAULT` of the only
+/// input of the transaction.
+fn taproot_key_spend_sighash(transaction: &Transaction, prevout: &TxOut) -> SigHash {
+    fn sha256<T: Encodable>(items: &[T]) -> impl AsRef<[u8]> {
+        items
+            .iter()
+            .fold(Sha256::default(), |hasher, item| {
+                hasher.chain(serialize(item))
+            })
+            .result()
+    }
+    let input = &transaction.input[0];
+
+    let digest = tagged_hash(TAP_SIGHASH_TAG)
+        .chain(&[TAP_SIGHASH_EPOCH, SIGHASH_DEFAULT])
+        .chain(&transaction.version.to_le_bytes())
+        .chain(&transaction.lock_time.to_le_bytes())
+        .chain(sha256(&[input.previous_output]))
+        .chain(sha256(&[prevout.value]))
+        .chain(sha256(&[prevout.script_pubkey.clone()]))
+        .chain(sha256(&[input.sequence]))
+        .chain(sha256(&transaction.output))
+        // key path spend without annex of the first input
+        .chain(&[0u8])
+        .chain(&0u32.to_le_bytes())
+        .result();
+
+    SigHash::from_slice(&digest).expect("a SHA256 digest is 32 bytes")
+}
+
 fn make_spend_output(amount: bitcoin::Amount, X_to: &bitcoin::Address) -> TxOut {
     TxOut {
         value: amount.as_sat(),
@@ -222,6 +1158,192 @@ fn make_spend_output(amount: bitcoin::Amount, X_to: &bitcoin::Address) -> TxOut
     }
 }
 
+/// An output that lets the party publishing a spend transaction bump its fee with a child
+/// transaction.
+///
+/// Until the spend transaction has `ANCHOR_CSV_DELAY` confirmations, only the owner of `X` can
+/// spend the anchor, hence nobody else can pin the spend transaction with a large child. Afterwards
+/// anyone can sweep it so it does not linger in the UTXO set.
+fn anchor_output(X: &secp256k1::PublicKey) -> TxOut {
+    TxOut {
+        value: ANCHOR_OUTPUT_VALUE.as_sat(),
+        script_pubkey: v0_p2wsh(&anchor_witness_script(X)),
+    }
+}
+
+/// `<X> OP_CHECKSIG OP_IFDUP OP_NOTIF 16 OP_CSV OP_ENDIF`, the anchor script of BOLT 3.
+fn anchor_witness_script(X: &secp256k1::PublicKey) -> Script {
+    Builder::new()
+        .push_slice(&X.serialize_compressed())
+        .push_opcode(opcodes::all::OP_CHECKSIG)
+        .push_opcode(opcodes::all::OP_IFDUP)
+        .push_opcode(opcodes::all::OP_NOTIF)
+        .push_int(ANCHOR_CSV_DELAY)
+        .push_opcode(opcodes::all::OP_NOP3) // OP_CHECKSEQUENCEVERIFY
+        .push_opcode(opcodes::all::OP_ENDIF)
+        .into_script()
+}
+
+fn v0_p2wsh(witness_script: &Script) -> Script {
+    Builder::new()
+        .push_int(0)
+        .push_slice(&sha256::Hash::hash(witness_script.as_bytes())[..])
+        .into_script()
+}
+
+/// A P2WPKH output of the wallet of the party that bumps the fee of a spend transaction.
+#[derive(Debug, Clone)]
+pub struct FeeInput {
+    pub outpoint: OutPoint,
+    pub value: Amount,
+    pub key: secp256k1::KeyPair,
+}
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq)]
+#[error("fee input of {available} cannot pay the child fee of {required}")]
+pub struct InsufficientFeeInput {
+    available: Amount,
+    required: Amount,
+}
+
+#[derive(thiserror::Error, Debug, Clone, Copy, PartialEq)]
+#[error("transaction has no anchor output for the given key")]
+pub struct NoAnchorOutput;
+
+#[derive(thiserror::Error, Debug, Clone, Copy, PartialEq)]
+#[error("transaction does not spend an output of the fund transaction")]
+pub struct NotSpendingFundTransaction;
+
+/// Builds and signs a child transaction that spends the anchor output of a stuck redeem or refund
+/// transaction, so that both together pay `feerate`.
+///
+/// The fee the parent pays on its own is derived from the joint output of `fund` that it spends.
+/// `anchor_key` is the key of the party the anchor output is locked to, i.e. the redeeming party
+/// for a redeem transaction and the funding party for a refund transaction. The fee input pays
+/// for the bump, whatever is left of it goes to `change`.
+pub fn make_cpfp_transaction(
+    parent: &Transaction,
+    fund: &Transaction,
+    anchor_key: &secp256k1::KeyPair,
+    fee_input: &FeeInput,
+    feerate: FeeRate,
+    change: &Address,
+) -> anyhow::Result<Transaction> {
+    let X_anchor = anchor_key.to_pk();
+    if parent.output.get(ANCHOR_OUTPUT_INDEX as usize) != Some(&anchor_output(&X_anchor)) {
+        bail!(NoAnchorOutput)
+    }
+
+    let joint_output = match parent.input.as_slice() {
+        [input] if input.previous_output.txid == fund.txid() => fund
+            .output
+            .get(input.previous_output.vout as usize)
+            .ok_or(NotSpendingFundTransaction)?,
+        _ => bail!(NotSpendingFundTransaction),
+    };
+    let parent_outputs = parent.output.iter().map(|output| output.value).sum::<u64>();
+    let parent_fee = Amount::from_sat(joint_output.value)
+        .checked_sub(Amount::from_sat(parent_outputs))
+        .unwrap_or_else(|| Amount::from_sat(0));
+
+    let X = fee_input.key.to_pk();
+    let mut child = Transaction {
+        version: 2,
+        lock_time: 0,
+        input: vec![
+            TxIn {
+                previous_output: fee_input.outpoint,
+                script_sig: Script::new(),
+                sequence: 0xFFFF_FFFF,
+                witness: Vec::new(),
+            },
+            TxIn {
+                previous_output: OutPoint {
+                    txid: parent.txid(),
+                    vout: ANCHOR_OUTPUT_INDEX,
+                },
+                script_sig: Script::new(),
+                sequence: 0xFFFF_FFFF,
+                witness: Vec::new(),
+            },
+        ],
+        output: vec![make_spend_output(Amount::from_sat(0), change)],
+    };
+
+    // the segwit marker and flag are only serialized once the inputs have a witness
+    let child_weight =
+        child.get_weight() as u64 + 2 + P2WPKH_SATISFACTION_WEIGHT + ANCHOR_SATISFACTION_WEIGHT;
+    let package_fee = feerate.fee(parent.get_weight() as u64) + feerate.fee(child_weight);
+    // the child has to pay at least for itself to be relayed
+    let child_fee = std::cmp::max(
+        package_fee
+            .checked_sub(parent_fee)
+            .unwrap_or_else(|| Amount::from_sat(0)),
+        Amount::from_sat(vsize(child_weight)),
+    );
+
+    let available = fee_input.value + ANCHOR_OUTPUT_VALUE;
+    let required = child_fee + dust_threshold(&change.script_pubkey());
+    if available < required {
+        bail!(InsufficientFeeInput {
+            available,
+            required
+        })
+    }
+    child.output[0].value = (available - child_fee).as_sat();
+
+    let sighash_components = SighashComponents::new(&child);
+    let fee_input_digest = sighash_components.sighash_all(
+        &child.input[0],
+        &p2wpkh_script_code(&X),
+        fee_input.value.as_sat(),
+    );
+    let anchor_witness_script = anchor_witness_script(&X_anchor);
+    let anchor_digest = sighash_components.sighash_all(
+        &child.input[1],
+        &anchor_witness_script,
+        ANCHOR_OUTPUT_VALUE.as_sat(),
+    );
+
+    child.input[0].witness = vec![
+        sign_all(fee_input_digest, &fee_input.key),
+        X.serialize_compressed().to_vec(),
+    ];
+    child.input[1].witness = vec![
+        sign_all(anchor_digest, anchor_key),
+        anchor_witness_script.into_bytes(),
+    ];
+
+    Ok(child)
+}
+
+/// Signs a SIGHASH_ALL digest and serializes the signature the way a witness carries it.
+fn sign_all(digest: SigHash, x: &secp256k1::KeyPair) -> Vec<u8> {
+    let mut signature = secp256k1::sign(digest, x);
+    signature.normalize_s();
+
+    let mut signature = signature.serialize_der().as_ref().to_vec();
+    signature.push(SigHashType::All as u8);
+
+    signature
+}
+
+/// Converts a weight to virtual bytes, rounding up.
+fn vsize(weight: u64) -> u64 {
+    (weight + 3) / 4
+}
+
+/// BIP143 defines the script code of a P2WPKH output to be the P2PKH script of the key.
+fn p2wpkh_script_code(X: &secp256k1::PublicKey) -> Script {
+    Builder::new()
+        .push_opcode(opcodes::all::OP_DUP)
+        .push_opcode(opcodes::all::OP_HASH160)
+        .push_slice(&hash160::Hash::hash(&X.serialize_compressed())[..])
+        .push_opcode(opcodes::all::OP_EQUALVERIFY)
+        .push_opcode(opcodes::all::OP_CHECKSIG)
+        .into_script()
+}
+
 impl ToMessage for SigHash {
     fn to_message(&self) -> [u8; 32] {
         self.into_inner()
@@ -250,18 +1372,660 @@ mod tests {
     }
 
     #[test]
-    fn max_satisfaction_weight() {
-        let descriptor = descriptor(
-            &secp256k1::PublicKey::from_secret_key(
-                &secp256k1::SecretKey::random(&mut thread_rng()),
+    fn spend_tx_weight_bounds_signed_transaction() {
+        let x_from = secp256k1::KeyPair::random(&mut thread_rng());
+        let x_to = secp256k1::KeyPair::random(&mut thread_rng());
+        let joint_output = JointOutput::P2wsh {
+            X_from: x_from.to_pk(),
+            X_to: x_to.to_pk(),
+        };
+        let address = "bcrt1q6rhpng9evdsfnn833a4f4vej0asu6dk5srld6x"
+            .parse::<Address>()
+            .unwrap();
+        let transactions = make_transactions(
+            empty_psbt(),
+            Amount::from_sat(10_000),
+            Amount::from_sat(5_000),
+            &joint_output,
+            &x_to.to_pk(),
+            &x_from.to_pk(),
+            Expiry::block_height(100).unwrap(),
+            &address,
+            &address,
+        );
+
+        let p2wpkh = Builder::new()
+            .push_int(0)
+            .push_slice(&[0; 20])
+            .into_script();
+        let p2wsh = Builder::new()
+            .push_int(0)
+            .push_slice(&[0; 32])
+            .into_script();
+        let p2tr = Builder::new()
+            .push_int(1)
+            .push_slice(&[0; 32])
+            .into_script();
+        let p2pkh = p2wpkh_script_code(&x_from.to_pk());
+
+        for destination in vec![p2wpkh, p2wsh, p2tr, p2pkh] {
+            let mut redeem = transactions.redeem.clone();
+            redeem.output[0].script_pubkey = destination.clone();
+
+            let digest = joint_output.sighash(&redeem, Amount::from_sat(10_000));
+            let redeem = complete_spend_transaction(
+                redeem,
+                (x_from.to_pk(), secp256k1::sign(digest, &x_from)),
+                (x_to.to_pk(), secp256k1::sign(digest, &x_to)),
+            )
+            .unwrap();
+
+            let estimate = joint_output.spend_tx_weight(&destination);
+            let actual = redeem.get_weight() as u64;
+            // DER-encoded signatures are at most 73 bytes including the sighash type
+            assert!(estimate >= actual, "{} < {}", estimate, actual);
+            assert!(estimate - actual <= 8, "{} - {} > 8", estimate, actual);
+        }
+    }
+
+    #[test]
+    fn spend_tx_miner_fee_supports_feerate_per_vbyte() {
+        let p2wpkh = "bcrt1q6rhpng9evdsfnn833a4f4vej0asu6dk5srld6x"
+            .parse::<Address>()
+            .unwrap();
+        let X = secp256k1::KeyPair::random(&mut thread_rng()).to_pk();
+        let p2pkh = Address::p2pkh(
+            &::bitcoin::PublicKey::from_slice(&X.serialize_compressed()).unwrap(),
+            ::bitcoin::Network::Regtest,
+        );
+
+        let per_wu = spend_tx_miner_fee(
+            FeeRate::PerWeightUnit(Amount::from_sat(1)),
+            P2WSH_SATISFACTION_WEIGHT,
+            &p2wpkh,
+            &p2wpkh,
+        );
+        let per_vbyte = spend_tx_miner_fee(
+            FeeRate::PerVbyte(Amount::from_sat(1)),
+            P2WSH_SATISFACTION_WEIGHT,
+            &p2wpkh,
+            &p2wpkh,
+        );
+        assert_eq!(per_vbyte.as_sat(), (per_wu.as_sat() + 3) / 4);
+
+        // a P2PKH output is 3 bytes longer than a P2WPKH output
+        let mixed = spend_tx_miner_fee(
+            FeeRate::PerWeightUnit(Amount::from_sat(1)),
+            P2WSH_SATISFACTION_WEIGHT,
+            &p2wpkh,
+            &p2pkh,
+        );
+        assert_eq!(mixed.as_sat(), per_wu.as_sat() + 3 * 4);
+    }
+
+    #[test]
+    fn p2wsh_satisfaction_weight_matches_descriptor() {
+        let joint_output = JointOutput::P2wsh {
+            X_from: secp256k1::KeyPair::random(&mut thread_rng()).to_pk(),
+            X_to: secp256k1::KeyPair::random(&mut thread_rng()).to_pk(),
+        };
+
+        assert_eq!(
+            joint_output.max_satisfaction_weight(),
+            P2WSH_SATISFACTION_WEIGHT
+        );
+    }
+
+    #[test]
+    fn taproot_joint_output_is_spent_with_one_signature() {
+        use crate::secp256k1::{musig, schnorr};
+
+        let x_from = secp256k1::KeyPair::random(&mut thread_rng());
+        let x_to = secp256k1::KeyPair::random(&mut thread_rng());
+        let context = taproot_key_agg_context(&x_from.to_pk(), &x_to.to_pk()).unwrap();
+        let output_key = context.aggregate_key();
+        let address = "bcrt1q6rhpng9evdsfnn833a4f4vej0asu6dk5srld6x"
+            .parse::<Address>()
+            .unwrap();
+
+        let transactions = make_transactions(
+            empty_psbt(),
+            Amount::from_sat(10_000),
+            Amount::from_sat(5_000),
+            &JointOutputKind::P2tr
+                .joint_output(&x_from.to_pk(), &x_to.to_pk())
+                .unwrap(),
+            &x_to.to_pk(),
+            &x_from.to_pk(),
+            Expiry::block_height(100).unwrap(),
+            &address,
+            &address,
+        );
+        assert_eq!(
+            &transactions.fund().output[0].script_pubkey.as_bytes()[2..],
+            &output_key[..]
+        );
+        assert_ne!(transactions.redeem_tx_digest, transactions.refund_tx_digest);
+
+        let message = transactions.redeem_tx_digest.into_inner();
+        let (nonces, public_nonces): (Vec<_>, Vec<_>) = vec![&x_from, &x_to]
+            .into_iter()
+            .map(|x| musig::nonce_gen(x, None, None, &mut thread_rng()))
+            .unzip();
+        let session = musig::Session::new(&context, &public_nonces, &message, None).unwrap();
+        let partial_signatures = nonces
+            .into_iter()
+            .zip(vec![&x_from, &x_to])
+            .map(|(nonce, x)| session.partial_sign(&context, nonce, x).unwrap())
+            .collect::<Vec<_>>();
+        let signature = session.aggregate(&context, &partial_signatures).unwrap();
+        schnorr::verify(&output_key, &message, &signature).unwrap();
+
+        let unsigned_weight = transactions.redeem.get_weight() as u64;
+        let redeem = complete_taproot_spend_transaction(transactions.redeem.clone(), &signature);
+
+        // the segwit marker and flag add another 2 WU once the transaction has a witness
+        assert_eq!(
+            redeem.get_weight() as u64 - unsigned_weight,
+            TAPROOT_KEY_SPEND_SATISFACTION_WEIGHT + 2
+        );
+        assert_eq!(
+            extract_taproot_signature(redeem, transactions.redeem_tx_digest, &output_key).unwrap(),
+            signature
+        );
+        assert!(extract_taproot_signature(
+            transactions.redeem,
+            transactions.redeem_tx_digest,
+            &output_key
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn p2wpkh_joint_output_is_spent_with_joint_signature() {
+        let x = secp256k1::KeyPair::random(&mut thread_rng());
+        let address = "bcrt1q6rhpng9evdsfnn833a4f4vej0asu6dk5srld6x"
+            .parse::<Address>()
+            .unwrap();
+
+        let transactions = make_transactions(
+            empty_psbt(),
+            Amount::from_sat(10_000),
+            Amount::from_sat(5_000),
+            &JointOutput::P2wpkh { X: x.to_pk() },
+            &x.to_pk(),
+            &x.to_pk(),
+            Expiry::block_height(100).unwrap(),
+            &address,
+            &address,
+        );
+
+        let signature = secp256k1::sign(transactions.redeem_tx_digest, &x);
+        let redeem = complete_p2wpkh_spend_transaction(transactions.redeem, &x.to_pk(), signature);
+
+        transactions.fund().output[0]
+            .script_pubkey
+            .verify(0, 10_000, &serialize(&redeem))
+            .unwrap();
+    }
+
+    #[test]
+    fn fund_psbt_describes_joint_output() {
+        let X_from = secp256k1::KeyPair::random(&mut thread_rng()).to_pk();
+        let X_to = secp256k1::KeyPair::random(&mut thread_rng()).to_pk();
+        let address = "bcrt1q6rhpng9evdsfnn833a4f4vej0asu6dk5srld6x"
+            .parse::<Address>()
+            .unwrap();
+        let change_witness_script = Builder::new()
+            .push_opcode(opcodes::all::OP_PUSHNUM_1)
+            .into_script();
+
+        let mut partial_fund_psbt = Psbt::from_unsigned_tx(Transaction {
+            version: 2,
+            lock_time: 0,
+            input: Vec::new(),
+            output: vec![TxOut {
+                value: 1_000,
+                script_pubkey: address.script_pubkey(),
+            }],
+        })
+        .unwrap();
+        partial_fund_psbt.outputs[0].witness_script = Some(change_witness_script.clone());
+
+        let transactions = make_transactions(
+            partial_fund_psbt,
+            Amount::from_sat(10_000),
+            Amount::from_sat(5_000),
+            &JointOutputKind::P2wsh.joint_output(&X_from, &X_to).unwrap(),
+            &X_to,
+            &X_from,
+            Expiry::block_height(100).unwrap(),
+            &address,
+            &address,
+        );
+        let fund_psbt = transactions.fund_psbt;
+
+        assert_eq!(
+            fund_psbt.outputs[0].witness_script,
+            Some(descriptor(&X_from, &X_to).witness_script())
+        );
+        assert_eq!(
+            fund_psbt.outputs[1].witness_script,
+            Some(change_witness_script),
+            "metadata of the change output moves along with it"
+        );
+    }
+
+    #[test]
+    fn partial_fund_psbt_is_validated() {
+        let address = "bcrt1q6rhpng9evdsfnn833a4f4vej0asu6dk5srld6x"
+            .parse::<Address>()
+            .unwrap();
+        let mut psbt = Psbt::from_unsigned_tx(Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: OutPoint::default(),
+                script_sig: Script::new(),
+                sequence: 0xFFFF_FFFF,
+                witness: Vec::new(),
+            }],
+            output: vec![TxOut {
+                value: 5_000,
+                script_pubkey: address.script_pubkey(),
+            }],
+        })
+        .unwrap();
+        let joint_output_value = Amount::from_sat(10_000);
+
+        assert_eq!(
+            validate_partial_fund_psbt(&psbt, joint_output_value),
+            Err(InvalidPartialFundTransaction::UnknownPrevout(0))
+        );
+
+        psbt.inputs[0].witness_utxo = Some(TxOut {
+            value: 14_000,
+            script_pubkey: address.script_pubkey(),
+        });
+        assert_eq!(
+            validate_partial_fund_psbt(&psbt, joint_output_value),
+            Err(InvalidPartialFundTransaction::InsufficientValue {
+                inputs: Amount::from_sat(14_000),
+                joint_output: joint_output_value,
+                change: Amount::from_sat(5_000),
+            })
+        );
+
+        psbt.global.unsigned_tx.output[0].value = 200;
+        assert_eq!(
+            validate_partial_fund_psbt(&psbt, joint_output_value),
+            Err(InvalidPartialFundTransaction::DustChange {
+                index: 0,
+                value: Amount::from_sat(200),
+                threshold: Amount::from_sat(294),
+            })
+        );
+
+        psbt.global.unsigned_tx.output[0].value = 3_000;
+        validate_partial_fund_psbt(&psbt, joint_output_value).unwrap();
+
+        psbt.inputs[0].final_script_witness = Some(vec![vec![0; 72]]);
+        assert_eq!(
+            validate_partial_fund_psbt(&psbt, joint_output_value),
+            Err(InvalidPartialFundTransaction::AlreadySigned(0))
+        );
+    }
+
+    #[test]
+    fn partial_fund_psbt_metadata_must_match_transaction() {
+        let address = "bcrt1q6rhpng9evdsfnn833a4f4vej0asu6dk5srld6x"
+            .parse::<Address>()
+            .unwrap();
+        let mut psbt = Psbt::from_unsigned_tx(Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: OutPoint::default(),
+                script_sig: Script::new(),
+                sequence: 0xFFFF_FFFF,
+                witness: Vec::new(),
+            }],
+            output: vec![TxOut {
+                value: 3_000,
+                script_pubkey: address.script_pubkey(),
+            }],
+        })
+        .unwrap();
+        psbt.inputs[0].witness_utxo = Some(TxOut {
+            value: 14_000,
+            script_pubkey: address.script_pubkey(),
+        });
+        let joint_output_value = Amount::from_sat(10_000);
+
+        // an unsigned input without metadata must not be skipped
+        psbt.global.unsigned_tx.input.push(TxIn {
+            previous_output: OutPoint::default(),
+            script_sig: Script::new(),
+            sequence: 0xFFFF_FFFF,
+            witness: Vec::new(),
+        });
+        assert_eq!(
+            validate_partial_fund_psbt(&psbt, joint_output_value),
+            Err(InvalidPartialFundTransaction::InputCountMismatch {
+                inputs: 2,
+                metadata: 1,
+            })
+        );
+        psbt.global.unsigned_tx.input.pop();
+
+        psbt.outputs.push(Default::default());
+        assert_eq!(
+            validate_partial_fund_psbt(&psbt, joint_output_value),
+            Err(InvalidPartialFundTransaction::OutputCountMismatch {
+                outputs: 1,
+                metadata: 2,
+            })
+        );
+        psbt.outputs.pop();
+
+        validate_partial_fund_psbt(&psbt, joint_output_value).unwrap();
+    }
+
+    #[test]
+    fn cpfp_transaction_bumps_package_to_target_feerate() {
+        let x = secp256k1::KeyPair::random(&mut thread_rng());
+        let address = Address::p2wpkh(
+            &::bitcoin::PublicKey::from_slice(&x.to_pk().serialize_compressed()).unwrap(),
+            ::bitcoin::Network::Regtest,
+        );
+        let x_from = secp256k1::KeyPair::random(&mut thread_rng());
+        let x_to = secp256k1::KeyPair::random(&mut thread_rng());
+        let transactions = make_transactions(
+            empty_psbt(),
+            Amount::from_sat(10_000),
+            Amount::from_sat(5_000),
+            &JointOutputKind::P2wsh
+                .joint_output(&x_from.to_pk(), &x_to.to_pk())
+                .unwrap(),
+            &x_to.to_pk(),
+            &x_from.to_pk(),
+            Expiry::block_height(100).unwrap(),
+            &address,
+            &address,
+        );
+        let fund = transactions.fund().clone();
+        let parent = transactions.refund;
+        let parent_fee = Amount::from_sat(5_000) - ANCHOR_OUTPUT_VALUE;
+        let feerate = FeeRate::PerVbyte(Amount::from_sat(100));
+        let fee_input = FeeInput {
+            outpoint: OutPoint::default(),
+            value: Amount::from_sat(50_000),
+            key: x,
+        };
+
+        let child =
+            make_cpfp_transaction(&parent, &fund, &x_from, &fee_input, feerate, &address).unwrap();
+
+        address
+            .script_pubkey()
+            .verify(0, fee_input.value.as_sat(), &serialize(&child))
+            .unwrap();
+        parent.output[ANCHOR_OUTPUT_INDEX as usize]
+            .script_pubkey
+            .verify(1, ANCHOR_OUTPUT_VALUE.as_sat(), &serialize(&child))
+            .unwrap();
+
+        let child_fee = (fee_input.value + ANCHOR_OUTPUT_VALUE).as_sat() - child.output[0].value;
+        let package_fee =
+            feerate.fee(parent.get_weight() as u64) + feerate.fee(child.get_weight() as u64);
+        assert!(parent_fee.as_sat() + child_fee >= package_fee.as_sat());
+
+        // the anchor of the refund transaction belongs to the funding party
+        let error = make_cpfp_transaction(&parent, &fund, &x_to, &fee_input, feerate, &address)
+            .unwrap_err();
+        assert!(error.is::<NoAnchorOutput>());
+
+        let error = make_cpfp_transaction(&parent, &parent, &x_from, &fee_input, feerate, &address)
+            .unwrap_err();
+        assert!(error.is::<NotSpendingFundTransaction>());
+
+        let error = make_cpfp_transaction(
+            &parent,
+            &fund,
+            &x_from,
+            &FeeInput {
+                value: Amount::from_sat(1_000),
+                ..fee_input.clone()
+            },
+            feerate,
+            &address,
+        )
+        .unwrap_err();
+        assert!(error.is::<InsufficientFeeInput>());
+
+        let error = make_cpfp_transaction(&fund, &fund, &x_from, &fee_input, feerate, &address)
+            .unwrap_err();
+        assert!(error.is::<NoAnchorOutput>());
+    }
+
+    #[test]
+    fn refund_transaction_enforces_locktime() {
+        let expiry = Expiry::block_height(100).unwrap();
+        let address = "bcrt1q6rhpng9evdsfnn833a4f4vej0asu6dk5srld6x"
+            .parse::<Address>()
+            .unwrap();
+
+        let X_from = secp256k1::KeyPair::random(&mut thread_rng()).to_pk();
+        let X_to = secp256k1::KeyPair::random(&mut thread_rng()).to_pk();
+
+        let transactions = make_transactions(
+            empty_psbt(),
+            Amount::from_sat(10_000),
+            Amount::from_sat(5_000),
+            &JointOutputKind::P2wsh.joint_output(&X_from, &X_to).unwrap(),
+            &X_to,
+            &X_from,
+            expiry,
+            &address,
+            &address,
+        );
+
+        assert_eq!(transactions.refund.lock_time, expiry.lock_time());
+        assert!(transactions.refund.input[0].sequence < 0xFFFF_FFFF);
+    }
+
+    #[test]
+    fn refund_ladder_bumps_fee_at_same_locktime() {
+        let expiry = Expiry::block_height(100).unwrap();
+        let address = "bcrt1q6rhpng9evdsfnn833a4f4vej0asu6dk5srld6x"
+            .parse::<Address>()
+            .unwrap();
+        let x_from = secp256k1::KeyPair::random(&mut thread_rng());
+        let x_to = secp256k1::KeyPair::random(&mut thread_rng());
+
+        let transactions = make_transactions(
+            empty_psbt(),
+            Amount::from_sat(100_000),
+            Amount::from_sat(99_000),
+            &JointOutputKind::P2wsh
+                .joint_output(&x_from.to_pk(), &x_to.to_pk())
+                .unwrap(),
+            &x_to.to_pk(),
+            &x_from.to_pk(),
+            expiry,
+            &address,
+            &address,
+        );
+
+        assert_eq!(transactions.bumped_refunds.len(), REFUND_FEE_LADDER.len());
+        assert_eq!(
+            refund_transaction_count(
+                Amount::from_sat(100_000),
+                Amount::from_sat(99_000),
+                &address
             ),
-            &secp256k1::PublicKey::from_secret_key(
-                &secp256k1::SecretKey::random(&mut thread_rng()),
+            transactions.refund_digests().len()
+        );
+        let mut previous_fee = transactions.refund_fee;
+        for refund in transactions.bumped_refunds.iter() {
+            assert!(refund.fee > previous_fee);
+            assert_eq!(refund.transaction.lock_time, expiry.lock_time());
+            assert_eq!(
+                refund.transaction.input[0].previous_output,
+                transactions.refund.input[0].previous_output
+            );
+            previous_fee = refund.fee;
+        }
+
+        let sign_all = |x: &secp256k1::KeyPair| {
+            transactions
+                .refund_digests()
+                .into_iter()
+                .map(|digest| secp256k1::sign(digest, x))
+                .collect::<Vec<_>>()
+        };
+        let signed_refunds = transactions
+            .complete_refunds(
+                (&x_from.to_pk(), sign_all(&x_from)),
+                (&x_to.to_pk(), sign_all(&x_to)),
+            )
+            .unwrap();
+
+        assert_eq!(signed_refunds.refund().txid(), transactions.refund.txid());
+
+        let weight = signed_refunds.refund().get_weight() as u64;
+        let feerate = (1..)
+            .map(|sats| FeeRate::PerVbyte(Amount::from_sat(sats)))
+            .find(|feerate| feerate.fee(weight) > transactions.refund_fee)
+            .unwrap();
+        let expected = transactions
+            .bumped_refunds
+            .iter()
+            .find(|refund| refund.fee >= feerate.fee(weight))
+            .unwrap();
+        assert_eq!(
+            signed_refunds.for_feerate(feerate).unwrap().txid(),
+            expected.transaction.txid()
+        );
+
+        let unaffordable = FeeRate::PerWeightUnit(Amount::from_sat(1_000_000));
+        assert_eq!(
+            signed_refunds.for_feerate(unaffordable),
+            Err(NoRefundForFeerate(unaffordable))
+        );
+    }
+
+    #[test]
+    fn timelock_policy_requires_safety_margin() {
+        let promise_expiry = Expiry::block_height(100).unwrap();
+
+        let policy = TimelockPolicy::from_safety_margin(promise_expiry, 6).unwrap();
+        assert_eq!(policy.solver_expiry, Expiry::BlockHeight(106));
+
+        assert_eq!(
+            TimelockPolicy::new(promise_expiry, Expiry::BlockHeight(105), 6),
+            Err(UnsafeTimelocks::InsufficientSafetyMargin {
+                promise: 100,
+                solver: 105,
+                safety_margin: 6
+            })
+        );
+        assert_eq!(
+            TimelockPolicy::new(Expiry::BlockHeight(106), promise_expiry, 6),
+            Err(UnsafeTimelocks::InsufficientSafetyMargin {
+                promise: 106,
+                solver: 100,
+                safety_margin: 6
+            })
+        );
+        assert_eq!(
+            TimelockPolicy::new(promise_expiry, promise_expiry, 0),
+            Err(UnsafeTimelocks::ZeroSafetyMargin)
+        );
+        assert_eq!(
+            TimelockPolicy::new(promise_expiry, Expiry::Timestamp(LOCKTIME_THRESHOLD), 6),
+            Err(UnsafeTimelocks::MixedExpiryKinds)
+        );
+        assert_eq!(
+            TimelockPolicy::from_safety_margin(Expiry::BlockHeight(LOCKTIME_THRESHOLD - 1), 6),
+            Err(UnsafeTimelocks::SolverExpiryOverflow)
+        );
+        assert_eq!(
+            TimelockPolicy::new(
+                Expiry::BlockHeight(LOCKTIME_THRESHOLD),
+                Expiry::BlockHeight(LOCKTIME_THRESHOLD + 6),
+                6
             ),
+            Err(UnsafeTimelocks::ExpiryOutOfRange(LOCKTIME_THRESHOLD))
+        );
+    }
+
+    #[test]
+    fn deserializing_timelock_policy_validates_it() {
+        let promise_expiry = Expiry::block_height(100).unwrap();
+        let policy = TimelockPolicy::from_safety_margin(promise_expiry, 6).unwrap();
+
+        let bytes = serde_cbor::to_vec(&policy).unwrap();
+        assert_eq!(
+            serde_cbor::from_slice::<TimelockPolicy>(&bytes).unwrap(),
+            policy
         );
 
-        let max_weight = descriptor.max_satisfaction_weight() as u64;
+        let unsafe_policy = TimelockPolicy {
+            solver_expiry: promise_expiry,
+            ..policy
+        };
+        let bytes = serde_cbor::to_vec(&unsafe_policy).unwrap();
+        assert!(serde_cbor::from_slice::<TimelockPolicy>(&bytes).is_err());
+    }
+
+    #[test]
+    fn deserializing_expiry_checks_locktime_threshold() {
+        let expiry = Expiry::block_height(100).unwrap();
+        let bytes = serde_cbor::to_vec(&expiry).unwrap();
+        assert_eq!(serde_cbor::from_slice::<Expiry>(&bytes).unwrap(), expiry);
+
+        // both would be interpreted as the other kind of expiry in nLockTime
+        for expiry in &[
+            Expiry::BlockHeight(600_000_000),
+            Expiry::Timestamp(LOCKTIME_THRESHOLD - 1),
+        ] {
+            let bytes = serde_cbor::to_vec(expiry).unwrap();
+            assert!(serde_cbor::from_slice::<Expiry>(&bytes).is_err());
+        }
+
+        // a policy with two out-of-range expiries of the same kind is otherwise consistent
+        let policy = TimelockPolicy {
+            promise_expiry: Expiry::BlockHeight(600_000_000),
+            solver_expiry: Expiry::BlockHeight(600_000_006),
+            safety_margin: 6,
+        };
+        let bytes = serde_cbor::to_vec(&policy).unwrap();
+        assert!(serde_cbor::from_slice::<TimelockPolicy>(&bytes).is_err());
+    }
+
+    #[test]
+    fn expiry_kind_matches_locktime_threshold() {
+        assert!(Expiry::block_height(LOCKTIME_THRESHOLD).is_err());
+        assert!(Expiry::timestamp(LOCKTIME_THRESHOLD - 1).is_err());
 
-        assert_eq!(max_weight, MAX_SATISFACTION_WEIGHT)
+        assert_eq!(
+            Expiry::from_lock_time(LOCKTIME_THRESHOLD - 1),
+            Expiry::BlockHeight(LOCKTIME_THRESHOLD - 1)
+        );
+        assert_eq!(
+            Expiry::from_lock_time(LOCKTIME_THRESHOLD),
+            Expiry::Timestamp(LOCKTIME_THRESHOLD)
+        );
+    }
+
+    fn empty_psbt() -> Psbt {
+        Psbt::from_unsigned_tx(Transaction {
+            version: 2,
+            lock_time: 0,
+            input: Vec::new(),
+            output: Vec::new(),
+        })
+        .unwrap()
     }
 }
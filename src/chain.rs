@@ -0,0 +1,70 @@
+//! Access to the Bitcoin blockchain.
+//!
+//! [`Blockchain`] abstracts over the backend so that actors can react to chain events regardless
+//! of whether they are talking to a bitcoind node ([`bitcoind::Client`]) or to a chain simulated in
+//! memory ([`InMemoryBlockchain`]).
+
+pub mod bitcoind;
+mod in_memory;
+
+pub use self::in_memory::{
+    DoubleSpend, InMemoryBlockchain, InsufficientFee, InsufficientInputValue, InvalidScript,
+    MissingInput, NonFinal, NonFinalSequence,
+};
+use crate::bitcoin::{Expiry, OutPoint, Transaction, Txid};
+
+pub trait Blockchain {
+    /// Broadcasts the transaction and returns its id.
+    fn broadcast(&self, transaction: &Transaction) -> anyhow::Result<Txid>;
+
+    /// Returns the number of confirmations of the transaction.
+    ///
+    /// A transaction that is in the mempool has zero confirmations, an unknown transaction has
+    /// none.
+    fn confirmations(&self, txid: &Txid) -> anyhow::Result<Option<u32>>;
+
+    /// Returns the transaction that spends the given output, if any.
+    fn spending_transaction(&self, outpoint: &OutPoint) -> anyhow::Result<Option<Transaction>>;
+
+    /// Returns the height of the most recent block.
+    fn height(&self) -> anyhow::Result<u32>;
+
+    /// Returns the median time past of the most recent block as defined in BIP113.
+    fn median_time_past(&self) -> anyhow::Result<u32>;
+
+    /// Returns whether a transaction with the given expiry as its nLockTime can be included in the
+    /// next block.
+    fn is_expired(&self, expiry: Expiry) -> anyhow::Result<bool> {
+        let is_expired = match expiry {
+            Expiry::BlockHeight(height) => height <= self.height()?,
+            Expiry::Timestamp(timestamp) => timestamp < self.median_time_past()?,
+        };
+
+        Ok(is_expired)
+    }
+}
+
+impl<B> Blockchain for &B
+where
+    B: Blockchain + ?Sized,
+{
+    fn broadcast(&self, transaction: &Transaction) -> anyhow::Result<Txid> {
+        (**self).broadcast(transaction)
+    }
+
+    fn confirmations(&self, txid: &Txid) -> anyhow::Result<Option<u32>> {
+        (**self).confirmations(txid)
+    }
+
+    fn spending_transaction(&self, outpoint: &OutPoint) -> anyhow::Result<Option<Transaction>> {
+        (**self).spending_transaction(outpoint)
+    }
+
+    fn height(&self) -> anyhow::Result<u32> {
+        (**self).height()
+    }
+
+    fn median_time_past(&self) -> anyhow::Result<u32> {
+        (**self).median_time_past()
+    }
+}
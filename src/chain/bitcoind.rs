@@ -0,0 +1,171 @@
+//! A [`Blockchain`] backed by the JSON-RPC interface of bitcoind.
+
+use crate::bitcoin::{OutPoint, Transaction, Txid};
+use crate::chain::Blockchain;
+use ::bitcoin::{
+    consensus::{deserialize, encode::serialize_hex},
+    hashes::hex::FromHex,
+    Block,
+};
+use anyhow::Context;
+use serde::{de::DeserializeOwned, Deserialize};
+
+/// Error code bitcoind returns if a transaction or block is unknown.
+const RPC_INVALID_ADDRESS_OR_KEY: i32 = -5;
+
+#[derive(Debug, Clone)]
+pub struct Client {
+    url: String,
+    birthday: u32,
+}
+
+impl Client {
+    /// Creates a client for the bitcoind node at the given URL, including the credentials.
+    ///
+    /// bitcoind cannot look up the transaction spending an output, hence
+    /// [`Blockchain::spending_transaction`] scans the mempool and all blocks down to the
+    /// `birthday` height. The node must run with `-txindex` for
+    /// [`Blockchain::confirmations`] to find confirmed transactions.
+    pub fn new(url: impl Into<String>, birthday: u32) -> Self {
+        Self {
+            url: url.into(),
+            birthday,
+        }
+    }
+
+    fn rpc<T>(&self, method: &str, params: ureq::SerdeValue) -> anyhow::Result<T>
+    where
+        T: DeserializeOwned,
+    {
+        let response = ureq::post(&self.url).send_json(ureq::json!({
+            "jsonrpc": "1.0",
+            "method": method,
+            "params": params
+        }));
+
+        if let Some(error) = response.synthetic_error() {
+            anyhow::bail!("failed to reach bitcoind: {}", error)
+        }
+
+        let json = response.into_json()?;
+
+        match JsonRpcResponse::<T>::deserialize(json)? {
+            JsonRpcResponse {
+                result: Some(t), ..
+            } => Ok(t),
+            JsonRpcResponse { error: Some(e), .. } => Err(e.into()),
+            _ => Err(anyhow::anyhow!("invalid jsonrpc")),
+        }
+    }
+
+    fn raw_transaction(&self, txid: &Txid) -> anyhow::Result<Transaction> {
+        let hex = self.rpc::<String>("getrawtransaction", ureq::json!([txid.to_string()]))?;
+
+        decode(&hex)
+    }
+
+    fn block(&self, height: u32) -> anyhow::Result<Block> {
+        let hash = self.rpc::<String>("getblockhash", ureq::json!([height]))?;
+        let hex = self.rpc::<String>("getblock", ureq::json!([hash, 0]))?;
+
+        decode(&hex)
+    }
+}
+
+impl Blockchain for Client {
+    fn broadcast(&self, transaction: &Transaction) -> anyhow::Result<Txid> {
+        let txid = self
+            .rpc::<String>(
+                "sendrawtransaction",
+                ureq::json!([serialize_hex(transaction)]),
+            )
+            .context("bitcoind refused to broadcast raw transaction")?;
+
+        Ok(Txid::from_hex(&txid)?)
+    }
+
+    fn confirmations(&self, txid: &Txid) -> anyhow::Result<Option<u32>> {
+        let response = self.rpc::<RawTransactionVerbose>(
+            "getrawtransaction",
+            ureq::json!([txid.to_string(), true]),
+        );
+
+        match response {
+            Ok(RawTransactionVerbose { confirmations }) => Ok(Some(confirmations.unwrap_or(0))),
+            Err(e) => match e.downcast_ref::<JsonRpcError>() {
+                Some(JsonRpcError { code, .. }) if *code == RPC_INVALID_ADDRESS_OR_KEY => Ok(None),
+                _ => Err(e),
+            },
+        }
+    }
+
+    fn spending_transaction(&self, outpoint: &OutPoint) -> anyhow::Result<Option<Transaction>> {
+        let spends = |transaction: &Transaction| {
+            transaction
+                .input
+                .iter()
+                .any(|input| input.previous_output == *outpoint)
+        };
+
+        let mempool = self.rpc::<Vec<String>>("getrawmempool", ureq::json!([]))?;
+        for txid in mempool {
+            let transaction = self.raw_transaction(&Txid::from_hex(&txid)?)?;
+
+            if spends(&transaction) {
+                return Ok(Some(transaction));
+            }
+        }
+
+        for height in (self.birthday..=self.height()?).rev() {
+            let block = self.block(height)?;
+
+            if let Some(transaction) = block.txdata.into_iter().find(|tx| spends(tx)) {
+                return Ok(Some(transaction));
+            }
+        }
+
+        Ok(None)
+    }
+
+    fn height(&self) -> anyhow::Result<u32> {
+        self.rpc("getblockcount", ureq::json!([]))
+    }
+
+    fn median_time_past(&self) -> anyhow::Result<u32> {
+        let BlockchainInfo { mediantime } = self.rpc("getblockchaininfo", ureq::json!([]))?;
+
+        Ok(mediantime)
+    }
+}
+
+fn decode<T>(hex: &str) -> anyhow::Result<T>
+where
+    T: ::bitcoin::consensus::Decodable,
+{
+    let bytes = Vec::<u8>::from_hex(hex)?;
+
+    Ok(deserialize(&bytes)?)
+}
+
+#[derive(Deserialize)]
+struct JsonRpcResponse<T> {
+    result: Option<T>,
+    error: Option<JsonRpcError>,
+}
+
+#[derive(Debug, Deserialize, thiserror::Error)]
+#[error("{message}")]
+pub struct JsonRpcError {
+    pub code: i32,
+    pub message: String,
+}
+
+#[derive(Deserialize)]
+struct RawTransactionVerbose {
+    confirmations: Option<u32>,
+}
+
+#[derive(Deserialize)]
+struct BlockchainInfo {
+    mediantime: u32,
+}
@@ -0,0 +1,520 @@
+use crate::bitcoin::{Amount, Expiry, OutPoint, Transaction, TxOut, Txid};
+use crate::chain::Blockchain;
+use ::bitcoin::consensus::encode::serialize;
+use anyhow::Context;
+use std::{collections::HashMap, sync::Mutex};
+
+/// The sequence number of an input that does not opt into nLockTime enforcement.
+const SEQUENCE_FINAL: u32 = 0xFFFF_FFFF;
+
+/// If set in the sequence number of an input, BIP68 relative locktimes are disabled for it.
+const SEQUENCE_LOCKTIME_DISABLE_FLAG: u32 = 1 << 31;
+
+/// If set in the sequence number of an input, its relative locktime is measured in units of 512
+/// seconds instead of blocks.
+const SEQUENCE_LOCKTIME_TYPE_FLAG: u32 = 1 << 22;
+
+const SEQUENCE_LOCKTIME_MASK: u32 = 0x0000_FFFF;
+
+const SEQUENCE_LOCKTIME_GRANULARITY: u32 = 9;
+
+/// The minimum fee rate bitcoind relays transactions at, in satoshi per virtual byte.
+const MIN_RELAY_FEE_PER_VBYTE: u64 = 1;
+
+/// Block time and median time past of the first block of the chain.
+const GENESIS_TIME: u32 = 1_231_006_505;
+
+const BLOCK_INTERVAL: u32 = 600;
+
+/// A blockchain that lives in memory.
+///
+/// Every broadcast transaction is validated like a full node would, i.e. its inputs must exist and
+/// be unspent, it must pay at least the minimum relay fee, it must be final with respect to its
+/// nLockTime and the nSequence of its inputs (BIP68) and the witness of every input must satisfy
+/// the script of the output it spends. Valid transactions enter the mempool and are included in
+/// the next block produced by [`InMemoryBlockchain::mine`].
+///
+/// Blocks are produced in regular intervals of ten minutes, hence heights and timestamps are
+/// deterministic. Cloning the blockchain forks it: both copies evolve independently afterwards.
+#[derive(Debug)]
+pub struct InMemoryBlockchain {
+    inner: Mutex<Chain>,
+}
+
+#[derive(Debug, Clone)]
+struct Chain {
+    height: u32,
+    utxos: HashMap<OutPoint, Utxo>,
+    /// All known transactions together with the height of the block that includes them, or
+    /// `None` if they are still in the mempool.
+    transactions: HashMap<Txid, (Transaction, Option<u32>)>,
+    spent_by: HashMap<OutPoint, Txid>,
+    mempool: Vec<Txid>,
+}
+
+#[derive(Debug, Clone)]
+struct Utxo {
+    output: TxOut,
+    height: Option<u32>,
+}
+
+#[derive(thiserror::Error, Debug)]
+#[error("output {0} does not exist")]
+pub struct MissingInput(OutPoint);
+
+#[derive(thiserror::Error, Debug)]
+#[error("output {0} has already been spent")]
+pub struct DoubleSpend(OutPoint);
+
+#[derive(thiserror::Error, Debug)]
+#[error("outputs are worth more than inputs")]
+pub struct InsufficientInputValue;
+
+#[derive(thiserror::Error, Debug)]
+#[error("fee of {0} sat is below the minimum relay fee of {1} sat")]
+pub struct InsufficientFee(u64, u64);
+
+#[derive(thiserror::Error, Debug)]
+#[error("transaction is non-final")]
+pub struct NonFinal;
+
+#[derive(thiserror::Error, Debug)]
+#[error("relative locktime of input {0} has not expired")]
+pub struct NonFinalSequence(usize);
+
+#[derive(thiserror::Error, Debug)]
+#[error("witness of input {0} does not satisfy the script of the output it spends")]
+pub struct InvalidScript(usize);
+
+impl Default for InMemoryBlockchain {
+    fn default() -> Self {
+        Self {
+            inner: Mutex::new(Chain {
+                height: 0,
+                utxos: HashMap::new(),
+                transactions: HashMap::new(),
+                spent_by: HashMap::new(),
+                mempool: Vec::new(),
+            }),
+        }
+    }
+}
+
+impl Clone for InMemoryBlockchain {
+    fn clone(&self) -> Self {
+        let chain = self
+            .inner
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clone();
+
+        Self {
+            inner: Mutex::new(chain),
+        }
+    }
+}
+
+impl InMemoryBlockchain {
+    /// Mines a block containing the mempool and a transaction without inputs that pays the given
+    /// amount to the script.
+    pub fn fund(
+        &self,
+        script_pubkey: ::bitcoin::Script,
+        amount: Amount,
+    ) -> anyhow::Result<OutPoint> {
+        let mut chain = self.lock()?;
+        let height = chain.height + 1;
+
+        // the lock time makes every funding transaction unique
+        let transaction = Transaction {
+            version: 2,
+            lock_time: height,
+            input: Vec::new(),
+            output: vec![TxOut {
+                value: amount.as_sat(),
+                script_pubkey,
+            }],
+        };
+        let outpoint = OutPoint::new(transaction.txid(), 0);
+
+        chain.accept(transaction);
+        chain.mine(1);
+
+        Ok(outpoint)
+    }
+
+    /// Mines the given number of blocks, the first of which includes all transactions in the
+    /// mempool.
+    pub fn mine(&self, blocks: u32) -> anyhow::Result<()> {
+        self.lock()?.mine(blocks);
+
+        Ok(())
+    }
+
+    /// Returns the ids of the transactions in the mempool in the order they were broadcast.
+    pub fn mempool(&self) -> anyhow::Result<Vec<Txid>> {
+        Ok(self.lock()?.mempool.clone())
+    }
+
+    fn lock(&self) -> anyhow::Result<std::sync::MutexGuard<'_, Chain>> {
+        self.inner
+            .lock()
+            .map_err(|_| anyhow::anyhow!("blockchain lock is poisoned"))
+    }
+}
+
+impl Chain {
+    fn median_time_past(&self) -> u32 {
+        median_time_past_at(self.height)
+    }
+
+    fn validate(&self, transaction: &Transaction) -> anyhow::Result<()> {
+        let mut input_value = 0;
+        let serialized = serialize(transaction);
+
+        for (index, input) in transaction.input.iter().enumerate() {
+            let outpoint = input.previous_output;
+
+            if self.spent_by.contains_key(&outpoint) {
+                anyhow::bail!(DoubleSpend(outpoint))
+            }
+
+            let utxo = self
+                .utxos
+                .get(&outpoint)
+                .ok_or_else(|| MissingInput(outpoint))?;
+
+            utxo.output
+                .script_pubkey
+                .verify(index, utxo.output.value, &serialized)
+                .map_err(|_| InvalidScript(index))?;
+
+            if !self.is_sequence_final(transaction, input.sequence, utxo) {
+                anyhow::bail!(NonFinalSequence(index))
+            }
+
+            input_value += utxo.output.value;
+        }
+
+        let output_value = transaction
+            .output
+            .iter()
+            .map(|output| output.value)
+            .sum::<u64>();
+
+        if output_value > input_value {
+            anyhow::bail!(InsufficientInputValue)
+        }
+
+        let fee = input_value - output_value;
+        let min_relay_fee = vsize(transaction) * MIN_RELAY_FEE_PER_VBYTE;
+        if fee < min_relay_fee {
+            anyhow::bail!(InsufficientFee(fee, min_relay_fee))
+        }
+
+        if !self.is_final(transaction) {
+            anyhow::bail!(NonFinal)
+        }
+
+        Ok(())
+    }
+
+    /// Checks whether the transaction can be included in the next block.
+    fn is_final(&self, transaction: &Transaction) -> bool {
+        if transaction.lock_time == 0
+            || transaction
+                .input
+                .iter()
+                .all(|input| input.sequence == SEQUENCE_FINAL)
+        {
+            return true;
+        }
+
+        match Expiry::from_lock_time(transaction.lock_time) {
+            Expiry::BlockHeight(height) => height < self.height + 1,
+            Expiry::Timestamp(timestamp) => timestamp < self.median_time_past(),
+        }
+    }
+
+    /// Checks whether the relative locktime of an input spending the given output allows its
+    /// inclusion in the next block as defined in BIP68.
+    fn is_sequence_final(&self, transaction: &Transaction, sequence: u32, utxo: &Utxo) -> bool {
+        if transaction.version < 2 || sequence & SEQUENCE_LOCKTIME_DISABLE_FLAG != 0 {
+            return true;
+        }
+
+        let next_height = self.height + 1;
+        // an output in the mempool would be confirmed in the same block
+        let utxo_height = utxo.height.unwrap_or(next_height);
+        let value = sequence & SEQUENCE_LOCKTIME_MASK;
+
+        if sequence & SEQUENCE_LOCKTIME_TYPE_FLAG != 0 {
+            let utxo_time = median_time_past_at(utxo_height.saturating_sub(1));
+
+            self.median_time_past() - utxo_time >= value << SEQUENCE_LOCKTIME_GRANULARITY
+        } else {
+            next_height - utxo_height >= value
+        }
+    }
+
+    /// Adds a transaction to the mempool without validating it.
+    fn accept(&mut self, transaction: Transaction) {
+        let txid = transaction.txid();
+
+        for input in transaction.input.iter() {
+            self.utxos.remove(&input.previous_output);
+            self.spent_by.insert(input.previous_output, txid);
+        }
+
+        for (vout, output) in transaction.output.iter().enumerate() {
+            self.utxos.insert(
+                OutPoint::new(txid, vout as u32),
+                Utxo {
+                    output: output.clone(),
+                    height: None,
+                },
+            );
+        }
+
+        self.transactions.insert(txid, (transaction, None));
+        self.mempool.push(txid);
+    }
+
+    fn mine(&mut self, blocks: u32) {
+        if blocks == 0 {
+            return;
+        }
+
+        let height = self.height + 1;
+
+        for txid in self.mempool.drain(..) {
+            if let Some((transaction, confirmed_at)) = self.transactions.get_mut(&txid) {
+                *confirmed_at = Some(height);
+
+                for vout in 0..transaction.output.len() {
+                    if let Some(utxo) = self.utxos.get_mut(&OutPoint::new(txid, vout as u32)) {
+                        utxo.height = Some(height);
+                    }
+                }
+            }
+        }
+
+        self.height += blocks;
+    }
+}
+
+fn median_time_past_at(height: u32) -> u32 {
+    // with a constant block interval, the median of the last 11 blocks is the 6th most recent
+    GENESIS_TIME + height.saturating_sub(5) * BLOCK_INTERVAL
+}
+
+/// The virtual size of the transaction as defined in BIP141.
+fn vsize(transaction: &Transaction) -> u64 {
+    (transaction.get_weight() as u64 + 3) / 4
+}
+
+impl Blockchain for InMemoryBlockchain {
+    fn broadcast(&self, transaction: &Transaction) -> anyhow::Result<Txid> {
+        let mut chain = self.lock()?;
+        let txid = transaction.txid();
+
+        if transaction.input.is_empty() {
+            anyhow::bail!("transaction has no inputs")
+        }
+
+        chain
+            .validate(transaction)
+            .with_context(|| format!("transaction {} was rejected", txid))?;
+        chain.accept(transaction.clone());
+
+        Ok(txid)
+    }
+
+    fn confirmations(&self, txid: &Txid) -> anyhow::Result<Option<u32>> {
+        let chain = self.lock()?;
+
+        let confirmations = chain
+            .transactions
+            .get(txid)
+            .map(|(_, height)| match height {
+                Some(height) => chain.height - height + 1,
+                None => 0,
+            });
+
+        Ok(confirmations)
+    }
+
+    fn spending_transaction(&self, outpoint: &OutPoint) -> anyhow::Result<Option<Transaction>> {
+        let chain = self.lock()?;
+
+        let transaction = chain
+            .spent_by
+            .get(outpoint)
+            .and_then(|txid| chain.transactions.get(txid))
+            .map(|(transaction, _)| transaction.clone());
+
+        Ok(transaction)
+    }
+
+    fn height(&self) -> anyhow::Result<u32> {
+        Ok(self.lock()?.height)
+    }
+
+    fn median_time_past(&self) -> anyhow::Result<u32> {
+        Ok(self.lock()?.median_time_past())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ::bitcoin::{
+        blockdata::{opcodes, script::Builder},
+        hashes::{sha256, Hash},
+        Script, TxIn,
+    };
+
+    /// A P2WSH output that can be spent by anyone who reveals the script `OP_TRUE`.
+    fn anyone_can_spend() -> Script {
+        let witness_script = Builder::new().push_opcode(opcodes::OP_TRUE).into_script();
+
+        Builder::new()
+            .push_int(0)
+            .push_slice(&sha256::Hash::hash(witness_script.as_bytes())[..])
+            .into_script()
+    }
+
+    fn spend(outpoint: OutPoint, value: u64, lock_time: u32, witness: Vec<Vec<u8>>) -> Transaction {
+        Transaction {
+            version: 2,
+            lock_time,
+            input: vec![TxIn {
+                previous_output: outpoint,
+                script_sig: Script::new(),
+                sequence: 0xFFFF_FFFE,
+                witness,
+            }],
+            output: vec![TxOut {
+                value,
+                script_pubkey: anyone_can_spend(),
+            }],
+        }
+    }
+
+    fn op_true() -> Vec<Vec<u8>> {
+        vec![Builder::new()
+            .push_opcode(opcodes::OP_TRUE)
+            .into_script()
+            .into_bytes()]
+    }
+
+    #[test]
+    fn accepts_valid_spend_and_rejects_double_spend() {
+        let blockchain = InMemoryBlockchain::default();
+        let outpoint = blockchain
+            .fund(anyone_can_spend(), Amount::from_sat(10_000))
+            .unwrap();
+
+        let transaction = spend(outpoint, 9_000, 0, op_true());
+        let txid = blockchain.broadcast(&transaction).unwrap();
+
+        assert_eq!(blockchain.confirmations(&txid).unwrap(), Some(0));
+        assert_eq!(blockchain.mempool().unwrap(), vec![txid]);
+
+        blockchain.mine(1).unwrap();
+
+        assert_eq!(blockchain.confirmations(&txid).unwrap(), Some(1));
+        assert!(blockchain.mempool().unwrap().is_empty());
+        assert_eq!(
+            blockchain.spending_transaction(&outpoint).unwrap(),
+            Some(transaction)
+        );
+
+        let error = blockchain
+            .broadcast(&spend(outpoint, 8_000, 0, op_true()))
+            .unwrap_err();
+        assert!(error.chain().any(|cause| cause.is::<DoubleSpend>()));
+    }
+
+    #[test]
+    fn rejects_invalid_witness() {
+        let blockchain = InMemoryBlockchain::default();
+        let outpoint = blockchain
+            .fund(anyone_can_spend(), Amount::from_sat(10_000))
+            .unwrap();
+
+        let error = blockchain
+            .broadcast(&spend(outpoint, 9_000, 0, vec![vec![0x00]]))
+            .unwrap_err();
+
+        assert!(error.chain().any(|cause| cause.is::<InvalidScript>()));
+    }
+
+    #[test]
+    fn rejects_inflation() {
+        let blockchain = InMemoryBlockchain::default();
+        let outpoint = blockchain
+            .fund(anyone_can_spend(), Amount::from_sat(10_000))
+            .unwrap();
+
+        let error = blockchain
+            .broadcast(&spend(outpoint, 10_001, 0, op_true()))
+            .unwrap_err();
+
+        assert!(error
+            .chain()
+            .any(|cause| cause.is::<InsufficientInputValue>()));
+    }
+
+    #[test]
+    fn rejects_insufficient_fee() {
+        let blockchain = InMemoryBlockchain::default();
+        let outpoint = blockchain
+            .fund(anyone_can_spend(), Amount::from_sat(10_000))
+            .unwrap();
+
+        let error = blockchain
+            .broadcast(&spend(outpoint, 9_990, 0, op_true()))
+            .unwrap_err();
+
+        assert!(error.chain().any(|cause| cause.is::<InsufficientFee>()));
+    }
+
+    #[test]
+    fn enforces_relative_locktime() {
+        let blockchain = InMemoryBlockchain::default();
+        let outpoint = blockchain
+            .fund(anyone_can_spend(), Amount::from_sat(10_000))
+            .unwrap();
+        let mut transaction = spend(outpoint, 9_000, 0, op_true());
+        transaction.input[0].sequence = 10;
+
+        let error = blockchain.broadcast(&transaction).unwrap_err();
+        assert!(error.chain().any(|cause| cause.is::<NonFinalSequence>()));
+
+        blockchain.mine(8).unwrap();
+        assert!(blockchain.broadcast(&transaction).is_err());
+
+        blockchain.mine(1).unwrap();
+        blockchain.broadcast(&transaction).unwrap();
+    }
+
+    #[test]
+    fn enforces_locktime() {
+        let blockchain = InMemoryBlockchain::default();
+        let outpoint = blockchain
+            .fund(anyone_can_spend(), Amount::from_sat(10_000))
+            .unwrap();
+        let expiry = Expiry::BlockHeight(blockchain.height().unwrap() + 10);
+        let transaction = spend(outpoint, 9_000, expiry.lock_time(), op_true());
+
+        let error = blockchain.broadcast(&transaction).unwrap_err();
+        assert!(error.chain().any(|cause| cause.is::<NonFinal>()));
+        assert!(!blockchain.is_expired(expiry).unwrap());
+
+        blockchain.mine(10).unwrap();
+
+        assert!(blockchain.is_expired(expiry).unwrap());
+        blockchain.broadcast(&transaction).unwrap();
+    }
+}
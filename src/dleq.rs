@@ -1,9 +1,9 @@
 use crate::secp256k1;
-use sha2::Digest;
-use sha2::Sha256;
+use crate::transcript::Transcript;
+use crate::{verify_each, BatchVerificationError};
 use std::convert::TryInto;
 
-#[derive(Debug, Clone, serde::Serialize)]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct Proof {
     #[serde(with = "crate::serde::secp256k1_scalar")]
     s: secp256k1::Scalar,
@@ -16,93 +16,67 @@ pub struct Proof {
 pub struct DiscreteLogNotEqual;
 
 pub fn prove<R: rand::Rng>(
+    transcript: &mut Transcript,
     rng: &mut R,
     G: &secp256k1::PublicKey,
     Gx: &secp256k1::PublicKey,
     H: &secp256k1::PublicKey,
     Hx: &secp256k1::PublicKey,
-    x: secp256k1::Scalar,
+    x: &secp256k1::Scalar,
 ) -> Proof {
-    // NOTE: using rng for PoC and even early stage production but there
-    // are more robust ways of doing this which include hashing secret
-    // information along with randomness (see https://github.com/bitcoin/bips/pull/893/).
-    let r = secp256k1::KeyPair::random(rng);
-    let r = r.as_sk();
+    // the nonce commits to the witness and the statement, hence a weak rng cannot leak x
+    let r = secp256k1::derive_nonce(
+        &x.b32(),
+        &[
+            &G.serialize_compressed()[..],
+            &Gx.serialize_compressed()[..],
+            &H.serialize_compressed()[..],
+            &Hx.serialize_compressed()[..],
+        ],
+        &rng.gen(),
+    );
 
     // Gr
     let mut Gr = secp256k1::G.clone();
-    Gr.tweak_mul_assign(r).unwrap();
+    Gr.tweak_mul_assign(&r).unwrap();
 
     // Hr
     let mut Hr = H.clone();
-    Hr.tweak_mul_assign(r).unwrap();
+    Hr.tweak_mul_assign(&r).unwrap();
 
-    // c = H(G | Gx | H | Hx | Gr | Hr)
-    let mut hasher = Sha256::default();
-    hasher.input(&G.serialize_compressed() as &[u8]);
-    hasher.input(&Gx.serialize_compressed() as &[u8]);
-    hasher.input(&H.serialize_compressed() as &[u8]);
-    hasher.input(&Hx.serialize_compressed() as &[u8]);
-    hasher.input(&Gr.serialize_compressed() as &[u8]);
-    hasher.input(&Hr.serialize_compressed() as &[u8]);
+    let r: secp256k1::Scalar = r.into();
 
-    let r: secp256k1::Scalar = r.clone().into();
-
-    let c: secp256k1::Scalar = secp256k1::SecretKey::parse_slice(&hasher.result()[..])
-        .unwrap()
-        .into();
-    let s = r + c.clone() * x;
+    let c = challenge(transcript, G, Gx, H, Hx, &Gr, &Hr);
+    let s = r + &c * x;
 
     Proof { s, c }
 }
 
 pub fn verify(
+    transcript: &mut Transcript,
     G: &secp256k1::PublicKey,
     Gx: &secp256k1::PublicKey,
     H: &secp256k1::PublicKey,
     Hx: &secp256k1::PublicKey,
     proof: &Proof, // (s = r + cx, c)
 ) -> Result<(), DiscreteLogNotEqual> {
-    let c_neg = -proof.c.clone();
+    // the proof is provided by the counterparty, hence degenerate values must not panic
+    let s: secp256k1::SecretKey = proof
+        .s
+        .clone()
+        .try_into()
+        .map_err(|_| DiscreteLogNotEqual)?;
+    let c_neg: secp256k1::SecretKey = (-proof.c.clone())
+        .try_into()
+        .map_err(|_| DiscreteLogNotEqual)?;
 
     // Gr = Gs + (Gx * -c) = Gr + Gcx - Gcx
-    let Gr = {
-        let mut Gxc_neg = Gx.clone();
-        // TODO: Don't panic on things controlled by adversary
-        Gxc_neg
-            .tweak_mul_assign(&c_neg.clone().try_into().unwrap())
-            .unwrap();
-
-        let mut Gs = G.clone();
-        Gs.tweak_mul_assign(&proof.s.clone().try_into().unwrap())
-            .unwrap();
-        secp256k1::PublicKey::combine(&[Gxc_neg, Gs]).unwrap()
-    };
+    let Gr = mul_add(G, &s, Gx, &c_neg).ok_or(DiscreteLogNotEqual)?;
 
     // Hr = Hs + (Hx * -c) = Hr + Hcx - Hcx
-    let Hr = {
-        let mut Hxc_neg = Hx.clone();
-        Hxc_neg
-            .tweak_mul_assign(&c_neg.try_into().unwrap())
-            .unwrap();
-
-        let mut Hs = H.clone();
-        Hs.tweak_mul_assign(&proof.s.clone().try_into().unwrap())
-            .unwrap();
-        secp256k1::PublicKey::combine(&[Hxc_neg, Hs]).unwrap()
-    };
-
-    // c = H(G | Gx | H | Hx | Gr | Hr)
-    let mut hasher = Sha256::default();
-    hasher.input(&G.serialize_compressed() as &[u8]);
-    hasher.input(&Gx.serialize_compressed() as &[u8]);
-    hasher.input(&H.serialize_compressed() as &[u8]);
-    hasher.input(&Hx.serialize_compressed() as &[u8]);
-    hasher.input(&Gr.serialize_compressed() as &[u8]);
-    hasher.input(&Hr.serialize_compressed() as &[u8]);
-    let c = secp256k1::SecretKey::parse_slice(&hasher.result()[..])
-        .unwrap()
-        .into();
+    let Hr = mul_add(H, &s, Hx, &c_neg).ok_or(DiscreteLogNotEqual)?;
+
+    let c = challenge(transcript, G, Gx, H, Hx, &Gr, &Hr);
 
     // c == c'
     if proof.c != c {
@@ -112,10 +86,81 @@ pub fn verify(
     Ok(())
 }
 
+/// A statement `(G, Gx, H, Hx)` of a proof that `Gx` and `Hx` share the discrete logarithm.
+pub type Statement<'a> = (
+    &'a secp256k1::PublicKey,
+    &'a secp256k1::PublicKey,
+    &'a secp256k1::PublicKey,
+    &'a secp256k1::PublicKey,
+);
+
+/// Verifies many proofs in parallel and reports all that are invalid.
+///
+/// A proof only carries the challenge and the response but not the commitments `Gr` and `Hr`,
+/// hence the verification equations cannot be combined into one.
+pub fn verify_batch(
+    items: &[(Transcript, Statement<'_>, &Proof)],
+) -> Result<(), BatchVerificationError> {
+    verify_each(items, |(transcript, (G, Gx, H, Hx), proof)| {
+        verify(&mut transcript.clone(), G, Gx, H, Hx, proof)
+    })
+}
+
+/// Computes `c` from the transcript after absorbing `G | Gx | H | Hx | Gr | Hr`.
+fn challenge(
+    transcript: &mut Transcript,
+    G: &secp256k1::PublicKey,
+    Gx: &secp256k1::PublicKey,
+    H: &secp256k1::PublicKey,
+    Hx: &secp256k1::PublicKey,
+    Gr: &secp256k1::PublicKey,
+    Hr: &secp256k1::PublicKey,
+) -> secp256k1::Scalar {
+    transcript.domain_separator(b"dleq");
+    transcript.append_secp256k1_point(b"G", G);
+    transcript.append_secp256k1_point(b"Gx", Gx);
+    transcript.append_secp256k1_point(b"H", H);
+    transcript.append_secp256k1_point(b"Hx", Hx);
+    transcript.append_secp256k1_point(b"Gr", Gr);
+    transcript.append_secp256k1_point(b"Hr", Hr);
+
+    transcript.challenge_secp256k1_scalar(b"c")
+}
+
+/// Computes `Pa + Qb`, or `None` if the result is the point at infinity.
+fn mul_add(
+    P: &secp256k1::PublicKey,
+    a: &secp256k1::SecretKey,
+    Q: &secp256k1::PublicKey,
+    b: &secp256k1::SecretKey,
+) -> Option<secp256k1::PublicKey> {
+    let mut Pa = P.clone();
+    Pa.tweak_mul_assign(a).ok()?;
+
+    let mut Qb = Q.clone();
+    Qb.tweak_mul_assign(b).ok()?;
+
+    secp256k1::PublicKey::combine(&[Pa, Qb]).ok()
+}
+
+/// Generates proofs with arbitrary, mostly invalid, scalars.
+#[cfg(test)]
+pub fn arbitrary_proof() -> impl proptest::strategy::Strategy<Value = Proof> {
+    use proptest::strategy::Strategy;
+
+    (
+        secp256k1::arbitrary::scalar(),
+        secp256k1::arbitrary::scalar(),
+    )
+        .prop_map(|(s, c)| Proof { s, c })
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
     use crate::secp256k1;
+    use crate::transcript::SessionId;
+    use proptest::prelude::*;
 
     #[test]
     fn prove_and_verify() {
@@ -131,15 +176,148 @@ mod test {
         let mut Hx = H.clone();
         Hx.tweak_mul_assign(x_1.as_sk()).unwrap();
 
+        let session_id = SessionId::random(&mut rand::thread_rng());
+
         let proof = prove(
+            &mut Transcript::new(&session_id),
             &mut rand::thread_rng(),
             &secp256k1::G,
             &Gx,
             &H,
             &Hx,
-            x_1.to_sk().into(),
+            x_1.to_scalar().expose(),
         );
 
-        verify(&secp256k1::G, &Gx, &H, &Hx, &proof).unwrap()
+        verify(
+            &mut Transcript::new(&session_id),
+            &secp256k1::G,
+            &Gx,
+            &H,
+            &Hx,
+            &proof,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn reject_proof_from_other_session() {
+        let x = secp256k1::KeyPair::random_from_thread_rng();
+        let H = secp256k1::KeyPair::random_from_thread_rng().to_pk();
+
+        let mut Hx = H.clone();
+        Hx.tweak_mul_assign(x.as_sk()).unwrap();
+
+        let proof = prove(
+            &mut Transcript::new(&SessionId::from([0u8; 32])),
+            &mut rand::thread_rng(),
+            &secp256k1::G,
+            &x.to_pk(),
+            &H,
+            &Hx,
+            x.to_scalar().expose(),
+        );
+
+        let result = verify(
+            &mut Transcript::new(&SessionId::from([1u8; 32])),
+            &secp256k1::G,
+            &x.to_pk(),
+            &H,
+            &Hx,
+            &proof,
+        );
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn reject_proof_that_cancels_out() {
+        let x = secp256k1::KeyPair::random_from_thread_rng();
+        let H = secp256k1::KeyPair::random_from_thread_rng().to_pk();
+
+        let mut Hx = H.clone();
+        Hx.tweak_mul_assign(x.as_sk()).unwrap();
+
+        // s = cx makes Gs + (Gx * -c) the point at infinity
+        let c = secp256k1::KeyPair::random_from_thread_rng()
+            .to_scalar()
+            .expose()
+            .clone();
+        let s = &c * x.to_scalar().expose();
+
+        let result = verify(
+            &mut Transcript::new(&SessionId::from([0u8; 32])),
+            &secp256k1::G,
+            &x.to_pk(),
+            &H,
+            &Hx,
+            &Proof { s, c },
+        );
+
+        assert!(result.is_err());
+    }
+
+    proptest! {
+        #[test]
+        fn verify_does_not_panic(
+            Gx in secp256k1::arbitrary::public_key(),
+            H in secp256k1::arbitrary::public_key(),
+            Hx in secp256k1::arbitrary::public_key(),
+            proof in arbitrary_proof(),
+        ) {
+            let _ = verify(
+                &mut Transcript::new(&SessionId::from([0u8; 32])),
+                &secp256k1::G,
+                &Gx,
+                &H,
+                &Hx,
+                &proof,
+            );
+        }
+    }
+
+    #[test]
+    fn verify_batch_reports_invalid_proofs() {
+        let H = secp256k1::KeyPair::random_from_thread_rng().to_pk();
+        let session_id = SessionId::random(&mut rand::thread_rng());
+
+        let batch = (0..4)
+            .map(|_| {
+                let x = secp256k1::KeyPair::random_from_thread_rng();
+
+                let mut Hx = H.clone();
+                Hx.tweak_mul_assign(x.as_sk()).unwrap();
+
+                let proof = prove(
+                    &mut Transcript::new(&session_id),
+                    &mut rand::thread_rng(),
+                    &secp256k1::G,
+                    &x.to_pk(),
+                    &H,
+                    &Hx,
+                    x.to_scalar().expose(),
+                );
+
+                (x.to_pk(), Hx, proof)
+            })
+            .collect::<Vec<_>>();
+
+        let mut items = batch
+            .iter()
+            .map(|(Gx, Hx, proof)| {
+                (
+                    Transcript::new(&session_id),
+                    (&*secp256k1::G, Gx, &H, Hx),
+                    proof,
+                )
+            })
+            .collect::<Vec<_>>();
+        verify_batch(&items).expect("batch verifies");
+
+        // the proof of the first statement does not hold for the second one
+        items[1].2 = &batch[0].2;
+
+        let error = verify_batch(&items).unwrap_err();
+
+        assert_eq!(error.invalid(), &[1]);
     }
 }
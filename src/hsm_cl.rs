@@ -1,4 +1,6 @@
 use crate::secp256k1;
+use crate::secret::Secret;
+use crate::{verify_each, BatchVerificationError};
 
 use class_group::primitives::cl_dl::{self};
 use class_group::BinaryQF;
@@ -7,67 +9,156 @@ use curv::elliptic::curves::traits::ECPoint;
 use curv::elliptic::curves::traits::ECScalar;
 use curv::BigInt;
 use curv::{FE, GE};
+use std::convert::TryFrom;
 
 // See: https://eprint.iacr.org/2019/503.pdf Figure 9
-// This is the discriminant for the underlying class group that our CL group is built on.
-const CLASS_GROUP_DISCRIMINANT: usize = 1827; // Gives 128 bits of security
-
-static CL_GROUP: conquer_once::Lazy<cl_dl::ClassGroup> =
-    conquer_once::Lazy::new(|| cl_dl::ClassGroup::new(&CLASS_GROUP_DISCRIMINANT));
-
-// TODO: Cannot use a static class group because PARI segfaults :(
-// static CL_GROUP: conquer_once::Lazy<cl_dl::ClassGroup> = conquer_once::Lazy::new(|| {
-//     cl_dl::ClassGroup {
-//         delta_k: BigInt::from_hex("-d1f7b908bda125c9f7d4b2790282182db6ab5742112061cc8d72dd23de3d44bcdf186010430ceada6350bb6b277b54b765941dfbdd57273ebcfee36f52d4317c31b936bd9c094269a885ee2c851cc73bc450c4bb8d76335f850dfa693b568ee117e086aaed24dcab8a8f9d7dd59654c7cf71661f8da900f9a8c5f083e239691d2d54fe2bace3d6ab8b8f5320047a35fcb5786ccd44058dc40412ec1fb462321d891e7c58d879b3943"),
-//         delta_q: BigInt::from_hex("-d1f7b908bda125c9f7d4b2790282182ba107431b84bb0f68f1a10398ecec061c7dc83e20e1e78550cbd38c1198f5ff5fbb2d48259d0a0999ec038d95bde53611eedd89e85769b47fef9b77275c10be33f4a3471e5112ea573944fa89a8075b76faa27a24ed33a19614dfee187bbb5fc85741d6e450daa6f5bf52d354f34aa6149f1a051cd33402e0ccd39ef18c9c01f790fb40140504f2e0c986f11efa24ea43671fb7138348579984aaf24e31e5a68e7f8f998c7b13e7127fd3b6b156b5b00199dbc80102754f6cca5ab8d12e156704048a0a11eecc22d2ff9c576c43e8a1c4d9216d20108e890c3"),
-//         gq: BinaryQF {
-//             a: BigInt::from_hex("f20765baecfb8a1b19be7760ad748d3f456c418a4d6b9b432a8470604eaf5b1349df3b80b4ee179277b2a1d98d5fe1d1ee72f9fa86fb27e80ef370fe39a4f0d9b7c29dc1bcf2a0569de7857d3c187e0cd9977ef0582c86e7ad6b8d464870861eb49e99b6895642a965edb8a5d6f0f7ff14c005d9"),
-//             b: BigInt::from_hex("394b071a96868e58bc6c3883f1c2b1b4705c170f1cbe93c8bb987ff55947ae9c380b9f393d406483f9e76c78777dc97d79ce84d6e6f26af956d48b019716d1610bcd1c2d257571bb75c446b14d167d0784a36775b29af868b0ac3075f57c2bc21afccc5ac09f0f46810d4421bea0eaf3bf1de9291"),
-//             c: BigInt::from_hex("a5a17d7f257f75365e49034bcf4609a98096ddcd640ef671a81d60de2a303953bb3424772de99819b1ea5c1cc8303c820bdb58787a9bdf17b31a4ba9ad2425e5a79b90ed47dbdcab26e531cd376216546432fa988eed2a61b064fe52169da4b07c1a5576a07e2e4d72a20cfcda5e05344bbe0af1")
-//         },
-//         stilde: BigInt::from_hex("867cdc4399044c2e9fcc44892bb12ad5360a5ff21b1ce59bf6dd1c40cfbc24564bda51571949b97e7651d12b85a690bb00976995121f96d58952b7a26d88323fc34b3f30aadfc33446b221e42bfbeb11e2d1b0b4d48")
-//     }
-// });
-
-#[derive(Debug, Clone)]
+// This is the size of the fundamental discriminant of the underlying class group that our CL group
+// is built on. Together with the order of secp256k1 this gives a discriminant of ~1827 bits.
+const CLASS_GROUP_DISCRIMINANT: usize = 1348; // Gives 128 bits of security
+
+/// The seed from which the class group of this PoC is derived.
+pub const DEFAULT_CLASS_GROUP_SEED: &[u8] = b"A2L-POC";
+
+/// The public parameters of the class group used for HSM-CL.
+///
+/// All parties of the protocol need to agree on the same class group, otherwise they will not be
+/// able to verify each other's proofs. The group is derived deterministically from a public seed
+/// and can be serialized to be distributed to other processes.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(try_from = "ClassGroupParamsHex", into = "ClassGroupParamsHex")]
+pub struct ClassGroupParams {
+    inner: cl_dl::ClassGroup,
+}
+
+impl ClassGroupParams {
+    pub fn from_seed(seed: &[u8]) -> Self {
+        Self {
+            inner: cl_dl::ClassGroup::new_from_setup(
+                &CLASS_GROUP_DISCRIMINANT,
+                &BigInt::from(seed),
+            ),
+        }
+    }
+}
+
+impl PartialEq for ClassGroupParams {
+    fn eq(&self, other: &Self) -> bool {
+        self.inner.delta_k == other.inner.delta_k
+            && self.inner.delta_q == other.inner.delta_q
+            && self.inner.gq == other.inner.gq
+            && self.inner.stilde == other.inner.stilde
+    }
+}
+
+/// Hex-encoded representation of [`ClassGroupParams`] for serialization.
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+struct ClassGroupParamsHex {
+    delta_k: String,
+    delta_q: String,
+    gq_a: String,
+    gq_b: String,
+    gq_c: String,
+    stilde: String,
+}
+
+#[derive(thiserror::Error, Debug)]
+#[error("field {0} of the class group parameters is not a valid hex-encoded integer")]
+pub struct InvalidClassGroupParams(&'static str);
+
+impl std::convert::TryFrom<ClassGroupParamsHex> for ClassGroupParams {
+    type Error = InvalidClassGroupParams;
+
+    fn try_from(hex: ClassGroupParamsHex) -> Result<Self, Self::Error> {
+        let inner = cl_dl::ClassGroup {
+            delta_k: parse_hex("delta_k", &hex.delta_k)?,
+            delta_q: parse_hex("delta_q", &hex.delta_q)?,
+            gq: BinaryQF {
+                a: parse_hex("gq_a", &hex.gq_a)?,
+                b: parse_hex("gq_b", &hex.gq_b)?,
+                c: parse_hex("gq_c", &hex.gq_c)?,
+            },
+            stilde: parse_hex("stilde", &hex.stilde)?,
+        };
+
+        Ok(Self { inner })
+    }
+}
+
+impl From<ClassGroupParams> for ClassGroupParamsHex {
+    fn from(params: ClassGroupParams) -> Self {
+        let cl_dl::ClassGroup {
+            delta_k,
+            delta_q,
+            gq,
+            stilde,
+        } = params.inner;
+
+        Self {
+            delta_k: delta_k.to_hex(),
+            delta_q: delta_q.to_hex(),
+            gq_a: gq.a.to_hex(),
+            gq_b: gq.b.to_hex(),
+            gq_c: gq.c.to_hex(),
+            stilde: stilde.to_hex(),
+        }
+    }
+}
+
+/// `BigInt::from_hex` panics on invalid input, hence we check the characters upfront.
+fn parse_hex(field: &'static str, hex: &str) -> Result<BigInt, InvalidClassGroupParams> {
+    let digits = hex.strip_prefix('-').unwrap_or(hex);
+
+    if digits.is_empty() || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(InvalidClassGroupParams(field));
+    }
+
+    Ok(BigInt::from_hex(hex))
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct PublicKey {
     inner: BinaryQF,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct KeyPair {
-    inner: cl_dl::KeyPair,
+    inner: Secret<cl_dl::KeyPair>,
 }
 
 impl KeyPair {
     pub fn to_pk(&self) -> PublicKey {
         PublicKey {
-            inner: self.inner.public_key.clone(),
+            inner: self.inner.expose().public_key.clone(),
         }
     }
 }
 
-#[derive(Clone, Debug, serde::Serialize, PartialEq)]
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize, PartialEq)]
 pub struct Ciphertext {
     inner: cl_dl::Ciphertext,
 }
 
-#[derive(Clone, Debug, serde::Serialize)]
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
 pub struct Proof {
     inner: cl_dl::CLDLProof,
 }
 
-pub fn keygen() -> KeyPair {
+pub fn keygen(class_group: &ClassGroupParams) -> KeyPair {
     KeyPair {
-        inner: cl_dl::KeyPair::random(&CL_GROUP),
+        inner: Secret::new(cl_dl::KeyPair::random(&class_group.inner)),
     }
 }
 
-pub fn encrypt(public_key: &PublicKey, witness: &secp256k1::KeyPair) -> (Ciphertext, Proof) {
-    let x = ECScalar::from(&BigInt::from(witness.to_sk().serialize().as_ref()));
+pub fn encrypt(
+    class_group: &ClassGroupParams,
+    public_key: &PublicKey,
+    witness: &secp256k1::KeyPair,
+) -> (Ciphertext, Proof) {
+    let x = ECScalar::from(&BigInt::from(witness.as_sk().serialize().as_ref()));
     let X = GE::from_bytes(&witness.to_pk().serialize()[1..]).unwrap();
 
-    let (ciphertext, proof) = cl_dl::verifiably_encrypt(&CL_GROUP, &public_key.inner, (&x, &X));
+    let (ciphertext, proof) =
+        cl_dl::verifiably_encrypt(&class_group.inner, &public_key.inner, (&x, &X));
 
     (Ciphertext { inner: ciphertext }, Proof { inner: proof })
 }
@@ -77,6 +168,7 @@ pub fn encrypt(public_key: &PublicKey, witness: &secp256k1::KeyPair) -> (Ciphert
 pub struct VerificationError;
 
 pub fn verify(
+    class_group: &ClassGroupParams,
     public_key: &PublicKey,
     proof: &Proof,
     statement: (&Ciphertext, &secp256k1::PublicKey),
@@ -87,12 +179,31 @@ pub fn verify(
 
     proof
         .inner
-        .verify(&CL_GROUP, &public_key.inner, &ciphertext.inner, &encrypts)
+        .verify(
+            &class_group.inner,
+            &public_key.inner,
+            &ciphertext.inner,
+            &encrypts,
+        )
         .map_err(|_| VerificationError)?;
 
     Ok(())
 }
 
+/// Verifies many proofs under the same public key in parallel and reports all that are invalid.
+///
+/// The CL-DL proofs of the `class_group` crate do not expose their commitments, hence the proofs
+/// cannot be combined into a single check like Pointcheval-Sanders signatures or Pedersen proofs.
+pub fn verify_batch(
+    class_group: &ClassGroupParams,
+    public_key: &PublicKey,
+    items: &[(&Proof, (&Ciphertext, &secp256k1::PublicKey))],
+) -> Result<(), BatchVerificationError> {
+    verify_each(items, |&(proof, statement)| {
+        verify(class_group, public_key, proof, statement)
+    })
+}
+
 /// Randomizes the ciphertext and blinds the encrypted value multiplicatively by the returned secret key.
 // NOTE: that just blinding the ciphertext and encrypted value by a secp256k1
 // scalar is not enough here.  Although the inner value will be unrecognizable,
@@ -100,8 +211,11 @@ pub fn verify(
 // potentially be linked to the original ciphertext (without extra hardness
 // assumptions). Thus our blinding factor is sampled from a class group scalar
 // and then reduced.
-pub fn blind_ciphertext(ciphertext: &Ciphertext) -> (Ciphertext, secp256k1::SecretKey) {
-    let cg_scalar = BigInt::sample_below(&(&CL_GROUP.stilde * BigInt::from(2).pow(40)));
+pub fn blind_ciphertext(
+    class_group: &ClassGroupParams,
+    ciphertext: &Ciphertext,
+) -> (Ciphertext, secp256k1::SecretKey) {
+    let cg_scalar = BigInt::sample_below(&(&class_group.inner.stilde * BigInt::from(2).pow(40)));
     let randomized = cl_dl::eval_scal(&ciphertext.inner, &cg_scalar);
     let secp256k1_scalar =
         secp256k1::SecretKey::parse_slice(BigInt::to_vec(&cg_scalar.mod_floor(&FE::q())).as_ref())
@@ -110,15 +224,56 @@ pub fn blind_ciphertext(ciphertext: &Ciphertext) -> (Ciphertext, secp256k1::Secr
     (Ciphertext { inner: randomized }, secp256k1_scalar)
 }
 
-pub fn decrypt(keypair: &KeyPair, ciphertext: &Ciphertext) -> secp256k1::SecretKey {
-    let fe = cl_dl::decrypt(&CL_GROUP, &keypair.inner.secret_key, &ciphertext.inner).to_big_int();
+/// Computes an encryption of `a * m + b` from an encryption of `m` without knowing `m`.
+pub fn eval_affine(
+    class_group: &ClassGroupParams,
+    public_key: &PublicKey,
+    ciphertext: &Ciphertext,
+    a: &secp256k1::Scalar,
+    b: &secp256k1::Scalar,
+) -> Ciphertext {
+    let a = BigInt::from(a.b32().as_ref());
+    let b: FE = ECScalar::from(&BigInt::from(b.b32().as_ref()));
+
+    let (encrypted_b, _) = cl_dl::encrypt(&class_group.inner, &public_key.inner, &b);
+    let inner = cl_dl::eval_sum(&cl_dl::eval_scal(&ciphertext.inner, &a), &encrypted_b);
+
+    Ciphertext { inner }
+}
+
+pub fn decrypt(
+    class_group: &ClassGroupParams,
+    keypair: &KeyPair,
+    ciphertext: &Ciphertext,
+) -> secp256k1::SecretKey {
+    let scalar = decrypt_scalar(class_group, keypair, ciphertext);
+
+    secp256k1::SecretKey::try_from(scalar).unwrap()
+}
+
+/// Decrypts a ciphertext which may encrypt zero, e.g. because it was crafted by the counterparty.
+pub fn decrypt_scalar(
+    class_group: &ClassGroupParams,
+    keypair: &KeyPair,
+    ciphertext: &Ciphertext,
+) -> secp256k1::Scalar {
+    let fe = cl_dl::decrypt(
+        &class_group.inner,
+        &keypair.inner.expose().secret_key,
+        &ciphertext.inner,
+    )
+    .to_big_int();
     let bytes = BigInt::to_vec(&fe);
 
     let mut bytes_32 = [0u8; 32];
     // copy into the least significant bytes
     bytes_32[32 - bytes.len()..].copy_from_slice(&bytes[..]);
 
-    secp256k1::SecretKey::parse(&bytes_32).unwrap()
+    let mut scalar = secp256k1::Scalar::default();
+    // the plaintext space is the scalar field of secp256k1, hence this never overflows
+    let _ = scalar.set_b32(&bytes_32);
+
+    scalar
 }
 
 #[cfg(test)]
@@ -128,21 +283,28 @@ mod test {
 
     #[test]
     fn end_to_end() {
-        let kp = keygen();
+        let class_group = ClassGroupParams::from_seed(DEFAULT_CLASS_GROUP_SEED);
+        let kp = keygen(&class_group);
         let public_key = kp.to_pk();
         let msg = crate::secp256k1::KeyPair::random(&mut rand::thread_rng());
 
-        let (ciphertext, proof) = encrypt(&public_key, &msg);
+        let (ciphertext, proof) = encrypt(&class_group, &public_key, &msg);
 
-        assert!(verify(&public_key, &proof, (&ciphertext, &msg.to_pk())).is_ok());
+        assert!(verify(
+            &class_group,
+            &public_key,
+            &proof,
+            (&ciphertext, &msg.to_pk())
+        )
+        .is_ok());
 
         assert_eq!(
-            decrypt(&kp, &ciphertext),
-            msg.to_sk(),
+            &decrypt(&class_group, &kp, &ciphertext),
+            msg.as_sk(),
             "decryption yields original encrypted message"
         );
 
-        let (blinded_ciphertext, blinding) = blind_ciphertext(&ciphertext);
+        let (blinded_ciphertext, blinding) = blind_ciphertext(&class_group, &ciphertext);
 
         assert_ne!(
             blinded_ciphertext, ciphertext,
@@ -150,52 +312,107 @@ mod test {
         );
 
         assert!(
-            verify(&public_key, &proof, (&blinded_ciphertext, &msg.to_pk()),).is_err(),
+            verify(
+                &class_group,
+                &public_key,
+                &proof,
+                (&blinded_ciphertext, &msg.to_pk()),
+            )
+            .is_err(),
             "proof should not longer work on mutated ciphertext"
         );
 
-        let decrypted_blinded = decrypt(&kp, &blinded_ciphertext);
+        let decrypted_blinded = decrypt(&class_group, &kp, &blinded_ciphertext);
 
         assert_eq!(
             Into::<Scalar>::into(decrypted_blinded),
-            Into::<Scalar>::into(blinding) * Into::<Scalar>::into(msg.to_sk()),
+            &Into::<Scalar>::into(blinding) * msg.to_scalar().expose(),
             "cipthertext multiplication produced same result as scalar multiplication"
         )
     }
 
     #[test]
-    fn make_class_group() {
-        let class_group =
-            cl_dl::ClassGroup::new_from_setup(&1348, &BigInt::from(b"A2L-POC".as_ref()));
-
-        let delta_k = class_group.delta_k.to_hex();
-        let delta_q = class_group.delta_q.to_hex();
-        let stilde = class_group.stilde.to_hex();
-        let gqa = class_group.gq.a.to_hex();
-        let gqb = class_group.gq.b.to_hex();
-        let gqc = class_group.gq.c.to_hex();
-
-        let reconstructed = cl_dl::ClassGroup {
-            delta_k: BigInt::from_hex(&delta_k),
-            delta_q: BigInt::from_hex(&delta_q),
-            gq: BinaryQF {
-                a: BigInt::from_hex(&gqa),
-                b: BigInt::from_hex(&gqb),
-                c: BigInt::from_hex(&gqc),
-            },
-            stilde: BigInt::from_hex(&stilde),
-        };
+    fn verify_batch_reports_invalid_proofs() {
+        let class_group = ClassGroupParams::from_seed(DEFAULT_CLASS_GROUP_SEED);
+        let public_key = keygen(&class_group).to_pk();
+
+        let batch = (0..3)
+            .map(|_| {
+                let msg = crate::secp256k1::KeyPair::random(&mut rand::thread_rng());
+                let (ciphertext, proof) = encrypt(&class_group, &public_key, &msg);
+
+                (ciphertext, proof, msg.to_pk())
+            })
+            .collect::<Vec<_>>();
+
+        let mut items = batch
+            .iter()
+            .map(|(ciphertext, proof, X)| (proof, (ciphertext, X)))
+            .collect::<Vec<_>>();
+        verify_batch(&class_group, &public_key, &items).expect("batch verifies");
+
+        // the ciphertext of the first statement does not encrypt the key of the third one
+        items[2].1 = (&batch[0].0, &batch[2].2);
+
+        let error = verify_batch(&class_group, &public_key, &items).unwrap_err();
+
+        assert_eq!(error.invalid(), &[2]);
+    }
+
+    #[test]
+    fn eval_affine_on_ciphertext() {
+        let class_group = ClassGroupParams::from_seed(DEFAULT_CLASS_GROUP_SEED);
+        let kp = keygen(&class_group);
+        let m = crate::secp256k1::KeyPair::random(&mut rand::thread_rng());
+        let a = crate::secp256k1::KeyPair::random(&mut rand::thread_rng())
+            .to_scalar()
+            .expose()
+            .clone();
+        let b = crate::secp256k1::KeyPair::random(&mut rand::thread_rng())
+            .to_scalar()
+            .expose()
+            .clone();
+
+        let (ciphertext, _) = encrypt(&class_group, &kp.to_pk(), &m);
+        let evaluated = eval_affine(&class_group, &kp.to_pk(), &ciphertext, &a, &b);
+
+        assert_eq!(
+            decrypt_scalar(&class_group, &kp, &evaluated),
+            &a * m.to_scalar().expose() + b
+        );
+    }
+
+    #[test]
+    fn class_group_is_deterministic() {
+        let class_group = ClassGroupParams::from_seed(DEFAULT_CLASS_GROUP_SEED);
+        let other = ClassGroupParams::from_seed(DEFAULT_CLASS_GROUP_SEED);
+
+        assert_eq!(class_group, other);
+    }
+
+    #[test]
+    fn proof_verifies_against_loaded_class_group() {
+        let class_group = ClassGroupParams::from_seed(DEFAULT_CLASS_GROUP_SEED);
+        let bytes = serde_cbor::to_vec(&class_group).unwrap();
+        let loaded = serde_cbor::from_slice::<ClassGroupParams>(&bytes).unwrap();
+
+        assert_eq!(class_group, loaded);
+
+        let kp = keygen(&class_group);
+        let msg = crate::secp256k1::KeyPair::random(&mut rand::thread_rng());
+        let (ciphertext, proof) = encrypt(&class_group, &kp.to_pk(), &msg);
+
+        assert!(verify(&loaded, &kp.to_pk(), &proof, (&ciphertext, &msg.to_pk())).is_ok());
+    }
+
+    #[test]
+    fn reject_invalid_hex_in_class_group() {
+        let mut hex =
+            ClassGroupParamsHex::from(ClassGroupParams::from_seed(DEFAULT_CLASS_GROUP_SEED));
+        hex.stilde = String::from("not hex");
+
+        let bytes = serde_cbor::to_vec(&hex).unwrap();
 
-        assert_eq!(class_group.delta_q, reconstructed.delta_q);
-        assert_eq!(class_group.delta_k, reconstructed.delta_k);
-        assert_eq!(class_group.stilde, reconstructed.stilde);
-        assert_eq!(class_group.gq, reconstructed.gq);
-
-        println!("delta_k = {}", delta_k);
-        println!("delta_q = {}", delta_q);
-        println!("stilde = {}", stilde);
-        println!("gqa = {}", gqa);
-        println!("gqb = {}", gqc);
-        println!("gqc = {}", gqb);
+        assert!(serde_cbor::from_slice::<ClassGroupParams>(&bytes).is_err());
     }
 }
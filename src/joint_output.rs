@@ -0,0 +1,583 @@
+//! Signing the transactions that spend a joint output of either [`JointOutputKind`].
+//!
+//! The funding party needs the signatures of the redeeming party on the refund transactions before
+//! it funds the joint output, the redeeming party needs an adaptor signature of the funding party
+//! on the redeem transaction. A P2WSH joint output takes one ECDSA signature per party. A P2TR
+//! joint output takes a single MuSig2 signature of both parties, for which each party commits to
+//! [`Nonces`]: one per refund transaction and one for the redeem transaction. MuSig2 nonces depend
+//! neither on the keys nor on the transactions, hence the funding party sends them along with its
+//! key and the protocol does not need another round trip.
+
+use crate::bitcoin::{self, JointOutputKind, SigHash, SignedRefunds, Transaction, Transactions};
+use crate::secp256k1::{self, musig, schnorr, KeyPair, PublicKey, SecretKey};
+use ::bitcoin::hashes::Hash;
+use anyhow::{bail, Context};
+use rand::Rng;
+
+#[derive(Debug, thiserror::Error)]
+#[error("signatures do not match the kind of the joint output")]
+pub struct WrongJointOutputKind;
+
+#[derive(Debug, thiserror::Error)]
+#[error("expected nonces for {expected} refund transactions, received {actual}")]
+pub struct WrongNumberOfNonces {
+    expected: usize,
+    actual: usize,
+}
+
+/// The MuSig2 nonces a party commits to for the spend transactions of a P2TR joint output.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Nonces {
+    /// One nonce per refund transaction, in the order of `Transactions::refund_digests`.
+    refunds: Vec<musig::PublicNonce>,
+    redeem: musig::PublicNonce,
+}
+
+impl Nonces {
+    fn ensure_refunds(&self, expected: usize) -> Result<(), WrongNumberOfNonces> {
+        if self.refunds.len() != expected {
+            return Err(WrongNumberOfNonces {
+                expected,
+                actual: self.refunds.len(),
+            });
+        }
+
+        Ok(())
+    }
+}
+
+/// The secret counterpart of [`Nonces`], which is used up as the transactions are signed.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct SecretNonces {
+    refunds: Vec<musig::SecretNonce>,
+    redeem: musig::SecretNonce,
+    public: Nonces,
+}
+
+impl SecretNonces {
+    fn new(x: &KeyPair, refund_count: usize, rng: &mut impl Rng) -> Self {
+        let mut nonce_gen = || musig::nonce_gen(x, None, None, rng);
+
+        let (refunds, public_refunds) = (0..refund_count).map(|_| nonce_gen()).unzip();
+        let (redeem, public_redeem) = nonce_gen();
+
+        Self {
+            refunds,
+            redeem,
+            public: Nonces {
+                refunds: public_refunds,
+                redeem: public_redeem,
+            },
+        }
+    }
+
+    pub fn public(&self) -> &Nonces {
+        &self.public
+    }
+}
+
+/// Generates the nonces the funding party commits to along with its key, only a P2TR joint output
+/// needs any.
+pub fn nonce_gen(
+    kind: JointOutputKind,
+    x: &KeyPair,
+    refund_count: usize,
+    rng: &mut impl Rng,
+) -> Option<SecretNonces> {
+    match kind {
+        JointOutputKind::P2wsh => None,
+        JointOutputKind::P2tr => Some(SecretNonces::new(x, refund_count, rng)),
+    }
+}
+
+/// The signatures of the redeeming party on the refund transactions, in the order of
+/// `Transactions::refund_digests`.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub enum RefundSignatures {
+    P2wsh(#[serde(with = "crate::serde::secp256k1_signature_vec")] Vec<secp256k1::Signature>),
+    /// Partial signatures made with the refund nonces of both parties, along with the nonces the
+    /// redeeming party commits to.
+    P2tr {
+        nonces: Nonces,
+        partial_signatures: Vec<musig::PartialSignature>,
+    },
+}
+
+/// The share of the funding party in the signature on the redeem transaction, encrypted under the
+/// adaptor point.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub enum RedeemSignature {
+    P2wsh(secp256k1::EncryptedSignature),
+    P2tr(musig::PartialSignature),
+}
+
+/// Signs the refund transactions for the funding party.
+///
+/// For a P2TR joint output, the redeeming party answers the nonces of the funding party with its
+/// own and uses up the refund nonces of both. The redeem nonces of both parties are returned to
+/// sign the redeem transaction with later on.
+pub fn sign_refunds(
+    kind: JointOutputKind,
+    transactions: &Transactions,
+    x_to: &KeyPair,
+    X_from: &PublicKey,
+    nonces_from: Option<Nonces>,
+    rng: &mut impl Rng,
+) -> anyhow::Result<(RefundSignatures, Option<RedeemNonces>)> {
+    let digests = transactions.refund_digests();
+
+    match (kind, nonces_from) {
+        (JointOutputKind::P2wsh, None) => {
+            let signatures = digests
+                .into_iter()
+                .map(|digest| secp256k1::sign(digest, x_to))
+                .collect();
+
+            Ok((RefundSignatures::P2wsh(signatures), None))
+        }
+        (JointOutputKind::P2tr, Some(nonces_from)) => {
+            nonces_from.ensure_refunds(digests.len())?;
+
+            let signers = Signers {
+                X_from: X_from.clone(),
+                X_to: x_to.to_pk(),
+                funding: false,
+            };
+            let context = signers.context()?;
+            let SecretNonces {
+                refunds,
+                redeem,
+                public,
+            } = SecretNonces::new(x_to, digests.len(), rng);
+
+            let partial_signatures = digests
+                .iter()
+                .zip(refunds)
+                .zip(public.refunds.iter().zip(&nonces_from.refunds))
+                .map(|((digest, secret_nonce), (nonce_to, nonce_from))| {
+                    let session = musig::Session::new(
+                        &context,
+                        &[nonce_from.clone(), nonce_to.clone()],
+                        &digest.into_inner(),
+                        None,
+                    )?;
+
+                    Ok(session.partial_sign(&context, secret_nonce, x_to)?)
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?;
+
+            let redeem_nonces = RedeemNonces {
+                secret: redeem,
+                nonce: public.redeem.clone(),
+                nonce_counterparty: nonces_from.redeem,
+                signers,
+            };
+            let signatures = RefundSignatures::P2tr {
+                nonces: public,
+                partial_signatures,
+            };
+
+            Ok((signatures, Some(redeem_nonces)))
+        }
+        _ => bail!(WrongJointOutputKind),
+    }
+}
+
+/// Completes the refund transactions of the funding party with the signatures of the redeeming
+/// party.
+///
+/// For a P2TR joint output, the refund nonces of the funding party are used up. The redeem nonces
+/// of both parties are returned to sign the redeem transaction with later on.
+pub fn complete_refunds(
+    transactions: &Transactions,
+    x_from: &KeyPair,
+    X_to: &PublicKey,
+    nonces: Option<SecretNonces>,
+    signatures: RefundSignatures,
+) -> anyhow::Result<(SignedRefunds, Option<RedeemNonces>)> {
+    let digests = transactions.refund_digests();
+
+    match (nonces, signatures) {
+        (None, RefundSignatures::P2wsh(sigs_to)) => {
+            let sigs_from = digests
+                .iter()
+                .zip(&sigs_to)
+                .map(|(digest, sig_to)| {
+                    secp256k1::verify(*digest, sig_to, X_to)
+                        .context("failed to verify refund signature of the counterparty")?;
+
+                    Ok(secp256k1::sign(*digest, x_from))
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?;
+
+            let signed_refunds =
+                transactions.complete_refunds((&x_from.to_pk(), sigs_from), (X_to, sigs_to))?;
+
+            Ok((signed_refunds, None))
+        }
+        (
+            Some(SecretNonces {
+                refunds,
+                redeem,
+                public,
+            }),
+            RefundSignatures::P2tr {
+                nonces: nonces_to,
+                partial_signatures,
+            },
+        ) => {
+            nonces_to.ensure_refunds(digests.len())?;
+
+            let signers = Signers {
+                X_from: x_from.to_pk(),
+                X_to: X_to.clone(),
+                funding: true,
+            };
+            let context = signers.context()?;
+
+            let signatures = digests
+                .iter()
+                .zip(refunds.into_iter().zip(&public.refunds))
+                .zip(nonces_to.refunds.iter().zip(&partial_signatures))
+                .map(
+                    |((digest, (secret_nonce, nonce_from)), (nonce_to, partial_signature_to))| {
+                        let session = musig::Session::new(
+                            &context,
+                            &[nonce_from.clone(), nonce_to.clone()],
+                            &digest.into_inner(),
+                            None,
+                        )?;
+                        session
+                            .partial_verify(&context, nonce_to, X_to, partial_signature_to)
+                            .context("failed to verify refund signature of the counterparty")?;
+                        let partial_signature_from =
+                            session.partial_sign(&context, secret_nonce, x_from)?;
+
+                        session.aggregate(
+                            &context,
+                            &[partial_signature_from, partial_signature_to.clone()],
+                        )
+                    },
+                )
+                .collect::<anyhow::Result<Vec<_>>>()?;
+
+            let signed_refunds = transactions.complete_taproot_refunds(signatures)?;
+            let redeem_nonces = RedeemNonces {
+                secret: redeem,
+                nonce: public.redeem,
+                nonce_counterparty: nonces_to.redeem,
+                signers,
+            };
+
+            Ok((signed_refunds, Some(redeem_nonces)))
+        }
+        _ => bail!(WrongJointOutputKind),
+    }
+}
+
+/// The keys of both parties of a P2TR joint output.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+struct Signers {
+    #[serde(with = "crate::serde::secp256k1_public_key")]
+    X_from: PublicKey,
+    #[serde(with = "crate::serde::secp256k1_public_key")]
+    X_to: PublicKey,
+    /// Whether we are the funding party, i.e. `X_from` is our key.
+    funding: bool,
+}
+
+impl Signers {
+    fn context(&self) -> Result<musig::KeyAggContext, musig::PointAtInfinity> {
+        bitcoin::taproot_key_agg_context(&self.X_from, &self.X_to)
+    }
+
+    fn X_counterparty(&self) -> &PublicKey {
+        if self.funding {
+            &self.X_to
+        } else {
+            &self.X_from
+        }
+    }
+}
+
+/// The nonces both parties committed to for the redeem transaction of a P2TR joint output, along
+/// with the secret part of our own.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct RedeemNonces {
+    secret: musig::SecretNonce,
+    nonce: musig::PublicNonce,
+    nonce_counterparty: musig::PublicNonce,
+    signers: Signers,
+}
+
+impl RedeemNonces {
+    /// Signs our share of the redeem transaction, encrypted under `Y`.
+    pub fn sign(
+        self,
+        redeem_tx_digest: SigHash,
+        x: &KeyPair,
+        Y: &PublicKey,
+    ) -> anyhow::Result<RedeemSession> {
+        let context = self.signers.context()?;
+        let session = musig::Session::new(
+            &context,
+            &[self.nonce.clone(), self.nonce_counterparty.clone()],
+            &redeem_tx_digest.into_inner(),
+            Some(Y),
+        )?;
+        let partial_signature = session.partial_sign(&context, self.secret, x)?;
+
+        Ok(RedeemSession {
+            nonce: self.nonce,
+            nonce_counterparty: self.nonce_counterparty,
+            signers: self.signers,
+            Y: Y.clone(),
+            redeem_tx_digest,
+            partial_signature,
+        })
+    }
+}
+
+/// A MuSig2 session on the redeem transaction of a P2TR joint output in which we have signed our
+/// share.
+///
+/// Sessions cannot be persisted, hence it is recomputed from the nonces whenever it is needed.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct RedeemSession {
+    nonce: musig::PublicNonce,
+    nonce_counterparty: musig::PublicNonce,
+    signers: Signers,
+    #[serde(with = "crate::serde::secp256k1_public_key")]
+    Y: PublicKey,
+    #[serde(with = "crate::serde::bitcoin_sighash")]
+    redeem_tx_digest: SigHash,
+    partial_signature: musig::PartialSignature,
+}
+
+impl RedeemSession {
+    pub fn partial_signature(&self) -> &musig::PartialSignature {
+        &self.partial_signature
+    }
+
+    /// Verifies the share of the counterparty and aggregates it with ours into the adaptor
+    /// signature on the redeem transaction.
+    pub fn aggregate(
+        &self,
+        partial_signature: &musig::PartialSignature,
+    ) -> anyhow::Result<EncryptedRedeemSignature> {
+        let context = self.signers.context()?;
+        let message = self.redeem_tx_digest.into_inner();
+        let session = musig::Session::new(
+            &context,
+            &[self.nonce.clone(), self.nonce_counterparty.clone()],
+            &message,
+            Some(&self.Y),
+        )?;
+        session
+            .partial_verify(
+                &context,
+                &self.nonce_counterparty,
+                self.signers.X_counterparty(),
+                partial_signature,
+            )
+            .context("failed to verify redeem signature of the counterparty")?;
+
+        let encrypted_signature = session.aggregate_encrypted(
+            &context,
+            &[self.partial_signature.clone(), partial_signature.clone()],
+        )?;
+        schnorr::encverify(
+            &context.aggregate_key(),
+            &self.Y,
+            &message,
+            &encrypted_signature,
+        )?;
+
+        Ok(EncryptedRedeemSignature {
+            output_key: context.aggregate_key(),
+            Y: self.Y.clone(),
+            redeem_tx_digest: self.redeem_tx_digest,
+            encrypted_signature,
+        })
+    }
+}
+
+/// The signature of both parties on the redeem transaction of a P2TR joint output, encrypted under
+/// the adaptor point `Y`.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct EncryptedRedeemSignature {
+    output_key: [u8; 32],
+    #[serde(with = "crate::serde::secp256k1_public_key")]
+    Y: PublicKey,
+    #[serde(with = "crate::serde::bitcoin_sighash")]
+    redeem_tx_digest: SigHash,
+    encrypted_signature: schnorr::EncryptedSignature,
+}
+
+impl EncryptedRedeemSignature {
+    /// Decrypts the signature with the discrete logarithm of `Y` and completes the redeem
+    /// transaction with it.
+    pub fn complete(&self, redeem: Transaction, y: &SecretKey) -> anyhow::Result<Transaction> {
+        let signature = schnorr::decsig(y, &self.encrypted_signature);
+        schnorr::verify(
+            &self.output_key,
+            &self.redeem_tx_digest.into_inner(),
+            &signature,
+        )
+        .context("failed to verify redeem signature after decryption")?;
+
+        Ok(bitcoin::complete_taproot_spend_transaction(
+            redeem, &signature,
+        ))
+    }
+
+    /// Recovers the discrete logarithm of `Y` from the signature of the published redeem
+    /// transaction.
+    pub fn recover(&self, redeem: Transaction) -> anyhow::Result<KeyPair> {
+        let signature =
+            bitcoin::extract_taproot_signature(redeem, self.redeem_tx_digest, &self.output_key)?;
+
+        Ok(schnorr::recover(
+            &self.Y,
+            &self.encrypted_signature,
+            &signature,
+        )?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bitcoin::{Address, Amount, Expiry, Psbt};
+    use rand::thread_rng;
+
+    const FUND_AMOUNT: Amount = Amount::from_sat(10_000);
+    const SPEND_AMOUNT: Amount = Amount::from_sat(5_000);
+
+    fn address() -> Address {
+        "bcrt1q6rhpng9evdsfnn833a4f4vej0asu6dk5srld6x"
+            .parse()
+            .unwrap()
+    }
+
+    fn make_transactions(
+        kind: JointOutputKind,
+        X_from: &PublicKey,
+        X_to: &PublicKey,
+    ) -> Transactions {
+        bitcoin::make_transactions(
+            Psbt::from_unsigned_tx(Transaction {
+                version: 2,
+                lock_time: 0,
+                input: Vec::new(),
+                output: Vec::new(),
+            })
+            .unwrap(),
+            FUND_AMOUNT,
+            SPEND_AMOUNT,
+            &kind.joint_output(X_from, X_to).unwrap(),
+            X_to,
+            X_from,
+            Expiry::block_height(100).unwrap(),
+            &address(),
+            &address(),
+        )
+    }
+
+    #[test]
+    fn taproot_redeem_signature_reveals_adaptor_secret() {
+        let x_from = KeyPair::random(&mut thread_rng());
+        let x_to = KeyPair::random(&mut thread_rng());
+        let y = KeyPair::random(&mut thread_rng());
+        let transactions = make_transactions(JointOutputKind::P2tr, &x_from.to_pk(), &x_to.to_pk());
+
+        let nonces_from = nonce_gen(
+            JointOutputKind::P2tr,
+            &x_from,
+            bitcoin::refund_transaction_count(FUND_AMOUNT, SPEND_AMOUNT, &address()),
+            &mut thread_rng(),
+        )
+        .unwrap();
+        let (signatures, redeem_nonces_to) = sign_refunds(
+            JointOutputKind::P2tr,
+            &transactions,
+            &x_to,
+            &x_from.to_pk(),
+            Some(nonces_from.public().clone()),
+            &mut thread_rng(),
+        )
+        .unwrap();
+        let (signed_refunds, redeem_nonces_from) = complete_refunds(
+            &transactions,
+            &x_from,
+            &x_to.to_pk(),
+            Some(nonces_from),
+            signatures,
+        )
+        .unwrap();
+        assert!(signed_refunds
+            .transactions()
+            .all(|refund| refund.input[0].witness.len() == 1));
+
+        let session_from = redeem_nonces_from
+            .unwrap()
+            .sign(transactions.redeem_tx_digest, &x_from, &y.to_pk())
+            .unwrap();
+        let session_to = redeem_nonces_to
+            .unwrap()
+            .sign(transactions.redeem_tx_digest, &x_to, &y.to_pk())
+            .unwrap();
+
+        let redeem = session_to
+            .aggregate(session_from.partial_signature())
+            .unwrap()
+            .complete(transactions.redeem, y.as_sk())
+            .unwrap();
+        let recovered = session_from
+            .aggregate(session_to.partial_signature())
+            .unwrap()
+            .recover(redeem)
+            .unwrap();
+
+        assert_eq!(recovered.to_pk(), y.to_pk());
+    }
+
+    #[test]
+    fn reject_signatures_of_other_kind() {
+        let x_from = KeyPair::random(&mut thread_rng());
+        let x_to = KeyPair::random(&mut thread_rng());
+        let transactions =
+            make_transactions(JointOutputKind::P2wsh, &x_from.to_pk(), &x_to.to_pk());
+        let nonces_from = nonce_gen(JointOutputKind::P2tr, &x_from, 1, &mut thread_rng()).unwrap();
+
+        let error = sign_refunds(
+            JointOutputKind::P2wsh,
+            &transactions,
+            &x_to,
+            &x_from.to_pk(),
+            Some(nonces_from.public().clone()),
+            &mut thread_rng(),
+        )
+        .unwrap_err();
+        assert!(error.is::<WrongJointOutputKind>());
+
+        let (signatures, _) = sign_refunds(
+            JointOutputKind::P2wsh,
+            &transactions,
+            &x_to,
+            &x_from.to_pk(),
+            None,
+            &mut thread_rng(),
+        )
+        .unwrap();
+        let error = complete_refunds(
+            &transactions,
+            &x_from,
+            &x_to.to_pk(),
+            Some(nonces_from),
+            signatures,
+        )
+        .unwrap_err();
+        assert!(error.is::<WrongJointOutputKind>());
+    }
+}
@@ -0,0 +1,265 @@
+//! Tumbler keys that are rotated in epochs.
+//!
+//! Every epoch has its own HSM-CL key pair, under which puzzles are encrypted, and its own
+//! Pointcheval-Sanders key pair, with which tokens are issued. Tokens are only accepted in the
+//! epoch they have been issued in, hence the Pointcheval-Sanders key pair of an epoch is dropped as
+//! soon as the next epoch starts. A puzzle may still be solved after its epoch has ended, hence the
+//! HSM-CL key pair of an epoch is kept until the puzzle promise of every puzzle that has been
+//! encrypted under it has expired.
+//!
+//! A single keyring has to be shared by all sessions of a tumbler, in both sub-protocols.
+
+use crate::bitcoin::Expiry;
+use crate::{hsm_cl, pointcheval_sanders, Epoch, TOKEN_MESSAGES};
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard},
+};
+
+/// The keys of a tumbler, shared by all of its sessions.
+///
+/// A keyring without any keys, e.g. the one of a tumbler that has been restored from persisted
+/// state, rejects every epoch.
+#[derive(Debug, Default)]
+pub struct Keyring {
+    inner: RwLock<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    current: Option<CurrentEpoch>,
+    HE: BTreeMap<Epoch, PuzzleKey>,
+}
+
+#[derive(Debug)]
+struct CurrentEpoch {
+    epoch: Epoch,
+    PS: pointcheval_sanders::KeyPair,
+}
+
+#[derive(Debug)]
+struct PuzzleKey {
+    keypair: hsm_cl::KeyPair,
+    /// The expiries of the puzzle promises whose puzzles are encrypted under this key.
+    outstanding: Vec<Expiry>,
+}
+
+#[derive(thiserror::Error, Debug, Clone, Copy, PartialEq)]
+#[error("epoch {0} is not the current epoch")]
+pub struct InactiveEpoch(pub Epoch);
+
+#[derive(thiserror::Error, Debug, Clone, Copy, PartialEq)]
+#[error("no puzzle key is kept for epoch {0}")]
+pub struct NoPuzzleKey(pub Epoch);
+
+#[derive(thiserror::Error, Debug, Clone, Copy, PartialEq)]
+#[error("epoch {new} does not follow the current epoch {current}")]
+pub struct EpochNotIncreasing {
+    current: Epoch,
+    new: Epoch,
+}
+
+impl Keyring {
+    pub fn new(
+        epoch: Epoch,
+        HE: hsm_cl::KeyPair,
+        PS: pointcheval_sanders::KeyPair,
+    ) -> anyhow::Result<Self> {
+        let keyring = Self::default();
+        keyring.rotate(epoch, HE, PS)?;
+
+        Ok(keyring)
+    }
+
+    /// Starts a new epoch with fresh keys.
+    ///
+    /// Tokens of the previous epoch are no longer accepted from now on. Its HSM-CL key pair is kept
+    /// as long as puzzles encrypted under it are outstanding, see [`Keyring::prune`].
+    pub fn rotate(
+        &self,
+        epoch: Epoch,
+        HE: hsm_cl::KeyPair,
+        PS: pointcheval_sanders::KeyPair,
+    ) -> anyhow::Result<()> {
+        PS.public_key.ensure_messages(TOKEN_MESSAGES)?;
+
+        let mut inner = self.write();
+
+        if let Some(current) = &inner.current {
+            if epoch <= current.epoch {
+                anyhow::bail!(EpochNotIncreasing {
+                    current: current.epoch,
+                    new: epoch,
+                })
+            }
+        }
+
+        inner.current = Some(CurrentEpoch { epoch, PS });
+        inner.HE.insert(
+            epoch,
+            PuzzleKey {
+                keypair: HE,
+                outstanding: Vec::new(),
+            },
+        );
+        inner
+            .HE
+            .retain(|&key_epoch, key| key_epoch == epoch || !key.outstanding.is_empty());
+
+        Ok(())
+    }
+
+    /// Drops the HSM-CL key pairs of past epochs once all their puzzle promises have expired.
+    ///
+    /// `now` is the height or median time past of the latest block. Puzzle promises whose expiry
+    /// is of the other kind are kept. `watcher::Watcher::prune_keyring` calls this with both for
+    /// the latest block.
+    pub fn prune(&self, now: Expiry) {
+        let mut inner = self.write();
+        let current = inner.current.as_ref().map(|current| current.epoch);
+
+        for key in inner.HE.values_mut() {
+            key.outstanding.retain(|expiry| !has_passed(*expiry, now));
+        }
+        inner
+            .HE
+            .retain(|&epoch, key| Some(epoch) == current || !key.outstanding.is_empty());
+    }
+
+    pub fn current_epoch(&self) -> Option<Epoch> {
+        self.read().current.as_ref().map(|current| current.epoch)
+    }
+
+    /// Returns the key pair that issues and verifies tokens of the given epoch.
+    pub fn token_keypair(
+        &self,
+        epoch: Epoch,
+    ) -> Result<pointcheval_sanders::KeyPair, InactiveEpoch> {
+        match &self.read().current {
+            Some(current) if current.epoch == epoch => Ok(current.PS.clone()),
+            _ => Err(InactiveEpoch(epoch)),
+        }
+    }
+
+    /// Returns the key to encrypt a new puzzle under and remembers that a puzzle whose promise
+    /// expires at `promise_expiry` may be solved with it.
+    pub fn issue_puzzle(
+        &self,
+        epoch: Epoch,
+        promise_expiry: Expiry,
+    ) -> Result<hsm_cl::PublicKey, InactiveEpoch> {
+        let mut inner = self.write();
+
+        if inner.current.as_ref().map(|current| current.epoch) != Some(epoch) {
+            return Err(InactiveEpoch(epoch));
+        }
+
+        let key = inner
+            .HE
+            .get_mut(&epoch)
+            .expect("key of the current epoch is always kept");
+        key.outstanding.push(promise_expiry);
+
+        Ok(key.keypair.to_pk())
+    }
+
+    /// Returns the key pair to decrypt a puzzle of the given epoch.
+    pub fn puzzle_keypair(&self, epoch: Epoch) -> Result<hsm_cl::KeyPair, NoPuzzleKey> {
+        self.read()
+            .HE
+            .get(&epoch)
+            .map(|key| key.keypair.clone())
+            .ok_or(NoPuzzleKey(epoch))
+    }
+
+    fn read(&self) -> RwLockReadGuard<'_, Inner> {
+        // every update leaves the keyring consistent, hence a panic while holding the lock does not
+        // invalidate it
+        self.inner.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, Inner> {
+        self.inner.write().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+fn has_passed(expiry: Expiry, now: Expiry) -> bool {
+    match (expiry, now) {
+        (Expiry::BlockHeight(expiry), Expiry::BlockHeight(now))
+        | (Expiry::Timestamp(expiry), Expiry::Timestamp(now)) => now > expiry,
+        _ => false,
+    }
+}
+
+/// The keyring of a tumbler that has been restored from persisted state.
+///
+/// A keyring cannot be persisted along with the state that references it, it has to be
+/// reattached using `with_keyring`. Until then, every epoch is rejected.
+pub(crate) fn detached() -> Arc<Keyring> {
+    Arc::new(Keyring::default())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand::thread_rng;
+
+    fn keys(
+        class_group: &hsm_cl::ClassGroupParams,
+    ) -> (hsm_cl::KeyPair, pointcheval_sanders::KeyPair) {
+        (
+            hsm_cl::keygen(class_group),
+            pointcheval_sanders::keygen(TOKEN_MESSAGES, &mut thread_rng()),
+        )
+    }
+
+    #[test]
+    fn rotation_rejects_tokens_of_previous_epoch() {
+        let class_group = hsm_cl::ClassGroupParams::from_seed(hsm_cl::DEFAULT_CLASS_GROUP_SEED);
+        let (HE, PS) = keys(&class_group);
+        let keyring = Keyring::new(0, HE, PS).unwrap();
+
+        keyring.token_keypair(0).unwrap();
+
+        let (HE, PS) = keys(&class_group);
+        keyring.rotate(1, HE, PS).unwrap();
+
+        assert_eq!(keyring.token_keypair(0).unwrap_err(), InactiveEpoch(0));
+        assert_eq!(
+            keyring
+                .issue_puzzle(0, Expiry::BlockHeight(100))
+                .unwrap_err(),
+            InactiveEpoch(0)
+        );
+        assert_eq!(keyring.puzzle_keypair(0).unwrap_err(), NoPuzzleKey(0));
+        keyring.token_keypair(1).unwrap();
+
+        let (HE, PS) = keys(&class_group);
+        let error = keyring.rotate(1, HE, PS).unwrap_err();
+
+        assert!(error.is::<EpochNotIncreasing>());
+    }
+
+    #[test]
+    fn puzzle_key_is_kept_until_promises_expire() {
+        let class_group = hsm_cl::ClassGroupParams::from_seed(hsm_cl::DEFAULT_CLASS_GROUP_SEED);
+        let (HE, PS) = keys(&class_group);
+        let keyring = Keyring::new(0, HE, PS).unwrap();
+
+        keyring.issue_puzzle(0, Expiry::BlockHeight(100)).unwrap();
+
+        let (HE, PS) = keys(&class_group);
+        keyring.rotate(1, HE, PS).unwrap();
+
+        keyring.prune(Expiry::BlockHeight(100));
+        keyring
+            .puzzle_keypair(0)
+            .expect("promise has not expired yet");
+
+        keyring.prune(Expiry::BlockHeight(101));
+        assert_eq!(keyring.puzzle_keypair(0).unwrap_err(), NoPuzzleKey(0));
+        keyring
+            .puzzle_keypair(1)
+            .expect("key of the current epoch is kept");
+    }
+}
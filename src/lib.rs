@@ -2,32 +2,54 @@
 #![allow(clippy::large_enum_variant)]
 
 mod bitcoin;
-mod dleq;
-mod pedersen;
 
+pub mod chain;
+pub mod dleq;
 pub mod hsm_cl;
+pub mod joint_output;
+pub mod keyring;
+pub mod pedersen;
 pub mod pointcheval_sanders;
+pub mod protocol;
 pub mod puzzle_promise;
 pub mod puzzle_solver;
 pub mod receiver;
 pub mod secp256k1;
+pub mod secret;
 pub mod sender;
 mod serde;
+pub mod state_store;
+pub mod token_store;
+pub mod transcript;
+pub mod two_party_ecdsa;
+pub mod watcher;
 
-pub use self::bitcoin::spend_tx_miner_fee;
+pub use self::bitcoin::{
+    complete_p2wpkh_spend_transaction, make_cpfp_transaction, make_transactions,
+    spend_tx_miner_fee, taproot_key_agg_context, Expiry, FeeInput, FeeRate, InsufficientFeeInput,
+    InvalidExpiry, InvalidPartialFundTransaction, JointOutput, JointOutputKind, NoAnchorOutput,
+    NoRefundForFeerate, NotSpendingFundTransaction, SignedRefunds, TimelockPolicy, UnsafeTimelocks,
+    WrongNumberOfRefundSignatures, ANCHOR_OUTPUT_VALUE, P2WPKH_SATISFACTION_WEIGHT,
+    P2WSH_SATISFACTION_WEIGHT, TAPROOT_KEY_SPEND_SATISFACTION_WEIGHT,
+};
 use rand::Rng;
+use rayon::prelude::*;
 use std::fmt;
 
+/// Only the names of the message and the state are kept, the state itself may hold secrets.
 #[derive(thiserror::Error, Debug)]
 #[error("received an unexpected message {message} given the current state {state}")]
-pub struct UnexpectedMessage<M: fmt::Display + fmt::Debug, S: fmt::Display + fmt::Debug> {
-    message: M,
-    state: S,
+pub struct UnexpectedMessage {
+    message: String,
+    state: String,
 }
 
-impl<M: fmt::Display + fmt::Debug, S: fmt::Display + fmt::Debug> UnexpectedMessage<M, S> {
-    pub fn new(message: M, state: S) -> Self {
-        Self { message, state }
+impl UnexpectedMessage {
+    pub fn new(message: impl fmt::Display, state: impl fmt::Display) -> Self {
+        Self {
+            message: message.to_string(),
+            state: state.to_string(),
+        }
     }
 }
 
@@ -37,13 +59,15 @@ pub struct UnexpectedTransaction;
 
 #[derive(thiserror::Error, Debug)]
 #[error("state {state} is not meant to produce a message")]
-pub struct NoMessage<S: fmt::Display + fmt::Debug> {
-    state: S,
+pub struct NoMessage {
+    state: String,
 }
 
-impl<S: fmt::Display + fmt::Debug> NoMessage<S> {
-    pub fn new(state: S) -> Self {
-        Self { state }
+impl NoMessage {
+    pub fn new(state: impl fmt::Display) -> Self {
+        Self {
+            state: state.to_string(),
+        }
     }
 }
 
@@ -51,15 +75,69 @@ impl<S: fmt::Display + fmt::Debug> NoMessage<S> {
 #[error("the current state is not meant to produce a transaction")]
 pub struct NoTransaction;
 
-#[derive(Clone, Debug, ::serde::Serialize)]
+#[derive(thiserror::Error, Debug, Clone, PartialEq)]
+#[error("items {invalid:?} of the batch failed to verify")]
+pub struct BatchVerificationError {
+    invalid: Vec<usize>,
+}
+
+impl BatchVerificationError {
+    /// The indices of the items that failed to verify, in ascending order.
+    pub fn invalid(&self) -> &[usize] {
+        &self.invalid
+    }
+}
+
+/// Verifies every item of a batch on its own, in parallel, and reports all that failed.
+fn verify_each<T, E>(
+    items: &[T],
+    verify: impl Fn(&T) -> Result<(), E> + Sync,
+) -> Result<(), BatchVerificationError>
+where
+    T: Sync,
+{
+    let invalid = items
+        .par_iter()
+        .enumerate()
+        .filter(|(_, item)| verify(item).is_err())
+        .map(|(index, _)| index)
+        .collect::<Vec<_>>();
+
+    if invalid.is_empty() {
+        Ok(())
+    } else {
+        Err(BatchVerificationError { invalid })
+    }
+}
+
+#[derive(Clone, Debug, ::serde::Serialize, ::serde::Deserialize)]
 pub struct Lock {
     pub c_alpha_prime: hsm_cl::Ciphertext,
     #[serde(with = "crate::serde::secp256k1_public_key")]
     pub A_prime: secp256k1::PublicKey,
+    /// The epoch of the tumbler key the puzzle is encrypted under.
+    pub epoch: Epoch,
 }
 
 pub type Token = bls12_381::Scalar;
 
+/// Identifies the period in which a token has been issued.
+pub type Epoch = u64;
+
+/// The number of messages signed in a token: the token itself, the tumble amount and the epoch.
+pub const TOKEN_MESSAGES: usize = 3;
+
+/// The messages of a token that are known to the tumbler, i.e. all but the token itself.
+fn token_attributes(
+    tumble_amount: bitcoin::Amount,
+    epoch: Epoch,
+) -> [bls12_381::Scalar; TOKEN_MESSAGES - 1] {
+    [
+        bls12_381::Scalar::from(tumble_amount.as_sat()),
+        bls12_381::Scalar::from(epoch),
+    ]
+}
+
 fn random_bls12_381_scalar(rng: &mut impl Rng) -> bls12_381::Scalar {
     let mut bytes = [0u8; 64];
     rng.fill_bytes(&mut bytes[..]);
@@ -1,54 +1,75 @@
-use crate::random_bls12_381_scalar;
-use bls12_381::{G1Affine, Scalar};
+use crate::secret::Secret;
+use crate::transcript::Transcript;
+use crate::{random_bls12_381_scalar, verify_each, BatchVerificationError};
+use bls12_381::{G1Affine, G1Projective, Scalar};
 use rand::Rng;
-use sha2::{Digest, Sha512};
 
 pub type Commitment = G1Affine;
 
-#[derive(Debug, serde::Serialize, Clone)]
+#[derive(Debug, serde::Serialize, serde::Deserialize, Clone)]
 pub struct Decommitment {
-    #[serde(with = "crate::serde::bls12_381_scalar")]
-    pub m: Scalar,
-    #[serde(with = "crate::serde::bls12_381_scalar")]
-    pub r: Scalar,
+    pub m: Vec<Secret<Scalar>>,
+    pub r: Secret<Scalar>,
 }
 
+/// Commits to the messages `m`, each of them with its own generator in `H`.
+///
+/// # Panics
+///
+/// Panics if the number of generators and messages differ.
 pub fn commit(
     G: &G1Affine,
-    H: &G1Affine,
-    m: &Scalar,
+    H: &[G1Affine],
+    m: &[Scalar],
     rng: &mut impl Rng,
 ) -> (Commitment, Decommitment) {
+    assert_eq!(H.len(), m.len(), "every message needs its own generator");
+
     let r = random_bls12_381_scalar(rng);
-    let C = G * r + H * m;
+    let C = multi_exp(G, r, H, m);
 
-    (C.into(), Decommitment { m: *m, r })
+    (
+        C.into(),
+        Decommitment {
+            m: m.iter().copied().map(Secret::new).collect(),
+            r: Secret::new(r),
+        },
+    )
 }
 
-#[derive(Debug, serde::Serialize, Clone)]
+#[derive(Debug, serde::Serialize, serde::Deserialize, Clone)]
 pub struct Proof {
     #[serde(with = "crate::serde::bls12_381_g1affine")]
     C_prime: G1Affine,
-    #[serde(with = "crate::serde::bls12_381_scalar")]
-    u: Scalar,
+    #[serde(with = "crate::serde::bls12_381_scalar_vec")]
+    u: Vec<Scalar>,
     #[serde(with = "crate::serde::bls12_381_scalar")]
     v: Scalar,
 }
 
 #[allow(clippy::many_single_char_names)]
 pub fn prove(
+    transcript: &mut Transcript,
     G: &G1Affine,
-    H: &G1Affine,
+    H: &[G1Affine],
     C: &Commitment,
     Decommitment { m, r }: &Decommitment,
     rng: &mut impl Rng,
 ) -> Proof {
-    let (y, s) = (random_bls12_381_scalar(rng), random_bls12_381_scalar(rng));
+    let y = H
+        .iter()
+        .map(|_| random_bls12_381_scalar(rng))
+        .collect::<Vec<_>>();
+    let s = random_bls12_381_scalar(rng);
 
-    let C_prime = G1Affine::from(G * s + H * y);
-    let k = hash(C, &C_prime);
-    let u = y + k * m;
-    let v = s + k * r;
+    let C_prime = G1Affine::from(multi_exp(G, s, H, &y));
+    let k = challenge(transcript, G, H, C, &C_prime);
+    let u = y
+        .iter()
+        .zip(m)
+        .map(|(y_i, m_i)| y_i + k * m_i.expose())
+        .collect();
+    let v = s + k * r.expose();
 
     Proof { C_prime, u, v }
 }
@@ -58,46 +79,202 @@ pub fn prove(
 pub struct ProofRejected;
 
 pub fn verify(
+    transcript: &mut Transcript,
     G: &G1Affine,
-    H: &G1Affine,
+    H: &[G1Affine],
     C: &Commitment,
     Proof { C_prime, u, v }: Proof,
 ) -> Result<(), ProofRejected> {
-    let k = hash(C, &C_prime);
+    if u.len() != H.len() {
+        return Err(ProofRejected);
+    }
+
+    let k = challenge(transcript, G, H, C, &C_prime);
 
-    if G * v + H * u == C_prime + C * k {
+    if multi_exp(G, v, H, &u) == C_prime + C * k {
         Ok(())
     } else {
         Err(ProofRejected)
     }
 }
 
-fn hash(C: &G1Affine, C_prime: &G1Affine) -> Scalar {
-    let mut hasher = Sha512::new();
-    hasher.input(C.to_uncompressed().as_ref());
-    hasher.input(C_prime.to_uncompressed().as_ref());
-    let k = hasher.result();
+/// Verifies many proofs for commitments with the same generators at once.
+///
+/// The equations `G * v_i + sum_j H_j * u_ij == C'_i + C_i * k_i` are combined with random
+/// coefficients `r_i`, hence every generator is multiplied only once for the whole batch. If the
+/// combined check fails, every proof is verified on its own to find the invalid ones.
+pub fn verify_batch(
+    G: &G1Affine,
+    H: &[G1Affine],
+    items: &[(Transcript, &Commitment, &Proof)],
+    rng: &mut impl Rng,
+) -> Result<(), BatchVerificationError> {
+    // a proof for the wrong number of messages cannot be combined with the others
+    let all_lengths_match = items.iter().all(|(_, _, proof)| proof.u.len() == H.len());
+
+    if all_lengths_match {
+        let mut v = Scalar::zero();
+        let mut u = vec![Scalar::zero(); H.len()];
+        let mut rhs = G1Projective::identity();
+
+        for (
+            transcript,
+            C,
+            Proof {
+                C_prime,
+                u: u_i,
+                v: v_i,
+            },
+        ) in items
+        {
+            let k = challenge(&mut transcript.clone(), G, H, C, C_prime);
+            let r = random_bls12_381_scalar(rng);
+
+            v += r * v_i;
+            for (u, u_ij) in u.iter_mut().zip(u_i) {
+                *u += r * u_ij;
+            }
+            rhs += C_prime * r + *C * (r * k);
+        }
+
+        if multi_exp(G, v, H, &u) == rhs {
+            return Ok(());
+        }
+    }
+
+    verify_each(items, |(transcript, C, proof)| {
+        verify(&mut transcript.clone(), G, H, C, (*proof).clone())
+    })
+}
+
+/// Computes `G * a + sum_i H_i * b_i`.
+fn multi_exp(G: &G1Affine, a: Scalar, H: &[G1Affine], b: &[Scalar]) -> G1Projective {
+    H.iter()
+        .zip(b)
+        .fold(G * a, |acc, (H_i, b_i)| acc + H_i * b_i)
+}
+
+/// Computes `k` from the transcript after absorbing `G | H_1 | .. | H_n | C | C'`.
+fn challenge(
+    transcript: &mut Transcript,
+    G: &G1Affine,
+    H: &[G1Affine],
+    C: &G1Affine,
+    C_prime: &G1Affine,
+) -> Scalar {
+    transcript.domain_separator(b"pedersen");
+    transcript.append_bls12_381_g1(b"G", G);
+    transcript.append_u32(b"n", H.len() as u32);
+    for H_i in H {
+        transcript.append_bls12_381_g1(b"H", H_i);
+    }
+    transcript.append_bls12_381_g1(b"C", C);
+    transcript.append_bls12_381_g1(b"C'", C_prime);
 
-    let mut bytes = [0u8; 64];
-    bytes.copy_from_slice(k.as_slice());
-    Scalar::from_bytes_wide(&bytes)
+    transcript.challenge_bls12_381_scalar(b"k")
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::transcript::SessionId;
+
+    fn random_generator(rng: &mut impl Rng) -> G1Affine {
+        G1Affine::from(G1Affine::generator() * random_bls12_381_scalar(rng))
+    }
+
+    fn random_messages(n: usize, rng: &mut impl Rng) -> Vec<Scalar> {
+        (0..n).map(|_| random_bls12_381_scalar(rng)).collect()
+    }
 
     #[test]
     fn pedersen_roundtrip() {
         let mut rng = rand::thread_rng();
-        let G = G1Affine::from(G1Affine::generator() * random_bls12_381_scalar(&mut rng));
-        let H = G1Affine::from(G1Affine::generator() * random_bls12_381_scalar(&mut rng));
-        let m = random_bls12_381_scalar(&mut rng);
+        let G = random_generator(&mut rng);
+        let H = vec![random_generator(&mut rng), random_generator(&mut rng)];
+        let m = random_messages(2, &mut rng);
+
+        let session_id = SessionId::random(&mut rng);
 
         let (C, D) = commit(&G, &H, &m, &mut rng);
-        let proof = prove(&G, &H, &C, &D, &mut rng);
-        let res = verify(&G, &H, &C, proof);
+        let proof = prove(&mut Transcript::new(&session_id), &G, &H, &C, &D, &mut rng);
+        let res = verify(&mut Transcript::new(&session_id), &G, &H, &C, proof);
 
         assert_eq!(res, Ok(()));
     }
+
+    #[test]
+    fn reject_proof_for_fewer_generators() {
+        let mut rng = rand::thread_rng();
+        let G = random_generator(&mut rng);
+        let H = vec![random_generator(&mut rng), random_generator(&mut rng)];
+        let m = random_messages(2, &mut rng);
+
+        let session_id = SessionId::random(&mut rng);
+
+        let (C, D) = commit(&G, &H, &m, &mut rng);
+        let proof = prove(&mut Transcript::new(&session_id), &G, &H, &C, &D, &mut rng);
+        let res = verify(&mut Transcript::new(&session_id), &G, &H[..1], &C, proof);
+
+        assert_eq!(res, Err(ProofRejected));
+    }
+
+    #[test]
+    fn reject_proof_from_other_session() {
+        let mut rng = rand::thread_rng();
+        let G = random_generator(&mut rng);
+        let H = vec![random_generator(&mut rng)];
+        let m = random_messages(1, &mut rng);
+
+        let (C, D) = commit(&G, &H, &m, &mut rng);
+        let proof = prove(
+            &mut Transcript::new(&SessionId::from([0u8; 32])),
+            &G,
+            &H,
+            &C,
+            &D,
+            &mut rng,
+        );
+        let res = verify(
+            &mut Transcript::new(&SessionId::from([1u8; 32])),
+            &G,
+            &H,
+            &C,
+            proof,
+        );
+
+        assert_eq!(res, Err(ProofRejected));
+    }
+
+    #[test]
+    fn verify_batch_reports_invalid_proofs() {
+        let mut rng = rand::thread_rng();
+        let G = random_generator(&mut rng);
+        let H = vec![random_generator(&mut rng), random_generator(&mut rng)];
+
+        let batch = (0..4)
+            .map(|_| {
+                let session_id = SessionId::random(&mut rng);
+                let m = random_messages(2, &mut rng);
+
+                let (C, D) = commit(&G, &H, &m, &mut rng);
+                let proof = prove(&mut Transcript::new(&session_id), &G, &H, &C, &D, &mut rng);
+
+                (session_id, C, proof)
+            })
+            .collect::<Vec<_>>();
+
+        let items = batch
+            .iter()
+            .map(|(session_id, C, proof)| (Transcript::new(session_id), C, proof))
+            .collect::<Vec<_>>();
+        verify_batch(&G, &H, &items, &mut rng).expect("batch verifies");
+
+        let mut items = items;
+        items[2].0 = Transcript::new(&SessionId::random(&mut rng));
+
+        let error = verify_batch(&G, &H, &items, &mut rng).unwrap_err();
+
+        assert_eq!(error.invalid(), &[2]);
+    }
 }
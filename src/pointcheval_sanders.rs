@@ -1,24 +1,50 @@
 //! Implementation of Pointcheval-Sanders signature scheme for Pedersen Commitments
 //! As described in https://eprint.iacr.org/2015/525.pdf
 
-use crate::random_bls12_381_scalar;
-use bls12_381::{G1Affine, G1Projective, G2Affine, Gt, Scalar};
+use crate::secret::Secret;
+use crate::transcript::Transcript;
+use crate::{random_bls12_381_scalar, verify_each, BatchVerificationError};
+use bls12_381::{
+    multi_miller_loop, G1Affine, G1Projective, G2Affine, G2Prepared, G2Projective, Gt, Scalar,
+};
 use rand::Rng;
 
-#[derive(Debug, Clone, PartialEq)]
+/// Public key for signatures on `n` messages, with one element `Y1_i`, `Y2_i` per message.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
 pub struct PublicKey {
-    pub Y1: G1Affine,
+    #[serde(with = "crate::serde::bls12_381_g1affine_vec")]
+    pub Y1: Vec<G1Affine>,
+    #[serde(with = "crate::serde::bls12_381_g2affine")]
     pub X2: G2Affine,
-    pub Y2: G2Affine,
+    #[serde(with = "crate::serde::bls12_381_g2affine_vec")]
+    pub Y2: Vec<G2Affine>,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+impl PublicKey {
+    /// The number of messages signed under this key.
+    pub fn messages(&self) -> usize {
+        self.Y2.len()
+    }
+
+    pub fn ensure_messages(&self, expected: usize) -> Result<(), WrongNumberOfMessages> {
+        if self.messages() == expected {
+            Ok(())
+        } else {
+            Err(WrongNumberOfMessages {
+                expected,
+                actual: self.messages(),
+            })
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
 pub struct KeyPair {
-    pub secret_key: G1Affine,
+    pub secret_key: Secret<G1Affine>,
     pub public_key: PublicKey,
 }
 
-#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
 pub struct Signature {
     #[serde(with = "crate::serde::bls12_381_g1affine")]
     pub sigma1: G1Affine,
@@ -35,40 +61,68 @@ impl Signature {
     }
 }
 
-pub fn keygen(rng: &mut impl Rng) -> KeyPair {
-    let (x, y) = (random_bls12_381_scalar(rng), random_bls12_381_scalar(rng));
+#[derive(Debug, thiserror::Error, PartialEq)]
+#[error("public key signs {expected} messages but {actual} were given")]
+pub struct WrongNumberOfMessages {
+    expected: usize,
+    actual: usize,
+}
+
+pub fn keygen(messages: usize, rng: &mut impl Rng) -> KeyPair {
+    let x = random_bls12_381_scalar(rng);
+    let y = (0..messages)
+        .map(|_| random_bls12_381_scalar(rng))
+        .collect::<Vec<_>>();
 
     let (G1, G2) = (G1Affine::generator(), G2Affine::generator());
     let (X1, X2) = (G1 * x, G2 * x);
-    let (Y1, Y2) = (G1 * y, G2 * y);
 
     KeyPair {
-        secret_key: X1.into(),
+        secret_key: Secret::new(X1.into()),
         public_key: PublicKey {
-            Y1: Y1.into(),
+            Y1: y.iter().map(|y_i| (G1 * y_i).into()).collect(),
             X2: X2.into(),
-            Y2: Y2.into(),
+            Y2: y.iter().map(|y_i| (G2 * y_i).into()).collect(),
         },
     }
 }
 
-pub fn sign(keypair: &KeyPair, C: G1Affine, rng: &mut impl Rng) -> Signature {
-    let X1 = &keypair.secret_key;
+/// Signs the messages committed to in `C` followed by `public_messages`.
+///
+/// The committed messages take the first positions, i.e. `C` has to be a commitment with the
+/// generators `Y1_1 .. Y1_k` where `k` is the number of messages minus the public ones.
+pub fn sign(
+    keypair: &KeyPair,
+    C: G1Affine,
+    public_messages: &[Scalar],
+    rng: &mut impl Rng,
+) -> Result<Signature, WrongNumberOfMessages> {
+    let Y1 = &keypair.public_key.Y1;
+    let committed = Y1
+        .len()
+        .checked_sub(public_messages.len())
+        .ok_or(WrongNumberOfMessages {
+            expected: Y1.len(),
+            actual: public_messages.len(),
+        })?;
+
+    let X1 = keypair.secret_key.expose();
     let G1 = G1Affine::generator();
     let u = random_bls12_381_scalar(rng);
-    let C = G1Projective::from(C);
+    let C = Y1[committed..]
+        .iter()
+        .zip(public_messages)
+        .fold(G1Projective::from(C), |C, (Y1_i, m_i)| C + Y1_i * m_i);
 
     let sigmaprime1 = G1 * u;
     let sigmaprime2 = (X1 + C) * u;
 
-    Signature::new(sigmaprime1, sigmaprime2)
+    Ok(Signature::new(sigmaprime1, sigmaprime2))
 }
 
-pub fn unblind(blinded: Signature, pedersen_blinding: Scalar) -> Signature {
-    let r = &pedersen_blinding;
-
+pub fn unblind(blinded: Signature, pedersen_blinding: &Scalar) -> Signature {
     let sigma1 = blinded.sigma1;
-    let sigma2 = blinded.sigma2 + (-sigma1 * r);
+    let sigma2 = blinded.sigma2 + (-sigma1 * pedersen_blinding);
 
     Signature::new(sigma1, sigma2)
 }
@@ -79,27 +133,87 @@ pub struct InvalidSignature;
 
 pub fn verify(
     public_key: &PublicKey,
-    m: &Scalar,
+    messages: &[Scalar],
     signature: &Signature,
 ) -> Result<(), InvalidSignature> {
     // for the signature to be valid, sigma1 MUST NOT be equal to the identity element
-    if signature.sigma1 == G1Affine::identity() {
+    if signature.sigma1 == G1Affine::identity() || messages.len() != public_key.messages() {
         return Err(InvalidSignature);
     }
 
     let G2 = G2Affine::generator();
-    let X2 = &public_key.X2;
-    let Y2 = &public_key.Y2;
+    let X2 = G2Projective::from(public_key.X2);
     let s1 = signature.sigma1;
     let s2 = signature.sigma2;
 
-    if pairing(s1, X2 + Y2 * m) == pairing(s2, G2) {
+    let X2_Y2_m = public_key
+        .Y2
+        .iter()
+        .zip(messages)
+        .fold(X2, |acc, (Y2_i, m_i)| acc + Y2_i * m_i);
+
+    if pairing(s1, X2_Y2_m) == pairing(s2, G2) {
         Ok(())
     } else {
         Err(InvalidSignature)
     }
 }
 
+/// Verifies many signatures under the same public key with `n + 2` pairings in total.
+///
+/// The pairing equations of all signatures are combined with random coefficients `r_i`, i.e.
+/// `e(sum r_i s1_i, X2) * prod_j e(sum r_i m_ij s1_i, Y2_j) == e(sum r_i s2_i, G2)`. If the
+/// combined check fails, every signature is verified on its own to find the invalid ones.
+pub fn verify_batch(
+    public_key: &PublicKey,
+    items: &[(&[Scalar], &Signature)],
+    rng: &mut impl Rng,
+) -> Result<(), BatchVerificationError> {
+    // a signature with sigma1 equal to the identity element vanishes from the combination
+    let can_be_combined = items.iter().all(|(messages, signature)| {
+        signature.sigma1 != G1Affine::identity() && messages.len() == public_key.messages()
+    });
+
+    if can_be_combined {
+        let mut s1 = G1Projective::identity();
+        let mut s1_m = vec![G1Projective::identity(); public_key.messages()];
+        let mut s2 = G1Projective::identity();
+
+        for &(messages, signature) in items {
+            let r = random_bls12_381_scalar(rng);
+
+            s1 += signature.sigma1 * r;
+            for (s1_m, m) in s1_m.iter_mut().zip(messages) {
+                *s1_m += signature.sigma1 * (r * m);
+            }
+            s2 += signature.sigma2 * r;
+        }
+
+        let terms = std::iter::once((G1Affine::from(s1), G2Prepared::from(public_key.X2)))
+            .chain(
+                s1_m.into_iter()
+                    .zip(&public_key.Y2)
+                    .map(|(s1_m, Y2)| (G1Affine::from(s1_m), G2Prepared::from(*Y2))),
+            )
+            .chain(std::iter::once((
+                G1Affine::from(-s2),
+                G2Prepared::from(G2Affine::generator()),
+            )))
+            .collect::<Vec<_>>();
+        let terms = terms.iter().map(|(p, q)| (p, q)).collect::<Vec<_>>();
+
+        let combined = multi_miller_loop(&terms).final_exponentiation();
+
+        if combined == Gt::identity() {
+            return Ok(());
+        }
+    }
+
+    verify_each(items, |&(messages, signature)| {
+        verify(public_key, messages, signature)
+    })
+}
+
 /// Convenience pairing function that allows us to pass parameters without noise of parenthesis or `.into()` calls.
 fn pairing<P, Q>(p: P, q: Q) -> Gt
 where
@@ -118,32 +232,384 @@ pub fn randomize(signature: &Signature, rng: &mut impl Rng) -> Signature {
     Signature::new(sigma1, sigma2)
 }
 
+/// Identifies a shown signature without revealing the messages it is on.
+///
+/// The serial number is `G1 * m_1` for the first message `m_1`, hence the same signed message
+/// always shows with the same serial number, which allows to detect double-spending.
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct SerialNumber(#[serde(with = "crate::serde::bls12_381_g1affine")] G1Affine);
+
+impl SerialNumber {
+    pub fn to_bytes(&self) -> [u8; 48] {
+        self.0.to_compressed()
+    }
+}
+
+impl From<G1Affine> for SerialNumber {
+    fn from(point: G1Affine) -> Self {
+        Self(point)
+    }
+}
+
+/// Shows a signature on `k` hidden messages followed by public ones in zero-knowledge.
+///
+/// In addition to randomizing the signature, its second element is blinded with `kappa` and the
+/// hidden messages are moved into `W = G2 * kappa + sum_i Y2_i * m_i`. The proof shows knowledge
+/// of an opening of `W` whose first message is the discrete logarithm of the serial number.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Show {
+    pub signature: Signature,
+    #[serde(with = "crate::serde::bls12_381_g2affine")]
+    W: G2Affine,
+    pub serial_number: SerialNumber,
+    proof: ShowProof,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+struct ShowProof {
+    #[serde(with = "crate::serde::bls12_381_g2affine")]
+    W_prime: G2Affine,
+    #[serde(with = "crate::serde::bls12_381_g1affine")]
+    serial_number_prime: G1Affine,
+    #[serde(with = "crate::serde::bls12_381_scalar_vec")]
+    u: Vec<Scalar>,
+    #[serde(with = "crate::serde::bls12_381_scalar")]
+    v: Scalar,
+}
+
+/// Shows the signature on `hidden` messages, which take the first positions.
+///
+/// # Panics
+///
+/// Panics if there are no hidden messages or more than the public key signs.
+#[allow(clippy::many_single_char_names)]
+pub fn show(
+    transcript: &mut Transcript,
+    public_key: &PublicKey,
+    signature: &Signature,
+    hidden: &[Scalar],
+    rng: &mut impl Rng,
+) -> Show {
+    assert!(
+        !hidden.is_empty() && hidden.len() <= public_key.messages(),
+        "at least one and at most all messages are hidden"
+    );
+
+    let (G1, G2) = (G1Affine::generator(), G2Affine::generator());
+    let Y2 = &public_key.Y2[..hidden.len()];
+    let (r, kappa) = (random_bls12_381_scalar(rng), random_bls12_381_scalar(rng));
+
+    let signature = Signature::new(
+        signature.sigma1 * r,
+        (signature.sigma2 + signature.sigma1 * kappa) * r,
+    );
+    let W = G2Affine::from(multi_exp_g2(&G2, kappa, Y2, hidden));
+    let serial_number = SerialNumber(G1Affine::from(G1 * hidden[0]));
+
+    let y = hidden
+        .iter()
+        .map(|_| random_bls12_381_scalar(rng))
+        .collect::<Vec<_>>();
+    let s = random_bls12_381_scalar(rng);
+
+    let W_prime = G2Affine::from(multi_exp_g2(&G2, s, Y2, &y));
+    let serial_number_prime = G1Affine::from(G1 * y[0]);
+
+    let k = show_challenge(
+        transcript,
+        &signature,
+        &W,
+        &serial_number,
+        &W_prime,
+        &serial_number_prime,
+    );
+    let u = y
+        .iter()
+        .zip(hidden)
+        .map(|(y_i, m_i)| y_i + k * m_i)
+        .collect();
+    let v = s + k * kappa;
+
+    Show {
+        signature,
+        W,
+        serial_number,
+        proof: ShowProof {
+            W_prime,
+            serial_number_prime,
+            u,
+            v,
+        },
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("signature show is invalid")]
+pub struct InvalidShow;
+
+/// Verifies that the show is of a valid signature on some hidden messages followed by
+/// `public_messages`.
+pub fn verify_show(
+    transcript: &mut Transcript,
+    public_key: &PublicKey,
+    public_messages: &[Scalar],
+    Show {
+        signature,
+        W,
+        serial_number,
+        proof:
+            ShowProof {
+                W_prime,
+                serial_number_prime,
+                u,
+                v,
+            },
+    }: &Show,
+) -> Result<(), InvalidShow> {
+    let hidden = u.len();
+
+    if hidden == 0
+        || hidden + public_messages.len() != public_key.messages()
+        || signature.sigma1 == G1Affine::identity()
+    {
+        return Err(InvalidShow);
+    }
+
+    let (G1, G2) = (G1Affine::generator(), G2Affine::generator());
+    let k = show_challenge(
+        transcript,
+        signature,
+        W,
+        serial_number,
+        W_prime,
+        serial_number_prime,
+    );
+
+    let knows_opening = multi_exp_g2(&G2, *v, &public_key.Y2[..hidden], u) == W_prime + W * k;
+    let knows_serial_number = G1 * u[0] == serial_number_prime + serial_number.0 * k;
+
+    if !knows_opening || !knows_serial_number {
+        return Err(InvalidShow);
+    }
+
+    let X2_W_Y2_m = multi_exp_g2(
+        &public_key.X2,
+        Scalar::one(),
+        &public_key.Y2[hidden..],
+        public_messages,
+    ) + W;
+
+    if pairing(signature.sigma1, X2_W_Y2_m) == pairing(signature.sigma2, G2) {
+        Ok(())
+    } else {
+        Err(InvalidShow)
+    }
+}
+
+/// Computes `G * a + sum_i H_i * b_i` in G2.
+fn multi_exp_g2(G: &G2Affine, a: Scalar, H: &[G2Affine], b: &[Scalar]) -> G2Projective {
+    H.iter()
+        .zip(b)
+        .fold(G * a, |acc, (H_i, b_i)| acc + H_i * b_i)
+}
+
+/// Computes `k` from the transcript after absorbing `sigma1 | sigma2 | W | S | W' | S'`.
+fn show_challenge(
+    transcript: &mut Transcript,
+    signature: &Signature,
+    W: &G2Affine,
+    serial_number: &SerialNumber,
+    W_prime: &G2Affine,
+    serial_number_prime: &G1Affine,
+) -> Scalar {
+    transcript.domain_separator(b"pointcheval-sanders-show");
+    transcript.append_bls12_381_g1(b"sigma1", &signature.sigma1);
+    transcript.append_bls12_381_g1(b"sigma2", &signature.sigma2);
+    transcript.append_bls12_381_g2(b"W", W);
+    transcript.append_bls12_381_g1(b"S", &serial_number.0);
+    transcript.append_bls12_381_g2(b"W'", W_prime);
+    transcript.append_bls12_381_g1(b"S'", serial_number_prime);
+
+    transcript.challenge_bls12_381_scalar(b"k")
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
     use crate::pedersen::{commit, Decommitment};
+    use crate::transcript::SessionId;
     use rand::thread_rng;
 
     #[test]
     fn pointcheval_sanders_end_to_end() {
-        let keypair = keygen(&mut thread_rng());
-        let message = random_bls12_381_scalar(&mut thread_rng());
+        let keypair = keygen(3, &mut thread_rng());
+        let hidden = random_bls12_381_scalar(&mut thread_rng());
+        let public = [Scalar::from(42u64), Scalar::from(7u64)];
 
         let (commitment, Decommitment { r: blinding, .. }) = commit(
             &G1Affine::generator(),
-            &keypair.public_key.Y1,
-            &message,
+            &keypair.public_key.Y1[..1],
+            &[hidden],
             &mut rand::thread_rng(),
         );
-        let blinded_sig = sign(&keypair, commitment, &mut thread_rng());
-        let sig = unblind(blinded_sig, blinding);
+        let blinded_sig = sign(&keypair, commitment, &public, &mut thread_rng()).unwrap();
+        let sig = unblind(blinded_sig, blinding.expose());
 
-        verify(&keypair.public_key, &message, &sig).expect("unblinded signature verifies");
+        let messages = [hidden, public[0], public[1]];
+        verify(&keypair.public_key, &messages, &sig).expect("unblinded signature verifies");
 
         let randomized = randomize(&sig, &mut thread_rng());
 
         assert_ne!(randomized, sig, "randomized signature is different");
 
-        verify(&keypair.public_key, &message, &randomized).expect("randomized signature verifies")
+        verify(&keypair.public_key, &messages, &randomized).expect("randomized signature verifies")
+    }
+
+    #[test]
+    fn signature_does_not_verify_with_other_public_messages() {
+        let keypair = keygen(2, &mut thread_rng());
+        let hidden = random_bls12_381_scalar(&mut thread_rng());
+
+        let (commitment, Decommitment { r: blinding, .. }) = commit(
+            &G1Affine::generator(),
+            &keypair.public_key.Y1[..1],
+            &[hidden],
+            &mut rand::thread_rng(),
+        );
+        let blinded_sig = sign(
+            &keypair,
+            commitment,
+            &[Scalar::from(1u64)],
+            &mut thread_rng(),
+        )
+        .unwrap();
+        let sig = unblind(blinded_sig, blinding.expose());
+
+        assert!(verify(&keypair.public_key, &[hidden, Scalar::from(2u64)], &sig).is_err());
+        assert!(verify(&keypair.public_key, &[hidden], &sig).is_err());
+    }
+
+    #[test]
+    fn sign_rejects_too_many_public_messages() {
+        let keypair = keygen(1, &mut thread_rng());
+
+        let error = sign(
+            &keypair,
+            G1Affine::generator(),
+            &[Scalar::one(), Scalar::one()],
+            &mut thread_rng(),
+        )
+        .unwrap_err();
+
+        assert_eq!(
+            error,
+            WrongNumberOfMessages {
+                expected: 1,
+                actual: 2
+            }
+        );
+    }
+
+    #[test]
+    fn show_verifies_and_keeps_serial_number() {
+        let keypair = keygen(3, &mut thread_rng());
+        let (messages, sig) = signed_messages(&keypair);
+        let session_id = SessionId::random(&mut thread_rng());
+
+        let show_1 = show(
+            &mut Transcript::new(&session_id),
+            &keypair.public_key,
+            &sig,
+            &messages[..1],
+            &mut thread_rng(),
+        );
+        let show_2 = show(
+            &mut Transcript::new(&session_id),
+            &keypair.public_key,
+            &sig,
+            &messages[..1],
+            &mut thread_rng(),
+        );
+
+        verify_show(
+            &mut Transcript::new(&session_id),
+            &keypair.public_key,
+            &messages[1..],
+            &show_1,
+        )
+        .expect("show verifies");
+        assert_ne!(show_1.signature, show_2.signature);
+        assert_eq!(show_1.serial_number, show_2.serial_number);
+    }
+
+    #[test]
+    fn show_does_not_verify_with_other_public_messages_or_session() {
+        let keypair = keygen(3, &mut thread_rng());
+        let (messages, sig) = signed_messages(&keypair);
+        let session_id = SessionId::random(&mut thread_rng());
+
+        let shown = show(
+            &mut Transcript::new(&session_id),
+            &keypair.public_key,
+            &sig,
+            &messages[..1],
+            &mut thread_rng(),
+        );
+
+        assert!(verify_show(
+            &mut Transcript::new(&session_id),
+            &keypair.public_key,
+            &[messages[1], messages[1]],
+            &shown,
+        )
+        .is_err());
+        assert!(verify_show(
+            &mut Transcript::new(&SessionId::random(&mut thread_rng())),
+            &keypair.public_key,
+            &messages[1..],
+            &shown,
+        )
+        .is_err());
+    }
+
+    fn signed_messages(keypair: &KeyPair) -> (Vec<Scalar>, Signature) {
+        let messages = (0..keypair.public_key.messages())
+            .map(|_| random_bls12_381_scalar(&mut thread_rng()))
+            .collect::<Vec<_>>();
+
+        let (commitment, Decommitment { r: blinding, .. }) = commit(
+            &G1Affine::generator(),
+            &keypair.public_key.Y1[..1],
+            &messages[..1],
+            &mut thread_rng(),
+        );
+        let blinded_sig = sign(keypair, commitment, &messages[1..], &mut thread_rng()).unwrap();
+
+        (messages, unblind(blinded_sig, blinding.expose()))
+    }
+
+    #[test]
+    fn verify_batch_reports_invalid_signatures() {
+        let keypair = keygen(2, &mut thread_rng());
+        let mut batch = (0..4)
+            .map(|_| signed_messages(&keypair))
+            .collect::<Vec<_>>();
+
+        let items = batch
+            .iter()
+            .map(|(m, sig)| (&m[..], sig))
+            .collect::<Vec<_>>();
+        verify_batch(&keypair.public_key, &items, &mut thread_rng()).expect("batch verifies");
+
+        batch[1].0[1] = random_bls12_381_scalar(&mut thread_rng());
+        batch[3].1 = randomize(&batch[2].1, &mut thread_rng());
+
+        let items = batch
+            .iter()
+            .map(|(m, sig)| (&m[..], sig))
+            .collect::<Vec<_>>();
+        let error = verify_batch(&keypair.public_key, &items, &mut thread_rng()).unwrap_err();
+
+        assert_eq!(error.invalid(), &[1, 3]);
     }
 }
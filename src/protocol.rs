@@ -0,0 +1,189 @@
+//! Generic interface to drive the actors of the protocol.
+//!
+//! The traits in this module abstract over the concrete actors, which allows the protocol to be
+//! driven by [`run_happy_path`] and [`run_refund`] regardless of how messages are exchanged between
+//! the parties and which blockchain the transactions are broadcast to.
+
+mod run_happy_path;
+mod run_refund;
+
+pub use self::run_happy_path::run_happy_path;
+pub use self::run_refund::run_refund;
+use crate::{bitcoin, puzzle_promise, puzzle_solver, receiver::Receiver, sender::Sender};
+use rand::Rng;
+
+/// Moves an actor (or the blockchain) to its next state upon receiving a message.
+pub trait Transition<M>: Sized {
+    fn transition(self, message: M, rng: &mut impl Rng) -> anyhow::Result<Self>;
+}
+
+/// Produces the message an actor wants to send in its current state.
+pub trait NextMessage<M> {
+    fn next_message(&self) -> anyhow::Result<M>;
+}
+
+/// Produces a transaction an actor wants to broadcast in its current state.
+pub trait MakeTransaction<T> {
+    fn make_transaction(&self) -> anyhow::Result<T>;
+}
+
+pub trait WaitForLocktime: Sized {
+    /// Advances the blockchain until the given transaction's nLockTime no longer prevents its inclusion.
+    fn wait_for_locktime(self, transaction: &bitcoin::Transaction) -> anyhow::Result<Self>;
+}
+
+/// Carries messages from one party to another.
+pub trait Transport<M> {
+    /// Delivers the message and returns it the way the recipient received it.
+    fn deliver(&mut self, message: M) -> anyhow::Result<M>;
+}
+
+/// Hands messages from one party to another within the same process.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct InMemoryTransport;
+
+impl<M> Transport<M> for InMemoryTransport {
+    fn deliver(&mut self, message: M) -> anyhow::Result<M> {
+        Ok(message)
+    }
+}
+
+impl Transition<puzzle_promise::Message> for puzzle_promise::Tumbler {
+    fn transition(
+        self,
+        message: puzzle_promise::Message,
+        rng: &mut impl Rng,
+    ) -> anyhow::Result<Self> {
+        self.transition(message, rng)
+    }
+}
+
+impl NextMessage<puzzle_promise::Message> for puzzle_promise::Tumbler {
+    fn next_message(&self) -> anyhow::Result<puzzle_promise::Message> {
+        self.next_message()
+    }
+}
+
+impl MakeTransaction<puzzle_promise::FundTransaction> for puzzle_promise::Tumbler {
+    fn make_transaction(&self) -> anyhow::Result<puzzle_promise::FundTransaction> {
+        self.fund_transaction()
+    }
+}
+
+impl MakeTransaction<puzzle_promise::RefundTransaction> for puzzle_promise::Tumbler {
+    fn make_transaction(&self) -> anyhow::Result<puzzle_promise::RefundTransaction> {
+        self.refund_transaction()
+    }
+}
+
+impl Transition<puzzle_solver::Message> for puzzle_solver::Tumbler {
+    fn transition(
+        self,
+        message: puzzle_solver::Message,
+        rng: &mut impl Rng,
+    ) -> anyhow::Result<Self> {
+        self.transition_on_message(message, rng)
+    }
+}
+
+impl Transition<puzzle_solver::FundTransaction> for puzzle_solver::Tumbler {
+    fn transition(
+        self,
+        transaction: puzzle_solver::FundTransaction,
+        rng: &mut impl Rng,
+    ) -> anyhow::Result<Self> {
+        self.transition_on_transaction(transaction, rng)
+    }
+}
+
+impl NextMessage<puzzle_solver::Message> for puzzle_solver::Tumbler {
+    fn next_message(&self) -> anyhow::Result<puzzle_solver::Message> {
+        self.next_message()
+    }
+}
+
+impl MakeTransaction<puzzle_solver::RedeemTransaction> for puzzle_solver::Tumbler {
+    fn make_transaction(&self) -> anyhow::Result<puzzle_solver::RedeemTransaction> {
+        self.redeem_transaction()
+    }
+}
+
+impl Transition<puzzle_promise::Message> for Receiver {
+    fn transition(
+        self,
+        message: puzzle_promise::Message,
+        rng: &mut impl Rng,
+    ) -> anyhow::Result<Self> {
+        self.transition_on_puzzle_promise_message(message, rng)
+    }
+}
+
+impl Transition<puzzle_solver::Message> for Receiver {
+    fn transition(
+        self,
+        message: puzzle_solver::Message,
+        rng: &mut impl Rng,
+    ) -> anyhow::Result<Self> {
+        self.transition_on_puzzle_solver_message(message, rng)
+    }
+}
+
+impl NextMessage<puzzle_promise::Message> for Receiver {
+    fn next_message(&self) -> anyhow::Result<puzzle_promise::Message> {
+        self.next_puzzle_promise_message()
+    }
+}
+
+impl MakeTransaction<puzzle_promise::RedeemTransaction> for Receiver {
+    fn make_transaction(&self) -> anyhow::Result<puzzle_promise::RedeemTransaction> {
+        self.redeem_transaction()
+    }
+}
+
+impl Transition<puzzle_promise::Message> for Sender {
+    fn transition(
+        self,
+        message: puzzle_promise::Message,
+        rng: &mut impl Rng,
+    ) -> anyhow::Result<Self> {
+        self.transition_on_puzzle_promise_message(message, rng)
+    }
+}
+
+impl Transition<puzzle_solver::Message> for Sender {
+    fn transition(
+        self,
+        message: puzzle_solver::Message,
+        rng: &mut impl Rng,
+    ) -> anyhow::Result<Self> {
+        self.transition_on_puzzle_solver_message(message, rng)
+    }
+}
+
+impl Transition<puzzle_solver::RedeemTransaction> for Sender {
+    fn transition(
+        self,
+        transaction: puzzle_solver::RedeemTransaction,
+        _: &mut impl Rng,
+    ) -> anyhow::Result<Self> {
+        self.transition_on_transaction(transaction)
+    }
+}
+
+impl MakeTransaction<puzzle_solver::FundTransaction> for Sender {
+    fn make_transaction(&self) -> anyhow::Result<puzzle_solver::FundTransaction> {
+        self.unsigned_fund_transaction()
+    }
+}
+
+impl MakeTransaction<puzzle_solver::RefundTransaction> for Sender {
+    fn make_transaction(&self) -> anyhow::Result<puzzle_solver::RefundTransaction> {
+        self.signed_refund_transaction()
+    }
+}
+
+impl NextMessage<puzzle_solver::Message> for Sender {
+    fn next_message(&self) -> anyhow::Result<puzzle_solver::Message> {
+        self.next_puzzle_solver_message()
+    }
+}
@@ -1,13 +1,14 @@
-use crate::harness::{MakeTransaction, NextMessage, Transition};
-use a2l::{puzzle_promise, puzzle_solver};
+use crate::protocol::{MakeTransaction, NextMessage, Transition, Transport};
+use crate::{bitcoin, puzzle_promise, puzzle_solver};
 use anyhow::Context;
 use rand::Rng;
 
-pub fn run_happy_path<TP, TS, S, R, B>(
+pub fn run_happy_path<TP, TS, S, R, T, B>(
     tumbler_promise0: TP,
     tumbler_solver0: TS,
     sender0: S,
     receiver0: R,
+    transport: &mut T,
     blockchain: B,
     rng: &mut impl Rng,
 ) -> anyhow::Result<(TP, TS, S, R, B)>
@@ -28,11 +29,12 @@ where
         + NextMessage<puzzle_promise::Message>
         + Transition<puzzle_solver::Message>
         + MakeTransaction<puzzle_promise::RedeemTransaction>,
+    T: Transport<puzzle_promise::Message> + Transport<puzzle_solver::Message>,
     B: Transition<bitcoin::Transaction>,
 {
-    let ps_message0 = sender0.next_message()?;
+    let ps_message0 = transport.deliver(sender0.next_message()?)?;
     let tumbler_solver1 = tumbler_solver0.transition(ps_message0, rng)?;
-    let ps_message1 = tumbler_solver1.next_message()?;
+    let ps_message1 = transport.deliver(tumbler_solver1.next_message()?)?;
     let sender1 = sender0.transition(ps_message1, rng)?;
 
     let fund_transaction = sender1.make_transaction()?;
@@ -41,21 +43,21 @@ where
         .context("failed to broadcast sender's fund transaction")?;
 
     let tumbler_solver2 = tumbler_solver1.transition(fund_transaction, rng)?;
-    let ps_message2 = tumbler_solver2.next_message()?;
+    let ps_message2 = transport.deliver(tumbler_solver2.next_message()?)?;
     let sender2 = sender1.transition(ps_message2, rng)?;
 
-    let ps_message3 = sender2.next_message()?;
+    let ps_message3 = transport.deliver(sender2.next_message()?)?;
     let receiver1 = receiver0.transition(ps_message3, rng)?;
 
-    let pp_message0 = receiver1.next_message()?;
+    let pp_message0 = transport.deliver(receiver1.next_message()?)?;
     let tumbler_promise1 = tumbler_promise0.transition(pp_message0, rng)?;
-    let pp_message1 = tumbler_promise1.next_message()?;
+    let pp_message1 = transport.deliver(tumbler_promise1.next_message()?)?;
     let receiver2 = receiver1.transition(pp_message1, rng)?;
-    let pp_message2 = receiver2.next_message()?;
+    let pp_message2 = transport.deliver(receiver2.next_message()?)?;
     let tumbler_promise2 = tumbler_promise1.transition(pp_message2, rng)?;
-    let pp_message3 = tumbler_promise2.next_message()?;
+    let pp_message3 = transport.deliver(tumbler_promise2.next_message()?)?;
     let receiver3 = receiver2.transition(pp_message3, rng)?;
-    let pp_message4 = receiver3.next_message()?;
+    let pp_message4 = transport.deliver(receiver3.next_message()?)?;
 
     let sender3 = sender2.transition(pp_message4, rng)?;
 
@@ -64,11 +66,11 @@ where
         .transition(fund_transaction.into(), rng)
         .context("failed to broadcast tumbler's fund transaction")?;
 
-    let ps_message4 = sender3.next_message()?;
+    let ps_message4 = transport.deliver(sender3.next_message()?)?;
     let tumbler_solver3 = tumbler_solver2.transition(ps_message4, rng)?;
-    let ps_message5 = tumbler_solver3.next_message()?;
+    let ps_message5 = transport.deliver(tumbler_solver3.next_message()?)?;
     let sender4 = sender3.transition(ps_message5, rng)?;
-    let ps_message6 = sender4.next_message()?;
+    let ps_message6 = transport.deliver(sender4.next_message()?)?;
     let tumbler_solver4 = tumbler_solver3.transition(ps_message6, rng)?;
 
     let redeem_transaction = tumbler_solver4.make_transaction()?;
@@ -77,7 +79,7 @@ where
         .context("failed to broadcast tumbler's redeem transaction")?;
 
     let sender5 = sender4.transition(redeem_transaction, rng)?;
-    let ps_message7 = sender5.next_message()?;
+    let ps_message7 = transport.deliver(sender5.next_message()?)?;
     let receiver4 = receiver3.transition(ps_message7, rng)?;
 
     let redeem_transaction = receiver4.make_transaction()?;
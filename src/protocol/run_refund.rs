@@ -1,13 +1,14 @@
-use crate::harness::{MakeTransaction, NextMessage, Transition};
-use a2l::{puzzle_promise, puzzle_solver};
+use crate::protocol::{MakeTransaction, NextMessage, Transition, Transport, WaitForLocktime};
+use crate::{bitcoin, puzzle_promise, puzzle_solver};
 use anyhow::Context;
 use rand::Rng;
 
-pub fn run_refund<TP, TS, S, R, B>(
+pub fn run_refund<TP, TS, S, R, T, B>(
     tumbler_promise0: TP,
     tumbler_solver0: TS,
     sender0: S,
     receiver0: R,
+    transport: &mut T,
     blockchain: B,
     rng: &mut impl Rng,
 ) -> anyhow::Result<(TP, TS, S, R, B)>
@@ -27,11 +28,12 @@ where
     R: Transition<puzzle_promise::Message>
         + NextMessage<puzzle_promise::Message>
         + Transition<puzzle_solver::Message>,
-    B: Transition<bitcoin::Transaction>,
+    T: Transport<puzzle_promise::Message> + Transport<puzzle_solver::Message>,
+    B: Transition<bitcoin::Transaction> + WaitForLocktime,
 {
-    let ps_message0 = sender0.next_message()?;
+    let ps_message0 = transport.deliver(sender0.next_message()?)?;
     let tumbler_solver1 = tumbler_solver0.transition(ps_message0, rng)?;
-    let ps_message1 = tumbler_solver1.next_message()?;
+    let ps_message1 = transport.deliver(tumbler_solver1.next_message()?)?;
     let sender1 = sender0.transition(ps_message1, rng)?;
 
     let fund_transaction: puzzle_solver::FundTransaction = sender1.make_transaction()?;
@@ -40,21 +42,21 @@ where
         .context("failed to broadcast sender's fund transaction")?;
 
     let tumbler_solver2 = tumbler_solver1.transition(fund_transaction, rng)?;
-    let ps_message2 = tumbler_solver2.next_message()?;
+    let ps_message2 = transport.deliver(tumbler_solver2.next_message()?)?;
     let sender2 = sender1.transition(ps_message2, rng)?;
 
-    let ps_message3 = sender2.next_message()?;
+    let ps_message3 = transport.deliver(sender2.next_message()?)?;
     let receiver1 = receiver0.transition(ps_message3, rng)?;
 
-    let pp_message0 = receiver1.next_message()?;
+    let pp_message0 = transport.deliver(receiver1.next_message()?)?;
     let tumbler_promise1 = tumbler_promise0.transition(pp_message0, rng)?;
-    let pp_message1 = tumbler_promise1.next_message()?;
+    let pp_message1 = transport.deliver(tumbler_promise1.next_message()?)?;
     let receiver2 = receiver1.transition(pp_message1, rng)?;
-    let pp_message2 = receiver2.next_message()?;
+    let pp_message2 = transport.deliver(receiver2.next_message()?)?;
     let tumbler_promise2 = tumbler_promise1.transition(pp_message2, rng)?;
-    let pp_message3 = tumbler_promise2.next_message()?;
+    let pp_message3 = transport.deliver(tumbler_promise2.next_message()?)?;
     let receiver3 = receiver2.transition(pp_message3, rng)?;
-    let pp_message4 = receiver3.next_message()?;
+    let pp_message4 = transport.deliver(receiver3.next_message()?)?;
 
     let sender3 = sender2.transition(pp_message4, rng)?;
 
@@ -63,15 +65,19 @@ where
         .transition(fund_transaction.into(), rng)
         .context("failed to broadcast tumbler's fund transaction")?;
 
-    let refund_transaction: puzzle_promise::RefundTransaction =
-        tumbler_promise2.make_transaction()?;
+    let refund_transaction: bitcoin::Transaction =
+        MakeTransaction::<puzzle_promise::RefundTransaction>::make_transaction(&tumbler_promise2)?
+            .into();
     let blockchain = blockchain
-        .transition(refund_transaction.into(), rng)
+        .wait_for_locktime(&refund_transaction)?
+        .transition(refund_transaction, rng)
         .context("failed to broadcast tumbler's refund transaction")?;
 
-    let refund_transaction: puzzle_solver::RefundTransaction = sender3.make_transaction()?;
+    let refund_transaction: bitcoin::Transaction =
+        MakeTransaction::<puzzle_solver::RefundTransaction>::make_transaction(&sender3)?.into();
     let blockchain = blockchain
-        .transition(refund_transaction.into(), rng)
+        .wait_for_locktime(&refund_transaction)?
+        .transition(refund_transaction, rng)
         .context("failed to broadcast sender's refund transaction")?;
 
     Ok((
@@ -1,27 +1,41 @@
+use crate::keyring::Keyring;
+use crate::token_store::TokenStore;
+use crate::transcript::{SessionId, Transcript};
 use crate::Lock;
 use crate::{
-    bitcoin, hsm_cl, pointcheval_sanders, secp256k1, NoMessage, NoTransaction, Token,
-    UnexpectedMessage,
+    bitcoin, hsm_cl, joint_output, pointcheval_sanders, secp256k1, token_attributes, Epoch,
+    NoMessage, NoTransaction, UnexpectedMessage,
 };
-use anyhow::Context;
 use rand::Rng;
+use std::sync::Arc;
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
 pub struct Params {
+    /// Identifies this run of the protocol, every proof exchanged in it is bound to it.
+    pub session_id: SessionId,
+    #[serde(with = "crate::serde::bitcoin_address")]
     pub redeem_identity: bitcoin::Address,
+    #[serde(with = "crate::serde::bitcoin_address")]
     pub refund_identity: bitcoin::Address,
-    pub expiry: u32,
+    pub timelocks: bitcoin::TimelockPolicy,
+    #[serde(with = "crate::serde::bitcoin_amount")]
     tumble_amount: bitcoin::Amount,
-    spend_transaction_fee_per_wu: bitcoin::Amount,
-    /// A fully-funded transaction that is only missing the joint output.
+    /// Only tokens issued for this epoch and the tumble amount are accepted.
+    pub epoch: Epoch,
+    spend_transaction_feerate: bitcoin::FeeRate,
+    /// The kind of output the tumbler locks its funds for the receiver in.
+    pub joint_output: bitcoin::JointOutputKind,
+    /// An unsigned, fully-funded PSBT that is only missing the joint output.
     ///
     /// Fully-funded means we expect this transaction to have enough inputs to pay the joint output
     /// of value `amount` and in addition have one or more change outputs that already incorporate
-    /// the fee the user is willing to pay.
-    pub partial_fund_transaction: bitcoin::Transaction,
+    /// the fee the user is willing to pay. The joint output is inserted at index 0, the returned
+    /// PSBT can then be signed by whichever wallet created this one.
+    #[serde(with = "crate::serde::bitcoin_psbt")]
+    pub partial_fund_psbt: bitcoin::Psbt,
 }
 
-#[derive(Debug, derive_more::From, serde::Serialize, strum_macros::Display)]
+#[derive(Debug, derive_more::From, serde::Serialize, serde::Deserialize, strum_macros::Display)]
 pub enum Message {
     Message0(Message0),
     Message1(Message1),
@@ -30,14 +44,15 @@ pub enum Message {
     Message4(Message4),
 }
 
-#[derive(Debug, serde::Serialize)]
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
 pub struct Message0 {
-    #[serde(with = "crate::serde::bls12_381_scalar")]
-    pub token: Token,
-    pub sig_token_rand: pointcheval_sanders::Signature,
+    /// The epoch the token has been issued in.
+    pub epoch: Epoch,
+    /// Shows the token signature without revealing the token.
+    pub show_token: pointcheval_sanders::Show,
 }
 
-#[derive(Debug, serde::Serialize)]
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
 pub struct Message1 {
     #[serde(with = "crate::serde::secp256k1_public_key")]
     pub X_t: secp256k1::PublicKey,
@@ -45,22 +60,23 @@ pub struct Message1 {
     pub A: secp256k1::PublicKey,
     pub c_alpha: hsm_cl::Ciphertext,
     pub pi_alpha: hsm_cl::Proof,
+    /// The MuSig2 nonces of the tumbler if the joint output is a P2TR output.
+    pub nonces_t: Option<joint_output::Nonces>,
 }
 
-#[derive(Debug, serde::Serialize)]
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
 pub struct Message2 {
     #[serde(with = "crate::serde::secp256k1_public_key")]
     pub X_r: secp256k1::PublicKey,
-    #[serde(with = "crate::serde::secp256k1_signature")]
-    pub sig_refund_r: secp256k1::Signature,
+    pub sigs_refund_r: joint_output::RefundSignatures,
 }
 
-#[derive(Debug, serde::Serialize)]
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
 pub struct Message3 {
-    pub sig_redeem_t: secp256k1::EncryptedSignature,
+    pub sig_redeem_t: joint_output::RedeemSignature,
 }
 
-#[derive(Debug, serde::Serialize)]
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
 pub struct Message4 {
     pub l: Lock,
 }
@@ -92,7 +108,9 @@ impl From<RefundTransaction> for bitcoin::Transaction {
     }
 }
 
-#[derive(Debug, derive_more::From, Clone, strum_macros::Display)]
+#[derive(
+    Debug, derive_more::From, Clone, serde::Serialize, serde::Deserialize, strum_macros::Display,
+)]
 pub enum Tumbler {
     Tumbler0(Tumbler0),
     Tumbler1(Tumbler1),
@@ -102,11 +120,32 @@ pub enum Tumbler {
 impl Tumbler {
     pub fn new(
         params: Params,
-        HE: hsm_cl::KeyPair,
-        PS: pointcheval_sanders::KeyPair,
+        class_group: hsm_cl::ClassGroupParams,
+        keyring: Arc<Keyring>,
+        token_store: Arc<dyn TokenStore>,
         rng: &mut impl Rng,
-    ) -> Self {
-        Tumbler0::new(params, HE, PS, rng).into()
+    ) -> anyhow::Result<Self> {
+        Ok(Tumbler0::new(params, class_group, keyring, token_store, rng)?.into())
+    }
+
+    /// Attaches the token store after the tumbler has been restored from persisted state.
+    pub fn with_token_store(self, token_store: Arc<dyn TokenStore>) -> Self {
+        match self {
+            Tumbler::Tumbler0(inner) => Tumbler0 {
+                token_store,
+                ..inner
+            }
+            .into(),
+            state => state,
+        }
+    }
+
+    /// Attaches the keyring after the tumbler has been restored from persisted state.
+    pub fn with_keyring(self, keyring: Arc<Keyring>) -> Self {
+        match self {
+            Tumbler::Tumbler0(inner) => Tumbler0 { keyring, ..inner }.into(),
+            state => state,
+        }
     }
 
     pub fn transition(self, message: Message, rng: &mut impl Rng) -> anyhow::Result<Self> {
@@ -127,7 +166,7 @@ impl Tumbler {
         let message = match self {
             Tumbler::Tumbler1(inner) => inner.next_message().into(),
             Tumbler::Tumbler2(inner) => inner.next_message().into(),
-            state => anyhow::bail!(NoMessage::new(state.clone())),
+            state => anyhow::bail!(NoMessage::new(state)),
         };
 
         Ok(message)
@@ -142,6 +181,14 @@ impl Tumbler {
         Ok(transaction)
     }
 
+    /// Returns the fund transaction as a PSBT for the tumbler's wallet to sign.
+    pub fn unsigned_fund_psbt(&self) -> anyhow::Result<bitcoin::Psbt> {
+        match self {
+            Tumbler::Tumbler2(inner) => Ok(inner.transactions.fund_psbt.clone()),
+            _ => anyhow::bail!(NoTransaction),
+        }
+    }
+
     pub fn refund_transaction(&self) -> anyhow::Result<RefundTransaction> {
         let transaction = match self {
             Tumbler::Tumbler2(inner) => inner.signed_refund_transaction(),
@@ -150,76 +197,136 @@ impl Tumbler {
 
         Ok(transaction)
     }
+
+    /// Returns the cheapest signed refund transaction that pays at least `feerate`.
+    pub fn signed_refund_transaction_for_feerate(
+        &self,
+        feerate: bitcoin::FeeRate,
+    ) -> anyhow::Result<RefundTransaction> {
+        let transaction = match self {
+            Tumbler::Tumbler2(inner) => inner.signed_refunds.for_feerate(feerate)?.clone(),
+            _ => anyhow::bail!(NoTransaction),
+        };
+
+        Ok(RefundTransaction(transaction))
+    }
+
+    pub(crate) fn signed_refunds(&self) -> anyhow::Result<&bitcoin::SignedRefunds> {
+        match self {
+            Tumbler::Tumbler2(inner) => Ok(&inner.signed_refunds),
+            _ => anyhow::bail!(NoTransaction),
+        }
+    }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct Tumbler0 {
     x_t: secp256k1::KeyPair,
     params: Params,
-    HE: hsm_cl::KeyPair,
-    PE: pointcheval_sanders::KeyPair,
+    class_group: hsm_cl::ClassGroupParams,
+    #[serde(skip, default = "crate::keyring::detached")]
+    keyring: Arc<Keyring>,
+    #[serde(skip, default = "crate::token_store::detached")]
+    token_store: Arc<dyn TokenStore>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct Tumbler1 {
     x_t: secp256k1::KeyPair,
+    nonces: Option<joint_output::SecretNonces>,
     a: secp256k1::KeyPair,
     params: Params,
-    HE: hsm_cl::KeyPair,
     c_alpha: hsm_cl::Ciphertext,
     pi_alpha: hsm_cl::Proof,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct Tumbler2 {
     x_t: secp256k1::KeyPair,
     a: secp256k1::KeyPair,
-    signed_refund_transaction: bitcoin::Transaction,
+    signed_refunds: bitcoin::SignedRefunds,
     transactions: bitcoin::Transactions,
-    sig_redeem_t: secp256k1::EncryptedSignature,
+    sig_redeem_t: joint_output::RedeemSignature,
 }
 
 impl Tumbler0 {
     pub fn new(
         params: Params,
-        HE: hsm_cl::KeyPair,
-        PE: pointcheval_sanders::KeyPair,
+        class_group: hsm_cl::ClassGroupParams,
+        keyring: Arc<Keyring>,
+        token_store: Arc<dyn TokenStore>,
         rng: &mut impl Rng,
-    ) -> Self {
+    ) -> anyhow::Result<Self> {
+        params.timelocks.validate()?;
+        bitcoin::validate_partial_fund_psbt(
+            &params.partial_fund_psbt,
+            params.tumbler_receiver_joint_output_value(),
+        )?;
+        keyring.token_keypair(params.epoch)?;
+
         let x_t = secp256k1::KeyPair::random(rng);
 
-        Self {
+        Ok(Self {
             x_t,
             params,
-            HE,
-            PE,
-        }
+            class_group,
+            keyring,
+            token_store,
+        })
     }
 
     pub fn receive(
         self,
-        Message0 {
-            token,
-            sig_token_rand,
-        }: Message0,
+        Message0 { epoch, show_token }: Message0,
         rng: &mut impl Rng,
     ) -> anyhow::Result<Tumbler1> {
-        pointcheval_sanders::verify(&self.PE.public_key, &token, &sig_token_rand)?;
+        if epoch != self.params.epoch {
+            anyhow::bail!(WrongEpoch {
+                expected: self.params.epoch,
+                actual: epoch
+            })
+        }
 
+        // fails if the epoch has ended since the session started
+        let PS = self.keyring.token_keypair(epoch)?;
+        pointcheval_sanders::verify_show(
+            &mut Transcript::new(&self.params.session_id),
+            &PS.public_key,
+            &token_attributes(self.params.tumble_amount, epoch),
+            &show_token,
+        )?;
+        self.token_store.spend(&show_token.serial_number)?;
+
+        let HE = self
+            .keyring
+            .issue_puzzle(epoch, self.params.timelocks.promise_expiry)?;
         let a = secp256k1::KeyPair::random(rng);
-        let (c_alpha, pi_alpha) = hsm_cl::encrypt(&self.HE.to_pk(), &a);
+        let (c_alpha, pi_alpha) = hsm_cl::encrypt(&self.class_group, &HE, &a);
+        let nonces = joint_output::nonce_gen(
+            self.params.joint_output,
+            &self.x_t,
+            self.params.refund_transaction_count(),
+            rng,
+        );
 
         Ok(Tumbler1 {
             x_t: self.x_t,
+            nonces,
             a,
             c_alpha,
             pi_alpha,
             params: self.params,
-            HE: self.HE,
         })
     }
 }
 
+#[derive(Debug, thiserror::Error)]
+#[error("expected a token of epoch {expected}, received a token of epoch {actual}")]
+struct WrongEpoch {
+    expected: Epoch,
+    actual: Epoch,
+}
+
 impl Tumbler1 {
     pub fn next_message(&self) -> Message1 {
         let X_t = self.x_t.to_pk();
@@ -230,48 +337,61 @@ impl Tumbler1 {
             A,
             c_alpha: self.c_alpha.clone(),
             pi_alpha: self.pi_alpha.clone(),
+            nonces_t: self.nonces.as_ref().map(|nonces| nonces.public().clone()),
         }
     }
 
     pub fn receive(
         self,
-        Message2 { X_r, sig_refund_r }: Message2,
+        Message2 { X_r, sigs_refund_r }: Message2,
         rng: &mut impl Rng,
     ) -> anyhow::Result<Tumbler2> {
+        let joint_output = self
+            .params
+            .joint_output
+            .joint_output(&self.x_t.to_pk(), &X_r)?;
         let transactions = bitcoin::make_transactions(
-            self.params.partial_fund_transaction.clone(),
+            self.params.partial_fund_psbt.clone(),
             self.params.tumbler_receiver_joint_output_value(),
             self.params.tumbler_receiver_joint_output_takeout(),
-            &self.x_t.to_pk(),
+            &joint_output,
             &X_r,
-            self.params.expiry,
+            &self.x_t.to_pk(),
+            self.params.timelocks.promise_expiry,
             &self.params.redeem_identity,
             &self.params.refund_identity,
         );
 
-        let signed_refund_transaction = {
-            secp256k1::verify(transactions.refund_tx_digest, &sig_refund_r, &X_r)
-                .context("failed to verify receiver refund signature")?;
-
-            let sig_refund_t = secp256k1::sign(transactions.refund_tx_digest, &self.x_t);
-
-            bitcoin::complete_spend_transaction(
-                transactions.refund.clone(),
-                (self.x_t.to_pk(), sig_refund_t),
-                (X_r, sig_refund_r),
-            )?
-        };
-
-        let sig_redeem_t = secp256k1::encsign(
-            transactions.redeem_tx_digest,
+        let (signed_refunds, redeem_nonces) = joint_output::complete_refunds(
+            &transactions,
             &self.x_t,
-            &self.a.to_pk(),
-            rng,
-        );
+            &X_r,
+            self.nonces,
+            sigs_refund_r,
+        )?;
+
+        let sig_redeem_t = match redeem_nonces {
+            None => joint_output::RedeemSignature::P2wsh(secp256k1::encsign(
+                &mut Transcript::new(&self.params.session_id),
+                transactions.redeem_tx_digest,
+                &self.x_t,
+                &self.a.to_pk(),
+                rng,
+            )),
+            Some(redeem_nonces) => {
+                let session = redeem_nonces.sign(
+                    transactions.redeem_tx_digest,
+                    &self.x_t,
+                    &self.a.to_pk(),
+                )?;
+
+                joint_output::RedeemSignature::P2tr(session.partial_signature().clone())
+            }
+        };
 
         Ok(Tumbler2 {
             x_t: self.x_t,
-            signed_refund_transaction,
+            signed_refunds,
             a: self.a,
             transactions,
             sig_redeem_t,
@@ -287,10 +407,10 @@ impl Tumbler2 {
     }
 
     pub fn unsigned_fund_transaction(&self) -> FundTransaction {
-        FundTransaction(self.transactions.fund.clone())
+        FundTransaction(self.transactions.fund().clone())
     }
     pub fn signed_refund_transaction(&self) -> RefundTransaction {
-        RefundTransaction(self.signed_refund_transaction.clone())
+        RefundTransaction(self.signed_refunds.refund().clone())
     }
     pub fn x_t(&self) -> &secp256k1::KeyPair {
         &self.x_t
@@ -299,31 +419,54 @@ impl Tumbler2 {
 
 impl Params {
     pub fn new(
+        session_id: SessionId,
         redeem_identity: bitcoin::Address,
         refund_identity: bitcoin::Address,
-        expiry: u32,
+        timelocks: bitcoin::TimelockPolicy,
         tumble_amount: bitcoin::Amount,
-        spend_transaction_fee_per_wu: bitcoin::Amount,
-        partial_fund_transaction: bitcoin::Transaction,
+        epoch: Epoch,
+        spend_transaction_feerate: bitcoin::FeeRate,
+        joint_output: bitcoin::JointOutputKind,
+        partial_fund_psbt: bitcoin::Psbt,
     ) -> Self {
         Self {
+            session_id,
             redeem_identity,
             refund_identity,
-            expiry,
+            timelocks,
             tumble_amount,
-            spend_transaction_fee_per_wu,
-            partial_fund_transaction,
+            epoch,
+            spend_transaction_feerate,
+            joint_output,
+            partial_fund_psbt,
         }
     }
 
     /// Returns how much the tumbler has to put into the joint output in the fund transaction.
+    ///
+    /// Besides the takeout, this pays the miner fee and the anchor output of the spend transaction.
     pub fn tumbler_receiver_joint_output_value(&self) -> bitcoin::Amount {
         self.tumbler_receiver_joint_output_takeout()
-            + bitcoin::spend_tx_miner_fee(self.spend_transaction_fee_per_wu)
+            + bitcoin::spend_tx_miner_fee(
+                self.spend_transaction_feerate,
+                self.joint_output.max_satisfaction_weight(),
+                &self.redeem_identity,
+                &self.refund_identity,
+            )
+            + bitcoin::ANCHOR_OUTPUT_VALUE
     }
 
     /// Returns how much the receiver is supposed to take out of the joint output funded by the tumbler.
     pub fn tumbler_receiver_joint_output_takeout(&self) -> bitcoin::Amount {
         self.tumble_amount
     }
+
+    /// Returns how many refund transactions the tumbler needs MuSig2 nonces for.
+    pub(crate) fn refund_transaction_count(&self) -> usize {
+        bitcoin::refund_transaction_count(
+            self.tumbler_receiver_joint_output_value(),
+            self.tumbler_receiver_joint_output_takeout(),
+            &self.refund_identity,
+        )
+    }
 }
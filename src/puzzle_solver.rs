@@ -1,26 +1,44 @@
+use crate::keyring::Keyring;
+use crate::secp256k1::musig;
+use crate::secret::Secret;
+use crate::transcript::{SessionId, Transcript};
 use crate::{
-    bitcoin, hsm_cl, pedersen, pointcheval_sanders, puzzle_solver, secp256k1, NoMessage,
-    NoTransaction, Token, UnexpectedMessage, UnexpectedTransaction,
+    bitcoin, hsm_cl, joint_output, pedersen, pointcheval_sanders, puzzle_solver, secp256k1,
+    token_attributes, Epoch, NoMessage, NoTransaction, Token, UnexpectedMessage,
+    UnexpectedTransaction,
 };
 use rand::Rng;
+use std::sync::Arc;
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
 pub struct Params {
+    /// Identifies this run of the protocol, every proof exchanged in it is bound to it.
+    pub session_id: SessionId,
+    #[serde(with = "crate::serde::bitcoin_address")]
     pub redeem_identity: bitcoin::Address,
+    #[serde(with = "crate::serde::bitcoin_address")]
     pub refund_identity: bitcoin::Address,
-    pub expiry: u32,
+    pub timelocks: bitcoin::TimelockPolicy,
+    #[serde(with = "crate::serde::bitcoin_amount")]
     tumble_amount: bitcoin::Amount,
+    /// The epoch the token issued in this run is bound to, alongside the tumble amount.
+    pub epoch: Epoch,
+    #[serde(with = "crate::serde::bitcoin_amount")]
     tumbler_fee: bitcoin::Amount,
-    spend_transaction_fee_per_wu: bitcoin::Amount,
-    /// A fully-funded transaction that is only missing the joint output.
+    spend_transaction_feerate: bitcoin::FeeRate,
+    /// The kind of output the sender locks its funds for the tumbler in.
+    pub joint_output: bitcoin::JointOutputKind,
+    /// An unsigned, fully-funded PSBT that is only missing the joint output.
     ///
     /// Fully-funded means we expect this transaction to have enough inputs to pay the joint output
     /// of value `amount` and in addition have one or more change outputs that already incorporate
-    /// the fee the user is willing to pay.
-    pub partial_fund_transaction: bitcoin::Transaction,
+    /// the fee the user is willing to pay. The joint output is inserted at index 0, the returned
+    /// PSBT can then be signed by whichever wallet created this one.
+    #[serde(with = "crate::serde::bitcoin_psbt")]
+    pub partial_fund_psbt: bitcoin::Psbt,
 }
 
-#[derive(Debug, derive_more::From, serde::Serialize, strum_macros::Display)]
+#[derive(Debug, derive_more::From, serde::Serialize, serde::Deserialize, strum_macros::Display)]
 pub enum Message {
     Message0(Message0),
     Message1(Message1),
@@ -32,55 +50,58 @@ pub enum Message {
     Message7(Message7),
 }
 
-#[derive(Debug, serde::Serialize)]
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
 pub struct Message0 {
     #[serde(with = "crate::serde::secp256k1_public_key")]
     pub X_s: secp256k1::PublicKey,
     #[serde(with = "crate::serde::bls12_381_g1affine")]
     pub C: pedersen::Commitment,
     pub pi_C: pedersen::Proof,
+    /// The MuSig2 nonces of the sender if the joint output is a P2TR output.
+    pub nonces_s: Option<joint_output::Nonces>,
 }
 
-#[derive(Debug, serde::Serialize)]
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
 pub struct Message1 {
     #[serde(with = "crate::serde::secp256k1_public_key")]
     pub X_t: secp256k1::PublicKey,
-    #[serde(with = "crate::serde::secp256k1_signature")]
-    pub sig_refund_t: secp256k1::Signature,
+    pub sigs_refund_t: joint_output::RefundSignatures,
 }
 
-#[derive(Debug, serde::Serialize)]
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
 pub struct Message2 {
     pub sig_token_blind: pointcheval_sanders::Signature,
 }
 
-#[derive(Debug, serde::Serialize)]
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
 pub struct Message3 {
-    #[serde(with = "crate::serde::bls12_381_scalar")]
-    pub token: Token,
+    pub token: Secret<Token>,
     pub sig_token_rand: pointcheval_sanders::Signature,
 }
 
-#[derive(Debug, serde::Serialize)]
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
 pub struct Message4 {
     pub c_alpha_prime_prime: hsm_cl::Ciphertext,
+    /// The epoch of the tumbler key the puzzle is encrypted under.
+    pub epoch: Epoch,
 }
 
-#[derive(Debug, serde::Serialize)]
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
 pub struct Message5 {
     #[serde(with = "crate::serde::secp256k1_public_key")]
     pub A_prime_prime: secp256k1::PublicKey,
+    /// The share of the tumbler in the signature on the redeem transaction of a P2TR joint output.
+    pub partial_sig_redeem_t: Option<musig::PartialSignature>,
 }
 
-#[derive(Debug, serde::Serialize)]
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
 pub struct Message6 {
-    pub sig_redeem_s: secp256k1::EncryptedSignature,
+    pub sig_redeem_s: joint_output::RedeemSignature,
 }
 
-#[derive(Debug, serde::Serialize)]
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
 pub struct Message7 {
-    #[serde(with = "crate::serde::secp256k1_secret_key")]
-    pub alpha_macron: secp256k1::SecretKey,
+    pub alpha_macron: Secret<secp256k1::SecretKey>,
 }
 
 #[derive(Clone, Debug)]
@@ -110,7 +131,9 @@ impl From<RefundTransaction> for bitcoin::Transaction {
     }
 }
 
-#[derive(Debug, derive_more::From, Clone, strum_macros::Display)]
+#[derive(
+    Debug, derive_more::From, Clone, serde::Serialize, serde::Deserialize, strum_macros::Display,
+)]
 pub enum Tumbler {
     Tumbler0(Tumbler0),
     Tumbler1(Tumbler1),
@@ -122,21 +145,37 @@ pub enum Tumbler {
 impl Tumbler {
     pub fn new(
         params: puzzle_solver::Params,
-        HE: hsm_cl::KeyPair,
-        PS: pointcheval_sanders::KeyPair,
+        class_group: hsm_cl::ClassGroupParams,
+        keyring: Arc<Keyring>,
         rng: &mut impl Rng,
-    ) -> Self {
-        let tumbler = Tumbler0::new(params, HE, PS, rng);
+    ) -> anyhow::Result<Self> {
+        let tumbler = Tumbler0::new(params, class_group, keyring, rng)?;
+
+        Ok(tumbler.into())
+    }
 
-        tumbler.into()
+    /// Attaches the keyring after the tumbler has been restored from persisted state.
+    pub fn with_keyring(self, keyring: Arc<Keyring>) -> Self {
+        match self {
+            Tumbler::Tumbler0(inner) => Tumbler0 { keyring, ..inner }.into(),
+            Tumbler::Tumbler1(inner) => Tumbler1 { keyring, ..inner }.into(),
+            Tumbler::Tumbler2(inner) => Tumbler2 { keyring, ..inner }.into(),
+            state => state,
+        }
     }
 
-    pub fn transition_on_message(self, message: Message) -> anyhow::Result<Self> {
+    pub fn transition_on_message(
+        self,
+        message: Message,
+        rng: &mut impl Rng,
+    ) -> anyhow::Result<Self> {
         let tumbler = match (self, message) {
             (Tumbler::Tumbler0(inner), Message::Message0(message)) => {
+                inner.receive(message, rng)?.into()
+            }
+            (Tumbler::Tumbler2(inner), Message::Message4(message)) => {
                 inner.receive(message)?.into()
             }
-            (Tumbler::Tumbler2(inner), Message::Message4(message)) => inner.receive(message).into(),
             (Tumbler::Tumbler3(inner), Message::Message6(message)) => {
                 inner.receive(message)?.into()
             }
@@ -164,7 +203,7 @@ impl Tumbler {
             Tumbler::Tumbler1(inner) => inner.next_message().into(),
             Tumbler::Tumbler2(inner) => inner.next_message().into(),
             Tumbler::Tumbler3(inner) => inner.next_message().into(),
-            state => anyhow::bail!(NoMessage::new(state.clone())),
+            state => anyhow::bail!(NoMessage::new(state)),
         };
 
         Ok(message)
@@ -180,91 +219,136 @@ impl Tumbler {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct Tumbler0 {
     x_t: secp256k1::KeyPair,
     params: puzzle_solver::Params,
-    HE: hsm_cl::KeyPair,
-    PS: pointcheval_sanders::KeyPair,
+    class_group: hsm_cl::ClassGroupParams,
+    #[serde(skip, default = "crate::keyring::detached")]
+    keyring: Arc<Keyring>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct Tumbler1 {
     transactions: bitcoin::Transactions,
-    sig_refund_t: secp256k1::Signature,
+    sigs_refund_t: joint_output::RefundSignatures,
+    redeem_nonces: Option<joint_output::RedeemNonces>,
+    #[serde(with = "crate::serde::secp256k1_public_key")]
     X_s: secp256k1::PublicKey,
     x_t: secp256k1::KeyPair,
+    #[serde(with = "crate::serde::bls12_381_g1affine")]
     C: pedersen::Commitment,
-    HE: hsm_cl::KeyPair,
-    PS: pointcheval_sanders::KeyPair,
+    #[serde(with = "crate::serde::bitcoin_amount")]
+    tumble_amount: bitcoin::Amount,
+    epoch: Epoch,
+    class_group: hsm_cl::ClassGroupParams,
+    #[serde(skip, default = "crate::keyring::detached")]
+    keyring: Arc<Keyring>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct Tumbler2 {
     sig_token_blind: pointcheval_sanders::Signature,
     transactions: bitcoin::Transactions,
+    redeem_nonces: Option<joint_output::RedeemNonces>,
+    #[serde(with = "crate::serde::secp256k1_public_key")]
     X_s: secp256k1::PublicKey,
     x_t: secp256k1::KeyPair,
-    HE: hsm_cl::KeyPair,
+    class_group: hsm_cl::ClassGroupParams,
+    #[serde(skip, default = "crate::keyring::detached")]
+    keyring: Arc<Keyring>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct Tumbler3 {
     gamma: secp256k1::KeyPair,
     transactions: bitcoin::Transactions,
+    redeem_session: Option<joint_output::RedeemSession>,
+    #[serde(with = "crate::serde::secp256k1_public_key")]
     X_s: secp256k1::PublicKey,
     x_t: secp256k1::KeyPair,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct Tumbler4 {
+    #[serde(with = "crate::serde::bitcoin_transaction")]
     signed_redeem_transaction: bitcoin::Transaction,
 }
 
 impl Tumbler0 {
     pub fn new(
         params: puzzle_solver::Params,
-        HE: hsm_cl::KeyPair,
-        PS: pointcheval_sanders::KeyPair,
+        class_group: hsm_cl::ClassGroupParams,
+        keyring: Arc<Keyring>,
         rng: &mut impl Rng,
-    ) -> Self {
-        Self {
+    ) -> anyhow::Result<Self> {
+        params.timelocks.validate()?;
+        keyring.token_keypair(params.epoch)?;
+
+        Ok(Self {
             params,
             x_t: secp256k1::KeyPair::random(rng),
-            HE,
-            PS,
-        }
+            class_group,
+            keyring,
+        })
     }
 
-    pub fn receive(self, Message0 { X_s, C, pi_C }: Message0) -> anyhow::Result<Tumbler1> {
+    pub fn receive(
+        self,
+        Message0 {
+            X_s,
+            C,
+            pi_C,
+            nonces_s,
+        }: Message0,
+        rng: &mut impl Rng,
+    ) -> anyhow::Result<Tumbler1> {
+        let PS = self.keyring.token_keypair(self.params.epoch)?;
         pedersen::verify(
+            &mut Transcript::new(&self.params.session_id),
             &bls12_381::G1Affine::generator(),
-            &self.PS.public_key.Y1,
+            // the token is the only message the sender commits to
+            &PS.public_key.Y1[..1],
             &C,
             pi_C,
         )?;
 
+        let joint_output = self
+            .params
+            .joint_output
+            .joint_output(&X_s, &self.x_t.to_pk())?;
         let transactions = bitcoin::make_transactions(
-            self.params.partial_fund_transaction.clone(),
+            self.params.partial_fund_psbt.clone(),
             self.params.sender_tumbler_joint_output_value(),
             self.params.sender_tumbler_joint_output_takeout(),
-            &X_s,
+            &joint_output,
             &self.x_t.to_pk(),
-            self.params.expiry,
+            &X_s,
+            self.params.timelocks.solver_expiry,
             &self.params.redeem_identity,
             &self.params.refund_identity,
         );
 
-        let sig_refund_t = secp256k1::sign(transactions.refund_tx_digest, &self.x_t);
+        let (sigs_refund_t, redeem_nonces) = joint_output::sign_refunds(
+            self.params.joint_output,
+            &transactions,
+            &self.x_t,
+            &X_s,
+            nonces_s,
+            rng,
+        )?;
 
         Ok(Tumbler1 {
             transactions,
-            sig_refund_t,
+            sigs_refund_t,
+            redeem_nonces,
             X_s,
             x_t: self.x_t,
             C,
-            HE: self.HE,
-            PS: self.PS,
+            tumble_amount: self.params.tumble_amount,
+            epoch: self.params.epoch,
+            class_group: self.class_group,
+            keyring: self.keyring,
         })
     }
 }
@@ -279,7 +363,7 @@ struct WrongTransaction {
 impl Tumbler1 {
     pub fn next_message(&self) -> Message1 {
         Message1 {
-            sig_refund_t: self.sig_refund_t.clone(),
+            sigs_refund_t: self.sigs_refund_t.clone(),
             X_t: self.x_t.to_pk(),
         }
     }
@@ -289,7 +373,7 @@ impl Tumbler1 {
         fund_transaction: FundTransaction,
         rng: &mut impl Rng,
     ) -> anyhow::Result<Tumbler2> {
-        let expected_txid = self.transactions.fund.txid();
+        let expected_txid = self.transactions.fund().txid();
         let actual_tx_id = fund_transaction.0.txid();
 
         if actual_tx_id != expected_txid {
@@ -299,14 +383,23 @@ impl Tumbler1 {
             })
         }
 
-        let sig_token_blind = pointcheval_sanders::sign(&self.PS, self.C, rng);
+        // fails if the epoch has ended since the session started, the sender will refund
+        let PS = self.keyring.token_keypair(self.epoch)?;
+        let sig_token_blind = pointcheval_sanders::sign(
+            &PS,
+            self.C,
+            &token_attributes(self.tumble_amount, self.epoch),
+            rng,
+        )?;
 
         Ok(Tumbler2 {
             sig_token_blind,
+            redeem_nonces: self.redeem_nonces,
             x_t: self.x_t,
             X_s: self.X_s,
             transactions: self.transactions,
-            HE: self.HE,
+            class_group: self.class_group,
+            keyring: self.keyring,
         })
     }
 }
@@ -322,16 +415,30 @@ impl Tumbler2 {
         self,
         Message4 {
             c_alpha_prime_prime,
+            epoch,
         }: Message4,
-    ) -> Tumbler3 {
-        let gamma = hsm_cl::decrypt(&self.HE, &c_alpha_prime_prime).into();
+    ) -> anyhow::Result<Tumbler3> {
+        let HE = self.keyring.puzzle_keypair(epoch)?;
+        let gamma: secp256k1::KeyPair =
+            hsm_cl::decrypt(&self.class_group, &HE, &c_alpha_prime_prime).into();
+
+        // the sender can only complete the signature on the redeem transaction with gamma
+        let redeem_session = match self.redeem_nonces {
+            Some(nonces) => Some(nonces.sign(
+                self.transactions.redeem_tx_digest,
+                &self.x_t,
+                &gamma.to_pk(),
+            )?),
+            None => None,
+        };
 
-        Tumbler3 {
+        Ok(Tumbler3 {
             transactions: self.transactions,
+            redeem_session,
             x_t: self.x_t,
             X_s: self.X_s,
             gamma,
-        }
+        })
     }
 }
 
@@ -339,29 +446,41 @@ impl Tumbler3 {
     pub fn next_message(&self) -> Message5 {
         Message5 {
             A_prime_prime: self.gamma.to_pk(),
+            partial_sig_redeem_t: self
+                .redeem_session
+                .as_ref()
+                .map(|session| session.partial_signature().clone()),
         }
     }
 
     pub fn receive(self, Message6 { sig_redeem_s }: Message6) -> anyhow::Result<Tumbler4> {
         let Self {
             transactions,
+            redeem_session,
             x_t,
             X_s,
             gamma,
-            ..
         } = self;
 
-        let signed_redeem_transaction = {
-            let sig_redeem_s = secp256k1::decsig(&gamma, &sig_redeem_s);
-            secp256k1::verify(transactions.redeem_tx_digest, &sig_redeem_s, &X_s)?;
+        let signed_redeem_transaction = match (sig_redeem_s, redeem_session) {
+            (joint_output::RedeemSignature::P2wsh(sig_redeem_s), None) => {
+                let sig_redeem_s = secp256k1::decsig(&gamma, &sig_redeem_s)?;
+                secp256k1::verify(transactions.redeem_tx_digest, &sig_redeem_s, &X_s)?;
 
-            let sig_redeem_t = secp256k1::sign(transactions.redeem_tx_digest, &x_t);
+                let sig_redeem_t = secp256k1::sign(transactions.redeem_tx_digest, &x_t);
 
-            bitcoin::complete_spend_transaction(
-                transactions.redeem,
-                (X_s, sig_redeem_s),
-                (x_t.to_pk(), sig_redeem_t),
-            )?
+                bitcoin::complete_spend_transaction(
+                    transactions.redeem,
+                    (X_s, sig_redeem_s),
+                    (x_t.to_pk(), sig_redeem_t),
+                )?
+            }
+            (joint_output::RedeemSignature::P2tr(partial_sig_redeem_s), Some(redeem_session)) => {
+                redeem_session
+                    .aggregate(&partial_sig_redeem_s)?
+                    .complete(transactions.redeem, gamma.as_sk())?
+            }
+            _ => anyhow::bail!(joint_output::WrongJointOutputKind),
         };
 
         Ok(Tumbler4 {
@@ -378,33 +497,57 @@ impl Tumbler4 {
 
 impl puzzle_solver::Params {
     pub fn new(
+        session_id: SessionId,
         redeem_identity: bitcoin::Address,
         refund_identity: bitcoin::Address,
-        expiry: u32,
+        timelocks: bitcoin::TimelockPolicy,
         tumble_amount: bitcoin::Amount,
+        epoch: Epoch,
         tumbler_fee: bitcoin::Amount,
-        spend_transaction_fee_per_wu: bitcoin::Amount,
-        partial_fund_transaction: bitcoin::Transaction,
+        spend_transaction_feerate: bitcoin::FeeRate,
+        joint_output: bitcoin::JointOutputKind,
+        partial_fund_psbt: bitcoin::Psbt,
     ) -> Self {
         Self {
+            session_id,
             redeem_identity,
             refund_identity,
-            expiry,
+            timelocks,
             tumble_amount,
+            epoch,
             tumbler_fee,
-            spend_transaction_fee_per_wu,
-            partial_fund_transaction,
+            spend_transaction_feerate,
+            joint_output,
+            partial_fund_psbt,
         }
     }
 
     /// Returns how much the sender has to put into the joint output in the fund transaction.
+    ///
+    /// On top of the takeout, the sender covers the miner fee and the anchor output of whichever
+    /// transaction spends the joint output.
     pub fn sender_tumbler_joint_output_value(&self) -> bitcoin::Amount {
         self.sender_tumbler_joint_output_takeout()
-            + bitcoin::spend_tx_miner_fee(self.spend_transaction_fee_per_wu)
+            + bitcoin::spend_tx_miner_fee(
+                self.spend_transaction_feerate,
+                self.joint_output.max_satisfaction_weight(),
+                &self.redeem_identity,
+                &self.refund_identity,
+            )
+            + bitcoin::ANCHOR_OUTPUT_VALUE
     }
 
     /// Returns how much the tumbler is supposed to take out of the joint output funded by the sender.
     pub fn sender_tumbler_joint_output_takeout(&self) -> bitcoin::Amount {
         self.tumble_amount + self.tumbler_fee
     }
+
+    /// Returns how many refund transactions the sender needs MuSig2 nonces for.
+    pub(crate) fn refund_transaction_count(&self) -> usize {
+        bitcoin::refund_transaction_count(
+            self.sender_tumbler_joint_output_value(),
+            self.sender_tumbler_joint_output_takeout(),
+            &self.refund_identity,
+        )
+    }
 }
@@ -1,13 +1,17 @@
+use crate::secret::Secret;
+use crate::transcript::{SessionId, Transcript};
 use crate::{
-    bitcoin, hsm_cl, pointcheval_sanders, puzzle_promise, puzzle_solver, secp256k1, Lock,
-    NoMessage, NoTransaction, Token, UnexpectedMessage,
+    bitcoin, hsm_cl, joint_output, pointcheval_sanders, puzzle_promise, puzzle_solver, secp256k1,
+    Epoch, Lock, NoMessage, NoTransaction, UnexpectedMessage, TOKEN_MESSAGES,
 };
 use ::bitcoin::hashes::Hash;
 use anyhow::Context;
 use rand::Rng;
-use std::convert::TryFrom;
+use std::slice;
 
-#[derive(Debug, derive_more::From, Clone, strum_macros::Display)]
+#[derive(
+    Debug, derive_more::From, Clone, serde::Serialize, serde::Deserialize, strum_macros::Display,
+)]
 pub enum Receiver {
     Receiver0(Receiver0),
     Receiver1(Receiver1),
@@ -17,8 +21,14 @@ pub enum Receiver {
 }
 
 impl Receiver {
-    pub fn new(params: puzzle_promise::Params, rng: &mut impl Rng, HE: hsm_cl::PublicKey) -> Self {
-        Receiver0::new(params, rng, HE).into()
+    pub fn new(
+        params: puzzle_promise::Params,
+        rng: &mut impl Rng,
+        class_group: hsm_cl::ClassGroupParams,
+        HE: hsm_cl::PublicKey,
+        PS: pointcheval_sanders::PublicKey,
+    ) -> anyhow::Result<Self> {
+        Ok(Receiver0::new(params, rng, class_group, HE, PS)?.into())
     }
 
     pub fn transition_on_puzzle_promise_message(
@@ -28,7 +38,7 @@ impl Receiver {
     ) -> anyhow::Result<Self> {
         let receiver = match (self, message) {
             (Receiver::Receiver1(inner), puzzle_promise::Message::Message1(message)) => {
-                inner.receive(message)?.into()
+                inner.receive(message, rng)?.into()
             }
             (Receiver::Receiver2(inner), puzzle_promise::Message::Message3(message)) => {
                 inner.receive(message, rng)?.into()
@@ -42,10 +52,11 @@ impl Receiver {
     pub fn transition_on_puzzle_solver_message(
         self,
         message: puzzle_solver::Message,
+        rng: &mut impl Rng,
     ) -> anyhow::Result<Self> {
         let receiver = match (self, message) {
             (Receiver::Receiver0(inner), puzzle_solver::Message::Message3(message)) => {
-                inner.receive(message).into()
+                inner.receive(message, rng).into()
             }
             (Receiver::Receiver3(inner), puzzle_solver::Message::Message7(message)) => {
                 inner.receive(message)?.into()
@@ -61,7 +72,7 @@ impl Receiver {
             Receiver::Receiver1(inner) => inner.next_message().into(),
             Receiver::Receiver2(inner) => inner.next_message().into(),
             Receiver::Receiver3(inner) => inner.next_message().into(),
-            state => anyhow::bail!(NoMessage::new(state.clone())),
+            state => anyhow::bail!(NoMessage::new(state)),
         };
 
         Ok(message)
@@ -77,56 +88,89 @@ impl Receiver {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct Receiver0 {
     x_r: secp256k1::KeyPair,
     params: puzzle_promise::Params,
+    class_group: hsm_cl::ClassGroupParams,
     HE: hsm_cl::PublicKey,
+    PS: pointcheval_sanders::PublicKey,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct Receiver1 {
     x_r: secp256k1::KeyPair,
     params: puzzle_promise::Params,
+    class_group: hsm_cl::ClassGroupParams,
     HE: hsm_cl::PublicKey,
-    token: Token,
-    sig_token_rand: pointcheval_sanders::Signature,
+    show_token: pointcheval_sanders::Show,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct Receiver2 {
+    session_id: SessionId,
+    epoch: Epoch,
     x_r: secp256k1::KeyPair,
+    #[serde(with = "crate::serde::secp256k1_public_key")]
     X_t: secp256k1::PublicKey,
+    class_group: hsm_cl::ClassGroupParams,
     c_alpha: hsm_cl::Ciphertext,
+    #[serde(with = "crate::serde::secp256k1_public_key")]
     A: secp256k1::PublicKey,
     transactions: bitcoin::Transactions,
-    sig_refund_r: secp256k1::Signature,
+    sigs_refund_r: joint_output::RefundSignatures,
+    redeem_nonces: Option<joint_output::RedeemNonces>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct Receiver3 {
+    epoch: Epoch,
     x_r: secp256k1::KeyPair,
+    #[serde(with = "crate::serde::secp256k1_public_key")]
     X_t: secp256k1::PublicKey,
-    beta: secp256k1::SecretKey,
+    beta: Secret<secp256k1::SecretKey>,
     c_alpha_prime: hsm_cl::Ciphertext,
+    #[serde(with = "crate::serde::secp256k1_public_key")]
     A_prime: secp256k1::PublicKey,
-    sig_redeem_r: secp256k1::Signature,
-    sig_redeem_t: secp256k1::EncryptedSignature,
+    sig_redeem: RedeemSignatures,
     transactions: bitcoin::Transactions,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct Receiver4 {
+    #[serde(with = "crate::serde::bitcoin_transaction")]
     signed_redeem_transaction: bitcoin::Transaction,
 }
 
+/// The signatures on the redeem transaction, which the receiver completes once it learns alpha.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+enum RedeemSignatures {
+    P2wsh {
+        #[serde(with = "crate::serde::secp256k1_signature")]
+        sig_redeem_r: secp256k1::Signature,
+        sig_redeem_t: secp256k1::EncryptedSignature,
+    },
+    P2tr(joint_output::EncryptedRedeemSignature),
+}
+
 impl Receiver0 {
-    pub fn new(params: puzzle_promise::Params, rng: &mut impl Rng, HE: hsm_cl::PublicKey) -> Self {
-        Self {
+    pub fn new(
+        params: puzzle_promise::Params,
+        rng: &mut impl Rng,
+        class_group: hsm_cl::ClassGroupParams,
+        HE: hsm_cl::PublicKey,
+        PS: pointcheval_sanders::PublicKey,
+    ) -> anyhow::Result<Self> {
+        params.timelocks.validate()?;
+        PS.ensure_messages(TOKEN_MESSAGES)?;
+
+        Ok(Self {
             x_r: secp256k1::KeyPair::random(rng),
             params,
+            class_group,
             HE,
-        }
+            PS,
+        })
     }
 
     pub fn receive(
@@ -135,13 +179,23 @@ impl Receiver0 {
             token,
             sig_token_rand,
         }: puzzle_solver::Message3,
+        rng: &mut impl Rng,
     ) -> Receiver1 {
+        // the tumbler only learns the serial number of the token, never the token itself
+        let show_token = pointcheval_sanders::show(
+            &mut Transcript::new(&self.params.session_id),
+            &self.PS,
+            &sig_token_rand,
+            slice::from_ref(token.expose()),
+            rng,
+        );
+
         Receiver1 {
             x_r: self.x_r,
             params: self.params,
+            class_group: self.class_group,
             HE: self.HE,
-            token,
-            sig_token_rand,
+            show_token,
         }
     }
 }
@@ -149,8 +203,8 @@ impl Receiver0 {
 impl Receiver1 {
     pub fn next_message(&self) -> puzzle_promise::Message0 {
         puzzle_promise::Message0 {
-            sig_token_rand: self.sig_token_rand.clone(),
-            token: self.token,
+            epoch: self.params.epoch,
+            show_token: self.show_token.clone(),
         }
     }
 
@@ -161,34 +215,53 @@ impl Receiver1 {
             c_alpha,
             pi_alpha,
             A,
+            nonces_t,
         }: puzzle_promise::Message1,
+        rng: &mut impl Rng,
     ) -> anyhow::Result<Receiver2> {
         let Receiver1 {
-            x_r, params, HE, ..
+            x_r,
+            params,
+            class_group,
+            HE,
+            ..
         } = self;
 
         let statement = (&c_alpha, &A);
-        hsm_cl::verify(&HE, &pi_alpha, statement)?;
+        hsm_cl::verify(&class_group, &HE, &pi_alpha, statement)?;
+        let joint_output = params.joint_output.joint_output(&X_t, &x_r.to_pk())?;
         let transactions = bitcoin::make_transactions(
-            params.partial_fund_transaction.clone(),
+            params.partial_fund_psbt.clone(),
             params.tumbler_receiver_joint_output_value(),
             params.tumbler_receiver_joint_output_takeout(),
-            &X_t,
+            &joint_output,
             &x_r.to_pk(),
-            params.expiry,
+            &X_t,
+            params.timelocks.promise_expiry,
             &params.redeem_identity,
             &params.refund_identity,
         );
 
-        let sig_refund_r = secp256k1::sign(transactions.refund_tx_digest, &x_r);
+        let (sigs_refund_r, redeem_nonces) = joint_output::sign_refunds(
+            params.joint_output,
+            &transactions,
+            &x_r,
+            &X_t,
+            nonces_t,
+            rng,
+        )?;
 
         Ok(Receiver2 {
+            session_id: params.session_id,
+            epoch: params.epoch,
             x_r,
             X_t,
+            class_group,
             c_alpha,
             A,
             transactions,
-            sig_refund_r,
+            sigs_refund_r,
+            redeem_nonces,
         })
     }
 }
@@ -197,7 +270,7 @@ impl Receiver2 {
     pub fn next_message(&self) -> puzzle_promise::Message2 {
         puzzle_promise::Message2 {
             X_r: self.x_r.to_pk(),
-            sig_refund_r: self.sig_refund_r.clone(),
+            sigs_refund_r: self.sigs_refund_r.clone(),
         }
     }
 
@@ -207,24 +280,44 @@ impl Receiver2 {
         _rng: &mut impl Rng,
     ) -> anyhow::Result<Receiver3> {
         let Self {
+            session_id,
+            epoch,
             x_r,
             X_t,
+            class_group,
             A,
             c_alpha,
             transactions,
+            redeem_nonces,
             ..
         } = self;
 
-        secp256k1::encverify(
-            &X_t,
-            &A,
-            &transactions.redeem_tx_digest.into_inner(),
-            &sig_redeem_t,
-        )?;
+        let sig_redeem = match (sig_redeem_t, redeem_nonces) {
+            (joint_output::RedeemSignature::P2wsh(sig_redeem_t), None) => {
+                secp256k1::encverify(
+                    &mut Transcript::new(&session_id),
+                    &X_t,
+                    &A,
+                    &transactions.redeem_tx_digest.into_inner(),
+                    &sig_redeem_t,
+                )?;
+
+                let sig_redeem_r = secp256k1::sign(transactions.redeem_tx_digest, &x_r);
+
+                RedeemSignatures::P2wsh {
+                    sig_redeem_r,
+                    sig_redeem_t,
+                }
+            }
+            (joint_output::RedeemSignature::P2tr(partial_sig_redeem_t), Some(redeem_nonces)) => {
+                let session = redeem_nonces.sign(transactions.redeem_tx_digest, &x_r, &A)?;
 
-        let sig_redeem_r = secp256k1::sign(transactions.redeem_tx_digest, &x_r);
+                RedeemSignatures::P2tr(session.aggregate(&partial_sig_redeem_t)?)
+            }
+            _ => anyhow::bail!(joint_output::WrongJointOutputKind),
+        };
 
-        let (c_alpha_prime, beta) = hsm_cl::blind_ciphertext(&c_alpha);
+        let (c_alpha_prime, beta) = hsm_cl::blind_ciphertext(&class_group, &c_alpha);
         let A_prime = {
             let mut A_prime = A;
             A_prime.tweak_mul_assign(&beta).unwrap();
@@ -232,13 +325,13 @@ impl Receiver2 {
         };
 
         Ok(Receiver3 {
+            epoch,
             x_r,
             X_t,
-            beta,
+            beta: Secret::new(beta),
             c_alpha_prime,
             A_prime,
-            sig_redeem_r,
-            sig_redeem_t,
+            sig_redeem,
             transactions,
         })
     }
@@ -253,30 +346,37 @@ impl Receiver3 {
             X_t,
             x_r,
             transactions,
-            sig_redeem_t,
-            sig_redeem_r,
+            sig_redeem,
             beta,
             ..
         } = self;
 
-        let alpha = {
-            let alpha_macron: secp256k1::Scalar = alpha_macron.into();
-            let beta: secp256k1::Scalar = beta.into();
-
-            alpha_macron * beta.inv()
+        // alpha = alpha_macron * beta^-1
+        let mut alpha = beta.expose().inv();
+        alpha.tweak_mul_assign(alpha_macron.expose())?;
+
+        let signed_redeem_transaction = match sig_redeem {
+            RedeemSignatures::P2wsh {
+                sig_redeem_r,
+                sig_redeem_t,
+            } => {
+                let sig_redeem_t =
+                    secp256k1::decsig(&secp256k1::KeyPair::from(alpha), &sig_redeem_t)?;
+
+                secp256k1::verify(transactions.redeem_tx_digest, &sig_redeem_t, &X_t)
+                    .context("failed to verify tumbler redeem signature after decryption")?;
+
+                bitcoin::complete_spend_transaction(
+                    transactions.redeem,
+                    (X_t, sig_redeem_t),
+                    (x_r.to_pk(), sig_redeem_r),
+                )?
+            }
+            RedeemSignatures::P2tr(sig_redeem) => {
+                sig_redeem.complete(transactions.redeem, &alpha)?
+            }
         };
 
-        let sig_redeem_t = secp256k1::decsig(&secp256k1::KeyPair::try_from(alpha)?, &sig_redeem_t);
-
-        secp256k1::verify(transactions.redeem_tx_digest, &sig_redeem_t, &X_t)
-            .context("failed to verify tumbler redeem signature after decryption")?;
-
-        let signed_redeem_transaction = bitcoin::complete_spend_transaction(
-            transactions.redeem,
-            (X_t, sig_redeem_t),
-            (x_r.to_pk(), sig_redeem_r),
-        )?;
-
         Ok(Receiver4 {
             signed_redeem_transaction,
         })
@@ -286,6 +386,7 @@ impl Receiver3 {
         let l = Lock {
             c_alpha_prime: self.c_alpha_prime.clone(),
             A_prime: self.A_prime.clone(),
+            epoch: self.epoch,
         };
 
         puzzle_promise::Message4 { l }
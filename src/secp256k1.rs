@@ -1,16 +1,26 @@
 mod constants;
 mod enc;
 mod keypair;
+pub mod musig;
+mod nonce;
+pub mod schnorr;
 
 pub use self::constants::G;
 pub use self::enc::{
-    decsig, encsign, encverify, recover, EncryptedSignature, InvalidEncryptedSignature,
+    decsig, encsign, encverify, recover, EncryptedSignature, InvalidEncryptedSignature, KeyMismatch,
 };
 pub use self::keypair::{KeyPair, XCoor};
+pub(crate) use self::nonce::{derive_nonce, tagged_hash};
 pub use secp256k1::{curve::Affine, curve::Scalar, PublicKey, SecretKey, Signature};
 
 use secp256k1::Message;
 
+/// `2^256` modulo the order of the group.
+const TWO_POW_256_MOD_N: [u8; 32] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x01, 0x45, 0x51, 0x23, 0x19, 0x50, 0xb7, 0x5f,
+    0xc4, 0x40, 0x2d, 0xa1, 0x73, 0x2f, 0xc9, 0xbe, 0xbf,
+];
+
 pub trait ToMessage {
     fn to_message(&self) -> [u8; 32];
 }
@@ -39,3 +49,76 @@ pub fn verify<M: ToMessage>(
         Err(InvalidSignature)
     }
 }
+
+/// Reduces a 64-byte big-endian integer modulo the order of the group.
+///
+/// Unlike reducing 32 bytes, the result is statistically indistinguishable from a uniformly random
+/// scalar if the bytes are, and no value has to be rejected.
+pub fn scalar_from_bytes_wide(bytes: &[u8; 64]) -> Scalar {
+    let (hi, lo) = bytes.split_at(32);
+
+    scalar_from_bytes(hi) * scalar_from_bytes(&TWO_POW_256_MOD_N) + scalar_from_bytes(lo)
+}
+
+/// Reduces 32 big-endian bytes modulo the order of the group.
+fn scalar_from_bytes(bytes: &[u8]) -> Scalar {
+    let mut b32 = [0u8; 32];
+    b32.copy_from_slice(bytes);
+
+    // any 32-byte value is smaller than twice the group order, hence one reduction suffices
+    let mut scalar = Scalar::default();
+    let _ = scalar.set_b32(&b32);
+
+    scalar
+}
+
+/// Strategies for generating arbitrary values, including degenerate ones.
+#[cfg(test)]
+pub mod arbitrary {
+    use super::{PublicKey, Scalar, SecretKey};
+    use proptest::prelude::*;
+
+    pub fn scalar() -> impl Strategy<Value = Scalar> {
+        prop_oneof![
+            Just(Scalar::default()),
+            any::<[u8; 32]>().prop_map(|bytes| {
+                let mut scalar = Scalar::default();
+                let _ = scalar.set_b32(&bytes);
+
+                scalar
+            })
+        ]
+    }
+
+    pub fn secret_key() -> impl Strategy<Value = SecretKey> {
+        any::<[u8; 32]>().prop_filter_map("not a valid secret key", |bytes| {
+            SecretKey::parse(&bytes).ok()
+        })
+    }
+
+    pub fn public_key() -> impl Strategy<Value = PublicKey> {
+        secret_key().prop_map(|secret_key| PublicKey::from_secret_key(&secret_key))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn wide_reduction_matches_big_integer_arithmetic() {
+        let mut bytes = [0u8; 64];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+
+        assert_eq!(
+            hex::encode(scalar_from_bytes_wide(&bytes).b32()),
+            "76730d0e2c1f94d0a845c9e5f7ee405eefef04abf8e3ce754279c7d6b07c7885"
+        );
+        assert_eq!(
+            hex::encode(scalar_from_bytes_wide(&[0xff; 64]).b32()),
+            "9d671cd581c69bc5e697f5e45bcd07c6741496c20e7cf878896cf21467d7d13f"
+        );
+    }
+}
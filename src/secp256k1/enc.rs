@@ -1,4 +1,5 @@
 use crate::dleq;
+use crate::secp256k1::derive_nonce;
 use crate::secp256k1::Affine;
 use crate::secp256k1::SecretKey;
 use crate::secp256k1::ToMessage;
@@ -6,9 +7,10 @@ use crate::secp256k1::XCoor;
 use crate::secp256k1::G;
 use crate::secp256k1::{KeyPair, Scalar};
 use crate::secp256k1::{PublicKey, Signature};
-use std::convert::{TryFrom, TryInto};
+use crate::transcript::Transcript;
+use std::convert::TryFrom;
 
-#[derive(Debug, Clone, serde::Serialize)]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct EncryptedSignature {
     #[serde(with = "crate::serde::secp256k1_public_key")]
     R: PublicKey,
@@ -20,6 +22,7 @@ pub struct EncryptedSignature {
 }
 
 pub fn encsign<M, S: AsRef<SecretKey>, R: rand::Rng>(
+    transcript: &mut Transcript,
     message: M,
     x: &S,
     Y: &PublicKey,
@@ -28,7 +31,12 @@ pub fn encsign<M, S: AsRef<SecretKey>, R: rand::Rng>(
 where
     M: ToMessage,
 {
-    let r = SecretKey::random(rng);
+    let message = message.to_message();
+    let r = derive_nonce(
+        &x.as_ref().serialize(),
+        &[&message[..], &Y.serialize_compressed()[..]],
+        &rng.gen(),
+    );
 
     let R_hat = {
         let mut R_hat = G.clone();
@@ -44,7 +52,12 @@ where
         R
     };
 
-    let proof = dleq::prove(rng, &*G, &R_hat, &Y, &R, r.clone().into());
+    absorb_statement(
+        transcript,
+        &PublicKey::from_secret_key(x.as_ref()),
+        &message,
+    );
+    let proof = dleq::prove(transcript, rng, &*G, &R_hat, &Y, &R, &r.clone().into());
 
     let s_hat = {
         let R_x = SecretKey::parse(&R.x_coor()).unwrap();
@@ -52,7 +65,7 @@ where
         let mut s_hat = R_x;
         s_hat.tweak_mul_assign(x.as_ref()).unwrap();
         s_hat
-            .tweak_add_assign(&SecretKey::parse(&message.to_message()).unwrap())
+            .tweak_add_assign(&SecretKey::parse(&message).unwrap())
             .unwrap();
 
         let r_inv = r.inv();
@@ -71,10 +84,19 @@ where
 }
 
 #[derive(Debug, Clone, thiserror::Error)]
-#[error("invalid encrypted signature")]
-pub struct InvalidEncryptedSignature;
+pub enum InvalidEncryptedSignature {
+    #[error("R and R_hat do not have the same discrete logarithm with respect to Y and G")]
+    InvalidProof,
+    #[error("x-coordinate of R is not a valid scalar")]
+    RNotAScalar,
+    #[error("message hash is not a valid scalar")]
+    MessageNotAScalar,
+    #[error("encrypted signature does not match public key and message")]
+    Mismatch,
+}
 
 pub fn encverify(
+    transcript: &mut Transcript,
     X: &PublicKey,
     Y: &PublicKey,
     message_hash: &[u8; 32],
@@ -84,62 +106,75 @@ pub fn encverify(
         s_hat,
         proof,
     }: &EncryptedSignature,
-) -> anyhow::Result<()> {
-    //TODO: check that s_hat is not 0 -- it will cause a panic
-    dleq::verify(&G, R_hat, Y, R, proof)?;
-
-    //TODO: Don't panic on something that can be provided by a malicious party
-    // ::parse(0) panics
-    let R_x = SecretKey::parse(&R.x_coor()).unwrap();
+) -> Result<(), InvalidEncryptedSignature> {
+    absorb_statement(transcript, X, message_hash);
+    dleq::verify(transcript, &G, R_hat, Y, R, proof)
+        .map_err(|_| InvalidEncryptedSignature::InvalidProof)?;
 
-    let message_hash = SecretKey::parse(message_hash).unwrap();
+    let R_x = SecretKey::parse(&R.x_coor()).map_err(|_| InvalidEncryptedSignature::RNotAScalar)?;
+    let message_hash =
+        SecretKey::parse(message_hash).map_err(|_| InvalidEncryptedSignature::MessageNotAScalar)?;
 
+    // s_hat is a valid secret key and therefore never zero
     let s_hat_inv = s_hat.inv();
 
+    // neither factor is zero, hence neither are the products
     let U0 = {
         let mut u0 = message_hash;
-        u0.tweak_mul_assign(&s_hat_inv).unwrap();
+        u0.tweak_mul_assign(&s_hat_inv)
+            .map_err(|_| InvalidEncryptedSignature::Mismatch)?;
 
-        let mut U0 = G.clone();
-        U0.tweak_mul_assign(&u0).unwrap();
-        U0
+        PublicKey::from_secret_key(&u0)
     };
 
     let U1 = {
         let mut u1 = R_x;
-        u1.tweak_mul_assign(&s_hat_inv).unwrap();
+        u1.tweak_mul_assign(&s_hat_inv)
+            .map_err(|_| InvalidEncryptedSignature::Mismatch)?;
+
         let mut U1 = X.clone();
-        U1.tweak_mul_assign(&u1).unwrap();
+        U1.tweak_mul_assign(&u1)
+            .map_err(|_| InvalidEncryptedSignature::Mismatch)?;
         U1
     };
 
-    let R_hat_candidate = PublicKey::combine(&[U0, U1]).unwrap();
+    // U0 + U1 may be the point at infinity which R_hat can never be
+    let R_hat_candidate =
+        PublicKey::combine(&[U0, U1]).map_err(|_| InvalidEncryptedSignature::Mismatch)?;
 
     if &R_hat_candidate != R_hat {
-        return Err(InvalidEncryptedSignature.into());
+        return Err(InvalidEncryptedSignature::Mismatch);
     }
 
     Ok(())
 }
 
+/// Binds the proof of the encrypted signature to the signing key and the message.
+fn absorb_statement(transcript: &mut Transcript, X: &PublicKey, message: &[u8; 32]) {
+    transcript.domain_separator(b"ecdsa-encrypted-signature");
+    transcript.append_secp256k1_point(b"X", X);
+    transcript.append_message(b"message", message);
+}
+
 pub fn decsig<S: AsRef<SecretKey>>(
     y: &S,
     EncryptedSignature { R, s_hat, .. }: &EncryptedSignature,
-) -> Signature {
+) -> Result<Signature, InvalidEncryptedSignature> {
     let s = {
         let y_inv = y.as_ref().inv();
 
         let mut s = s_hat.clone();
-        s.tweak_mul_assign(&y_inv).unwrap();
+        s.tweak_mul_assign(&y_inv)
+            .map_err(|_| InvalidEncryptedSignature::Mismatch)?;
         s
     };
 
-    let R_x = R.x_coor();
+    let R_x = SecretKey::parse(&R.x_coor()).map_err(|_| InvalidEncryptedSignature::RNotAScalar)?;
 
-    Signature {
+    Ok(Signature {
         s: s.into(),
-        r: SecretKey::parse(&R_x).unwrap().into(),
-    }
+        r: R_x.into(),
+    })
 }
 
 #[derive(Debug, Clone, thiserror::Error)]
@@ -150,7 +185,7 @@ pub fn recover(
     Y: &PublicKey,
     EncryptedSignature { s_hat, .. }: &EncryptedSignature,
     Signature { s, .. }: &Signature,
-) -> anyhow::Result<Result<KeyPair, KeyMismatch>> {
+) -> Result<KeyPair, KeyMismatch> {
     let y_macron = {
         let s_inv = s.inv();
         let s_hat: Scalar = s_hat.clone().into();
@@ -158,30 +193,34 @@ pub fn recover(
         s_hat * s_inv
     };
 
-    let Gy_macron: Affine = {
-        let mut Gy_macron = G.clone();
-        Gy_macron.tweak_mul_assign(&y_macron.clone().try_into()?)?;
+    // y_macron is zero if s is, in which case it cannot be the discrete logarithm of Y
+    let keypair = KeyPair::try_from(y_macron.clone()).map_err(|_| KeyMismatch)?;
 
-        Gy_macron.into()
-    };
+    let Gy_macron: Affine = keypair.to_pk().into();
     let Y: Affine = Y.clone().into();
 
-    let keypair = if Gy_macron == Y {
-        KeyPair::try_from(y_macron)?
+    if Gy_macron == Y {
+        Ok(keypair)
     } else if Gy_macron == Y.neg() {
-        KeyPair::try_from(-y_macron)?
+        KeyPair::try_from(-y_macron).map_err(|_| KeyMismatch)
     } else {
-        return Ok(Err(KeyMismatch));
-    };
-
-    Ok(Ok(keypair))
+        Err(KeyMismatch)
+    }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::secp256k1::arbitrary;
+    use crate::transcript::SessionId;
+    use proptest::prelude::*;
+    use rand::rngs::mock::StepRng;
     use secp256k1::Message;
 
+    fn transcript() -> Transcript {
+        Transcript::new(&SessionId::from([0u8; 32]))
+    }
+
     impl ToMessage for [u8; 32] {
         fn to_message(&self) -> [u8; 32] {
             *self
@@ -194,9 +233,63 @@ mod test {
         let y = KeyPair::random_from_thread_rng();
         let message = b"xxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxx";
 
-        let encsig = encsign(*message, &x, &y.to_pk(), &mut rand::thread_rng());
+        let encsig = encsign(
+            &mut transcript(),
+            *message,
+            &x,
+            &y.to_pk(),
+            &mut rand::thread_rng(),
+        );
+
+        encverify(&mut transcript(), &x.to_pk(), &y.to_pk(), message, &encsig).unwrap();
+    }
+
+    #[test]
+    fn reject_encrypted_signature_from_other_session() {
+        let x = KeyPair::random_from_thread_rng();
+        let y = KeyPair::random_from_thread_rng();
+        let message = b"xxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxx";
+
+        let encsig = encsign(
+            &mut transcript(),
+            *message,
+            &x,
+            &y.to_pk(),
+            &mut rand::thread_rng(),
+        );
+
+        let result = encverify(
+            &mut Transcript::new(&SessionId::from([1u8; 32])),
+            &x.to_pk(),
+            &y.to_pk(),
+            message,
+            &encsig,
+        );
+
+        assert!(result.is_err());
+    }
 
-        encverify(&x.to_pk(), &y.to_pk(), message, &encsig).unwrap();
+    #[test]
+    fn constant_rng_does_not_reuse_nonce() {
+        let x = KeyPair::random_from_thread_rng();
+        let y = KeyPair::random_from_thread_rng();
+
+        let encsig_1 = encsign(
+            &mut transcript(),
+            *b"11111111111111111111111111111111",
+            &x,
+            &y.to_pk(),
+            &mut StepRng::new(0, 0),
+        );
+        let encsig_2 = encsign(
+            &mut transcript(),
+            *b"22222222222222222222222222222222",
+            &x,
+            &y.to_pk(),
+            &mut StepRng::new(0, 0),
+        );
+
+        assert_ne!(encsig_1.R, encsig_2.R);
     }
 
     #[test]
@@ -206,9 +299,15 @@ mod test {
 
         let message = b"mmmmmmmmmmmmmmmmmmmmmmmmmmmmmmmm";
 
-        let encsig = encsign(*message, &x, &y.to_pk(), &mut rand::thread_rng());
+        let encsig = encsign(
+            &mut transcript(),
+            *message,
+            &x,
+            &y.to_pk(),
+            &mut rand::thread_rng(),
+        );
 
-        let sig = decsig(&y, &encsig);
+        let sig = decsig(&y, &encsig).unwrap();
 
         assert!(::secp256k1::verify(
             &Message::parse(message),
@@ -224,11 +323,62 @@ mod test {
 
         let message = b"mmmmmmmmmmmmmmmmmmmmmmmmmmmmmmmm";
 
-        let encsig = encsign(*message, &x, &y.to_pk(), &mut rand::thread_rng());
-        let sig = decsig(&y, &encsig);
+        let encsig = encsign(
+            &mut transcript(),
+            *message,
+            &x,
+            &y.to_pk(),
+            &mut rand::thread_rng(),
+        );
+        let sig = decsig(&y, &encsig).unwrap();
 
-        let y_tag = recover(&y.to_pk(), &encsig, &sig).unwrap().unwrap();
+        let y_tag = recover(&y.to_pk(), &encsig, &sig).unwrap();
 
         assert_eq!(y, y_tag);
     }
+
+    fn arbitrary_encrypted_signature() -> impl Strategy<Value = EncryptedSignature> {
+        (
+            arbitrary::public_key(),
+            arbitrary::public_key(),
+            arbitrary::secret_key(),
+            dleq::arbitrary_proof(),
+        )
+            .prop_map(|(R, R_hat, s_hat, proof)| EncryptedSignature {
+                R,
+                R_hat,
+                s_hat,
+                proof,
+            })
+    }
+
+    proptest! {
+        #[test]
+        fn encverify_does_not_panic(
+            X in arbitrary::public_key(),
+            Y in arbitrary::public_key(),
+            message in any::<[u8; 32]>(),
+            encsig in arbitrary_encrypted_signature(),
+        ) {
+            prop_assert!(encverify(&mut transcript(), &X, &Y, &message, &encsig).is_err());
+        }
+
+        #[test]
+        fn decsig_does_not_panic(
+            y in arbitrary::secret_key(),
+            encsig in arbitrary_encrypted_signature(),
+        ) {
+            let _ = decsig(&KeyPair::from(y), &encsig);
+        }
+
+        #[test]
+        fn recover_does_not_panic(
+            Y in arbitrary::public_key(),
+            encsig in arbitrary_encrypted_signature(),
+            r in arbitrary::scalar(),
+            s in arbitrary::scalar(),
+        ) {
+            let _ = recover(&Y, &encsig, &Signature { r, s });
+        }
+    }
 }
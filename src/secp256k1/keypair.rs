@@ -1,3 +1,4 @@
+use crate::secret::Secret;
 use secp256k1::curve::Scalar;
 use secp256k1::PublicKey;
 use secp256k1::SecretKey;
@@ -5,7 +6,7 @@ use std::convert::TryFrom;
 
 #[derive(PartialEq, Debug, Clone)]
 pub struct KeyPair {
-    sk: SecretKey,
+    sk: Secret<SecretKey>,
     pk: PublicKey,
 }
 
@@ -20,23 +21,52 @@ impl KeyPair {
         let sk = SecretKey::random(rand);
         let pk = PublicKey::from_secret_key(&sk);
 
-        Self { sk, pk }
+        Self {
+            sk: Secret::new(sk),
+            pk,
+        }
     }
 
     pub fn to_pk(&self) -> PublicKey {
         self.pk.clone()
     }
 
-    pub fn to_sk(&self) -> SecretKey {
+    pub fn to_sk(&self) -> Secret<SecretKey> {
         self.sk.clone()
     }
 
-    pub fn into_sk(self) -> SecretKey {
+    pub fn into_sk(self) -> Secret<SecretKey> {
         self.sk
     }
 
+    /// Returns the secret key as a scalar for arithmetic with other scalars.
+    pub fn to_scalar(&self) -> Secret<Scalar> {
+        Secret::new(self.sk.expose().clone().into())
+    }
+
     pub fn as_sk(&self) -> &SecretKey {
-        &self.sk
+        self.sk.expose()
+    }
+}
+
+/// Only the secret key is serialized, the public key is recomputed when deserializing.
+impl serde::Serialize for KeyPair {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        crate::serde::secp256k1_secret_key::serialize(self.sk.expose(), serializer)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for KeyPair {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let secret_key = crate::serde::secp256k1_secret_key::deserialize(deserializer)?;
+
+        Ok(Self::from(secret_key))
     }
 }
 
@@ -44,7 +74,7 @@ impl From<SecretKey> for KeyPair {
     fn from(secret_key: SecretKey) -> Self {
         Self {
             pk: PublicKey::from_secret_key(&secret_key),
-            sk: secret_key,
+            sk: Secret::new(secret_key),
         }
     }
 }
@@ -57,7 +87,7 @@ impl TryFrom<Scalar> for KeyPair {
 
         let pair = Self {
             pk: PublicKey::from_secret_key(&secret_key),
-            sk: secret_key,
+            sk: Secret::new(secret_key),
         };
 
         Ok(pair)
@@ -82,9 +112,6 @@ impl XCoor for PublicKey {
 #[cfg(test)]
 impl KeyPair {
     pub fn random_from_thread_rng() -> Self {
-        let sk = SecretKey::random(&mut rand::thread_rng());
-        let pk = PublicKey::from_secret_key(&sk);
-
-        Self { sk, pk }
+        Self::random(&mut rand::thread_rng())
     }
 }
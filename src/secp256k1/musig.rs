@@ -0,0 +1,476 @@
+//! Two-round MuSig2 multi-signatures as specified in BIP327.
+//!
+//! The signers aggregate their public keys into a single x-only key, optionally tweaked for a
+//! taproot key path spend as in BIP86, exchange two nonce points each and produce one partial
+//! signature each. If the session is started with an adaptor point, the aggregated signature is a
+//! [`schnorr::EncryptedSignature`] under that point instead of a valid signature.
+
+use crate::secp256k1::schnorr::{
+    self, challenge, conditional_negate, has_even_y, hash_to_scalar, mul, mul_base, negate, sum,
+    x_only,
+};
+use crate::secp256k1::{derive_nonce, tagged_hash, KeyPair};
+use crate::secret::Secret;
+use anyhow::bail;
+use secp256k1::{curve::Scalar, PublicKey, SecretKey};
+use sha2::Digest;
+
+const KEY_AGG_LIST_TAG: &[u8] = b"KeyAgg list";
+const KEY_AGG_COEFFICIENT_TAG: &[u8] = b"KeyAgg coefficient";
+const NONCE_COEFFICIENT_TAG: &[u8] = b"MuSig/noncecoef";
+const TAP_TWEAK_TAG: &[u8] = b"TapTweak";
+
+#[derive(Debug, Clone, Copy, thiserror::Error)]
+#[error("aggregate is the point at infinity")]
+pub struct PointAtInfinity;
+
+#[derive(Debug, Clone, Copy, thiserror::Error)]
+#[error("public key is not part of the aggregate key")]
+pub struct UnknownSigner;
+
+#[derive(Debug, Clone, Copy, thiserror::Error)]
+#[error("partial signature does not match public key and nonce")]
+pub struct InvalidPartialSignature;
+
+#[derive(Debug, Clone, Copy, thiserror::Error)]
+#[error("session was started with an adaptor point")]
+pub struct EncryptedSession;
+
+#[derive(Debug, Clone, Copy, thiserror::Error)]
+#[error("session was started without an adaptor point")]
+pub struct NoAdaptor;
+
+/// The aggregate of the signers' public keys together with the tweaks applied to it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyAggContext {
+    pubkeys: Vec<PublicKey>,
+    coefficients: Vec<Scalar>,
+    Q: PublicKey,
+    /// Whether the accumulated sign of the tweaks is negative.
+    gacc_negated: bool,
+    tacc: Scalar,
+}
+
+impl KeyAggContext {
+    pub fn new(pubkeys: Vec<PublicKey>) -> Result<Self, PointAtInfinity> {
+        let list = pubkeys
+            .iter()
+            .fold(tagged_hash(KEY_AGG_LIST_TAG), |hasher, X| {
+                hasher.chain(&X.serialize_compressed()[..])
+            })
+            .result();
+
+        // the second distinct key gets the coefficient 1 which saves one exponentiation
+        let second = pubkeys.iter().find(|X| *X != &pubkeys[0]).cloned();
+
+        let coefficients = pubkeys
+            .iter()
+            .map(|X| {
+                if Some(X) == second.as_ref() {
+                    let mut one = Scalar::default();
+                    one.set_int(1);
+
+                    one
+                } else {
+                    hash_to_scalar(
+                        tagged_hash(KEY_AGG_COEFFICIENT_TAG)
+                            .chain(&list)
+                            .chain(&X.serialize_compressed()[..]),
+                    )
+                }
+            })
+            .collect::<Vec<_>>();
+
+        let Q = sum(&pubkeys
+            .iter()
+            .zip(&coefficients)
+            .map(|(X, a)| mul(X, a))
+            .collect::<Vec<_>>())
+        .ok_or(PointAtInfinity)?;
+
+        Ok(Self {
+            pubkeys,
+            coefficients,
+            Q,
+            gacc_negated: false,
+            tacc: Scalar::default(),
+        })
+    }
+
+    /// Tweaks the aggregate key for a taproot output without a script path as in BIP86.
+    pub fn with_taproot_tweak(self) -> Result<Self, PointAtInfinity> {
+        let t = hash_to_scalar(tagged_hash(TAP_TWEAK_TAG).chain(&x_only(&self.Q)));
+        let negated = !has_even_y(&self.Q);
+
+        // Q' = gQ + tG with g = -1 if Q has an odd y-coordinate
+        let gQ = if negated { negate(&self.Q) } else { self.Q };
+        let Q = sum(&[Some(gQ), mul_base(&t)]).ok_or(PointAtInfinity)?;
+
+        Ok(Self {
+            Q,
+            gacc_negated: self.gacc_negated ^ negated,
+            tacc: t + conditional_negate(self.tacc, negated),
+            ..self
+        })
+    }
+
+    /// The x-only public key the signers produce signatures for.
+    pub fn aggregate_key(&self) -> [u8; 32] {
+        x_only(&self.Q)
+    }
+
+    fn coefficient(&self, X: &PublicKey) -> Result<Scalar, UnknownSigner> {
+        self.pubkeys
+            .iter()
+            .position(|pubkey| pubkey == X)
+            .map(|index| self.coefficients[index].clone())
+            .ok_or(UnknownSigner)
+    }
+
+    /// Whether the signers have to negate their secret keys, which is the case if exactly one of
+    /// the aggregate key and the accumulated tweaks is negated.
+    fn negate_secret_keys(&self) -> bool {
+        !has_even_y(&self.Q) ^ self.gacc_negated
+    }
+}
+
+/// The secret part of a signer's nonce.
+///
+/// Using a secret nonce in two sessions leaks the secret key, hence it is consumed by
+/// [`Session::partial_sign`]. It can only be cloned and serialized so that the states of the
+/// protocol can hold it until they sign, the transition that signs consumes the state as well.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct SecretNonce {
+    k_1: Secret<Scalar>,
+    k_2: Secret<Scalar>,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct PublicNonce {
+    #[serde(with = "crate::serde::secp256k1_public_key")]
+    R_1: PublicKey,
+    #[serde(with = "crate::serde::secp256k1_public_key")]
+    R_2: PublicKey,
+}
+
+/// Generates a signer's nonce for signing the message under the aggregate key.
+///
+/// As in BIP327, the aggregate key and the message are optional: a signer that has to send its
+/// nonce before it knows the other signers' keys or the message can leave them out.
+///
+/// Unlike single-signer nonces, MuSig2 nonces must never repeat even for the same message because
+/// the other signers' nonces may differ, hence the RNG must provide fresh randomness.
+pub fn nonce_gen<R: rand::Rng>(
+    x: &KeyPair,
+    aggregate_key: Option<&[u8; 32]>,
+    message: Option<&[u8; 32]>,
+    rng: &mut R,
+) -> (SecretNonce, PublicNonce) {
+    let aux_rand: [u8; 32] = rng.gen();
+    let (aggregate_key, message) = (optional(aggregate_key), optional(message));
+    let derive = |index: u8| -> SecretKey {
+        derive_nonce(
+            &x.as_sk().serialize(),
+            &[
+                &x.to_pk().serialize_compressed()[..],
+                &aggregate_key[..],
+                &message[..],
+                &[index][..],
+            ],
+            &aux_rand,
+        )
+    };
+    let (k_1, k_2) = (derive(1), derive(2));
+
+    let public_nonce = PublicNonce {
+        R_1: PublicKey::from_secret_key(&k_1),
+        R_2: PublicKey::from_secret_key(&k_2),
+    };
+    let secret_nonce = SecretNonce {
+        k_1: Secret::new(k_1.into()),
+        k_2: Secret::new(k_2.into()),
+    };
+
+    (secret_nonce, public_nonce)
+}
+
+/// Prefixes the data with whether it is present, so that leaving it out cannot collide with any
+/// value.
+fn optional(data: Option<&[u8; 32]>) -> [u8; 33] {
+    let mut bytes = [0u8; 33];
+    if let Some(data) = data {
+        bytes[0] = 1;
+        bytes[1..].copy_from_slice(data);
+    }
+
+    bytes
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct PartialSignature(#[serde(with = "crate::serde::secp256k1_scalar")] Scalar);
+
+/// A signing session of all signers of a [`KeyAggContext`] for one message.
+#[derive(Debug, Clone)]
+pub struct Session {
+    b: Scalar,
+    R: PublicKey,
+    e: Scalar,
+    adaptor: Option<PublicKey>,
+}
+
+impl Session {
+    pub fn new(
+        context: &KeyAggContext,
+        nonces: &[PublicNonce],
+        message: &[u8; 32],
+        adaptor: Option<&PublicKey>,
+    ) -> Result<Self, PointAtInfinity> {
+        let R_1 = sum(&nonces
+            .iter()
+            .map(|nonce| Some(nonce.R_1.clone()))
+            .collect::<Vec<_>>())
+        .ok_or(PointAtInfinity)?;
+        let R_2 = sum(&nonces
+            .iter()
+            .map(|nonce| Some(nonce.R_2.clone()))
+            .collect::<Vec<_>>())
+        .ok_or(PointAtInfinity)?;
+
+        let b = hash_to_scalar(
+            tagged_hash(NONCE_COEFFICIENT_TAG)
+                .chain(&R_1.serialize_compressed()[..])
+                .chain(&R_2.serialize_compressed()[..])
+                .chain(&context.aggregate_key())
+                .chain(message),
+        );
+
+        // R = R_1 + b * R_2 (+ T)
+        let R = sum(&[Some(R_1), mul(&R_2, &b), adaptor.cloned()]).ok_or(PointAtInfinity)?;
+        let e = challenge(&x_only(&R), &context.aggregate_key(), message);
+
+        Ok(Self {
+            b,
+            R,
+            e,
+            adaptor: adaptor.cloned(),
+        })
+    }
+
+    pub fn partial_sign(
+        &self,
+        context: &KeyAggContext,
+        SecretNonce { k_1, k_2 }: SecretNonce,
+        x: &KeyPair,
+    ) -> Result<PartialSignature, UnknownSigner> {
+        let a = context.coefficient(&x.to_pk())?;
+        // negating e * a instead of the secret key negates their product all the same
+        let ea = conditional_negate(self.e.clone() * a, context.negate_secret_keys());
+        let k = conditional_negate(
+            k_1.expose() + &(&self.b * k_2.expose()),
+            !has_even_y(&self.R),
+        );
+
+        Ok(PartialSignature(k + &ea * x.to_scalar().expose()))
+    }
+
+    pub fn partial_verify(
+        &self,
+        context: &KeyAggContext,
+        PublicNonce { R_1, R_2 }: &PublicNonce,
+        X: &PublicKey,
+        PartialSignature(s): &PartialSignature,
+    ) -> Result<(), InvalidPartialSignature> {
+        let a = context
+            .coefficient(X)
+            .map_err(|_| InvalidPartialSignature)?;
+
+        let R = sum(&[Some(R_1.clone()), mul(R_2, &self.b)]).ok_or(InvalidPartialSignature)?;
+        let R = if has_even_y(&self.R) { R } else { negate(&R) };
+
+        // sG == ±(R_1 + b * R_2) + e * a * g * X
+        let eag = conditional_negate(self.e.clone() * a, context.negate_secret_keys());
+        let expected = sum(&[Some(R), mul(X, &eag)]);
+
+        if expected.is_none() || mul_base(s) != expected {
+            return Err(InvalidPartialSignature);
+        }
+
+        Ok(())
+    }
+
+    /// Aggregates the partial signatures into a signature for the aggregate key.
+    pub fn aggregate(
+        &self,
+        context: &KeyAggContext,
+        partial_signatures: &[PartialSignature],
+    ) -> anyhow::Result<schnorr::Signature> {
+        if self.adaptor.is_some() {
+            bail!(EncryptedSession)
+        }
+
+        let signature =
+            schnorr::Signature::new(x_only(&self.R), self.s(context, partial_signatures));
+
+        Ok(signature)
+    }
+
+    /// Aggregates the partial signatures into a signature for the aggregate key that is encrypted
+    /// under the adaptor point of the session.
+    pub fn aggregate_encrypted(
+        &self,
+        context: &KeyAggContext,
+        partial_signatures: &[PartialSignature],
+    ) -> anyhow::Result<schnorr::EncryptedSignature> {
+        if self.adaptor.is_none() {
+            bail!(NoAdaptor)
+        }
+
+        Ok(schnorr::EncryptedSignature::new(
+            self.R.clone(),
+            self.s(context, partial_signatures),
+        ))
+    }
+
+    /// Computes `s = sum(s_i) + e * g * tacc`.
+    fn s(&self, context: &KeyAggContext, partial_signatures: &[PartialSignature]) -> Scalar {
+        let tweak = conditional_negate(
+            self.e.clone() * context.tacc.clone(),
+            !has_even_y(&context.Q),
+        );
+
+        partial_signatures
+            .iter()
+            .fold(tweak, |s, PartialSignature(s_i)| s + s_i.clone())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn sign_with(
+        context: &KeyAggContext,
+        signers: &[KeyPair],
+        message: &[u8; 32],
+        adaptor: Option<&PublicKey>,
+    ) -> (Session, Vec<PartialSignature>) {
+        let (secret_nonces, public_nonces): (Vec<_>, Vec<_>) = signers
+            .iter()
+            .map(|x| {
+                nonce_gen(
+                    x,
+                    Some(&context.aggregate_key()),
+                    Some(message),
+                    &mut rand::thread_rng(),
+                )
+            })
+            .unzip();
+
+        let session = Session::new(context, &public_nonces, message, adaptor).unwrap();
+
+        let partial_signatures = signers
+            .iter()
+            .zip(secret_nonces)
+            .zip(&public_nonces)
+            .map(|((x, secret_nonce), public_nonce)| {
+                let partial_signature = session.partial_sign(context, secret_nonce, x).unwrap();
+                session
+                    .partial_verify(context, public_nonce, &x.to_pk(), &partial_signature)
+                    .unwrap();
+
+                partial_signature
+            })
+            .collect();
+
+        (session, partial_signatures)
+    }
+
+    #[test]
+    fn aggregate_signature_verifies() {
+        let signers = vec![
+            KeyPair::random_from_thread_rng(),
+            KeyPair::random_from_thread_rng(),
+        ];
+        let message = b"mmmmmmmmmmmmmmmmmmmmmmmmmmmmmmmm";
+
+        for context in vec![
+            KeyAggContext::new(signers.iter().map(KeyPair::to_pk).collect()).unwrap(),
+            KeyAggContext::new(signers.iter().map(KeyPair::to_pk).collect())
+                .unwrap()
+                .with_taproot_tweak()
+                .unwrap(),
+        ] {
+            let (session, partial_signatures) = sign_with(&context, &signers, message, None);
+            let signature = session.aggregate(&context, &partial_signatures).unwrap();
+
+            schnorr::verify(&context.aggregate_key(), message, &signature).unwrap();
+        }
+    }
+
+    #[test]
+    fn aggregate_encrypted_signature_decrypts() {
+        let signers = vec![
+            KeyPair::random_from_thread_rng(),
+            KeyPair::random_from_thread_rng(),
+        ];
+        let y = KeyPair::random_from_thread_rng();
+        let message = b"mmmmmmmmmmmmmmmmmmmmmmmmmmmmmmmm";
+        let context = KeyAggContext::new(signers.iter().map(KeyPair::to_pk).collect())
+            .unwrap()
+            .with_taproot_tweak()
+            .unwrap();
+
+        let (session, partial_signatures) =
+            sign_with(&context, &signers, message, Some(&y.to_pk()));
+        let encsig = session
+            .aggregate_encrypted(&context, &partial_signatures)
+            .unwrap();
+
+        schnorr::encverify(&context.aggregate_key(), &y.to_pk(), message, &encsig).unwrap();
+
+        let signature = schnorr::decsig(y.as_sk(), &encsig);
+        schnorr::verify(&context.aggregate_key(), message, &signature).unwrap();
+        assert_eq!(
+            schnorr::recover(&y.to_pk(), &encsig, &signature).unwrap(),
+            y
+        );
+    }
+
+    #[test]
+    fn reject_partial_signature_of_other_signer() {
+        let signers = vec![
+            KeyPair::random_from_thread_rng(),
+            KeyPair::random_from_thread_rng(),
+        ];
+        let message = b"mmmmmmmmmmmmmmmmmmmmmmmmmmmmmmmm";
+        let context = KeyAggContext::new(signers.iter().map(KeyPair::to_pk).collect()).unwrap();
+
+        let (secret_nonce, public_nonce) = nonce_gen(
+            &signers[0],
+            Some(&context.aggregate_key()),
+            Some(message),
+            &mut rand::thread_rng(),
+        );
+        let (_, other_nonce) = nonce_gen(&signers[1], None, None, &mut rand::thread_rng());
+        let session = Session::new(
+            &context,
+            &[public_nonce.clone(), other_nonce],
+            message,
+            None,
+        )
+        .unwrap();
+
+        let partial_signature = session
+            .partial_sign(&context, secret_nonce, &signers[0])
+            .unwrap();
+
+        assert!(session
+            .partial_verify(
+                &context,
+                &public_nonce,
+                &signers[1].to_pk(),
+                &partial_signature
+            )
+            .is_err());
+    }
+}
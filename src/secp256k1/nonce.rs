@@ -0,0 +1,109 @@
+//! Derivation of nonces from secret material in the style of BIP340.
+//!
+//! The nonce is a hash of the secret, masked with auxiliary randomness, and the data the nonce is
+//! used for. A weak or repeated RNG therefore cannot cause the same nonce to be used for
+//! different messages, while fresh randomness still protects against side-channel attacks on the
+//! otherwise fully deterministic derivation.
+
+use secp256k1::SecretKey;
+use sha2::{Digest, Sha256};
+
+const AUX_TAG: &[u8] = b"A2L/aux";
+const NONCE_TAG: &[u8] = b"A2L/nonce";
+
+/// Derives a nonce from the secret, the data it will be used for and auxiliary randomness.
+///
+/// Every element of `data` must have a fixed length, otherwise different inputs could be
+/// ambiguous. In the unlikely case that the hash is not a valid secret key, it is recomputed with
+/// a counter appended.
+pub fn derive_nonce(secret: &[u8; 32], data: &[&[u8]], aux_rand: &[u8; 32]) -> SecretKey {
+    let mut masked_secret = [0u8; 32];
+    let mask = tagged_hash(AUX_TAG).chain(aux_rand).result();
+    for (masked, (secret, mask)) in masked_secret.iter_mut().zip(secret.iter().zip(mask)) {
+        *masked = secret ^ mask;
+    }
+
+    let mut counter = 0u32;
+    loop {
+        let mut hasher = tagged_hash(NONCE_TAG).chain(&masked_secret);
+        for element in data {
+            hasher.input(element);
+        }
+        if counter > 0 {
+            hasher.input(&counter.to_be_bytes());
+        }
+
+        if let Ok(nonce) = SecretKey::parse_slice(&hasher.result()[..]) {
+            return nonce;
+        }
+
+        counter += 1;
+    }
+}
+
+/// Returns a hasher that is prefixed with the tag as defined in BIP340.
+pub fn tagged_hash(tag: &[u8]) -> Sha256 {
+    let tag = Sha256::digest(tag);
+
+    Sha256::default().chain(&tag).chain(&tag)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct TestVector {
+        secret: &'static str,
+        message: &'static str,
+        Y: &'static str,
+        aux_rand: &'static str,
+        nonce: &'static str,
+    }
+
+    const TEST_VECTORS: &[TestVector] = &[
+        TestVector {
+            secret: "0000000000000000000000000000000000000000000000000000000000000001",
+            message: "0000000000000000000000000000000000000000000000000000000000000000",
+            Y: "02c6047f9441ed7d6d3045406e95c07cd85c778e4b8cef3ca7abac09b95c709ee5",
+            aux_rand: "0000000000000000000000000000000000000000000000000000000000000000",
+            nonce: "3e3e383ddae2705312a317306b63b288516d2202dd956844a8ce7ef5d3dc36b5",
+        },
+        TestVector {
+            secret: "b7e151628aed2a6abf7158809cf4f3c762e7160f38b4da56a784d9045190cfef",
+            message: "243f6a8885a308d313198a2e03707344a4093822299f31d0082efa98ec4e6c89",
+            Y: "02f9308a019258c31049344f85f89d5229b531c845836f99b08601f113bce036f9",
+            aux_rand: "0101010101010101010101010101010101010101010101010101010101010101",
+            nonce: "9d21b39f9b1df95ca3cf5f238d77686a0a9094aeeb90096c26c1fbb7b9ef419f",
+        },
+        TestVector {
+            secret: "b7e151628aed2a6abf7158809cf4f3c762e7160f38b4da56a784d9045190cfef",
+            message: "243f6a8885a308d313198a2e03707344a4093822299f31d0082efa98ec4e6c89",
+            Y: "02f9308a019258c31049344f85f89d5229b531c845836f99b08601f113bce036f9",
+            aux_rand: "0202020202020202020202020202020202020202020202020202020202020202",
+            nonce: "b0b0a59bc73861da225df616efcc9398f59e76fadb9d13e9f99062f89c794643",
+        },
+    ];
+
+    fn b32(hex: &str) -> [u8; 32] {
+        let mut bytes = [0u8; 32];
+        bytes.copy_from_slice(&hex::decode(hex).unwrap());
+
+        bytes
+    }
+
+    #[test]
+    fn known_answers() {
+        for vector in TEST_VECTORS {
+            let nonce = derive_nonce(
+                &b32(vector.secret),
+                &[
+                    &b32(vector.message)[..],
+                    &hex::decode(vector.Y).unwrap()[..],
+                ],
+                &b32(vector.aux_rand),
+            );
+
+            assert_eq!(hex::encode(nonce.serialize()), vector.nonce);
+        }
+    }
+}
@@ -0,0 +1,413 @@
+//! BIP340 Schnorr signatures and adaptor signatures on top of them.
+//!
+//! Public keys are x-only, i.e. they are identified with the point of even y-coordinate that has
+//! the given x-coordinate. Secret keys whose public key has an odd y-coordinate are negated before
+//! signing.
+//!
+//! An encrypted signature under the encryption key `Y = yG` commits to the nonce `R = kG + Y`.
+//! Adding `y` to it yields a valid BIP340 signature and subtracting it from such a signature
+//! reveals `y`.
+
+use crate::secp256k1::{derive_nonce, tagged_hash, InvalidSignature, KeyMismatch, KeyPair};
+use secp256k1::{curve::Scalar, PublicKey, SecretKey};
+use sha2::{Digest, Sha256};
+use std::convert::TryFrom;
+
+const AUX_TAG: &[u8] = b"BIP0340/aux";
+const NONCE_TAG: &[u8] = b"BIP0340/nonce";
+const CHALLENGE_TAG: &[u8] = b"BIP0340/challenge";
+
+/// A BIP340 signature `(x(R), s)`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Signature {
+    R_x: [u8; 32],
+    s: Scalar,
+}
+
+impl Signature {
+    pub(crate) fn new(R_x: [u8; 32], s: Scalar) -> Self {
+        Self { R_x, s }
+    }
+
+    pub fn serialize(&self) -> [u8; 64] {
+        let mut bytes = [0u8; 64];
+        bytes[..32].copy_from_slice(&self.R_x);
+        bytes[32..].copy_from_slice(&self.s.b32());
+
+        bytes
+    }
+
+    pub fn parse(bytes: &[u8; 64]) -> Result<Self, InvalidSignature> {
+        let mut R_x = [0u8; 32];
+        R_x.copy_from_slice(&bytes[..32]);
+
+        let mut s_bytes = [0u8; 32];
+        s_bytes.copy_from_slice(&bytes[32..]);
+        let mut s = Scalar::default();
+        if bool::from(s.set_b32(&s_bytes)) {
+            return Err(InvalidSignature);
+        }
+
+        Ok(Self { R_x, s })
+    }
+}
+
+/// Signs the message as specified in BIP340.
+pub fn sign(x: &SecretKey, message: &[u8; 32], aux_rand: &[u8; 32]) -> Signature {
+    let P = PublicKey::from_secret_key(x);
+    let d = conditional_negate(x.clone().into(), !has_even_y(&P));
+
+    let mut masked_secret = d.b32();
+    let mask = tagged_hash(AUX_TAG).chain(aux_rand).result();
+    for (masked, mask) in masked_secret.iter_mut().zip(mask) {
+        *masked ^= mask;
+    }
+
+    let k = hash_to_scalar(
+        tagged_hash(NONCE_TAG)
+            .chain(&masked_secret)
+            .chain(&x_only(&P))
+            .chain(message),
+    );
+    let R = mul_base(&k).expect("nonce is not zero except with negligible probability");
+    let k = conditional_negate(k, !has_even_y(&R));
+
+    let e = challenge(&x_only(&R), &x_only(&P), message);
+
+    Signature {
+        R_x: x_only(&R),
+        s: k + e * d,
+    }
+}
+
+/// Verifies a BIP340 signature against the x-only public key.
+pub fn verify(
+    P_x: &[u8; 32],
+    message: &[u8; 32],
+    Signature { R_x, s }: &Signature,
+) -> Result<(), InvalidSignature> {
+    let P = lift_x(P_x).ok_or(InvalidSignature)?;
+    let e = challenge(R_x, P_x, message);
+
+    // R = sG - eP
+    let R = sum(&[mul_base(s), mul(&P, &e).map(|eP| negate(&eP))]).ok_or(InvalidSignature)?;
+
+    if !has_even_y(&R) || &x_only(&R) != R_x {
+        return Err(InvalidSignature);
+    }
+
+    Ok(())
+}
+
+/// A BIP340 signature that can only be completed with the discrete logarithm of the encryption
+/// key.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct EncryptedSignature {
+    #[serde(with = "crate::serde::secp256k1_public_key")]
+    R: PublicKey,
+    #[serde(with = "crate::serde::secp256k1_scalar")]
+    s_hat: Scalar,
+}
+
+impl EncryptedSignature {
+    pub(crate) fn new(R: PublicKey, s_hat: Scalar) -> Self {
+        Self { R, s_hat }
+    }
+}
+
+#[derive(Debug, Clone, thiserror::Error)]
+pub enum InvalidEncryptedSignature {
+    #[error("public key is not the x-coordinate of a point")]
+    InvalidPublicKey,
+    #[error("nonce of the encrypted signature is the encryption key")]
+    NonceIsEncryptionKey,
+    #[error("encrypted signature does not match public key and message")]
+    Mismatch,
+}
+
+pub fn encsign<R: rand::Rng>(
+    x: &SecretKey,
+    message: &[u8; 32],
+    Y: &PublicKey,
+    rng: &mut R,
+) -> EncryptedSignature {
+    let P = PublicKey::from_secret_key(x);
+    let d = conditional_negate(x.clone().into(), !has_even_y(&P));
+
+    let k = derive_nonce(
+        &d.b32(),
+        &[&x_only(&P)[..], &message[..], &Y.serialize_compressed()[..]],
+        &rng.gen(),
+    );
+
+    let R = PublicKey::combine(&[PublicKey::from_secret_key(&k), Y.clone()])
+        .expect("nonce is not the negated encryption key except with negligible probability");
+    let k = conditional_negate(k.into(), !has_even_y(&R));
+
+    let e = challenge(&x_only(&R), &x_only(&P), message);
+
+    EncryptedSignature {
+        R,
+        s_hat: k + e * d,
+    }
+}
+
+pub fn encverify(
+    P_x: &[u8; 32],
+    Y: &PublicKey,
+    message: &[u8; 32],
+    EncryptedSignature { R, s_hat }: &EncryptedSignature,
+) -> Result<(), InvalidEncryptedSignature> {
+    let P = lift_x(P_x).ok_or(InvalidEncryptedSignature::InvalidPublicKey)?;
+    let e = challenge(&x_only(R), P_x, message);
+
+    // R - Y is the nonce point the signer committed to, negated if R has an odd y-coordinate
+    let R_minus_Y = sum(&[Some(R.clone()), Some(negate(Y))])
+        .ok_or(InvalidEncryptedSignature::NonceIsEncryptionKey)?;
+    let R_minus_Y = if has_even_y(R) {
+        R_minus_Y
+    } else {
+        negate(&R_minus_Y)
+    };
+
+    // s_hat * G == ±(R - Y) + eP
+    let expected = sum(&[Some(R_minus_Y), mul(&P, &e)]);
+
+    if expected.is_none() || mul_base(s_hat) != expected {
+        return Err(InvalidEncryptedSignature::Mismatch);
+    }
+
+    Ok(())
+}
+
+pub fn decsig(y: &SecretKey, EncryptedSignature { R, s_hat }: &EncryptedSignature) -> Signature {
+    let y = conditional_negate(y.clone().into(), !has_even_y(R));
+
+    Signature {
+        R_x: x_only(R),
+        s: s_hat.clone() + y,
+    }
+}
+
+pub fn recover(
+    Y: &PublicKey,
+    EncryptedSignature { R, s_hat }: &EncryptedSignature,
+    Signature { s, .. }: &Signature,
+) -> Result<KeyPair, KeyMismatch> {
+    let y = conditional_negate(s.clone() + -s_hat.clone(), !has_even_y(R));
+
+    // y is zero if s equals s_hat, in which case it cannot be the discrete logarithm of Y
+    let keypair = KeyPair::try_from(y).map_err(|_| KeyMismatch)?;
+
+    if &keypair.to_pk() != Y {
+        return Err(KeyMismatch);
+    }
+
+    Ok(keypair)
+}
+
+/// Returns the point with even y-coordinate and the given x-coordinate, if there is one.
+pub fn lift_x(x: &[u8; 32]) -> Option<PublicKey> {
+    let mut compressed = [0u8; 33];
+    compressed[0] = 0x02;
+    compressed[1..].copy_from_slice(x);
+
+    PublicKey::parse_compressed(&compressed).ok()
+}
+
+pub fn x_only(P: &PublicKey) -> [u8; 32] {
+    let mut x = [0u8; 32];
+    x.copy_from_slice(&P.serialize_compressed()[1..]);
+
+    x
+}
+
+pub(crate) fn has_even_y(P: &PublicKey) -> bool {
+    P.serialize_compressed()[0] == 0x02
+}
+
+pub(crate) fn negate(P: &PublicKey) -> PublicKey {
+    let mut compressed = P.serialize_compressed();
+    compressed[0] ^= 0x01;
+
+    PublicKey::parse_compressed(&compressed).expect("negation of a point is a point")
+}
+
+pub(crate) fn conditional_negate(k: Scalar, negate: bool) -> Scalar {
+    if negate {
+        -k
+    } else {
+        k
+    }
+}
+
+/// Computes `kG`, or `None` if `k` is zero.
+pub(crate) fn mul_base(k: &Scalar) -> Option<PublicKey> {
+    let k = SecretKey::try_from(k.clone()).ok()?;
+
+    Some(PublicKey::from_secret_key(&k))
+}
+
+/// Computes `kP`, or `None` if `k` is zero.
+pub(crate) fn mul(P: &PublicKey, k: &Scalar) -> Option<PublicKey> {
+    let k = SecretKey::try_from(k.clone()).ok()?;
+
+    let mut kP = P.clone();
+    kP.tweak_mul_assign(&k).ok()?;
+
+    Some(kP)
+}
+
+/// Adds the given points where `None` stands for the point at infinity, returning `None` if the
+/// sum is the point at infinity.
+pub(crate) fn sum(points: &[Option<PublicKey>]) -> Option<PublicKey> {
+    let points = points.iter().flatten().cloned().collect::<Vec<_>>();
+    if points.is_empty() {
+        return None;
+    }
+
+    PublicKey::combine(&points).ok()
+}
+
+/// Reduces the hash modulo the group order as BIP340 does for nonces and challenges.
+pub(crate) fn hash_to_scalar(hasher: Sha256) -> Scalar {
+    let mut bytes = [0u8; 32];
+    bytes.copy_from_slice(&hasher.result()[..]);
+
+    let mut scalar = Scalar::default();
+    let _ = scalar.set_b32(&bytes);
+
+    scalar
+}
+
+/// Computes `e = H_BIP0340/challenge(x(R) | x(P) | m)`.
+pub(crate) fn challenge(R_x: &[u8; 32], P_x: &[u8; 32], message: &[u8; 32]) -> Scalar {
+    hash_to_scalar(
+        tagged_hash(CHALLENGE_TAG)
+            .chain(R_x)
+            .chain(P_x)
+            .chain(message),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::secp256k1::arbitrary;
+    use proptest::prelude::*;
+
+    struct TestVector {
+        secret_key: &'static str,
+        public_key: &'static str,
+        aux_rand: &'static str,
+        message: &'static str,
+        signature: &'static str,
+    }
+
+    /// Test vectors 0 and 1 of BIP340.
+    const TEST_VECTORS: &[TestVector] = &[
+        TestVector {
+            secret_key: "0000000000000000000000000000000000000000000000000000000000000003",
+            public_key: "f9308a019258c31049344f85f89d5229b531c845836f99b08601f113bce036f9",
+            aux_rand: "0000000000000000000000000000000000000000000000000000000000000000",
+            message: "0000000000000000000000000000000000000000000000000000000000000000",
+            signature: "e907831f80848d1069a5371b402410364bdf1c5f8307b0084c55f1ce2dca8215\
+                        25f66a4a85ea8b71e482a74f382d2ce5ebeee8fdb2172f477df4900d310536c0",
+        },
+        TestVector {
+            secret_key: "b7e151628aed2a6abf7158809cf4f3c762e7160f38b4da56a784d9045190cfef",
+            public_key: "dff1d77f2a671c5f36183726db2341be58feae1da2deced843240f7b502ba659",
+            aux_rand: "0000000000000000000000000000000000000000000000000000000000000001",
+            message: "243f6a8885a308d313198a2e03707344a4093822299f31d0082efa98ec4e6c89",
+            signature: "6896bd60eeae296db48a229ff71dfe071bde413e6d43f917dc8dcf8c78de3341\
+                        8906d11ac976abccb20b091292bff4ea897efcb639ea871cfa95f6de339e4b0a",
+        },
+    ];
+
+    fn b32(hex: &str) -> [u8; 32] {
+        let mut bytes = [0u8; 32];
+        bytes.copy_from_slice(&hex::decode(hex).unwrap());
+
+        bytes
+    }
+
+    #[test]
+    fn known_answers() {
+        for vector in TEST_VECTORS {
+            let x = SecretKey::parse(&b32(vector.secret_key)).unwrap();
+            let P_x = b32(vector.public_key);
+            let message = b32(vector.message);
+
+            assert_eq!(x_only(&PublicKey::from_secret_key(&x)), P_x);
+
+            let signature = sign(&x, &message, &b32(vector.aux_rand));
+            assert_eq!(hex::encode(&signature.serialize()[..]), vector.signature);
+
+            verify(&P_x, &message, &signature).unwrap();
+            assert!(verify(&P_x, &[1u8; 32], &signature).is_err());
+        }
+    }
+
+    #[test]
+    fn encsign_and_encverify() {
+        let x = KeyPair::random_from_thread_rng();
+        let y = KeyPair::random_from_thread_rng();
+        let message = b"mmmmmmmmmmmmmmmmmmmmmmmmmmmmmmmm";
+
+        let encsig = encsign(x.as_sk(), message, &y.to_pk(), &mut rand::thread_rng());
+
+        encverify(&x_only(&x.to_pk()), &y.to_pk(), message, &encsig).unwrap();
+
+        let other = KeyPair::random_from_thread_rng();
+        assert!(encverify(&x_only(&other.to_pk()), &y.to_pk(), message, &encsig).is_err());
+    }
+
+    #[test]
+    fn encsign_and_decsig() {
+        let x = KeyPair::random_from_thread_rng();
+        let y = KeyPair::random_from_thread_rng();
+        let message = b"mmmmmmmmmmmmmmmmmmmmmmmmmmmmmmmm";
+
+        let encsig = encsign(x.as_sk(), message, &y.to_pk(), &mut rand::thread_rng());
+        let signature = decsig(y.as_sk(), &encsig);
+
+        verify(&x_only(&x.to_pk()), message, &signature).unwrap();
+    }
+
+    #[test]
+    fn recover_key_from_decrypted_signature() {
+        let x = KeyPair::random_from_thread_rng();
+        let y = KeyPair::random_from_thread_rng();
+        let message = b"mmmmmmmmmmmmmmmmmmmmmmmmmmmmmmmm";
+
+        let encsig = encsign(x.as_sk(), message, &y.to_pk(), &mut rand::thread_rng());
+        let signature = decsig(y.as_sk(), &encsig);
+
+        let recovered = recover(&y.to_pk(), &encsig, &signature).unwrap();
+
+        assert_eq!(recovered, y);
+    }
+
+    proptest! {
+        #[test]
+        fn encverify_does_not_panic(
+            P_x in any::<[u8; 32]>(),
+            Y in arbitrary::public_key(),
+            message in any::<[u8; 32]>(),
+            R in arbitrary::public_key(),
+            s_hat in arbitrary::scalar(),
+        ) {
+            let _ = encverify(&P_x, &Y, &message, &EncryptedSignature { R, s_hat });
+        }
+
+        #[test]
+        fn verify_does_not_panic(
+            P_x in any::<[u8; 32]>(),
+            message in any::<[u8; 32]>(),
+            R_x in any::<[u8; 32]>(),
+            s in arbitrary::scalar(),
+        ) {
+            let _ = verify(&P_x, &message, &Signature { R_x, s });
+        }
+    }
+}
@@ -0,0 +1,234 @@
+//! Secrets that are redacted when printed and zeroed when dropped.
+//!
+//! States of the protocol are cloned, persisted and embedded in errors, hence every secret they
+//! hold is wrapped in a [`Secret`] so it neither ends up in logs nor lingers in freed memory.
+
+use crate::secp256k1;
+use class_group::primitives::cl_dl;
+use curv::BigInt;
+use std::sync::atomic::{self, Ordering};
+use std::{fmt, ptr};
+
+pub struct Secret<T: Zeroize>(T);
+
+impl<T: Zeroize> Secret<T> {
+    pub fn new(value: T) -> Self {
+        Self(value)
+    }
+
+    /// Gives access to the secret value.
+    pub fn expose(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T: Zeroize> From<T> for Secret<T> {
+    fn from(value: T) -> Self {
+        Self::new(value)
+    }
+}
+
+impl<T: Zeroize + Clone> Clone for Secret<T> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<T: Zeroize + PartialEq> PartialEq for Secret<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.0 == other.0
+    }
+}
+
+impl<T: Zeroize> fmt::Debug for Secret<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("[REDACTED]")
+    }
+}
+
+impl<T: Zeroize> Drop for Secret<T> {
+    fn drop(&mut self) {
+        self.0.zeroize()
+    }
+}
+
+/// Overwrites a secret value before its memory is released.
+///
+/// `zeroize::Zeroize` cannot be implemented for the types of other crates, hence this trait is
+/// implemented for them instead, using `zeroize` wherever their internals are accessible.
+pub trait Zeroize {
+    fn zeroize(&mut self);
+}
+
+impl Zeroize for secp256k1::SecretKey {
+    /// Replaces the secret key with a fixed one.
+    ///
+    /// The scalar of a secret key is private, but `SecretKey` clears it when it is dropped, which
+    /// happens to the previous value on assignment.
+    fn zeroize(&mut self) {
+        *self = secp256k1::SecretKey::default();
+    }
+}
+
+impl Zeroize for secp256k1::Scalar {
+    fn zeroize(&mut self) {
+        zeroize::Zeroize::zeroize(&mut self.0[..]);
+    }
+}
+
+impl Zeroize for bls12_381::Scalar {
+    fn zeroize(&mut self) {
+        overwrite(self, bls12_381::Scalar::zero())
+    }
+}
+
+impl Zeroize for bls12_381::G1Affine {
+    fn zeroize(&mut self) {
+        overwrite(self, bls12_381::G1Affine::identity())
+    }
+}
+
+impl Zeroize for cl_dl::KeyPair {
+    /// Replaces the secret key with zero.
+    ///
+    /// The limbs of the previous value are owned by GMP, which releases them without overwriting
+    /// them. This is the best we can do without access to the internals of `BigInt`.
+    fn zeroize(&mut self) {
+        self.secret_key = BigInt::from(0);
+    }
+}
+
+/// Replaces a value whose internals are private in a way the compiler cannot elide.
+///
+/// `bls12_381` neither exposes its limbs nor implements `zeroize::Zeroize`, hence this is the
+/// only way to get rid of its secrets.
+fn overwrite<T: Copy>(value: &mut T, with: T) {
+    // sound because `value` is a valid reference and `T` has no drop glue to skip
+    unsafe { ptr::write_volatile(value, with) };
+    atomic::compiler_fence(Ordering::SeqCst);
+}
+
+impl serde::Serialize for Secret<secp256k1::SecretKey> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        crate::serde::secp256k1_secret_key::serialize(self.expose(), serializer)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Secret<secp256k1::SecretKey> {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        crate::serde::secp256k1_secret_key::deserialize(deserializer).map(Self::new)
+    }
+}
+
+impl serde::Serialize for Secret<secp256k1::Scalar> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        crate::serde::secp256k1_scalar::serialize(self.expose(), serializer)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Secret<secp256k1::Scalar> {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        crate::serde::secp256k1_scalar::deserialize(deserializer).map(Self::new)
+    }
+}
+
+impl serde::Serialize for Secret<bls12_381::Scalar> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        crate::serde::bls12_381_scalar::serialize(self.expose(), serializer)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Secret<bls12_381::Scalar> {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        crate::serde::bls12_381_scalar::deserialize(deserializer).map(Self::new)
+    }
+}
+
+impl serde::Serialize for Secret<bls12_381::G1Affine> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        crate::serde::bls12_381_g1affine::serialize(self.expose(), serializer)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Secret<bls12_381::G1Affine> {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        crate::serde::bls12_381_g1affine::deserialize(deserializer).map(Self::new)
+    }
+}
+
+impl serde::Serialize for Secret<cl_dl::KeyPair> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serde::Serialize::serialize(self.expose(), serializer)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Secret<cl_dl::KeyPair> {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        <cl_dl::KeyPair as serde::Deserialize>::deserialize(deserializer).map(Self::new)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn debug_output_is_redacted() {
+        let secret_key = secp256k1::KeyPair::random_from_thread_rng().as_sk().clone();
+        let secret = Secret::new(secret_key.clone());
+
+        assert_eq!(format!("{:?}", secret), "[REDACTED]");
+        assert!(
+            !format!("{:?}", secp256k1::KeyPair::from(secret_key.clone()))
+                .contains(&format!("{:?}", secret_key))
+        );
+    }
+
+    #[test]
+    fn zeroize_overwrites_scalar() {
+        let mut scalar = bls12_381::Scalar::from(42u64);
+
+        scalar.zeroize();
+
+        assert_eq!(scalar, bls12_381::Scalar::zero());
+    }
+
+    #[test]
+    fn zeroize_overwrites_secp256k1_scalar() {
+        let mut scalar = secp256k1::Scalar::from_int(42);
+
+        scalar.zeroize();
+
+        assert!(scalar.is_zero());
+    }
+}
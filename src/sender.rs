@@ -1,14 +1,18 @@
+use crate::secp256k1::musig;
+use crate::secret::Secret;
+use crate::transcript::{SessionId, Transcript};
 use crate::{
-    bitcoin, hsm_cl, pedersen,
+    bitcoin, hsm_cl, joint_output, pedersen,
     pointcheval_sanders::{self, randomize, unblind},
-    puzzle_promise, puzzle_solver, random_bls12_381_scalar, secp256k1, Lock, NoMessage,
-    NoTransaction, Token, UnexpectedMessage, UnexpectedTransaction,
+    puzzle_promise, puzzle_solver, random_bls12_381_scalar, secp256k1, Epoch, Lock, NoMessage,
+    NoTransaction, Token, UnexpectedMessage, UnexpectedTransaction, TOKEN_MESSAGES,
 };
-use anyhow::Context;
 use rand::Rng;
-use std::convert::TryInto;
+use std::slice;
 
-#[derive(Debug, derive_more::From, Clone, strum_macros::Display)]
+#[derive(
+    Debug, derive_more::From, Clone, serde::Serialize, serde::Deserialize, strum_macros::Display,
+)]
 pub enum Sender {
     Sender0(Sender0),
     Sender1(Sender1),
@@ -21,10 +25,11 @@ pub enum Sender {
 impl Sender {
     pub fn new(
         params: puzzle_solver::Params,
+        class_group: hsm_cl::ClassGroupParams,
         PS: pointcheval_sanders::PublicKey,
         rng: &mut impl Rng,
-    ) -> Self {
-        Sender0::new(params, PS, rng).into()
+    ) -> anyhow::Result<Self> {
+        Ok(Sender0::new(params, class_group, PS, rng)?.into())
     }
 
     pub fn transition_on_puzzle_promise_message(
@@ -82,7 +87,7 @@ impl Sender {
             Sender::Sender3(inner) => inner.next_message().into(),
             Sender::Sender4(inner) => inner.next_message().into(),
             Sender::Sender5(inner) => inner.next_message().into(),
-            state => anyhow::bail!(NoMessage::new(state.clone())),
+            state => anyhow::bail!(NoMessage::new(state)),
         };
 
         Ok(message)
@@ -95,74 +100,129 @@ impl Sender {
         }
     }
 
+    /// Returns the fund transaction as a PSBT for the sender's wallet to sign.
+    pub fn unsigned_fund_psbt(&self) -> anyhow::Result<bitcoin::Psbt> {
+        match self {
+            Sender::Sender1(inner) => Ok(inner.transactions.fund_psbt.clone()),
+            _ => anyhow::bail!(NoTransaction),
+        }
+    }
+
     pub fn signed_refund_transaction(&self) -> anyhow::Result<puzzle_solver::RefundTransaction> {
-        let transaction = match self {
-            Sender::Sender1(inner) => inner.signed_refund_transaction.clone(),
-            Sender::Sender2(inner) => inner.signed_refund_transaction.clone(),
-            Sender::Sender3(inner) => inner.signed_refund_transaction.clone(),
-            Sender::Sender4(inner) => inner.signed_refund_transaction.clone(),
+        let transaction = self.signed_refunds()?.refund().clone();
+
+        Ok(puzzle_solver::RefundTransaction(transaction))
+    }
+
+    /// Returns the cheapest signed refund transaction that pays at least `feerate`.
+    ///
+    /// Publishing a bumped refund instead of the one returned by `signed_refund_transaction`
+    /// gets the sender's coins back when the mempool has outgrown the agreed-upon fee.
+    pub fn signed_refund_transaction_for_feerate(
+        &self,
+        feerate: bitcoin::FeeRate,
+    ) -> anyhow::Result<puzzle_solver::RefundTransaction> {
+        let transaction = self.signed_refunds()?.for_feerate(feerate)?.clone();
+
+        Ok(puzzle_solver::RefundTransaction(transaction))
+    }
+
+    pub(crate) fn signed_refunds(&self) -> anyhow::Result<&bitcoin::SignedRefunds> {
+        let signed_refunds = match self {
+            Sender::Sender1(inner) => &inner.signed_refunds,
+            Sender::Sender2(inner) => &inner.signed_refunds,
+            Sender::Sender3(inner) => &inner.signed_refunds,
+            Sender::Sender4(inner) => &inner.signed_refunds,
             _ => anyhow::bail!(NoTransaction),
         };
 
-        Ok(puzzle_solver::RefundTransaction(transaction))
+        Ok(signed_refunds)
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct Sender0 {
     params: puzzle_solver::Params,
+    class_group: hsm_cl::ClassGroupParams,
     x_s: secp256k1::KeyPair,
-    token: Token,
+    nonces: Option<joint_output::SecretNonces>,
+    token: Secret<Token>,
+    #[serde(with = "crate::serde::bls12_381_g1affine")]
     C: pedersen::Commitment,
     pi_C: pedersen::Proof,
     D: pedersen::Decommitment,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct Sender1 {
-    signed_refund_transaction: bitcoin::Transaction,
+    session_id: SessionId,
+    signed_refunds: bitcoin::SignedRefunds,
     transactions: bitcoin::Transactions,
+    redeem_nonces: Option<joint_output::RedeemNonces>,
     x_s: secp256k1::KeyPair,
+    #[serde(with = "crate::serde::secp256k1_public_key")]
     X_t: secp256k1::PublicKey,
-    token: Token,
+    class_group: hsm_cl::ClassGroupParams,
+    token: Secret<Token>,
     D: pedersen::Decommitment,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct Sender2 {
-    signed_refund_transaction: bitcoin::Transaction,
+    session_id: SessionId,
+    signed_refunds: bitcoin::SignedRefunds,
     transactions: bitcoin::Transactions,
+    redeem_nonces: Option<joint_output::RedeemNonces>,
     x_s: secp256k1::KeyPair,
+    #[serde(with = "crate::serde::secp256k1_public_key")]
     X_t: secp256k1::PublicKey,
-    token: Token,
+    class_group: hsm_cl::ClassGroupParams,
+    token: Secret<Token>,
     sig_token_rand: pointcheval_sanders::Signature,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct Sender3 {
+    session_id: SessionId,
+    epoch: Epoch,
     x_s: secp256k1::KeyPair,
     c_alpha_prime_prime: hsm_cl::Ciphertext,
+    #[serde(with = "crate::serde::secp256k1_public_key")]
     A_prime: secp256k1::PublicKey,
-    tau: secp256k1::SecretKey,
+    tau: Secret<secp256k1::SecretKey>,
     transactions: bitcoin::Transactions,
-    signed_refund_transaction: bitcoin::Transaction,
+    redeem_nonces: Option<joint_output::RedeemNonces>,
+    signed_refunds: bitcoin::SignedRefunds,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct Sender4 {
-    sig_redeem_s: secp256k1::EncryptedSignature,
+    sig_redeem_s: RedeemSignatures,
+    #[serde(with = "crate::serde::secp256k1_public_key")]
     A_prime_prime: secp256k1::PublicKey,
     x_s: secp256k1::KeyPair,
-    tau: secp256k1::SecretKey,
+    tau: Secret<secp256k1::SecretKey>,
+    #[serde(with = "crate::serde::bitcoin_sighash")]
     redeem_tx_digest: bitcoin::SigHash,
-    signed_refund_transaction: bitcoin::Transaction,
+    signed_refunds: bitcoin::SignedRefunds,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct Sender5 {
     alpha_macron: secp256k1::KeyPair,
 }
 
+/// The signature of the sender on the redeem transaction, from which it learns gamma once the
+/// tumbler has published the redeem transaction.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+enum RedeemSignatures {
+    P2wsh(secp256k1::EncryptedSignature),
+    P2tr {
+        partial_sig_redeem_s: musig::PartialSignature,
+        encsig_redeem: joint_output::EncryptedRedeemSignature,
+    },
+}
+
 #[derive(thiserror::Error, Debug)]
 #[error("(A')^tau != A''")]
 pub struct AptNotEqualApp;
@@ -170,24 +230,50 @@ pub struct AptNotEqualApp;
 impl Sender0 {
     pub fn new(
         params: puzzle_solver::Params,
+        class_group: hsm_cl::ClassGroupParams,
         PS: pointcheval_sanders::PublicKey,
         rng: &mut impl Rng,
-    ) -> Self {
-        let token = random_bls12_381_scalar(rng);
+    ) -> anyhow::Result<Self> {
+        params.timelocks.validate()?;
+        bitcoin::validate_partial_fund_psbt(
+            &params.partial_fund_psbt,
+            params.sender_tumbler_joint_output_value(),
+        )?;
+        PS.ensure_messages(TOKEN_MESSAGES)?;
+
+        let token = Secret::new(random_bls12_381_scalar(rng));
 
         let G1 = bls12_381::G1Affine::generator();
-        let Y1 = &PS.Y1;
-        let (C, D) = pedersen::commit(&G1, Y1, &token, rng);
-        let pi_C = pedersen::prove(&G1, Y1, &C, &D, rng);
+        // the tumbler adds the tumble amount and the epoch to the token when signing
+        let Y1 = &PS.Y1[..1];
+        let (C, D) = pedersen::commit(&G1, Y1, slice::from_ref(token.expose()), rng);
+        let pi_C = pedersen::prove(
+            &mut Transcript::new(&params.session_id),
+            &G1,
+            Y1,
+            &C,
+            &D,
+            rng,
+        );
+
+        let x_s = secp256k1::KeyPair::random(rng);
+        let nonces = joint_output::nonce_gen(
+            params.joint_output,
+            &x_s,
+            params.refund_transaction_count(),
+            rng,
+        );
 
-        Self {
+        Ok(Self {
             params,
-            x_s: secp256k1::KeyPair::random(rng),
+            class_group,
+            x_s,
+            nonces,
             token,
             C,
             pi_C,
             D,
-        }
+        })
     }
 
     pub fn next_message(&self) -> puzzle_solver::Message0 {
@@ -195,42 +281,46 @@ impl Sender0 {
             X_s: self.x_s.to_pk(),
             C: self.C,
             pi_C: self.pi_C.clone(),
+            nonces_s: self.nonces.as_ref().map(|nonces| nonces.public().clone()),
         }
     }
 
     pub fn receive(
         self,
-        puzzle_solver::Message1 { X_t, sig_refund_t }: puzzle_solver::Message1,
+        puzzle_solver::Message1 { X_t, sigs_refund_t }: puzzle_solver::Message1,
     ) -> anyhow::Result<Sender1> {
+        let joint_output = self
+            .params
+            .joint_output
+            .joint_output(&self.x_s.to_pk(), &X_t)?;
         let transactions = bitcoin::make_transactions(
-            self.params.partial_fund_transaction.clone(),
+            self.params.partial_fund_psbt.clone(),
             self.params.sender_tumbler_joint_output_value(),
             self.params.sender_tumbler_joint_output_takeout(),
-            &self.x_s.to_pk(),
+            &joint_output,
             &X_t,
-            self.params.expiry,
+            &self.x_s.to_pk(),
+            self.params.timelocks.solver_expiry,
             &self.params.redeem_identity,
             &self.params.refund_identity,
         );
 
-        let sig_refund_s = {
-            secp256k1::verify(transactions.refund_tx_digest, &sig_refund_t, &X_t)
-                .context("failed to verify tumbler refund signature")?;
-
-            secp256k1::sign(transactions.refund_tx_digest, &self.x_s)
-        };
-
-        let signed_refund_transaction = bitcoin::complete_spend_transaction(
-            transactions.refund.clone(),
-            (self.x_s.to_pk(), sig_refund_s),
-            (X_t.clone(), sig_refund_t),
+        let (signed_refunds, redeem_nonces) = joint_output::complete_refunds(
+            &transactions,
+            &self.x_s,
+            &X_t,
+            self.nonces,
+            sigs_refund_t,
         )?;
 
         Ok(Sender1 {
-            signed_refund_transaction,
+            session_id: self.params.session_id,
+            signed_refunds,
             transactions,
+            redeem_nonces,
             X_t,
             x_s: self.x_s,
+            class_group: self.class_group,
             token: self.token,
             D: self.D,
         })
@@ -243,28 +333,31 @@ impl Sender1 {
         puzzle_solver::Message2 { sig_token_blind }: puzzle_solver::Message2,
         rng: &mut impl Rng,
     ) -> Sender2 {
-        let sig_token = unblind(sig_token_blind, self.D.r);
+        let sig_token = unblind(sig_token_blind, self.D.r.expose());
         let sig_token_rand = randomize(&sig_token, rng);
 
         Sender2 {
+            session_id: self.session_id,
             x_s: self.x_s,
             X_t: self.X_t,
+            class_group: self.class_group,
             transactions: self.transactions,
+            redeem_nonces: self.redeem_nonces,
             sig_token_rand,
-            signed_refund_transaction: self.signed_refund_transaction,
+            signed_refunds: self.signed_refunds,
             token: self.token,
         }
     }
 
     pub fn unsigned_fund_transaction(&self) -> puzzle_solver::FundTransaction {
-        puzzle_solver::FundTransaction(self.transactions.fund.clone())
+        puzzle_solver::FundTransaction(self.transactions.fund().clone())
     }
 }
 
 impl Sender2 {
     pub fn next_message(&self) -> puzzle_solver::Message3 {
         puzzle_solver::Message3 {
-            token: self.token,
+            token: self.token.clone(),
             sig_token_rand: self.sig_token_rand.clone(),
         }
     }
@@ -272,22 +365,28 @@ impl Sender2 {
     pub fn receive(
         self,
         puzzle_promise::Message4 {
-            l: Lock {
-                c_alpha_prime,
-                A_prime,
-            },
+            l:
+                Lock {
+                    c_alpha_prime,
+                    A_prime,
+                    epoch,
+                },
         }: puzzle_promise::Message4,
         _rng: &mut impl Rng,
     ) -> Sender3 {
-        let (c_alpha_prime_prime, tau) = hsm_cl::blind_ciphertext(&c_alpha_prime);
+        let (c_alpha_prime_prime, tau) =
+            hsm_cl::blind_ciphertext(&self.class_group, &c_alpha_prime);
 
         Sender3 {
+            session_id: self.session_id,
+            epoch,
             x_s: self.x_s,
             A_prime,
             c_alpha_prime_prime,
-            tau,
+            tau: Secret::new(tau),
             transactions: self.transactions,
-            signed_refund_transaction: self.signed_refund_transaction,
+            redeem_nonces: self.redeem_nonces,
+            signed_refunds: self.signed_refunds,
         }
     }
 }
@@ -296,29 +395,51 @@ impl Sender3 {
     pub fn next_message(&self) -> puzzle_solver::Message4 {
         puzzle_solver::Message4 {
             c_alpha_prime_prime: self.c_alpha_prime_prime.clone(),
+            epoch: self.epoch,
         }
     }
 
     pub fn receive(
         self,
-        puzzle_solver::Message5 { A_prime_prime }: puzzle_solver::Message5,
+        puzzle_solver::Message5 {
+            A_prime_prime,
+            partial_sig_redeem_t,
+        }: puzzle_solver::Message5,
         rng: &mut impl Rng,
     ) -> anyhow::Result<Sender4> {
         let A_prime_tau = {
             let mut A_prime_tau = self.A_prime.clone();
-            A_prime_tau.tweak_mul_assign(&self.tau).unwrap();
+            A_prime_tau.tweak_mul_assign(self.tau.expose()).unwrap();
             A_prime_tau
         };
         if A_prime_tau != A_prime_prime {
             anyhow::bail!(AptNotEqualApp)
         }
 
-        let sig_redeem_s = secp256k1::encsign(
-            self.transactions.redeem_tx_digest,
-            &self.x_s,
-            &A_prime_prime,
-            rng,
-        );
+        let sig_redeem_s = match (self.redeem_nonces, partial_sig_redeem_t) {
+            (None, None) => RedeemSignatures::P2wsh(secp256k1::encsign(
+                &mut Transcript::new(&self.session_id),
+                self.transactions.redeem_tx_digest,
+                &self.x_s,
+                &A_prime_prime,
+                rng,
+            )),
+            (Some(redeem_nonces), Some(partial_sig_redeem_t)) => {
+                let session = redeem_nonces.sign(
+                    self.transactions.redeem_tx_digest,
+                    &self.x_s,
+                    &A_prime_prime,
+                )?;
+                // the signature of the tumbler only reveals gamma if its share is valid
+                let encsig_redeem = session.aggregate(&partial_sig_redeem_t)?;
+
+                RedeemSignatures::P2tr {
+                    partial_sig_redeem_s: session.partial_signature().clone(),
+                    encsig_redeem,
+                }
+            }
+            _ => anyhow::bail!(joint_output::WrongJointOutputKind),
+        };
 
         Ok(Sender4 {
             sig_redeem_s,
@@ -326,16 +447,24 @@ impl Sender3 {
             x_s: self.x_s,
             tau: self.tau,
             redeem_tx_digest: self.transactions.redeem_tx_digest,
-            signed_refund_transaction: self.signed_refund_transaction,
+            signed_refunds: self.signed_refunds,
         })
     }
 }
 
 impl Sender4 {
     pub fn next_message(&self) -> puzzle_solver::Message6 {
-        puzzle_solver::Message6 {
-            sig_redeem_s: self.sig_redeem_s.clone(),
-        }
+        let sig_redeem_s = match &self.sig_redeem_s {
+            RedeemSignatures::P2wsh(sig_redeem_s) => {
+                joint_output::RedeemSignature::P2wsh(sig_redeem_s.clone())
+            }
+            RedeemSignatures::P2tr {
+                partial_sig_redeem_s,
+                ..
+            } => joint_output::RedeemSignature::P2tr(partial_sig_redeem_s.clone()),
+        };
+
+        puzzle_solver::Message6 { sig_redeem_s }
     }
 
     pub fn receive(
@@ -343,34 +472,37 @@ impl Sender4 {
         redeem_transaction: puzzle_solver::RedeemTransaction,
     ) -> anyhow::Result<Sender5> {
         let Self {
-            sig_redeem_s: encrypted_signature,
+            sig_redeem_s,
             A_prime_prime,
             tau,
             ..
         } = self;
 
-        let decrypted_signature = bitcoin::extract_signature_by_key(
-            redeem_transaction.0,
-            self.redeem_tx_digest,
-            &self.x_s.to_pk(),
-        )?;
-
-        let gamma =
-            secp256k1::recover(&A_prime_prime, &encrypted_signature, &decrypted_signature)??;
-        let alpha_macron = {
-            let gamma: secp256k1::Scalar = gamma.into_sk().into();
-            let tau: secp256k1::Scalar = tau.into();
+        let gamma = match sig_redeem_s {
+            RedeemSignatures::P2wsh(encrypted_signature) => {
+                let decrypted_signature = bitcoin::extract_signature_by_key(
+                    redeem_transaction.0,
+                    self.redeem_tx_digest,
+                    &self.x_s.to_pk(),
+                )?;
 
-            gamma * tau.inv()
+                secp256k1::recover(&A_prime_prime, &encrypted_signature, &decrypted_signature)?
+            }
+            RedeemSignatures::P2tr { encsig_redeem, .. } => {
+                encsig_redeem.recover(redeem_transaction.0)?
+            }
         };
+        // alpha_macron = gamma * tau^-1
+        let mut alpha_macron = tau.expose().inv();
+        alpha_macron.tweak_mul_assign(gamma.as_sk())?;
 
         Ok(Sender5 {
-            alpha_macron: alpha_macron.try_into()?,
+            alpha_macron: alpha_macron.into(),
         })
     }
 
     pub fn signed_refund_transaction(&self) -> bitcoin::Transaction {
-        self.signed_refund_transaction.clone()
+        self.signed_refunds.refund().clone()
     }
 }
 
@@ -1,53 +1,485 @@
+use serde::de::{self, SeqAccess, Visitor};
+use std::fmt;
+
+/// Deserializes a byte string of any length.
+///
+/// Self-describing formats like CBOR encode bytes natively, whereas formats such as JSON encode
+/// them as a sequence of numbers. We accept both.
+fn deserialize_byte_buf<'de, D>(deserializer: D) -> Result<Vec<u8>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    struct BytesVisitor;
+
+    impl<'de> Visitor<'de> for BytesVisitor {
+        type Value = Vec<u8>;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+            formatter.write_str("a byte string")
+        }
+
+        fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E>
+        where
+            E: de::Error,
+        {
+            Ok(v.to_vec())
+        }
+
+        fn visit_byte_buf<E>(self, v: Vec<u8>) -> Result<Self::Value, E>
+        where
+            E: de::Error,
+        {
+            Ok(v)
+        }
+
+        fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+        where
+            A: SeqAccess<'de>,
+        {
+            let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+            while let Some(byte) = seq.next_element()? {
+                bytes.push(byte);
+            }
+
+            Ok(bytes)
+        }
+    }
+
+    deserializer.deserialize_bytes(BytesVisitor)
+}
+
+/// Deserializes a byte string of exactly `expected_len` bytes.
+fn deserialize_bytes<'de, D>(deserializer: D, expected_len: usize) -> Result<Vec<u8>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let bytes = deserialize_byte_buf(deserializer)?;
+
+    if bytes.len() != expected_len {
+        return Err(de::Error::invalid_length(
+            bytes.len(),
+            &format!("{} bytes", expected_len).as_str(),
+        ));
+    }
+
+    Ok(bytes)
+}
+
+/// Parses a secp256k1 scalar, rejecting values that are not smaller than the group order.
+fn parse_secp256k1_scalar(bytes: &[u8]) -> Option<secp256k1::curve::Scalar> {
+    let mut b32 = [0u8; 32];
+    b32.copy_from_slice(bytes);
+
+    let mut scalar = secp256k1::curve::Scalar::default();
+    let overflow = scalar.set_b32(&b32);
+
+    if bool::from(overflow) {
+        return None;
+    }
+
+    Some(scalar)
+}
+
 pub mod secp256k1_secret_key {
+    use serde::de::Error;
+
     pub fn serialize<S>(secret_key: &secp256k1::SecretKey, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: serde::Serializer,
     {
         serializer.serialize_bytes(&secret_key.serialize())
     }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<secp256k1::SecretKey, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let bytes = super::deserialize_bytes(deserializer, 32)?;
+
+        secp256k1::SecretKey::parse_slice(&bytes)
+            .map_err(|_| D::Error::custom("secret key is zero or not smaller than group order"))
+    }
 }
 
 pub mod secp256k1_scalar {
+    use serde::de::Error;
+
     pub fn serialize<S>(scalar: &secp256k1::curve::Scalar, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: serde::Serializer,
     {
         serializer.serialize_bytes(scalar.b32().as_ref())
     }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<secp256k1::curve::Scalar, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let bytes = super::deserialize_bytes(deserializer, 32)?;
+
+        super::parse_secp256k1_scalar(&bytes)
+            .ok_or_else(|| D::Error::custom("scalar is not smaller than group order"))
+    }
 }
 
 pub mod secp256k1_public_key {
+    use serde::de::Error;
+
     pub fn serialize<S>(public_key: &secp256k1::PublicKey, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: serde::Serializer,
     {
         serializer.serialize_bytes(&public_key.serialize_compressed())
     }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<secp256k1::PublicKey, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let bytes = super::deserialize_bytes(deserializer, 33)?;
+
+        secp256k1::PublicKey::parse_slice(&bytes, Some(secp256k1::PublicKeyFormat::Compressed))
+            .map_err(|_| D::Error::custom("public key is not a point on the curve"))
+    }
 }
 
 pub mod secp256k1_signature {
+    use serde::de::Error;
+
     pub fn serialize<S>(signature: &secp256k1::Signature, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: serde::Serializer,
     {
         serializer.serialize_bytes(&signature.serialize())
     }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<secp256k1::Signature, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let bytes = super::deserialize_bytes(deserializer, 64)?;
+
+        let r = super::parse_secp256k1_scalar(&bytes[..32]);
+        let s = super::parse_secp256k1_scalar(&bytes[32..]);
+
+        match (r, s) {
+            (Some(r), Some(s)) if !r.is_zero() && !s.is_zero() => Ok(secp256k1::Signature { r, s }),
+            _ => Err(D::Error::custom(
+                "signature component is zero or not smaller than group order",
+            )),
+        }
+    }
 }
 
 pub mod bls12_381_g1affine {
+    use serde::de::Error;
+
     pub fn serialize<S>(ge: &bls12_381::G1Affine, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: serde::Serializer,
     {
         serializer.serialize_bytes(&ge.to_uncompressed())
     }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<bls12_381::G1Affine, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let bytes = super::deserialize_bytes(deserializer, 96)?;
+
+        let mut uncompressed = [0u8; 96];
+        uncompressed.copy_from_slice(&bytes);
+
+        // `from_uncompressed` checks that the point is on the curve and in the correct subgroup
+        Option::from(bls12_381::G1Affine::from_uncompressed(&uncompressed))
+            .ok_or_else(|| D::Error::custom("point is not a valid element of G1"))
+    }
 }
 
 pub mod bls12_381_scalar {
+    use serde::de::Error;
+
     pub fn serialize<S>(scalar: &bls12_381::Scalar, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: serde::Serializer,
     {
         serializer.serialize_bytes(&scalar.to_bytes())
     }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<bls12_381::Scalar, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let bytes = super::deserialize_bytes(deserializer, 32)?;
+
+        let mut b32 = [0u8; 32];
+        b32.copy_from_slice(&bytes);
+
+        Option::from(bls12_381::Scalar::from_bytes(&b32))
+            .ok_or_else(|| D::Error::custom("scalar is not in canonical form"))
+    }
+}
+
+pub mod bls12_381_g2affine {
+    use serde::de::Error;
+
+    pub fn serialize<S>(ge: &bls12_381::G2Affine, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.serialize_bytes(&ge.to_uncompressed())
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<bls12_381::G2Affine, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let bytes = super::deserialize_bytes(deserializer, 192)?;
+
+        let mut uncompressed = [0u8; 192];
+        uncompressed.copy_from_slice(&bytes);
+
+        // `from_uncompressed` checks that the point is on the curve and in the correct subgroup
+        Option::from(bls12_381::G2Affine::from_uncompressed(&uncompressed))
+            .ok_or_else(|| D::Error::custom("point is not a valid element of G2"))
+    }
+}
+
+/// Generates a `with` module for a `Vec` of elements that are (de)serialized with `$element`.
+macro_rules! vec_of {
+    ($name:ident, $element:ident, $ty:ty) => {
+        pub mod $name {
+            use super::$element as element;
+            use serde::{Deserialize, Serialize};
+
+            #[derive(Serialize, Deserialize)]
+            struct Element(#[serde(with = "element")] $ty);
+
+            pub fn serialize<S>(elements: &[$ty], serializer: S) -> Result<S::Ok, S::Error>
+            where
+                S: serde::Serializer,
+            {
+                serializer.collect_seq(elements.iter().map(|element| Element(*element)))
+            }
+
+            pub fn deserialize<'de, D>(deserializer: D) -> Result<Vec<$ty>, D::Error>
+            where
+                D: serde::Deserializer<'de>,
+            {
+                let elements = Vec::<Element>::deserialize(deserializer)?;
+
+                Ok(elements
+                    .into_iter()
+                    .map(|Element(element)| element)
+                    .collect())
+            }
+        }
+    };
+}
+
+vec_of!(
+    bls12_381_g1affine_vec,
+    bls12_381_g1affine,
+    bls12_381::G1Affine
+);
+vec_of!(
+    bls12_381_g2affine_vec,
+    bls12_381_g2affine,
+    bls12_381::G2Affine
+);
+vec_of!(bls12_381_scalar_vec, bls12_381_scalar, bls12_381::Scalar);
+vec_of!(
+    secp256k1_signature_vec,
+    secp256k1_signature,
+    secp256k1::Signature
+);
+
+pub mod bitcoin_transaction {
+    use serde::de::Error;
+
+    pub fn serialize<S>(
+        transaction: &bitcoin::Transaction,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.serialize_bytes(&bitcoin::consensus::serialize(transaction))
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<bitcoin::Transaction, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let bytes = super::deserialize_byte_buf(deserializer)?;
+
+        bitcoin::consensus::deserialize(&bytes).map_err(D::Error::custom)
+    }
+}
+
+pub mod bitcoin_psbt {
+    use bitcoin::util::psbt::PartiallySignedTransaction as Psbt;
+    use serde::de::Error;
+
+    pub fn serialize<S>(psbt: &Psbt, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.serialize_bytes(&bitcoin::consensus::serialize(psbt))
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Psbt, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let bytes = super::deserialize_byte_buf(deserializer)?;
+
+        bitcoin::consensus::deserialize(&bytes).map_err(D::Error::custom)
+    }
+}
+
+pub mod bitcoin_address {
+    use serde::de::Error;
+    use serde::Deserialize;
+
+    pub fn serialize<S>(address: &bitcoin::Address, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.serialize_str(&address.to_string())
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<bitcoin::Address, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let address = String::deserialize(deserializer)?;
+
+        address.parse().map_err(D::Error::custom)
+    }
+}
+
+pub mod bitcoin_amount {
+    use serde::Deserialize;
+
+    pub fn serialize<S>(amount: &bitcoin::Amount, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.serialize_u64(amount.as_sat())
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<bitcoin::Amount, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let sats = u64::deserialize(deserializer)?;
+
+        Ok(bitcoin::Amount::from_sat(sats))
+    }
+}
+
+pub mod bitcoin_sighash {
+    use bitcoin::hashes::Hash;
+    use serde::de::Error;
+
+    pub fn serialize<S>(
+        sighash: &bitcoin::hash_types::SigHash,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.serialize_bytes(&sighash[..])
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<bitcoin::hash_types::SigHash, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let bytes = super::deserialize_bytes(deserializer, 32)?;
+
+        bitcoin::hash_types::SigHash::from_slice(&bytes).map_err(D::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[derive(Debug, serde::Serialize, serde::Deserialize)]
+    struct Secp256k1Scalar(#[serde(with = "secp256k1_scalar")] secp256k1::curve::Scalar);
+
+    #[derive(Debug, serde::Serialize, serde::Deserialize)]
+    struct Secp256k1PublicKey(#[serde(with = "secp256k1_public_key")] secp256k1::PublicKey);
+
+    #[derive(Debug, serde::Serialize, serde::Deserialize)]
+    struct Bls12381G1Affine(#[serde(with = "bls12_381_g1affine")] bls12_381::G1Affine);
+
+    #[derive(Debug, serde::Serialize, serde::Deserialize)]
+    struct Bls12381Scalar(#[serde(with = "bls12_381_scalar")] bls12_381::Scalar);
+
+    #[derive(serde::Serialize)]
+    struct Bytes<'a>(#[serde(with = "bytes")] &'a [u8]);
+
+    mod bytes {
+        pub fn serialize<S>(bytes: &&[u8], serializer: S) -> Result<S::Ok, S::Error>
+        where
+            S: serde::Serializer,
+        {
+            serializer.serialize_bytes(bytes)
+        }
+    }
+
+    #[test]
+    fn reject_secp256k1_scalar_out_of_range() {
+        let bytes = serde_cbor::to_vec(&Bytes(&[0xFF; 32])).unwrap();
+
+        assert!(serde_cbor::from_slice::<Secp256k1Scalar>(&bytes).is_err());
+    }
+
+    #[test]
+    fn reject_secp256k1_point_not_on_curve() {
+        let mut point = [0u8; 33];
+        point[0] = 0x02;
+        point[32] = 0x05; // x = 5 is not the x-coordinate of any point on secp256k1
+
+        let bytes = serde_cbor::to_vec(&Bytes(&point)).unwrap();
+
+        assert!(serde_cbor::from_slice::<Secp256k1PublicKey>(&bytes).is_err());
+    }
+
+    #[test]
+    fn reject_bls12_381_point_not_on_curve() {
+        let mut point = bls12_381::G1Affine::generator().to_uncompressed();
+        point[95] ^= 0x01;
+
+        let bytes = serde_cbor::to_vec(&Bytes(&point)).unwrap();
+
+        assert!(serde_cbor::from_slice::<Bls12381G1Affine>(&bytes).is_err());
+    }
+
+    #[test]
+    fn reject_bls12_381_scalar_out_of_range() {
+        let bytes = serde_cbor::to_vec(&Bytes(&[0xFF; 32])).unwrap();
+
+        assert!(serde_cbor::from_slice::<Bls12381Scalar>(&bytes).is_err());
+    }
+
+    #[test]
+    fn reject_wrong_length() {
+        let bytes = serde_cbor::to_vec(&Bytes(&[0x01; 31])).unwrap();
+
+        assert!(serde_cbor::from_slice::<Secp256k1Scalar>(&bytes).is_err());
+    }
+
+    #[test]
+    fn roundtrip_over_json() {
+        let scalar = Bls12381Scalar(crate::random_bls12_381_scalar(&mut rand::thread_rng()));
+
+        let json = serde_json::to_string(&scalar).unwrap();
+        let deserialized = serde_json::from_str::<Bls12381Scalar>(&json).unwrap();
+
+        assert_eq!(scalar.0, deserialized.0);
+    }
 }
@@ -0,0 +1,197 @@
+//! Persistence of actor state so that a party can resume the protocol after a restart.
+//!
+//! Every state of `Sender`, `Receiver`, `puzzle_promise::Tumbler` and `puzzle_solver::Tumbler`
+//! can be serialized. States are stored as CBOR, prefixed with the version of the format they were
+//! written with. A state written with a different version is rejected instead of being
+//! misinterpreted.
+//!
+//! A restored `puzzle_promise::Tumbler` has to be given its token store again using
+//! `puzzle_promise::Tumbler::with_token_store`. A restored `puzzle_promise::Tumbler` or
+//! `puzzle_solver::Tumbler` has to be given its keyring again using `with_keyring`.
+
+use anyhow::Context;
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    fs::{self, File},
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+/// The version of the format in which states are persisted.
+///
+/// Must be incremented whenever the serialized representation of any state changes.
+pub const STATE_FORMAT_VERSION: u32 = 1;
+
+pub trait StateStore<S> {
+    /// Persists the state, replacing the previously persisted one.
+    fn save(&self, state: &S) -> anyhow::Result<()>;
+
+    /// Loads the last persisted state, if any.
+    fn load(&self) -> anyhow::Result<Option<S>>;
+}
+
+#[derive(thiserror::Error, Debug)]
+#[error("unsupported state format version {0}")]
+pub struct UnsupportedStateVersion(u32);
+
+#[derive(serde::Serialize)]
+struct VersionedStateRef<'a, S> {
+    version: u32,
+    state: &'a S,
+}
+
+#[derive(serde::Deserialize)]
+struct Version {
+    version: u32,
+}
+
+#[derive(serde::Deserialize)]
+struct VersionedState<S> {
+    state: S,
+}
+
+/// Encodes a state in the versioned format.
+pub fn serialize<S>(state: &S) -> anyhow::Result<Vec<u8>>
+where
+    S: Serialize,
+{
+    let bytes = serde_cbor::to_vec(&VersionedStateRef {
+        version: STATE_FORMAT_VERSION,
+        state,
+    })?;
+
+    Ok(bytes)
+}
+
+/// Decodes a state from the versioned format.
+pub fn deserialize<S>(bytes: &[u8]) -> anyhow::Result<S>
+where
+    S: DeserializeOwned,
+{
+    let Version { version } =
+        serde_cbor::from_slice(bytes).context("failed to read state format version")?;
+
+    if version != STATE_FORMAT_VERSION {
+        anyhow::bail!(UnsupportedStateVersion(version))
+    }
+
+    let VersionedState { state } =
+        serde_cbor::from_slice(bytes).context("failed to deserialize state")?;
+
+    Ok(state)
+}
+
+/// Persists the state of a single party in a file.
+///
+/// The file is replaced atomically, hence a crash while saving leaves the previous state intact.
+#[derive(Debug, Clone)]
+pub struct FileStateStore {
+    path: PathBuf,
+}
+
+impl FileStateStore {
+    pub fn new(path: impl AsRef<Path>) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+        }
+    }
+
+    fn temporary_path(&self) -> PathBuf {
+        let mut file_name = self
+            .path
+            .file_name()
+            .map(|name| name.to_os_string())
+            .unwrap_or_default();
+        file_name.push(".tmp");
+
+        self.path.with_file_name(file_name)
+    }
+}
+
+impl<S> StateStore<S> for FileStateStore
+where
+    S: Serialize + DeserializeOwned,
+{
+    fn save(&self, state: &S) -> anyhow::Result<()> {
+        let bytes = serialize(state)?;
+        let temporary_path = self.temporary_path();
+
+        let mut file = File::create(&temporary_path)
+            .with_context(|| format!("failed to create {}", temporary_path.display()))?;
+        file.write_all(&bytes)
+            .and_then(|_| file.sync_all())
+            .with_context(|| format!("failed to write {}", temporary_path.display()))?;
+
+        fs::rename(&temporary_path, &self.path)
+            .with_context(|| format!("failed to replace state in {}", self.path.display()))?;
+
+        Ok(())
+    }
+
+    fn load(&self) -> anyhow::Result<Option<S>> {
+        if !self.path.exists() {
+            return Ok(None);
+        }
+
+        let bytes = fs::read(&self.path)
+            .with_context(|| format!("failed to read state from {}", self.path.display()))?;
+
+        deserialize(&bytes).map(Some)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{bitcoin, secp256k1};
+    use rand::{thread_rng, Rng};
+
+    #[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+    struct State {
+        x: secp256k1::KeyPair,
+        timelocks: bitcoin::TimelockPolicy,
+    }
+
+    fn temporary_file() -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "a2l-state-store-{}",
+            hex::encode(thread_rng().gen::<[u8; 8]>())
+        ))
+    }
+
+    #[test]
+    fn file_store_roundtrip() {
+        let path = temporary_file();
+        let store = FileStateStore::new(&path);
+        let state = State {
+            x: secp256k1::KeyPair::random(&mut thread_rng()),
+            timelocks: bitcoin::TimelockPolicy::from_safety_margin(
+                bitcoin::Expiry::BlockHeight(100),
+                6,
+            )
+            .unwrap(),
+        };
+
+        assert_eq!(StateStore::<State>::load(&store).unwrap(), None);
+
+        store.save(&state).unwrap();
+        let loaded = StateStore::<State>::load(&store).unwrap();
+
+        assert_eq!(loaded, Some(state));
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn reject_unsupported_version() {
+        let bytes = serde_cbor::to_vec(&VersionedStateRef {
+            version: STATE_FORMAT_VERSION + 1,
+            state: &secp256k1::KeyPair::random(&mut thread_rng()),
+        })
+        .unwrap();
+
+        let error = deserialize::<secp256k1::KeyPair>(&bytes).unwrap_err();
+
+        assert!(error.is::<UnsupportedStateVersion>());
+    }
+}
@@ -0,0 +1,262 @@
+//! Double-spend protection for the anonymous tokens redeemed with `puzzle_promise::Tumbler`.
+//!
+//! `puzzle_solver::Tumbler` issues tokens by signing a commitment to them, hence it never sees a
+//! token or its serial number and has nothing to record. Only the redeeming side consults a store.
+//!
+//! A token is only worth one puzzle promise. The tumbler never learns the token itself, only the
+//! serial number of its show, which is the same every time the token is shown. The tumbler hence
+//! needs to remember the serial number of every token it has accepted in
+//! `puzzle_promise::Tumbler0::receive` and reject it if it is presented again. A single store has
+//! to be shared by all puzzle-promise sessions that accept tokens signed with the same
+//! Pointcheval-Sanders key.
+
+use crate::pointcheval_sanders::SerialNumber;
+use anyhow::Context;
+use std::{
+    collections::HashSet,
+    fmt,
+    fs::{File, OpenOptions},
+    io::{Read, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+const SERIAL_NUMBER_SIZE: usize = 48;
+
+/// Every file of a [`FileTokenStore`] starts with this magic followed by the big-endian format
+/// version.
+const FILE_MAGIC: &[u8; 4] = b"A2LT";
+const FILE_HEADER_SIZE: usize = 8;
+
+/// The version of the format in which `FileTokenStore` persists serial numbers.
+///
+/// Must be incremented whenever the size or encoding of serial numbers changes.
+pub const TOKEN_STORE_FORMAT_VERSION: u32 = 1;
+
+pub trait TokenStore: fmt::Debug + Send + Sync {
+    /// Marks the token with the given serial number as spent.
+    ///
+    /// Implementations must check and record the serial number atomically and fail with
+    /// [`TokenAlreadySpent`] if the token has been spent before.
+    fn spend(&self, serial_number: &SerialNumber) -> anyhow::Result<()>;
+}
+
+#[derive(thiserror::Error, Debug)]
+#[error("token has already been spent")]
+pub struct TokenAlreadySpent;
+
+#[derive(Debug, Default)]
+pub struct InMemoryTokenStore {
+    spent: Mutex<HashSet<[u8; SERIAL_NUMBER_SIZE]>>,
+}
+
+impl TokenStore for InMemoryTokenStore {
+    fn spend(&self, serial_number: &SerialNumber) -> anyhow::Result<()> {
+        let mut spent = self
+            .spent
+            .lock()
+            .map_err(|_| anyhow::anyhow!("token store lock is poisoned"))?;
+
+        if !spent.insert(serial_number.to_bytes()) {
+            anyhow::bail!(TokenAlreadySpent)
+        }
+
+        Ok(())
+    }
+}
+
+/// The token store of a tumbler that has been restored from persisted state.
+///
+/// A token store cannot be persisted along with the state that references it, it has to be
+/// reattached using `puzzle_promise::Tumbler::with_token_store`. Until then, every token is
+/// rejected.
+#[derive(Debug)]
+pub struct DetachedTokenStore;
+
+#[derive(thiserror::Error, Debug)]
+#[error("no token store attached")]
+pub struct TokenStoreDetached;
+
+impl TokenStore for DetachedTokenStore {
+    fn spend(&self, _: &SerialNumber) -> anyhow::Result<()> {
+        anyhow::bail!(TokenStoreDetached)
+    }
+}
+
+pub(crate) fn detached() -> Arc<dyn TokenStore> {
+    Arc::new(DetachedTokenStore)
+}
+
+/// Persists the serial numbers of spent tokens by appending them to a file.
+///
+/// All serial numbers are kept in memory as well, so lookups never touch the file.
+#[derive(Debug)]
+pub struct FileTokenStore {
+    path: PathBuf,
+    inner: Mutex<FileTokenStoreInner>,
+}
+
+#[derive(Debug)]
+struct FileTokenStoreInner {
+    file: File,
+    spent: HashSet<[u8; SERIAL_NUMBER_SIZE]>,
+}
+
+#[derive(thiserror::Error, Debug)]
+#[error("token store {} is corrupted", .0.display())]
+pub struct CorruptedTokenStore(PathBuf);
+
+#[derive(thiserror::Error, Debug)]
+#[error("token store {} has unsupported format version {}", .0.display(), .1)]
+pub struct UnsupportedTokenStoreVersion(PathBuf, u32);
+
+impl FileTokenStore {
+    /// Opens the token store at the given path, creating it if it does not exist.
+    ///
+    /// Stores in any other format than `TOKEN_STORE_FORMAT_VERSION` are refused.
+    pub fn open(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&path)
+            .with_context(|| format!("failed to open token store {}", path.display()))?;
+
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes)
+            .with_context(|| format!("failed to read token store {}", path.display()))?;
+
+        if bytes.is_empty() {
+            let mut header = FILE_MAGIC.to_vec();
+            header.extend_from_slice(&TOKEN_STORE_FORMAT_VERSION.to_be_bytes());
+
+            file.write_all(&header)
+                .and_then(|_| file.sync_data())
+                .with_context(|| format!("failed to write to token store {}", path.display()))?;
+            bytes = header;
+        }
+
+        if bytes.len() < FILE_HEADER_SIZE || bytes[..FILE_MAGIC.len()] != FILE_MAGIC[..] {
+            anyhow::bail!(CorruptedTokenStore(path))
+        }
+
+        let mut version = [0u8; 4];
+        version.copy_from_slice(&bytes[FILE_MAGIC.len()..FILE_HEADER_SIZE]);
+        let version = u32::from_be_bytes(version);
+        if version != TOKEN_STORE_FORMAT_VERSION {
+            anyhow::bail!(UnsupportedTokenStoreVersion(path, version))
+        }
+
+        let serial_numbers = &bytes[FILE_HEADER_SIZE..];
+        if serial_numbers.len() % SERIAL_NUMBER_SIZE != 0 {
+            anyhow::bail!(CorruptedTokenStore(path))
+        }
+
+        let spent = serial_numbers
+            .chunks_exact(SERIAL_NUMBER_SIZE)
+            .map(|chunk| {
+                let mut serial_number = [0u8; SERIAL_NUMBER_SIZE];
+                serial_number.copy_from_slice(chunk);
+                serial_number
+            })
+            .collect();
+
+        Ok(Self {
+            path,
+            inner: Mutex::new(FileTokenStoreInner { file, spent }),
+        })
+    }
+}
+
+impl TokenStore for FileTokenStore {
+    fn spend(&self, serial_number: &SerialNumber) -> anyhow::Result<()> {
+        let mut inner = self
+            .inner
+            .lock()
+            .map_err(|_| anyhow::anyhow!("token store lock is poisoned"))?;
+
+        let serial_number = serial_number.to_bytes();
+
+        if inner.spent.contains(&serial_number) {
+            anyhow::bail!(TokenAlreadySpent)
+        }
+
+        // the serial number must hit the disk before we accept the token, otherwise a crash would
+        // allow it to be spent again
+        let file = &mut inner.file;
+        file.write_all(&serial_number)
+            .and_then(|_| file.sync_data())
+            .with_context(|| format!("failed to write to token store {}", self.path.display()))?;
+        inner.spent.insert(serial_number);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::random_bls12_381_scalar;
+    use rand::{thread_rng, Rng};
+
+    fn random_serial_number() -> SerialNumber {
+        let m = random_bls12_381_scalar(&mut thread_rng());
+
+        SerialNumber::from(bls12_381::G1Affine::from(
+            bls12_381::G1Affine::generator() * m,
+        ))
+    }
+
+    #[test]
+    fn in_memory_store_rejects_reused_token() {
+        let store = InMemoryTokenStore::default();
+        let serial_number = random_serial_number();
+
+        store.spend(&serial_number).unwrap();
+        let error = store.spend(&serial_number).unwrap_err();
+
+        assert!(error.is::<TokenAlreadySpent>());
+        store.spend(&random_serial_number()).unwrap();
+    }
+
+    fn temp_path() -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "a2l-token-store-{}",
+            hex::encode(thread_rng().gen::<[u8; 8]>())
+        ))
+    }
+
+    #[test]
+    fn file_store_remembers_tokens_across_restarts() {
+        let path = temp_path();
+        let serial_number = random_serial_number();
+
+        FileTokenStore::open(&path)
+            .unwrap()
+            .spend(&serial_number)
+            .unwrap();
+
+        let store = FileTokenStore::open(&path).unwrap();
+        let error = store.spend(&serial_number).unwrap_err();
+
+        assert!(error.is::<TokenAlreadySpent>());
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn file_store_refuses_unsupported_version() {
+        let path = temp_path();
+        let mut header = FILE_MAGIC.to_vec();
+        header.extend_from_slice(&(TOKEN_STORE_FORMAT_VERSION + 1).to_be_bytes());
+        std::fs::write(&path, header).unwrap();
+
+        let error = FileTokenStore::open(&path).unwrap_err();
+
+        assert!(error.is::<UnsupportedTokenStoreVersion>());
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
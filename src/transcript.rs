@@ -0,0 +1,172 @@
+//! Fiat-Shamir transcripts in the spirit of Merlin.
+//!
+//! Every proof absorbs its statement into a [`Transcript`] that is keyed with the protocol name,
+//! the protocol version and a [`SessionId`]. Challenges are therefore bound to the session and
+//! the kind of proof, which prevents a proof from being replayed in another session or context.
+
+use crate::secp256k1;
+use rand::Rng;
+use sha2::{Digest, Sha512};
+use std::convert::TryFrom;
+
+pub const PROTOCOL_NAME: &[u8] = b"A2L";
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Identifies one run of a sub-protocol, both parties must agree on it before the first message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+pub struct SessionId([u8; 32]);
+
+impl SessionId {
+    pub fn random(rng: &mut impl Rng) -> Self {
+        Self(rng.gen())
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+}
+
+impl From<[u8; 32]> for SessionId {
+    fn from(bytes: [u8; 32]) -> Self {
+        Self(bytes)
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("session id must be 32 bytes long")]
+pub struct InvalidSessionId;
+
+impl TryFrom<&[u8]> for SessionId {
+    type Error = InvalidSessionId;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        <[u8; 32]>::try_from(bytes)
+            .map(Self)
+            .map_err(|_| InvalidSessionId)
+    }
+}
+
+#[derive(Clone)]
+pub struct Transcript {
+    hasher: Sha512,
+}
+
+impl Transcript {
+    pub fn new(session_id: &SessionId) -> Self {
+        let mut transcript = Self {
+            hasher: Sha512::default(),
+        };
+
+        transcript.append_message(b"protocol-name", PROTOCOL_NAME);
+        transcript.append_u32(b"protocol-version", PROTOCOL_VERSION);
+        transcript.append_message(b"session-id", session_id.as_bytes());
+
+        transcript
+    }
+
+    /// Separates the proof that follows from everything that was absorbed before.
+    pub fn domain_separator(&mut self, label: &'static [u8]) {
+        self.append_message(b"dom-sep", label)
+    }
+
+    pub fn append_message(&mut self, label: &'static [u8], message: &[u8]) {
+        // lengths are absorbed as well, hence distinct sequences never share an encoding
+        self.hasher.input(&(label.len() as u32).to_le_bytes());
+        self.hasher.input(label);
+        self.hasher.input(&(message.len() as u32).to_le_bytes());
+        self.hasher.input(message);
+    }
+
+    pub fn append_u32(&mut self, label: &'static [u8], value: u32) {
+        self.append_message(label, &value.to_le_bytes())
+    }
+
+    pub fn append_secp256k1_point(&mut self, label: &'static [u8], point: &secp256k1::PublicKey) {
+        self.append_message(label, &point.serialize_compressed())
+    }
+
+    pub fn append_bls12_381_g1(&mut self, label: &'static [u8], point: &bls12_381::G1Affine) {
+        self.append_message(label, &point.to_compressed())
+    }
+
+    pub fn append_bls12_381_g2(&mut self, label: &'static [u8], point: &bls12_381::G2Affine) {
+        self.append_message(label, &point.to_compressed())
+    }
+
+    /// Squeezes 64 bytes out of the transcript.
+    ///
+    /// The output is absorbed again, hence every challenge depends on all previous ones.
+    pub fn challenge_bytes(&mut self, label: &'static [u8]) -> [u8; 64] {
+        self.append_message(label, &[]);
+
+        let mut bytes = [0u8; 64];
+        bytes.copy_from_slice(&self.hasher.clone().result()[..]);
+        self.append_message(b"challenge", &bytes);
+
+        bytes
+    }
+
+    /// Computes a secp256k1 challenge by reducing all 64 bytes of output modulo the group order.
+    pub fn challenge_secp256k1_scalar(&mut self, label: &'static [u8]) -> secp256k1::Scalar {
+        secp256k1::scalar_from_bytes_wide(&self.challenge_bytes(label))
+    }
+
+    pub fn challenge_bls12_381_scalar(&mut self, label: &'static [u8]) -> bls12_381::Scalar {
+        bls12_381::Scalar::from_bytes_wide(&self.challenge_bytes(label))
+    }
+}
+
+impl std::fmt::Debug for Transcript {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Transcript").finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn challenges_depend_on_session_id() {
+        let mut a = Transcript::new(&SessionId::from([0u8; 32]));
+        let mut b = Transcript::new(&SessionId::from([1u8; 32]));
+
+        assert_ne!(&a.challenge_bytes(b"c")[..], &b.challenge_bytes(b"c")[..]);
+    }
+
+    #[test]
+    fn challenges_depend_on_message_framing() {
+        let session_id = SessionId::from([0u8; 32]);
+
+        let mut a = Transcript::new(&session_id);
+        a.append_message(b"m", b"ab");
+        a.append_message(b"m", b"c");
+
+        let mut b = Transcript::new(&session_id);
+        b.append_message(b"m", b"a");
+        b.append_message(b"m", b"bc");
+
+        assert_ne!(&a.challenge_bytes(b"c")[..], &b.challenge_bytes(b"c")[..]);
+    }
+
+    #[test]
+    fn consecutive_challenges_differ() {
+        let mut transcript = Transcript::new(&SessionId::from([0u8; 32]));
+
+        let first = transcript.challenge_bytes(b"c");
+        let second = transcript.challenge_bytes(b"c");
+
+        assert_ne!(&first[..], &second[..]);
+    }
+
+    #[test]
+    fn secp256k1_challenge_reduces_all_bytes() {
+        let mut a = Transcript::new(&SessionId::from([0u8; 32]));
+        let mut b = a.clone();
+
+        assert_eq!(
+            a.challenge_secp256k1_scalar(b"c"),
+            secp256k1::scalar_from_bytes_wide(&b.challenge_bytes(b"c"))
+        );
+    }
+}
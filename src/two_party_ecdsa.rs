@@ -0,0 +1,712 @@
+//! Two-party ECDSA with adaptor signatures.
+//!
+//! The parties hold multiplicative shares of a single secp256k1 key `X = x_1 * x_2 * G`, hence the
+//! joint output can be an ordinary P2WPKH output instead of a 2-of-2 script. The protocol follows
+//! Lindell's two-party ECDSA with the Paillier encryption replaced by HSM-CL: party 1 encrypts its
+//! key share under its HSM-CL key, party 2 homomorphically computes an encryption of its part of
+//! the signature and party 1 decrypts and finishes it. As the plaintext space of HSM-CL is the
+//! scalar field of secp256k1, no range proofs are needed.
+//!
+//! Every signing session is encrypted under a statement `Y`: the joint nonce is `R = k_1 k_2 Y`
+//! and party 1 ends up with an [`EncryptedSignature`] that can only be completed with the discrete
+//! logarithm of `Y`. A plain signature is a session encrypted under `G`.
+//!
+//! The funding party of a joint output plays party 1 because it is the one that ends up with the
+//! complete refund signature.
+//!
+//! The protocol actors do not run these rounds. Party 1 can only start a signing session once the
+//! key generation has finished, hence signing the refund transactions would take another round
+//! trip before the fund transaction, which MuSig2 nonces avoid for P2TR joint outputs.
+
+use crate::secp256k1::{derive_nonce, KeyPair, PublicKey, Scalar, SecretKey, Signature, XCoor, G};
+use crate::secret::Secret;
+use crate::transcript::{SessionId, Transcript};
+use crate::{dleq, hsm_cl};
+use rand::Rng;
+use std::convert::TryFrom;
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct KeyGenMessage1 {
+    #[serde(with = "crate::serde::secp256k1_public_key")]
+    pub X_1: PublicKey,
+    pub c_x_1: hsm_cl::Ciphertext,
+    pub pi_x_1: hsm_cl::Proof,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct KeyGenMessage2 {
+    #[serde(with = "crate::serde::secp256k1_public_key")]
+    pub X_2: PublicKey,
+    pub pi_x_2: dleq::Proof,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct SignMessage1 {
+    #[serde(with = "crate::serde::secp256k1_public_key")]
+    pub R_1: PublicKey,
+    /// The nonce of party 1 times the statement `Y`.
+    #[serde(with = "crate::serde::secp256k1_public_key")]
+    pub R_1_Y: PublicKey,
+    pub pi_R_1: dleq::Proof,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct SignMessage2 {
+    #[serde(with = "crate::serde::secp256k1_public_key")]
+    pub R_2: PublicKey,
+    /// The joint nonce `R = k_2 * R_1_Y`.
+    #[serde(with = "crate::serde::secp256k1_public_key")]
+    pub R: PublicKey,
+    pub pi_R: dleq::Proof,
+    pub c_s: hsm_cl::Ciphertext,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct SignMessage3 {
+    #[serde(with = "crate::serde::secp256k1_scalar")]
+    pub s_hat: Scalar,
+}
+
+#[derive(thiserror::Error, Debug)]
+#[error("counterparty does not know the discrete logarithm of its key share")]
+pub struct InvalidKeyShare;
+
+#[derive(thiserror::Error, Debug)]
+#[error("counterparty used inconsistent nonces")]
+pub struct InvalidNonce;
+
+#[derive(thiserror::Error, Debug)]
+#[error("encrypted signature does not match joint public key and message")]
+pub struct InvalidEncryptedSignature;
+
+#[derive(thiserror::Error, Debug)]
+#[error("signature is encrypted under a statement other than the generator")]
+pub struct EncryptedUnderStatement;
+
+#[derive(thiserror::Error, Debug)]
+#[error("recovered and given statements don't match")]
+pub struct KeyMismatch;
+
+/// Party 1 before the key generation.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Party1KeyGen {
+    session_id: SessionId,
+    x_1: KeyPair,
+    class_group: hsm_cl::ClassGroupParams,
+    HE: hsm_cl::KeyPair,
+    c_x_1: hsm_cl::Ciphertext,
+    pi_x_1: hsm_cl::Proof,
+}
+
+impl Party1KeyGen {
+    pub fn new(
+        session_id: SessionId,
+        class_group: hsm_cl::ClassGroupParams,
+        HE: hsm_cl::KeyPair,
+        rng: &mut impl Rng,
+    ) -> Self {
+        let x_1 = KeyPair::random(rng);
+        let (c_x_1, pi_x_1) = hsm_cl::encrypt(&class_group, &HE.to_pk(), &x_1);
+
+        Self {
+            session_id,
+            x_1,
+            class_group,
+            HE,
+            c_x_1,
+            pi_x_1,
+        }
+    }
+
+    pub fn next_message(&self) -> KeyGenMessage1 {
+        KeyGenMessage1 {
+            X_1: self.x_1.to_pk(),
+            c_x_1: self.c_x_1.clone(),
+            pi_x_1: self.pi_x_1.clone(),
+        }
+    }
+
+    pub fn receive(
+        self,
+        KeyGenMessage2 { X_2, pi_x_2 }: KeyGenMessage2,
+    ) -> Result<Party1, InvalidKeyShare> {
+        dleq::verify(
+            &mut transcript(&self.session_id, b"key-share-2", None),
+            &G,
+            &X_2,
+            &G,
+            &X_2,
+            &pi_x_2,
+        )
+        .map_err(|_| InvalidKeyShare)?;
+
+        let X = mul(&X_2, self.x_1.to_scalar().expose()).ok_or(InvalidKeyShare)?;
+
+        Ok(Party1 {
+            session_id: self.session_id,
+            x_1: self.x_1,
+            class_group: self.class_group,
+            HE: self.HE,
+            X,
+        })
+    }
+}
+
+/// Party 2 before the key generation.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Party2KeyGen {
+    session_id: SessionId,
+    x_2: KeyPair,
+    class_group: hsm_cl::ClassGroupParams,
+    HE: hsm_cl::PublicKey,
+    pi_x_2: dleq::Proof,
+}
+
+impl Party2KeyGen {
+    pub fn new(
+        session_id: SessionId,
+        class_group: hsm_cl::ClassGroupParams,
+        HE: hsm_cl::PublicKey,
+        rng: &mut impl Rng,
+    ) -> Self {
+        let x_2 = KeyPair::random(rng);
+        // a proof of equality of a discrete logarithm with itself is a proof of knowledge
+        let pi_x_2 = dleq::prove(
+            &mut transcript(&session_id, b"key-share-2", None),
+            rng,
+            &G,
+            &x_2.to_pk(),
+            &G,
+            &x_2.to_pk(),
+            x_2.to_scalar().expose(),
+        );
+
+        Self {
+            session_id,
+            x_2,
+            class_group,
+            HE,
+            pi_x_2,
+        }
+    }
+
+    pub fn next_message(&self) -> KeyGenMessage2 {
+        KeyGenMessage2 {
+            X_2: self.x_2.to_pk(),
+            pi_x_2: self.pi_x_2.clone(),
+        }
+    }
+
+    pub fn receive(
+        self,
+        KeyGenMessage1 { X_1, c_x_1, pi_x_1 }: KeyGenMessage1,
+    ) -> Result<Party2, InvalidKeyShare> {
+        hsm_cl::verify(&self.class_group, &self.HE, &pi_x_1, (&c_x_1, &X_1))
+            .map_err(|_| InvalidKeyShare)?;
+
+        let X = mul(&X_1, self.x_2.to_scalar().expose()).ok_or(InvalidKeyShare)?;
+
+        Ok(Party2 {
+            session_id: self.session_id,
+            x_2: self.x_2,
+            class_group: self.class_group,
+            HE: self.HE,
+            c_x_1,
+            X,
+        })
+    }
+}
+
+/// Party 1 after the key generation, i.e. the party that decrypts and finishes the signatures.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Party1 {
+    session_id: SessionId,
+    x_1: KeyPair,
+    class_group: hsm_cl::ClassGroupParams,
+    HE: hsm_cl::KeyPair,
+    #[serde(with = "crate::serde::secp256k1_public_key")]
+    X: PublicKey,
+}
+
+/// Party 2 after the key generation, i.e. the party that homomorphically computes its part of the
+/// signatures.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Party2 {
+    session_id: SessionId,
+    x_2: KeyPair,
+    class_group: hsm_cl::ClassGroupParams,
+    HE: hsm_cl::PublicKey,
+    c_x_1: hsm_cl::Ciphertext,
+    #[serde(with = "crate::serde::secp256k1_public_key")]
+    X: PublicKey,
+}
+
+/// A signing session of party 1 after it sent its nonce.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Party1Session {
+    k_1: Secret<Scalar>,
+    message: [u8; 32],
+    #[serde(with = "crate::serde::secp256k1_public_key")]
+    R_1: PublicKey,
+    #[serde(with = "crate::serde::secp256k1_public_key")]
+    R_1_Y: PublicKey,
+    pi_R_1: dleq::Proof,
+}
+
+/// A signing session of party 2 after it sent its nonce and its part of the signature.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Party2Session {
+    message: [u8; 32],
+    #[serde(with = "crate::serde::secp256k1_public_key")]
+    R: PublicKey,
+    #[serde(with = "crate::serde::secp256k1_public_key")]
+    R_hat: PublicKey,
+    sign_message: SignMessage2,
+}
+
+/// An ECDSA signature for the joint public key with the nonce `R = k * Y` that can only be
+/// completed with `y`.
+///
+/// Unlike [`crate::secp256k1::EncryptedSignature`], it does not carry a proof that `R_hat = k * G`
+/// and `R` share the discrete logarithm. Neither party knows `k`, but both derive `R` and `R_hat`
+/// from proven nonces during the signing session.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct EncryptedSignature {
+    #[serde(with = "crate::serde::secp256k1_public_key")]
+    R: PublicKey,
+    #[serde(with = "crate::serde::secp256k1_public_key")]
+    R_hat: PublicKey,
+    #[serde(with = "crate::serde::secp256k1_scalar")]
+    s_hat: Scalar,
+}
+
+impl Party1 {
+    pub fn joint_public_key(&self) -> PublicKey {
+        self.X.clone()
+    }
+
+    /// Starts a signing session for the message that is encrypted under `Y`.
+    pub fn start_session(
+        &self,
+        message: [u8; 32],
+        Y: &PublicKey,
+        rng: &mut impl Rng,
+    ) -> Party1Session {
+        let k_1 = derive_nonce(
+            &self.x_1.as_sk().serialize(),
+            &[&message[..], &Y.serialize_compressed()[..]],
+            &rng.gen(),
+        );
+
+        let R_1 = PublicKey::from_secret_key(&k_1);
+        let R_1_Y = mul(Y, &k_1.clone().into()).expect("nonce is not zero");
+        let pi_R_1 = dleq::prove(
+            &mut transcript(&self.session_id, b"nonce-1", Some(&message)),
+            rng,
+            &G,
+            &R_1,
+            Y,
+            &R_1_Y,
+            &k_1.clone().into(),
+        );
+
+        Party1Session {
+            k_1: Secret::new(k_1.into()),
+            message,
+            R_1,
+            R_1_Y,
+            pi_R_1,
+        }
+    }
+
+    /// Starts a signing session for a signature that is not encrypted.
+    pub fn start_plain_session(&self, message: [u8; 32], rng: &mut impl Rng) -> Party1Session {
+        self.start_session(message, &G, rng)
+    }
+}
+
+impl Party1Session {
+    pub fn next_message(&self) -> SignMessage1 {
+        SignMessage1 {
+            R_1: self.R_1.clone(),
+            R_1_Y: self.R_1_Y.clone(),
+            pi_R_1: self.pi_R_1.clone(),
+        }
+    }
+
+    /// Decrypts and finishes the signature of party 2.
+    pub fn receive(
+        self,
+        party: &Party1,
+        SignMessage2 { R_2, R, pi_R, c_s }: SignMessage2,
+    ) -> anyhow::Result<EncryptedSignature> {
+        dleq::verify(
+            &mut transcript(&party.session_id, b"nonce-2", Some(&self.message)),
+            &G,
+            &R_2,
+            &self.R_1_Y,
+            &R,
+            &pi_R,
+        )
+        .map_err(|_| InvalidNonce)?;
+
+        // s' = k_2^-1 * (m + r * x_1 * x_2)
+        let s_prime = hsm_cl::decrypt_scalar(&party.class_group, &party.HE, &c_s);
+        let s_hat = s_prime * self.k_1.expose().inv();
+        let R_hat = mul(&R_2, self.k_1.expose()).ok_or(InvalidNonce)?;
+
+        let encsig = EncryptedSignature { R, R_hat, s_hat };
+        verify_encrypted(&party.X, &self.message, &encsig)?;
+
+        Ok(encsig)
+    }
+}
+
+impl Party2 {
+    pub fn joint_public_key(&self) -> PublicKey {
+        self.X.clone()
+    }
+
+    /// Joins the signing session of party 1 for the message that is encrypted under `Y`.
+    pub fn join_session(
+        &self,
+        message: [u8; 32],
+        Y: &PublicKey,
+        SignMessage1 { R_1, R_1_Y, pi_R_1 }: SignMessage1,
+        rng: &mut impl Rng,
+    ) -> Result<Party2Session, InvalidNonce> {
+        dleq::verify(
+            &mut transcript(&self.session_id, b"nonce-1", Some(&message)),
+            &G,
+            &R_1,
+            Y,
+            &R_1_Y,
+            &pi_R_1,
+        )
+        .map_err(|_| InvalidNonce)?;
+
+        let k_2 = derive_nonce(
+            &self.x_2.as_sk().serialize(),
+            &[
+                &message[..],
+                &Y.serialize_compressed()[..],
+                &R_1_Y.serialize_compressed()[..],
+            ],
+            &rng.gen(),
+        );
+        let k_2_scalar: Scalar = k_2.clone().into();
+
+        let R_2 = PublicKey::from_secret_key(&k_2);
+        let R = mul(&R_1_Y, &k_2_scalar).ok_or(InvalidNonce)?;
+        let R_hat = mul(&R_1, &k_2_scalar).ok_or(InvalidNonce)?;
+        let pi_R = dleq::prove(
+            &mut transcript(&self.session_id, b"nonce-2", Some(&message)),
+            rng,
+            &G,
+            &R_2,
+            &R_1_Y,
+            &R,
+            &k_2_scalar,
+        );
+
+        // Enc(k_2^-1 * r * x_2 * x_1 + k_2^-1 * m)
+        let c_s = {
+            let k_2_inv = k_2_scalar.inv();
+            let r = x_coordinate(&R);
+            let x_2 = self.x_2.to_scalar();
+
+            hsm_cl::eval_affine(
+                &self.class_group,
+                &self.HE,
+                &self.c_x_1,
+                &(&(k_2_inv.clone() * r) * x_2.expose()),
+                &(k_2_inv * scalar(&message)),
+            )
+        };
+
+        Ok(Party2Session {
+            message,
+            R: R.clone(),
+            R_hat,
+            sign_message: SignMessage2 { R_2, R, pi_R, c_s },
+        })
+    }
+
+    /// Joins a signing session for a signature that is not encrypted.
+    pub fn join_plain_session(
+        &self,
+        message: [u8; 32],
+        sign_message: SignMessage1,
+        rng: &mut impl Rng,
+    ) -> Result<Party2Session, InvalidNonce> {
+        self.join_session(message, &G, sign_message, rng)
+    }
+}
+
+impl Party2Session {
+    pub fn next_message(&self) -> SignMessage2 {
+        self.sign_message.clone()
+    }
+
+    /// Checks the encrypted signature party 1 finished against the nonces of this session.
+    pub fn receive(
+        self,
+        party: &Party2,
+        SignMessage3 { s_hat }: SignMessage3,
+    ) -> Result<EncryptedSignature, InvalidEncryptedSignature> {
+        let encsig = EncryptedSignature {
+            R: self.R,
+            R_hat: self.R_hat,
+            s_hat,
+        };
+        verify_encrypted(&party.X, &self.message, &encsig)?;
+
+        Ok(encsig)
+    }
+}
+
+impl EncryptedSignature {
+    pub fn next_message(&self) -> SignMessage3 {
+        SignMessage3 {
+            s_hat: self.s_hat.clone(),
+        }
+    }
+
+    /// Returns the signature of a session that was encrypted under `G`.
+    pub fn into_signature(self) -> Result<Signature, EncryptedUnderStatement> {
+        if self.R != self.R_hat {
+            return Err(EncryptedUnderStatement);
+        }
+
+        Ok(Signature {
+            r: x_coordinate(&self.R),
+            s: self.s_hat,
+        })
+    }
+}
+
+pub fn decsig(
+    y: &SecretKey,
+    EncryptedSignature { R, s_hat, .. }: &EncryptedSignature,
+) -> Signature {
+    let y: Scalar = y.clone().into();
+
+    Signature {
+        r: x_coordinate(R),
+        s: s_hat.clone() * y.inv(),
+    }
+}
+
+pub fn recover(
+    Y: &PublicKey,
+    EncryptedSignature { s_hat, .. }: &EncryptedSignature,
+    Signature { s, .. }: &Signature,
+) -> Result<KeyPair, KeyMismatch> {
+    let y = s_hat.clone() * s.inv();
+
+    // the signature may have been normalized to the lower half of the scalar field
+    let candidates = [y.clone(), -y];
+
+    candidates
+        .iter()
+        .filter_map(|y| KeyPair::try_from(y.clone()).ok())
+        .find(|keypair| &keypair.to_pk() == Y)
+        .ok_or(KeyMismatch)
+}
+
+/// Checks `s_hat * R_hat == m * G + r * X`, i.e. that `s_hat` is a valid signature for the nonce
+/// `R_hat`.
+fn verify_encrypted(
+    X: &PublicKey,
+    message: &[u8; 32],
+    EncryptedSignature { R, R_hat, s_hat }: &EncryptedSignature,
+) -> Result<(), InvalidEncryptedSignature> {
+    let lhs = mul(R_hat, s_hat);
+
+    let mG = SecretKey::try_from(scalar(message))
+        .ok()
+        .map(|m| PublicKey::from_secret_key(&m));
+    let rX = mul(X, &x_coordinate(R));
+    let rhs = match (mG, rX) {
+        (Some(mG), Some(rX)) => PublicKey::combine(&[mG, rX]).ok(),
+        (mG, rX) => mG.or(rX),
+    };
+
+    if lhs.is_none() || lhs != rhs {
+        return Err(InvalidEncryptedSignature);
+    }
+
+    Ok(())
+}
+
+/// Starts the transcript of the proof with the given label, bound to the message if the proof is
+/// part of a signing session.
+fn transcript(
+    session_id: &SessionId,
+    label: &'static [u8],
+    message: Option<&[u8; 32]>,
+) -> Transcript {
+    let mut transcript = Transcript::new(session_id);
+    transcript.domain_separator(b"two-party-ecdsa");
+    transcript.append_message(b"proof", label);
+    if let Some(message) = message {
+        transcript.append_message(b"message", message);
+    }
+
+    transcript
+}
+
+/// Computes `kP`, or `None` if `k` is zero.
+fn mul(P: &PublicKey, k: &Scalar) -> Option<PublicKey> {
+    let k = SecretKey::try_from(k.clone()).ok()?;
+
+    let mut kP = P.clone();
+    kP.tweak_mul_assign(&k).ok()?;
+
+    Some(kP)
+}
+
+/// Reduces the x-coordinate of the point modulo the group order as ECDSA does for `r`.
+fn x_coordinate(R: &PublicKey) -> Scalar {
+    scalar(&R.x_coor())
+}
+
+fn scalar(bytes: &[u8; 32]) -> Scalar {
+    let mut scalar = Scalar::default();
+    let _ = scalar.set_b32(bytes);
+
+    scalar
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::secp256k1;
+
+    fn keygen() -> (Party1, Party2) {
+        let class_group = hsm_cl::ClassGroupParams::from_seed(hsm_cl::DEFAULT_CLASS_GROUP_SEED);
+        let HE = hsm_cl::keygen(&class_group);
+        let session_id = SessionId::random(&mut rand::thread_rng());
+
+        let party_1 = Party1KeyGen::new(
+            session_id,
+            class_group.clone(),
+            HE.clone(),
+            &mut rand::thread_rng(),
+        );
+        let party_2 =
+            Party2KeyGen::new(session_id, class_group, HE.to_pk(), &mut rand::thread_rng());
+
+        let message_1 = party_1.next_message();
+        let message_2 = party_2.next_message();
+
+        (
+            party_1.receive(message_2).unwrap(),
+            party_2.receive(message_1).unwrap(),
+        )
+    }
+
+    #[test]
+    fn keygen_rejects_key_share_from_other_session() {
+        let class_group = hsm_cl::ClassGroupParams::from_seed(hsm_cl::DEFAULT_CLASS_GROUP_SEED);
+        let HE = hsm_cl::keygen(&class_group);
+
+        let party_1 = Party1KeyGen::new(
+            SessionId::from([0u8; 32]),
+            class_group.clone(),
+            HE.clone(),
+            &mut rand::thread_rng(),
+        );
+        let party_2 = Party2KeyGen::new(
+            SessionId::from([1u8; 32]),
+            class_group,
+            HE.to_pk(),
+            &mut rand::thread_rng(),
+        );
+
+        assert!(party_1.receive(party_2.next_message()).is_err());
+    }
+
+    fn sign(
+        party_1: &Party1,
+        party_2: &Party2,
+        message: [u8; 32],
+        Y: &PublicKey,
+    ) -> (EncryptedSignature, EncryptedSignature) {
+        let session_1 = party_1.start_session(message, Y, &mut rand::thread_rng());
+        let session_2 = party_2
+            .join_session(
+                message,
+                Y,
+                session_1.next_message(),
+                &mut rand::thread_rng(),
+            )
+            .unwrap();
+
+        let encsig_1 = session_1
+            .receive(party_1, session_2.next_message())
+            .unwrap();
+        let encsig_2 = session_2.receive(party_2, encsig_1.next_message()).unwrap();
+
+        (encsig_1, encsig_2)
+    }
+
+    #[test]
+    fn parties_agree_on_joint_key() {
+        let (party_1, party_2) = keygen();
+
+        assert_eq!(party_1.joint_public_key(), party_2.joint_public_key());
+    }
+
+    #[test]
+    fn plain_signature_verifies_under_joint_key() {
+        let (party_1, party_2) = keygen();
+        let message = *b"mmmmmmmmmmmmmmmmmmmmmmmmmmmmmmmm";
+
+        let (encsig, _) = sign(&party_1, &party_2, message, &G);
+        let signature = encsig.into_signature().unwrap();
+
+        secp256k1::verify(message, &signature, &party_1.joint_public_key()).unwrap();
+    }
+
+    #[test]
+    fn encrypted_signature_decrypts_and_reveals_statement() {
+        let (party_1, party_2) = keygen();
+        let y = KeyPair::random_from_thread_rng();
+        let message = *b"mmmmmmmmmmmmmmmmmmmmmmmmmmmmmmmm";
+
+        let (encsig_1, encsig_2) = sign(&party_1, &party_2, message, &y.to_pk());
+        assert_eq!(encsig_1, encsig_2);
+        assert!(encsig_2.clone().into_signature().is_err());
+
+        let mut signature = decsig(y.as_sk(), &encsig_2);
+        signature.normalize_s();
+
+        secp256k1::verify(message, &signature, &party_2.joint_public_key()).unwrap();
+        assert_eq!(recover(&y.to_pk(), &encsig_1, &signature).unwrap(), y);
+    }
+
+    #[test]
+    fn reject_signature_share_for_other_message() {
+        let (party_1, party_2) = keygen();
+        let Y = KeyPair::random_from_thread_rng().to_pk();
+
+        let session_1 = party_1.start_session(
+            *b"11111111111111111111111111111111",
+            &Y,
+            &mut rand::thread_rng(),
+        );
+        let session_2 = party_2
+            .join_session(
+                *b"22222222222222222222222222222222",
+                &Y,
+                session_1.next_message(),
+                &mut rand::thread_rng(),
+            )
+            .unwrap();
+
+        assert!(session_1
+            .receive(&party_1, session_2.next_message())
+            .is_err());
+    }
+}
@@ -0,0 +1,294 @@
+//! Reacting to what happens to the joint outputs on the blockchain.
+//!
+//! Every joint output created by `bitcoin::make_transactions` is either spent by the redeem
+//! transaction of the counterparty or, once its expiry has passed, by the pre-signed refund
+//! transaction. The refund transactions spend the joint output and carry the expiry as their
+//! nLockTime, hence they are all a [`Watcher`] needs to know about the output it watches.
+
+use crate::bitcoin::{Expiry, SignedRefunds, Transaction, Txid};
+use crate::chain::Blockchain;
+use crate::keyring::Keyring;
+use crate::{puzzle_promise, puzzle_solver, sender::Sender};
+
+/// What happened to a watched joint output.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    /// The joint output has not been spent and the refund transaction is still timelocked, or the
+    /// fund transaction has not been broadcast yet.
+    Pending,
+    /// The joint output has been spent by the redeem transaction of the counterparty.
+    Redeemed(Transaction),
+    /// The joint output has been spent by the refund transaction or one of its bumped variants.
+    Refunded(Txid),
+}
+
+#[derive(thiserror::Error, Debug)]
+#[error("refund transaction must spend exactly one output but spends {0}")]
+pub struct NotOneInput(usize);
+
+/// Polls the blockchain for the fate of joint outputs.
+///
+/// A watcher does not keep any state, the actors are expected to call it periodically, e.g. on
+/// every new block.
+#[derive(Debug, Clone)]
+pub struct Watcher<B> {
+    blockchain: B,
+}
+
+impl<B> Watcher<B>
+where
+    B: Blockchain,
+{
+    pub fn new(blockchain: B) -> Self {
+        Self { blockchain }
+    }
+
+    /// Checks the joint output spent by the refund transactions and broadcasts the refund
+    /// transaction as soon as its nLockTime has expired without the output being redeemed.
+    ///
+    /// Any of the bumped refund transactions spending the output is reported as a refund.
+    pub fn watch(&self, refunds: &SignedRefunds) -> anyhow::Result<Event> {
+        let refund_transaction = refunds.refund();
+        let joint_output = match refund_transaction.input.as_slice() {
+            [input] => input.previous_output,
+            inputs => anyhow::bail!(NotOneInput(inputs.len())),
+        };
+
+        if let Some(transaction) = self.blockchain.spending_transaction(&joint_output)? {
+            let txid = transaction.txid();
+            let event = if refunds.transactions().any(|refund| refund.txid() == txid) {
+                Event::Refunded(txid)
+            } else {
+                Event::Redeemed(transaction)
+            };
+
+            return Ok(event);
+        }
+
+        if self.blockchain.confirmations(&joint_output.txid)?.is_none() {
+            return Ok(Event::Pending);
+        }
+
+        let expiry = Expiry::from_lock_time(refund_transaction.lock_time);
+        if !self.blockchain.is_expired(expiry)? {
+            return Ok(Event::Pending);
+        }
+
+        let txid = self.blockchain.broadcast(refund_transaction)?;
+
+        Ok(Event::Refunded(txid))
+    }
+
+    /// Watches the joint output the sender funded for the tumbler.
+    ///
+    /// If the tumbler redeemed the output, the sender transitions on the redeem transaction to
+    /// learn the solution of the puzzle. A sender that has not signed the refund transaction yet
+    /// or that has already learned the solution is returned unchanged.
+    pub fn watch_sender(&self, sender: Sender) -> anyhow::Result<(Sender, Event)> {
+        if let Sender::Sender0(_) | Sender::Sender5(_) = sender {
+            return Ok((sender, Event::Pending));
+        }
+
+        let event = self.watch(sender.signed_refunds()?)?;
+
+        let sender = match &event {
+            Event::Redeemed(transaction) => sender
+                .transition_on_transaction(puzzle_solver::RedeemTransaction(transaction.clone()))?,
+            _ => sender,
+        };
+
+        Ok((sender, event))
+    }
+
+    /// Watches the joint output the tumbler funded for the receiver.
+    pub fn watch_tumbler(&self, tumbler: &puzzle_promise::Tumbler) -> anyhow::Result<Event> {
+        self.watch(tumbler.signed_refunds()?)
+    }
+
+    /// Drops the puzzle keys of the keyring whose puzzle promises have all expired as of the latest
+    /// block.
+    ///
+    /// A tumbler is expected to call this on every new block alongside watching its joint outputs.
+    pub fn prune_keyring(&self, keyring: &Keyring) -> anyhow::Result<()> {
+        keyring.prune(Expiry::BlockHeight(self.blockchain.height()?));
+        keyring.prune(Expiry::Timestamp(self.blockchain.median_time_past()?));
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        bitcoin::TxOut, chain::InMemoryBlockchain, hsm_cl, pointcheval_sanders, TOKEN_MESSAGES,
+    };
+    use ::bitcoin::{
+        blockdata::{opcodes, script::Builder},
+        hashes::{sha256, Hash},
+        Amount, OutPoint, Script, TxIn,
+    };
+    use rand::thread_rng;
+
+    /// A P2WSH output that can be spent by anyone who reveals the script `OP_TRUE`.
+    fn anyone_can_spend() -> Script {
+        let witness_script = Builder::new().push_opcode(opcodes::OP_TRUE).into_script();
+
+        Builder::new()
+            .push_int(0)
+            .push_slice(&sha256::Hash::hash(witness_script.as_bytes())[..])
+            .into_script()
+    }
+
+    fn spend(joint_output: OutPoint, lock_time: u32) -> Transaction {
+        spend_with_fee(joint_output, lock_time, 1_000)
+    }
+
+    fn spend_with_fee(joint_output: OutPoint, lock_time: u32, fee: u64) -> Transaction {
+        Transaction {
+            version: 2,
+            lock_time,
+            input: vec![TxIn {
+                previous_output: joint_output,
+                script_sig: Script::new(),
+                sequence: 0xFFFF_FFFE,
+                witness: vec![Builder::new()
+                    .push_opcode(opcodes::OP_TRUE)
+                    .into_script()
+                    .into_bytes()],
+            }],
+            output: vec![TxOut {
+                value: 10_000 - fee,
+                script_pubkey: anyone_can_spend(),
+            }],
+        }
+    }
+
+    fn refunds(transactions: Vec<Transaction>) -> SignedRefunds {
+        SignedRefunds::from_transactions(
+            transactions
+                .into_iter()
+                .map(|transaction| {
+                    let fee = 10_000 - transaction.output[0].value;
+                    (Amount::from_sat(fee), transaction)
+                })
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn broadcasts_refund_after_expiry() {
+        let blockchain = InMemoryBlockchain::default();
+        let joint_output = blockchain
+            .fund(anyone_can_spend(), Amount::from_sat(10_000))
+            .unwrap();
+        let refund = spend(joint_output, blockchain.height().unwrap() + 10);
+        let refunds = refunds(vec![refund.clone()]);
+        let watcher = Watcher::new(&blockchain);
+
+        assert_eq!(watcher.watch(&refunds).unwrap(), Event::Pending);
+
+        blockchain.mine(10).unwrap();
+
+        assert_eq!(
+            watcher.watch(&refunds).unwrap(),
+            Event::Refunded(refund.txid())
+        );
+        assert_eq!(blockchain.mempool().unwrap(), vec![refund.txid()]);
+
+        // the refund is not broadcast twice
+        blockchain.mine(1).unwrap();
+        assert_eq!(
+            watcher.watch(&refunds).unwrap(),
+            Event::Refunded(refund.txid())
+        );
+    }
+
+    #[test]
+    fn reports_redeem() {
+        let blockchain = InMemoryBlockchain::default();
+        let joint_output = blockchain
+            .fund(anyone_can_spend(), Amount::from_sat(10_000))
+            .unwrap();
+        let refund = spend(joint_output, blockchain.height().unwrap() + 10);
+        let redeem = spend(joint_output, 0);
+        let watcher = Watcher::new(&blockchain);
+
+        blockchain.broadcast(&redeem).unwrap();
+        blockchain.mine(10).unwrap();
+
+        assert_eq!(
+            watcher.watch(&refunds(vec![refund])).unwrap(),
+            Event::Redeemed(redeem)
+        );
+    }
+
+    #[test]
+    fn reports_bumped_refund() {
+        let blockchain = InMemoryBlockchain::default();
+        let joint_output = blockchain
+            .fund(anyone_can_spend(), Amount::from_sat(10_000))
+            .unwrap();
+        let lock_time = blockchain.height().unwrap() + 10;
+        let refund = spend(joint_output, lock_time);
+        let bumped_refund = spend_with_fee(joint_output, lock_time, 2_000);
+        let refunds = refunds(vec![refund, bumped_refund.clone()]);
+        let watcher = Watcher::new(&blockchain);
+
+        blockchain.mine(10).unwrap();
+        blockchain.broadcast(&bumped_refund).unwrap();
+        blockchain.mine(1).unwrap();
+
+        assert_eq!(
+            watcher.watch(&refunds).unwrap(),
+            Event::Refunded(bumped_refund.txid())
+        );
+    }
+
+    #[test]
+    fn prunes_keyring_once_promises_expire() {
+        let blockchain = InMemoryBlockchain::default();
+        let class_group = hsm_cl::ClassGroupParams::from_seed(hsm_cl::DEFAULT_CLASS_GROUP_SEED);
+        let keyring = Keyring::new(
+            0,
+            hsm_cl::keygen(&class_group),
+            pointcheval_sanders::keygen(TOKEN_MESSAGES, &mut thread_rng()),
+        )
+        .unwrap();
+        let expiry = Expiry::BlockHeight(blockchain.height().unwrap() + 10);
+        keyring.issue_puzzle(0, expiry).unwrap();
+        keyring
+            .rotate(
+                1,
+                hsm_cl::keygen(&class_group),
+                pointcheval_sanders::keygen(TOKEN_MESSAGES, &mut thread_rng()),
+            )
+            .unwrap();
+        let watcher = Watcher::new(&blockchain);
+
+        blockchain.mine(10).unwrap();
+        watcher.prune_keyring(&keyring).unwrap();
+
+        keyring
+            .puzzle_keypair(0)
+            .expect("promise has not expired yet");
+
+        blockchain.mine(1).unwrap();
+        watcher.prune_keyring(&keyring).unwrap();
+
+        assert!(keyring.puzzle_keypair(0).is_err());
+    }
+
+    #[test]
+    fn waits_for_fund_transaction() {
+        let blockchain = InMemoryBlockchain::default();
+        let joint_output = OutPoint::new(spend(OutPoint::default(), 0).txid(), 0);
+        let refund = spend(joint_output, 0);
+
+        let event = Watcher::new(&blockchain)
+            .watch(&refunds(vec![refund]))
+            .unwrap();
+
+        assert_eq!(event, Event::Pending);
+    }
+}
@@ -1,29 +1,50 @@
 pub mod harness;
 
-use crate::harness::{
-    random_p2wpkh, run_happy_path, run_refund, MakeTransaction, NextMessage, Transition,
-};
+use crate::harness::random_p2wpkh;
 use a2l::{
-    hsm_cl, pointcheval_sanders, puzzle_promise, puzzle_solver,
+    chain::{Blockchain as _, InMemoryBlockchain},
+    hsm_cl,
+    keyring::Keyring,
+    pointcheval_sanders,
+    protocol::{
+        run_happy_path, run_refund, InMemoryTransport, MakeTransaction, NextMessage, Transition,
+        Transport, WaitForLocktime,
+    },
+    puzzle_promise, puzzle_solver,
     receiver::{self, Receiver},
     sender::{self, Sender},
+    state_store::{FileStateStore, StateStore},
+    token_store::{InMemoryTokenStore, TokenAlreadySpent},
+    transcript::SessionId,
+    watcher::{Event, Watcher},
+};
+use anyhow::{bail, Context};
+use bitcoin::{
+    secp256k1::{Message, Secp256k1},
+    util::{bip143::SighashComponents, psbt::PartiallySignedTransaction},
 };
-use anyhow::bail;
 use indicatif::ProgressIterator;
 use itertools::Itertools;
 use rand::{thread_rng, Rng};
-use serde::Serialize;
+use serde::{de::DeserializeOwned, Serialize};
 use std::{
     collections::HashMap,
     fmt,
+    path::PathBuf,
+    sync::Arc,
     time::{Duration, Instant},
 };
 
+const PROMISE_EXPIRY: a2l::Expiry = a2l::Expiry::BlockHeight(100);
+const TIMELOCK_SAFETY_MARGIN: u32 = 6;
+const FUND_TRANSACTION_FEE: bitcoin::Amount = bitcoin::Amount::from_sat(1_000);
+const EPOCH: a2l::Epoch = 0;
+
 #[test]
 fn dry_happy_path() {
     let (blockchain, tumbler_promise, tumbler_solver, sender, receiver) = make_actors::<NullStrategy>(
         bitcoin::Amount::from_sat(10_000_000),
-        bitcoin::Amount::from_sat(10),
+        a2l::FeeRate::PerWeightUnit(bitcoin::Amount::from_sat(10)),
         bitcoin::Amount::from_sat(10_000),
     );
 
@@ -32,6 +53,7 @@ fn dry_happy_path() {
         tumbler_solver,
         sender,
         receiver,
+        &mut InMemoryTransport,
         blockchain,
         &mut thread_rng(),
     );
@@ -43,7 +65,7 @@ fn dry_happy_path() {
 fn dry_refund() {
     let (blockchain, tumbler_promise, tumbler_solver, sender, receiver) = make_actors::<NullStrategy>(
         bitcoin::Amount::from_sat(10_000_000),
-        bitcoin::Amount::from_sat(10),
+        a2l::FeeRate::PerWeightUnit(bitcoin::Amount::from_sat(10)),
         bitcoin::Amount::from_sat(10_000),
     );
 
@@ -52,6 +74,246 @@ fn dry_refund() {
         tumbler_solver,
         sender,
         receiver,
+        &mut InMemoryTransport,
+        blockchain,
+        &mut thread_rng(),
+    );
+
+    res.unwrap();
+}
+
+#[test]
+fn dry_happy_path_taproot() {
+    let (blockchain, tumbler_promise, tumbler_solver, sender, receiver) =
+        make_taproot_actors::<NullStrategy>(
+            bitcoin::Amount::from_sat(10_000_000),
+            a2l::FeeRate::PerWeightUnit(bitcoin::Amount::from_sat(10)),
+            bitcoin::Amount::from_sat(10_000),
+        );
+
+    let res = run_happy_path(
+        tumbler_promise,
+        tumbler_solver,
+        sender,
+        receiver,
+        &mut InMemoryTransport,
+        blockchain,
+        &mut thread_rng(),
+    );
+
+    res.unwrap();
+}
+
+#[test]
+fn dry_refund_taproot() {
+    let (blockchain, tumbler_promise, tumbler_solver, sender, receiver) =
+        make_taproot_actors::<NullStrategy>(
+            bitcoin::Amount::from_sat(10_000_000),
+            a2l::FeeRate::PerWeightUnit(bitcoin::Amount::from_sat(10)),
+            bitcoin::Amount::from_sat(10_000),
+        );
+
+    let res = run_refund(
+        tumbler_promise,
+        tumbler_solver,
+        sender,
+        receiver,
+        &mut InMemoryTransport,
+        blockchain,
+        &mut thread_rng(),
+    );
+
+    res.unwrap();
+}
+
+#[test]
+fn refund_transactions_enforce_locktime() -> anyhow::Result<()> {
+    let (blockchain, tumbler_promise, tumbler_solver, sender, receiver) = make_actors::<NullStrategy>(
+        bitcoin::Amount::from_sat(10_000_000),
+        a2l::FeeRate::PerWeightUnit(bitcoin::Amount::from_sat(10)),
+        bitcoin::Amount::from_sat(10_000),
+    );
+
+    let (_, _, _, _, blockchain) = run_refund(
+        tumbler_promise,
+        tumbler_solver,
+        sender,
+        receiver,
+        &mut InMemoryTransport,
+        blockchain,
+        &mut thread_rng(),
+    )?;
+
+    let (tumbler_refund, sender_refund) = match blockchain.transactions.as_slice() {
+        [_, _, tumbler_refund, sender_refund] => (tumbler_refund, sender_refund),
+        _ => bail!("wrong transactions in blockchain"),
+    };
+
+    assert_eq!(
+        tumbler_refund.lock_time,
+        timelocks().promise_expiry.lock_time()
+    );
+    assert_eq!(
+        sender_refund.lock_time,
+        timelocks().solver_expiry.lock_time()
+    );
+
+    for refund in &[tumbler_refund, sender_refund] {
+        assert!(
+            refund
+                .input
+                .iter()
+                .all(|input| input.sequence < 0xFFFF_FFFF),
+            "a final input disables the locktime"
+        );
+    }
+
+    Ok(())
+}
+
+#[test]
+fn resume_from_persisted_state() {
+    let keys = TumblerKeys::random();
+    let (blockchain, mut tumbler_promise, mut tumbler_solver, sender, receiver) =
+        make_actors_with_keys::<PersistingStrategy>(
+            keys.clone(),
+            a2l::JointOutputKind::P2wsh,
+            bitcoin::Amount::from_sat(10_000_000),
+            a2l::FeeRate::PerWeightUnit(bitcoin::Amount::from_sat(10)),
+            bitcoin::Amount::from_sat(10_000),
+        );
+    tumbler_promise.strategy.keyring = Some(keys.keyring.clone());
+    tumbler_solver.strategy.keyring = Some(keys.keyring);
+
+    let res = run_happy_path(
+        tumbler_promise,
+        tumbler_solver,
+        sender,
+        receiver,
+        &mut InMemoryTransport,
+        blockchain,
+        &mut thread_rng(),
+    );
+
+    res.unwrap();
+}
+
+#[test]
+fn reject_unsafe_timelocks() {
+    let keys = TumblerKeys::random();
+    let mut params = make_dummy_puzzle_solver_params(
+        &mut Blockchain::default(),
+        a2l::JointOutputKind::P2wsh,
+        bitcoin::Amount::from_sat(10_000_000),
+        a2l::FeeRate::PerWeightUnit(bitcoin::Amount::from_sat(10)),
+        bitcoin::Amount::from_sat(10_000),
+    );
+    // the sender could refund before the tumbler had a chance to redeem
+    params.timelocks = a2l::TimelockPolicy {
+        promise_expiry: timelocks().solver_expiry,
+        solver_expiry: timelocks().promise_expiry,
+        safety_margin: TIMELOCK_SAFETY_MARGIN,
+    };
+
+    let error = sender::Sender::new(
+        params.clone(),
+        keys.class_group.clone(),
+        keys.ps_publickey.clone(),
+        &mut thread_rng(),
+    )
+    .unwrap_err();
+    assert!(error.is::<a2l::UnsafeTimelocks>());
+
+    let error =
+        puzzle_solver::Tumbler::new(params, keys.class_group, keys.keyring, &mut thread_rng())
+            .unwrap_err();
+    assert!(error.is::<a2l::UnsafeTimelocks>());
+}
+
+#[test]
+fn reject_underfunded_partial_fund_transaction() {
+    let keys = TumblerKeys::random();
+    let mut params = make_dummy_puzzle_solver_params(
+        &mut Blockchain::default(),
+        a2l::JointOutputKind::P2wsh,
+        bitcoin::Amount::from_sat(10_000_000),
+        a2l::FeeRate::PerWeightUnit(bitcoin::Amount::from_sat(10)),
+        bitcoin::Amount::from_sat(10_000),
+    );
+    params.partial_fund_psbt.inputs[0]
+        .witness_utxo
+        .as_mut()
+        .unwrap()
+        .value = 10_000_000;
+
+    let error = sender::Sender::new(
+        params,
+        keys.class_group,
+        keys.ps_publickey,
+        &mut thread_rng(),
+    )
+    .unwrap_err();
+    assert!(matches!(
+        error.downcast_ref(),
+        Some(a2l::InvalidPartialFundTransaction::InsufficientValue { .. })
+    ));
+}
+
+#[test]
+fn reject_reused_token() {
+    let (blockchain, tumbler_promise, tumbler_solver, sender, receiver) = make_actors::<NullStrategy>(
+        bitcoin::Amount::from_sat(10_000_000),
+        a2l::FeeRate::PerWeightUnit(bitcoin::Amount::from_sat(10)),
+        bitcoin::Amount::from_sat(10_000),
+    );
+
+    run_happy_path(
+        tumbler_promise.clone(),
+        tumbler_solver.clone(),
+        sender.clone(),
+        receiver.clone(),
+        &mut InMemoryTransport,
+        blockchain.fork(),
+        &mut thread_rng(),
+    )
+    .unwrap();
+
+    // the sender holds the same token again, the tumbler shares its token store with the first run
+    let error = run_happy_path(
+        tumbler_promise,
+        tumbler_solver,
+        sender,
+        receiver,
+        &mut InMemoryTransport,
+        blockchain,
+        &mut thread_rng(),
+    )
+    .map(|_| ())
+    .unwrap_err();
+
+    assert!(error.chain().any(|cause| cause.is::<TokenAlreadySpent>()));
+}
+
+#[test]
+fn watcher_feeds_redeem_to_sender() {
+    let (blockchain, tumbler_promise, tumbler_solver, sender, receiver) = make_actors::<NullStrategy>(
+        bitcoin::Amount::from_sat(10_000_000),
+        a2l::FeeRate::PerWeightUnit(bitcoin::Amount::from_sat(10)),
+        bitcoin::Amount::from_sat(10_000),
+    );
+    let sender = Actor {
+        inner: sender.inner,
+        strategy: WatchingStrategy {
+            chain: blockchain.chain.clone(),
+        },
+    };
+
+    let res = run_happy_path(
+        tumbler_promise,
+        tumbler_solver,
+        sender,
+        receiver,
+        &mut InMemoryTransport,
         blockchain,
         &mut thread_rng(),
     );
@@ -59,33 +321,53 @@ fn dry_refund() {
     res.unwrap();
 }
 
+#[test]
+fn watcher_leaves_sender_without_refund_unchanged() {
+    let (blockchain, _, _, sender, _) = make_actors::<NullStrategy>(
+        bitcoin::Amount::from_sat(10_000_000),
+        a2l::FeeRate::PerWeightUnit(bitcoin::Amount::from_sat(10)),
+        bitcoin::Amount::from_sat(10_000),
+    );
+
+    let (sender, event) = Watcher::new(blockchain.chain.as_ref())
+        .watch_sender(sender.inner)
+        .unwrap();
+
+    assert_eq!(event, Event::Pending);
+    assert!(matches!(sender, Sender::Sender0(_)));
+}
+
 #[test]
 fn happy_path_fees() -> anyhow::Result<()> {
     let tumble_amount = bitcoin::Amount::from_sat(10_000_000);
-    let spend_transaction_fee_per_wu = bitcoin::Amount::from_sat(10);
+    let spend_transaction_feerate = a2l::FeeRate::PerWeightUnit(bitcoin::Amount::from_sat(10));
     let tumbler_fee = bitcoin::Amount::from_sat(10_000);
     let (blockchain, tumbler_promise, tumbler_solver, sender, receiver) =
-        make_actors::<NullStrategy>(tumble_amount, spend_transaction_fee_per_wu, tumbler_fee);
+        make_actors::<NullStrategy>(tumble_amount, spend_transaction_feerate, tumbler_fee);
 
     let (_, _, _, _, blockchain) = run_happy_path(
         tumbler_promise,
         tumbler_solver,
         sender,
         receiver,
+        &mut InMemoryTransport,
         blockchain,
         &mut thread_rng(),
     )?;
 
-    let (sender_fund, tumbler_fund, tumbler_redeem, receiver_redeem) = match blockchain.0.as_slice()
-    {
-        [sender_fund, tumbler_fund, tumbler_redeem, receiver_redeem] => {
-            (sender_fund, tumbler_fund, tumbler_redeem, receiver_redeem)
-        }
-        _ => bail!("wrong transactions in blockchain"),
-    };
+    let (sender_fund, tumbler_fund, tumbler_redeem, receiver_redeem) =
+        match blockchain.transactions.as_slice() {
+            [sender_fund, tumbler_fund, tumbler_redeem, receiver_redeem] => {
+                (sender_fund, tumbler_fund, tumbler_redeem, receiver_redeem)
+            }
+            _ => bail!("wrong transactions in blockchain"),
+        };
     assert_eq!(
         bitcoin::Amount::from_sat(sender_fund.output[0].value),
-        tumble_amount + tumbler_fee + a2l::spend_tx_miner_fee(spend_transaction_fee_per_wu)
+        tumble_amount
+            + tumbler_fee
+            + spend_tx_miner_fee(a2l::JointOutputKind::P2wsh, spend_transaction_feerate)
+            + a2l::ANCHOR_OUTPUT_VALUE
     );
     assert_eq!(
         bitcoin::Amount::from_sat(tumbler_redeem.output[0].value),
@@ -93,7 +375,9 @@ fn happy_path_fees() -> anyhow::Result<()> {
     );
     assert_eq!(
         bitcoin::Amount::from_sat(tumbler_fund.output[0].value),
-        tumble_amount + a2l::spend_tx_miner_fee(spend_transaction_fee_per_wu)
+        tumble_amount
+            + spend_tx_miner_fee(a2l::JointOutputKind::P2wsh, spend_transaction_feerate)
+            + a2l::ANCHOR_OUTPUT_VALUE
     );
     assert_eq!(
         bitcoin::Amount::from_sat(receiver_redeem.output[0].value),
@@ -107,7 +391,7 @@ fn happy_path_fees() -> anyhow::Result<()> {
 fn redeem_transaction_size() -> anyhow::Result<()> {
     let (blockchain, tumbler_promise, tumbler_solver, sender, receiver) = make_actors::<NullStrategy>(
         bitcoin::Amount::from_sat(10_000_000),
-        bitcoin::Amount::from_sat(10),
+        a2l::FeeRate::PerWeightUnit(bitcoin::Amount::from_sat(10)),
         bitcoin::Amount::from_sat(10_000),
     );
 
@@ -116,11 +400,12 @@ fn redeem_transaction_size() -> anyhow::Result<()> {
         tumbler_solver,
         sender,
         receiver,
+        &mut InMemoryTransport,
         blockchain,
         &mut thread_rng(),
     )?;
 
-    let (tumbler_redeem, receiver_redeem) = match blockchain.0.as_slice() {
+    let (tumbler_redeem, receiver_redeem) = match blockchain.transactions.as_slice() {
         [_, _, tumbler_redeem, receiver_redeem] => (tumbler_redeem, receiver_redeem),
         _ => bail!("wrong transactions in blockchain"),
     };
@@ -138,12 +423,114 @@ fn redeem_transaction_size() -> anyhow::Result<()> {
     Ok(())
 }
 
+#[test]
+fn taproot_redeem_transactions_are_lighter() -> anyhow::Result<()> {
+    let tumble_amount = bitcoin::Amount::from_sat(10_000_000);
+    let spend_transaction_feerate = a2l::FeeRate::PerWeightUnit(bitcoin::Amount::from_sat(10));
+    let tumbler_fee = bitcoin::Amount::from_sat(10_000);
+
+    let (blockchain, tumbler_promise, tumbler_solver, sender, receiver) =
+        make_actors::<NullStrategy>(tumble_amount, spend_transaction_feerate, tumbler_fee);
+    let (_, _, _, _, p2wsh) = run_happy_path(
+        tumbler_promise,
+        tumbler_solver,
+        sender,
+        receiver,
+        &mut InMemoryTransport,
+        blockchain,
+        &mut thread_rng(),
+    )?;
+
+    let (blockchain, tumbler_promise, tumbler_solver, sender, receiver) =
+        make_taproot_actors::<NullStrategy>(tumble_amount, spend_transaction_feerate, tumbler_fee);
+    let (_, _, _, _, p2tr) = run_happy_path(
+        tumbler_promise,
+        tumbler_solver,
+        sender,
+        receiver,
+        &mut InMemoryTransport,
+        blockchain,
+        &mut thread_rng(),
+    )?;
+
+    let redeem_tx_weights = |blockchain: &Blockchain| match blockchain.transactions.as_slice() {
+        [_, _, tumbler_redeem, receiver_redeem] => {
+            Ok(tumbler_redeem.get_weight() + receiver_redeem.get_weight())
+        }
+        _ => bail!("wrong transactions in blockchain"),
+    };
+
+    // a single BIP340 signature replaces two ECDSA signatures and the witness script
+    assert!(redeem_tx_weights(&p2tr)? < redeem_tx_weights(&p2wsh)?);
+
+    // the joint outputs hold less as the spend transactions pay less fee
+    let (sender_fund, tumbler_fund) = match p2tr.transactions.as_slice() {
+        [sender_fund, tumbler_fund, _, _] => (sender_fund, tumbler_fund),
+        _ => bail!("wrong transactions in blockchain"),
+    };
+    let spend_tx_miner_fee =
+        spend_tx_miner_fee(a2l::JointOutputKind::P2tr, spend_transaction_feerate);
+    assert_eq!(
+        bitcoin::Amount::from_sat(sender_fund.output[0].value),
+        tumble_amount + tumbler_fee + spend_tx_miner_fee + a2l::ANCHOR_OUTPUT_VALUE
+    );
+    assert_eq!(
+        bitcoin::Amount::from_sat(tumbler_fund.output[0].value),
+        tumble_amount + spend_tx_miner_fee + a2l::ANCHOR_OUTPUT_VALUE
+    );
+
+    Ok(())
+}
+
+#[test]
+fn protocol_messages_roundtrip() -> anyhow::Result<()> {
+    let (blockchain, tumbler_promise, tumbler_solver, sender, receiver) = make_actors::<NullStrategy>(
+        bitcoin::Amount::from_sat(10_000_000),
+        a2l::FeeRate::PerWeightUnit(bitcoin::Amount::from_sat(10)),
+        bitcoin::Amount::from_sat(10_000),
+    );
+
+    run_happy_path(
+        tumbler_promise,
+        tumbler_solver,
+        sender,
+        receiver,
+        &mut SerdeRoundtripTransport,
+        blockchain,
+        &mut thread_rng(),
+    )?;
+
+    Ok(())
+}
+
+#[test]
+fn protocol_messages_roundtrip_taproot() -> anyhow::Result<()> {
+    let (blockchain, tumbler_promise, tumbler_solver, sender, receiver) =
+        make_taproot_actors::<NullStrategy>(
+            bitcoin::Amount::from_sat(10_000_000),
+            a2l::FeeRate::PerWeightUnit(bitcoin::Amount::from_sat(10)),
+            bitcoin::Amount::from_sat(10_000),
+        );
+
+    run_happy_path(
+        tumbler_promise,
+        tumbler_solver,
+        sender,
+        receiver,
+        &mut SerdeRoundtripTransport,
+        blockchain,
+        &mut thread_rng(),
+    )?;
+
+    Ok(())
+}
+
 #[test]
 fn protocol_bandwidth() -> anyhow::Result<()> {
     let (blockchain, tumbler_promise, tumbler_solver, sender, receiver) =
         make_actors::<BandwidthRecordingStrategy>(
             bitcoin::Amount::from_sat(10_000_000),
-            bitcoin::Amount::from_sat(10),
+            a2l::FeeRate::PerWeightUnit(bitcoin::Amount::from_sat(10)),
             bitcoin::Amount::from_sat(10_000),
         );
 
@@ -152,6 +539,7 @@ fn protocol_bandwidth() -> anyhow::Result<()> {
         tumbler_solver,
         sender,
         receiver,
+        &mut InMemoryTransport,
         blockchain,
         &mut thread_rng(),
     )?;
@@ -195,20 +583,26 @@ fn protocol_computation_time() -> anyhow::Result<()> {
     let mut per_message_computation_times = HashMap::<String, Vec<Duration>>::new();
     let mut full_protocol_computation_times = Vec::<Duration>::new();
 
-    let (blockchain, tumbler_promise, tumbler_solver, sender, receiver) =
-        make_actors::<TimeRecordingStrategy>(
-            bitcoin::Amount::from_sat(10_000_000),
-            bitcoin::Amount::from_sat(10),
-            bitcoin::Amount::from_sat(10_000),
-        );
+    let keys = TumblerKeys::random();
 
     for _ in (0..50).progress() {
+        // every run needs a fresh token, otherwise the tumbler rejects it as spent
+        let (blockchain, tumbler_promise, tumbler_solver, sender, receiver) =
+            make_actors_with_keys::<TimeRecordingStrategy>(
+                keys.clone(),
+                a2l::JointOutputKind::P2wsh,
+                bitcoin::Amount::from_sat(10_000_000),
+                a2l::FeeRate::PerWeightUnit(bitcoin::Amount::from_sat(10)),
+                bitcoin::Amount::from_sat(10_000),
+            );
+
         let (tumbler_promise, tumbler_solver, sender, receiver, _) = run_happy_path(
-            tumbler_promise.clone(),
-            tumbler_solver.clone(),
-            sender.clone(),
-            receiver.clone(),
-            blockchain.clone(),
+            tumbler_promise,
+            tumbler_solver,
+            sender,
+            receiver,
+            &mut InMemoryTransport,
+            blockchain,
             &mut thread_rng(),
         )?;
 
@@ -299,7 +693,7 @@ fn message_per_actor() -> HashMap<String, ActorKind> {
     let (blockchain, tumbler_promise, tumbler_solver, sender, receiver) =
         make_actors::<AssociateTransitionWithActorStrategy>(
             bitcoin::Amount::from_sat(10_000_000),
-            bitcoin::Amount::from_sat(10),
+            a2l::FeeRate::PerWeightUnit(bitcoin::Amount::from_sat(10)),
             bitcoin::Amount::from_sat(10_000),
         );
 
@@ -308,6 +702,7 @@ fn message_per_actor() -> HashMap<String, ActorKind> {
         tumbler_solver,
         sender,
         receiver,
+        &mut InMemoryTransport,
         blockchain,
         &mut thread_rng(),
     )
@@ -321,27 +716,193 @@ fn message_per_actor() -> HashMap<String, ActorKind> {
     message_per_actor
 }
 
-#[derive(Default, Debug, Clone)]
-struct Blockchain(Vec<bitcoin::Transaction>);
+/// The in-memory chain the dry tests run against.
+///
+/// Every transaction published by the protocol is validated like a full node would and mined right
+/// away. The partial fund transactions spend outputs of a wallet that signs them once the joint
+/// output has been added.
+#[derive(Default)]
+struct Blockchain {
+    chain: Arc<InMemoryBlockchain>,
+    wallet: Wallet,
+    /// The transactions published by the protocol in the order they were mined.
+    transactions: Vec<bitcoin::Transaction>,
+}
+
+impl Blockchain {
+    /// Returns a copy of the blockchain that evolves independently from this one.
+    fn fork(&self) -> Self {
+        Self {
+            chain: Arc::new(InMemoryBlockchain::clone(&self.chain)),
+            wallet: self.wallet.clone(),
+            transactions: self.transactions.clone(),
+        }
+    }
+
+    /// Creates a partial fund PSBT whose input is worth the fund amount plus the fee of the fund
+    /// transaction.
+    fn partial_fund_psbt(&mut self, fund_amount: bitcoin::Amount) -> PartiallySignedTransaction {
+        let amount = fund_amount + FUND_TRANSACTION_FEE;
+        let script_pubkey = self.wallet.address().script_pubkey();
+        let outpoint = self.chain.fund(script_pubkey.clone(), amount).unwrap();
+        self.wallet.coins.insert(outpoint, amount);
+
+        let mut psbt = PartiallySignedTransaction::from_unsigned_tx(bitcoin::Transaction {
+            lock_time: 0,
+            version: 2,
+            input: vec![bitcoin::TxIn {
+                previous_output: outpoint,
+                script_sig: bitcoin::Script::new(),
+                sequence: 0xFFFF_FFFF,
+                witness: Vec::new(),
+            }],
+            output: vec![],
+        })
+        .unwrap();
+        psbt.inputs[0].witness_utxo = Some(bitcoin::TxOut {
+            value: amount.as_sat(),
+            script_pubkey,
+        });
+
+        psbt
+    }
+}
 
 impl<T> Transition<T> for Blockchain
 where
     T: Into<bitcoin::Transaction>,
 {
-    fn transition(self, transaction: T, _: &mut impl rand::Rng) -> anyhow::Result<Self>
+    fn transition(mut self, transaction: T, _: &mut impl rand::Rng) -> anyhow::Result<Self>
     where
         Self: Sized,
     {
-        let mut vec = self.0;
-        vec.push(transaction.into());
-        Ok(Blockchain(vec))
+        let transaction = self.wallet.sign(transaction.into())?;
+
+        self.chain.broadcast(&transaction)?;
+        self.chain.mine(1)?;
+        self.transactions.push(transaction);
+
+        Ok(self)
+    }
+}
+
+impl WaitForLocktime for Blockchain {
+    fn wait_for_locktime(self, transaction: &bitcoin::Transaction) -> anyhow::Result<Self> {
+        let expiry = a2l::Expiry::from_lock_time(transaction.lock_time);
+
+        while !self.chain.is_expired(expiry)? {
+            self.chain.mine(1)?;
+        }
+
+        Ok(self)
+    }
+}
+
+/// Holds the P2WPKH outputs the partial fund transactions spend.
+#[derive(Clone)]
+struct Wallet {
+    private_key: bitcoin::PrivateKey,
+    coins: HashMap<bitcoin::OutPoint, bitcoin::Amount>,
+}
+
+impl Default for Wallet {
+    fn default() -> Self {
+        Self {
+            private_key: bitcoin::PrivateKey {
+                compressed: true,
+                network: bitcoin::Network::Regtest,
+                key: bitcoin::secp256k1::SecretKey::new(&mut bitcoin::secp256k1::rand::thread_rng()),
+            },
+            coins: HashMap::new(),
+        }
+    }
+}
+
+impl Wallet {
+    fn public_key(&self) -> bitcoin::PublicKey {
+        bitcoin::PublicKey::from_private_key(&Secp256k1::signing_only(), &self.private_key)
+    }
+
+    fn address(&self) -> bitcoin::Address {
+        bitcoin::Address::p2wpkh(&self.public_key(), bitcoin::Network::Regtest)
+    }
+
+    /// Signs every input of the transaction that spends one of the wallet's outputs.
+    fn sign(&self, mut transaction: bitcoin::Transaction) -> anyhow::Result<bitcoin::Transaction> {
+        let secp = Secp256k1::signing_only();
+        let public_key = self.public_key();
+        let script_code =
+            bitcoin::Address::p2pkh(&public_key, bitcoin::Network::Regtest).script_pubkey();
+        let sighash_components = SighashComponents::new(&transaction);
+
+        for input in transaction.input.iter_mut() {
+            let value = match self.coins.get(&input.previous_output) {
+                Some(value) => value,
+                None => continue,
+            };
+
+            let digest = sighash_components.sighash_all(input, &script_code, value.as_sat());
+            let signature = secp.sign(&Message::from_slice(&digest[..])?, &self.private_key.key);
+
+            let mut signature = signature.serialize_der().to_vec();
+            signature.push(bitcoin::SigHashType::All.as_u32() as u8);
+
+            input.witness = vec![signature, public_key.to_bytes()];
+        }
+
+        Ok(transaction)
+    }
+}
+
+#[derive(Clone)]
+struct TumblerKeys {
+    class_group: hsm_cl::ClassGroupParams,
+    keyring: Arc<Keyring>,
+    he_publickey: hsm_cl::PublicKey,
+    ps_publickey: pointcheval_sanders::PublicKey,
+}
+
+impl TumblerKeys {
+    fn random() -> Self {
+        let class_group = hsm_cl::ClassGroupParams::from_seed(hsm_cl::DEFAULT_CLASS_GROUP_SEED);
+        let he_keypair = hsm_cl::keygen(&class_group);
+        let ps_keypair = pointcheval_sanders::keygen(a2l::TOKEN_MESSAGES, &mut thread_rng());
+
+        Self {
+            class_group,
+            he_publickey: he_keypair.to_pk(),
+            ps_publickey: ps_keypair.public_key.clone(),
+            keyring: Arc::new(Keyring::new(EPOCH, he_keypair, ps_keypair).unwrap()),
+        }
     }
 }
 
 #[allow(clippy::type_complexity)]
 fn make_actors<S: Default>(
     tumble_amount: bitcoin::Amount,
-    spend_transaction_fee_per_wu: bitcoin::Amount,
+    spend_transaction_feerate: a2l::FeeRate,
+    tumbler_fee: bitcoin::Amount,
+) -> (
+    Blockchain,
+    Actor<puzzle_promise::Tumbler, S>,
+    Actor<puzzle_solver::Tumbler, S>,
+    Actor<Sender, S>,
+    Actor<Receiver, S>,
+) {
+    make_actors_with_keys(
+        TumblerKeys::random(),
+        a2l::JointOutputKind::P2wsh,
+        tumble_amount,
+        spend_transaction_feerate,
+        tumbler_fee,
+    )
+}
+
+/// Makes actors that lock their funds in P2TR joint outputs.
+#[allow(clippy::type_complexity)]
+fn make_taproot_actors<S: Default>(
+    tumble_amount: bitcoin::Amount,
+    spend_transaction_feerate: a2l::FeeRate,
     tumbler_fee: bitcoin::Amount,
 ) -> (
     Blockchain,
@@ -350,26 +911,56 @@ fn make_actors<S: Default>(
     Actor<Sender, S>,
     Actor<Receiver, S>,
 ) {
-    let he_keypair = hsm_cl::keygen();
-    let ps_keypair = pointcheval_sanders::keygen(&mut thread_rng());
+    make_actors_with_keys(
+        TumblerKeys::random(),
+        a2l::JointOutputKind::P2tr,
+        tumble_amount,
+        spend_transaction_feerate,
+        tumbler_fee,
+    )
+}
 
-    let blockchain = Blockchain::default();
+#[allow(clippy::type_complexity)]
+fn make_actors_with_keys<S: Default>(
+    TumblerKeys {
+        class_group,
+        keyring,
+        he_publickey,
+        ps_publickey,
+    }: TumblerKeys,
+    joint_output: a2l::JointOutputKind,
+    tumble_amount: bitcoin::Amount,
+    spend_transaction_feerate: a2l::FeeRate,
+    tumbler_fee: bitcoin::Amount,
+) -> (
+    Blockchain,
+    Actor<puzzle_promise::Tumbler, S>,
+    Actor<puzzle_solver::Tumbler, S>,
+    Actor<Sender, S>,
+    Actor<Receiver, S>,
+) {
+    let mut blockchain = Blockchain::default();
 
     let (tumbler_promise, receiver) = make_puzzle_promise_actors(
+        &mut blockchain,
+        joint_output,
         tumble_amount,
-        spend_transaction_fee_per_wu,
-        he_keypair.clone(),
-        he_keypair.to_pk(),
-        ps_keypair.clone(),
+        spend_transaction_feerate,
+        class_group.clone(),
+        keyring.clone(),
+        he_publickey,
+        ps_publickey.clone(),
     );
 
     let (tumbler_solver, sender) = make_puzzle_solver_actors(
+        &mut blockchain,
+        joint_output,
         tumble_amount,
-        spend_transaction_fee_per_wu,
+        spend_transaction_feerate,
         tumbler_fee,
-        he_keypair,
-        ps_keypair.clone(),
-        ps_keypair.public_key,
+        class_group,
+        keyring,
+        ps_publickey,
     );
 
     (
@@ -382,76 +973,136 @@ fn make_actors<S: Default>(
 }
 
 fn make_puzzle_promise_actors(
+    blockchain: &mut Blockchain,
+    joint_output: a2l::JointOutputKind,
     tumble_amount: bitcoin::Amount,
-    spend_transaction_fee_per_wu: bitcoin::Amount,
-    he_keypair: hsm_cl::KeyPair,
+    spend_transaction_feerate: a2l::FeeRate,
+    class_group: hsm_cl::ClassGroupParams,
+    keyring: Arc<Keyring>,
     he_publickey: hsm_cl::PublicKey,
-    ps_keypair: pointcheval_sanders::KeyPair,
+    ps_publickey: pointcheval_sanders::PublicKey,
 ) -> (puzzle_promise::Tumbler, Receiver) {
-    let params = make_dummy_puzzle_promise_params(tumble_amount, spend_transaction_fee_per_wu);
+    let params = make_dummy_puzzle_promise_params(
+        blockchain,
+        joint_output,
+        tumble_amount,
+        spend_transaction_feerate,
+    );
 
-    let tumbler =
-        puzzle_promise::Tumbler::new(params.clone(), he_keypair, ps_keypair, &mut thread_rng());
-    let receiver = receiver::Receiver::new(params, &mut thread_rng(), he_publickey);
+    let tumbler = puzzle_promise::Tumbler::new(
+        params.clone(),
+        class_group.clone(),
+        keyring,
+        Arc::new(InMemoryTokenStore::default()),
+        &mut thread_rng(),
+    )
+    .unwrap();
+    let receiver = receiver::Receiver::new(
+        params,
+        &mut thread_rng(),
+        class_group,
+        he_publickey,
+        ps_publickey,
+    )
+    .unwrap();
 
     (tumbler, receiver)
 }
 
 fn make_puzzle_solver_actors(
+    blockchain: &mut Blockchain,
+    joint_output: a2l::JointOutputKind,
     tumble_amount: bitcoin::Amount,
-    spend_transaction_fee_per_wu: bitcoin::Amount,
+    spend_transaction_feerate: a2l::FeeRate,
     tumbler_fee: bitcoin::Amount,
-    he_keypair: hsm_cl::KeyPair,
-    ps_keypair: pointcheval_sanders::KeyPair,
+    class_group: hsm_cl::ClassGroupParams,
+    keyring: Arc<Keyring>,
     ps_publickey: pointcheval_sanders::PublicKey,
 ) -> (puzzle_solver::Tumbler, Sender) {
-    let params =
-        make_dummy_puzzle_solver_params(tumble_amount, spend_transaction_fee_per_wu, tumbler_fee);
+    let params = make_dummy_puzzle_solver_params(
+        blockchain,
+        joint_output,
+        tumble_amount,
+        spend_transaction_feerate,
+        tumbler_fee,
+    );
 
-    let tumbler =
-        puzzle_solver::Tumbler::new(params.clone(), he_keypair, ps_keypair, &mut thread_rng());
-    let sender = sender::Sender::new(params, ps_publickey, &mut thread_rng());
+    let tumbler = puzzle_solver::Tumbler::new(
+        params.clone(),
+        class_group.clone(),
+        keyring,
+        &mut thread_rng(),
+    )
+    .unwrap();
+    let sender = sender::Sender::new(params, class_group, ps_publickey, &mut thread_rng()).unwrap();
 
     (tumbler, sender)
 }
 
+fn timelocks() -> a2l::TimelockPolicy {
+    a2l::TimelockPolicy::from_safety_margin(PROMISE_EXPIRY, TIMELOCK_SAFETY_MARGIN).unwrap()
+}
+
+/// All parties in these tests redeem and refund to P2WPKH addresses, whose keys do not affect the
+/// fee.
+fn spend_tx_miner_fee(
+    joint_output: a2l::JointOutputKind,
+    feerate: a2l::FeeRate,
+) -> bitcoin::Amount {
+    a2l::spend_tx_miner_fee(
+        feerate,
+        joint_output.max_satisfaction_weight(),
+        &random_p2wpkh(),
+        &random_p2wpkh(),
+    )
+}
+
 fn make_dummy_puzzle_promise_params(
+    blockchain: &mut Blockchain,
+    joint_output: a2l::JointOutputKind,
     tumble_amount: bitcoin::Amount,
-    spend_transaction_fee_per_wu: bitcoin::Amount,
+    spend_transaction_feerate: a2l::FeeRate,
 ) -> puzzle_promise::Params {
+    let fund_amount = tumble_amount
+        + spend_tx_miner_fee(joint_output, spend_transaction_feerate)
+        + a2l::ANCHOR_OUTPUT_VALUE;
+
     puzzle_promise::Params::new(
+        SessionId::random(&mut thread_rng()),
         random_p2wpkh(),
         random_p2wpkh(),
-        0,
+        timelocks(),
         tumble_amount,
-        spend_transaction_fee_per_wu,
-        bitcoin::Transaction {
-            lock_time: 0,
-            version: 2,
-            input: Vec::new(),
-            output: vec![],
-        },
+        EPOCH,
+        spend_transaction_feerate,
+        joint_output,
+        blockchain.partial_fund_psbt(fund_amount),
     )
 }
 
 fn make_dummy_puzzle_solver_params(
+    blockchain: &mut Blockchain,
+    joint_output: a2l::JointOutputKind,
     tumble_amount: bitcoin::Amount,
-    spend_transaction_fee_per_wu: bitcoin::Amount,
+    spend_transaction_feerate: a2l::FeeRate,
     tumbler_fee: bitcoin::Amount,
 ) -> puzzle_solver::Params {
+    let fund_amount = tumble_amount
+        + tumbler_fee
+        + spend_tx_miner_fee(joint_output, spend_transaction_feerate)
+        + a2l::ANCHOR_OUTPUT_VALUE;
+
     puzzle_solver::Params::new(
+        SessionId::random(&mut thread_rng()),
         random_p2wpkh(),
         random_p2wpkh(),
-        0,
+        timelocks(),
         tumble_amount,
+        EPOCH,
         tumbler_fee,
-        spend_transaction_fee_per_wu,
-        bitcoin::Transaction {
-            lock_time: 0,
-            version: 2,
-            input: Vec::new(),
-            output: vec![],
-        },
+        spend_transaction_feerate,
+        joint_output,
+        blockchain.partial_fund_psbt(fund_amount),
     )
 }
 
@@ -475,6 +1126,63 @@ struct TimeRecordingStrategy {
 #[derive(Default, Clone)]
 struct NullStrategy;
 
+/// Lets the sender learn the tumbler's redeem transaction from the chain instead of being handed it.
+#[derive(Clone)]
+struct WatchingStrategy {
+    chain: Arc<InMemoryBlockchain>,
+}
+
+/// Sends every message through CBOR and JSON before handing it to the recipient.
+struct SerdeRoundtripTransport;
+
+impl<M> Transport<M> for SerdeRoundtripTransport
+where
+    M: Serialize + DeserializeOwned,
+{
+    fn deliver(&mut self, message: M) -> anyhow::Result<M> {
+        let cbor = serde_cbor::to_vec(&message)?;
+        let message = serde_cbor::from_slice::<M>(&cbor)?;
+        assert_eq!(serde_cbor::to_vec(&message)?, cbor);
+
+        let json = serde_json::to_string(&message)?;
+        let message = serde_json::from_str::<M>(&json)?;
+        assert_eq!(serde_json::to_string(&message)?, json);
+
+        Ok(message)
+    }
+}
+
+/// Persists the actor after every transition and continues with the state loaded from disk, as if
+/// the actor had been restarted.
+///
+/// A restored tumbler is given the keyring again, if one has been set.
+struct PersistingStrategy {
+    path: PathBuf,
+    store: FileStateStore,
+    keyring: Option<Arc<Keyring>>,
+}
+
+impl Default for PersistingStrategy {
+    fn default() -> Self {
+        let path = std::env::temp_dir().join(format!(
+            "a2l-dry-state-{}",
+            hex::encode(thread_rng().gen::<[u8; 8]>())
+        ));
+
+        Self {
+            store: FileStateStore::new(&path),
+            path,
+            keyring: None,
+        }
+    }
+}
+
+impl Drop for PersistingStrategy {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
 impl<T, S> Actor<T, S>
 where
     S: Default,
@@ -568,6 +1276,112 @@ impl Transition<puzzle_solver::FundTransaction>
     }
 }
 
+impl Transition<puzzle_promise::Message> for Actor<Sender, WatchingStrategy> {
+    fn transition(
+        self,
+        message: puzzle_promise::Message,
+        rng: &mut impl Rng,
+    ) -> anyhow::Result<Self> {
+        let inner = self
+            .inner
+            .transition_on_puzzle_promise_message(message, rng)?;
+
+        Ok(Self {
+            inner,
+            strategy: self.strategy,
+        })
+    }
+}
+
+impl Transition<puzzle_solver::Message> for Actor<Sender, WatchingStrategy> {
+    fn transition(
+        self,
+        message: puzzle_solver::Message,
+        rng: &mut impl Rng,
+    ) -> anyhow::Result<Self> {
+        let inner = self
+            .inner
+            .transition_on_puzzle_solver_message(message, rng)?;
+
+        Ok(Self {
+            inner,
+            strategy: self.strategy,
+        })
+    }
+}
+
+impl Transition<puzzle_solver::RedeemTransaction> for Actor<Sender, WatchingStrategy> {
+    fn transition(
+        self,
+        _: puzzle_solver::RedeemTransaction,
+        _: &mut impl Rng,
+    ) -> anyhow::Result<Self> {
+        let (inner, event) = Watcher::new(self.strategy.chain.as_ref()).watch_sender(self.inner)?;
+
+        match event {
+            Event::Redeemed(_) => Ok(Self {
+                inner,
+                strategy: self.strategy,
+            }),
+            event => bail!("expected the tumbler's redeem transaction, got {:?}", event),
+        }
+    }
+}
+
+/// Gives a restored actor the services that are not persisted along with its state.
+trait Reattach {
+    fn reattach(self, keyring: Arc<Keyring>) -> Self;
+}
+
+impl Reattach for puzzle_promise::Tumbler {
+    fn reattach(self, keyring: Arc<Keyring>) -> Self {
+        self.with_keyring(keyring)
+    }
+}
+
+impl Reattach for puzzle_solver::Tumbler {
+    fn reattach(self, keyring: Arc<Keyring>) -> Self {
+        self.with_keyring(keyring)
+    }
+}
+
+impl Reattach for Sender {
+    fn reattach(self, _: Arc<Keyring>) -> Self {
+        self
+    }
+}
+
+impl Reattach for Receiver {
+    fn reattach(self, _: Arc<Keyring>) -> Self {
+        self
+    }
+}
+
+impl<M, T> Transition<M> for Actor<T, PersistingStrategy>
+where
+    T: Transition<M> + Reattach + Serialize + DeserializeOwned,
+{
+    fn transition(self, message: M, rng: &mut impl Rng) -> anyhow::Result<Self> {
+        let inner = Transition::transition(self.inner, message, rng)?;
+
+        self.strategy.store.save(&inner)?;
+        let inner: T = self
+            .strategy
+            .store
+            .load()?
+            .context("no state has been persisted")?;
+        let inner = match &self.strategy.keyring {
+            Some(keyring) => inner.reattach(keyring.clone()),
+            None => inner,
+        };
+
+        Ok(Self {
+            inner,
+            strategy: self.strategy,
+        })
+    }
+}
+
 impl<M, T> Transition<M> for Actor<T, TimeRecordingStrategy>
 where
     T: Transition<M>,
@@ -596,28 +1410,15 @@ trait TransitionName {
 
 impl TransitionName for puzzle_promise::Message {
     fn transition_name(&self) -> String {
-        match self {
-            puzzle_promise::Message::Message0(_) => String::from("puzzle_promise::Message0"),
-            puzzle_promise::Message::Message1(_) => String::from("puzzle_promise::Message1"),
-            puzzle_promise::Message::Message2(_) => String::from("puzzle_promise::Message2"),
-            puzzle_promise::Message::Message3(_) => String::from("puzzle_promise::Message3"),
-            puzzle_promise::Message::Message4(_) => String::from("puzzle_promise::Message4"),
-        }
+        // the variant name, e.g. `Message0`
+        format!("puzzle_promise::{}", self)
     }
 }
 
 impl TransitionName for puzzle_solver::Message {
     fn transition_name(&self) -> String {
-        match self {
-            puzzle_solver::Message::Message0(_) => String::from("puzzle_solver::Message0"),
-            puzzle_solver::Message::Message1(_) => String::from("puzzle_solver::Message1"),
-            puzzle_solver::Message::Message2(_) => String::from("puzzle_solver::Message2"),
-            puzzle_solver::Message::Message3(_) => String::from("puzzle_solver::Message3"),
-            puzzle_solver::Message::Message4(_) => String::from("puzzle_solver::Message4"),
-            puzzle_solver::Message::Message5(_) => String::from("puzzle_solver::Message5"),
-            puzzle_solver::Message::Message6(_) => String::from("puzzle_solver::Message6"),
-            puzzle_solver::Message::Message7(_) => String::from("puzzle_solver::Message7"),
-        }
+        // the variant name, e.g. `Message0`
+        format!("puzzle_solver::{}", self)
     }
 }
 
@@ -1,49 +1,70 @@
-pub mod harness;
-
-use crate::harness::{MakeTransaction, NextMessage, Transition};
+use a2l::keyring::Keyring;
+use a2l::protocol::{
+    run_happy_path, run_refund, InMemoryTransport, MakeTransaction, NextMessage, Transition,
+    WaitForLocktime,
+};
 use a2l::receiver::Receiver;
 use a2l::sender::Sender;
+use a2l::token_store::InMemoryTokenStore;
+use a2l::transcript::SessionId;
 use a2l::{hsm_cl, pointcheval_sanders, puzzle_promise, puzzle_solver, receiver, sender};
 use anyhow::Context;
 use bitcoin::{
-    consensus::deserialize, consensus::encode::serialize_hex, hashes::hex::FromHex, Transaction,
+    consensus::deserialize, consensus::encode::serialize_hex, hashes::hex::FromHex,
+    util::psbt::PartiallySignedTransaction, Transaction,
 };
-use harness::{run_happy_path, run_refund};
 use rand::{thread_rng, Rng};
 use serde::*;
+use std::sync::Arc;
 use testcontainers::{clients, images::coblox_bitcoincore::BitcoinCore, Container, Docker};
 use ureq::SerdeValue;
 
+/// Number of blocks after which the tumbler's refund transaction becomes valid.
+const REFUND_TIMELOCK: u32 = 10;
+/// Number of blocks the tumbler has to redeem before the sender's refund transaction becomes valid.
+const TIMELOCK_SAFETY_MARGIN: u32 = 6;
+
+/// Epoch for which the tumblers issue and accept tokens.
+const EPOCH: a2l::Epoch = 0;
+
 #[test]
 fn e2e_happy_path() -> anyhow::Result<()> {
     // global A2L parameters
-    let he_keypair = hsm_cl::keygen();
-    let ps_keypair = pointcheval_sanders::keygen(&mut thread_rng());
+    let class_group = hsm_cl::ClassGroupParams::from_seed(hsm_cl::DEFAULT_CLASS_GROUP_SEED);
+    let he_keypair = hsm_cl::keygen(&class_group);
+    let ps_keypair = pointcheval_sanders::keygen(a2l::TOKEN_MESSAGES, &mut thread_rng());
+    let he_publickey = he_keypair.to_pk();
+    let ps_publickey = ps_keypair.public_key.clone();
+    let keyring = Arc::new(Keyring::new(EPOCH, he_keypair, ps_keypair)?);
 
     // parameters for this instance of a2l
     let tumble_amount = bitcoin::Amount::from_sat(10_000_000);
-    let spend_transaction_fee_per_wu = bitcoin::Amount::from_sat(10);
+    let spend_transaction_feerate = a2l::FeeRate::PerWeightUnit(bitcoin::Amount::from_sat(10));
     let tumbler_fee = bitcoin::Amount::from_sat(10_000);
 
     let client = clients::Cli::default();
 
     let blockchain = BitcoindBlockchain::new(&client)?;
+    let timelocks = blockchain.timelocks_in(REFUND_TIMELOCK)?;
     let (tumbler_promise, receiver) = make_puzzle_promise_actors(
         &blockchain.bitcoind_url,
+        timelocks,
         tumble_amount,
-        spend_transaction_fee_per_wu,
-        he_keypair.clone(),
-        he_keypair.to_pk(),
-        ps_keypair.clone(),
+        spend_transaction_feerate,
+        class_group.clone(),
+        keyring.clone(),
+        he_publickey,
+        ps_publickey.clone(),
     )?;
     let (tumbler_solver, sender) = make_puzzle_solver_actors(
         &blockchain.bitcoind_url,
+        timelocks,
         tumble_amount,
-        spend_transaction_fee_per_wu,
+        spend_transaction_feerate,
         tumbler_fee,
-        he_keypair,
-        ps_keypair.clone(),
-        ps_keypair.public_key,
+        class_group,
+        keyring,
+        ps_publickey,
     )?;
 
     let (tumbler_promise, tumbler_solver, sender, receiver, _) = run_happy_path(
@@ -51,6 +72,7 @@ fn e2e_happy_path() -> anyhow::Result<()> {
         tumbler_solver,
         sender,
         receiver,
+        &mut InMemoryTransport,
         blockchain,
         &mut thread_rng(),
     )?;
@@ -88,33 +110,41 @@ fn e2e_happy_path() -> anyhow::Result<()> {
 #[test]
 fn e2e_refund() -> anyhow::Result<()> {
     // global A2L parameters
-    let he_keypair = hsm_cl::keygen();
-    let ps_keypair = pointcheval_sanders::keygen(&mut thread_rng());
+    let class_group = hsm_cl::ClassGroupParams::from_seed(hsm_cl::DEFAULT_CLASS_GROUP_SEED);
+    let he_keypair = hsm_cl::keygen(&class_group);
+    let ps_keypair = pointcheval_sanders::keygen(a2l::TOKEN_MESSAGES, &mut thread_rng());
+    let he_publickey = he_keypair.to_pk();
+    let ps_publickey = ps_keypair.public_key.clone();
+    let keyring = Arc::new(Keyring::new(EPOCH, he_keypair, ps_keypair)?);
 
     // parameters for this instance of a2l
     let tumble_amount = bitcoin::Amount::from_sat(10_000_000);
-    let spend_transaction_fee_per_wu = bitcoin::Amount::from_sat(10);
+    let spend_transaction_feerate = a2l::FeeRate::PerWeightUnit(bitcoin::Amount::from_sat(10));
     let tumbler_fee = bitcoin::Amount::from_sat(10_000);
 
     let client = clients::Cli::default();
 
     let blockchain = BitcoindBlockchain::new(&client)?;
+    let timelocks = blockchain.timelocks_in(REFUND_TIMELOCK)?;
     let (tumbler_promise, receiver) = make_puzzle_promise_actors(
         &blockchain.bitcoind_url,
+        timelocks,
         tumble_amount,
-        spend_transaction_fee_per_wu,
-        he_keypair.clone(),
-        he_keypair.to_pk(),
-        ps_keypair.clone(),
+        spend_transaction_feerate,
+        class_group.clone(),
+        keyring.clone(),
+        he_publickey,
+        ps_publickey.clone(),
     )?;
     let (tumbler_solver, sender) = make_puzzle_solver_actors(
         &blockchain.bitcoind_url,
+        timelocks,
         tumble_amount,
-        spend_transaction_fee_per_wu,
+        spend_transaction_feerate,
         tumbler_fee,
-        he_keypair,
-        ps_keypair.clone(),
-        ps_keypair.public_key,
+        class_group,
+        keyring,
+        ps_publickey,
     )?;
 
     let (tumbler_promise, tumbler_solver, sender, receiver, _) = run_refund(
@@ -122,6 +152,7 @@ fn e2e_refund() -> anyhow::Result<()> {
         tumbler_solver,
         sender,
         receiver,
+        &mut InMemoryTransport,
         blockchain,
         &mut thread_rng(),
     )?;
@@ -146,6 +177,68 @@ fn e2e_refund() -> anyhow::Result<()> {
     Ok(())
 }
 
+#[test]
+fn e2e_refund_is_rejected_before_expiry() -> anyhow::Result<()> {
+    // global A2L parameters
+    let class_group = hsm_cl::ClassGroupParams::from_seed(hsm_cl::DEFAULT_CLASS_GROUP_SEED);
+    let he_keypair = hsm_cl::keygen(&class_group);
+    let ps_keypair = pointcheval_sanders::keygen(a2l::TOKEN_MESSAGES, &mut thread_rng());
+    let he_publickey = he_keypair.to_pk();
+    let ps_publickey = ps_keypair.public_key.clone();
+    let keyring = Arc::new(Keyring::new(EPOCH, he_keypair, ps_keypair)?);
+
+    // parameters for this instance of a2l
+    let tumble_amount = bitcoin::Amount::from_sat(10_000_000);
+    let spend_transaction_feerate = a2l::FeeRate::PerWeightUnit(bitcoin::Amount::from_sat(10));
+    let tumbler_fee = bitcoin::Amount::from_sat(10_000);
+
+    let client = clients::Cli::default();
+
+    let blockchain = BitcoindBlockchain::new(&client)?;
+    let timelocks = blockchain.timelocks_in(REFUND_TIMELOCK)?;
+    let (tumbler_promise, receiver) = make_puzzle_promise_actors(
+        &blockchain.bitcoind_url,
+        timelocks,
+        tumble_amount,
+        spend_transaction_feerate,
+        class_group.clone(),
+        keyring.clone(),
+        he_publickey,
+        ps_publickey.clone(),
+    )?;
+    let (tumbler_solver, sender) = make_puzzle_solver_actors(
+        &blockchain.bitcoind_url,
+        timelocks,
+        tumble_amount,
+        spend_transaction_feerate,
+        tumbler_fee,
+        class_group,
+        keyring,
+        ps_publickey,
+    )?;
+
+    let error = match run_refund(
+        tumbler_promise,
+        tumbler_solver,
+        sender,
+        receiver,
+        &mut InMemoryTransport,
+        ImpatientBlockchain(blockchain),
+        &mut thread_rng(),
+    ) {
+        Ok(_) => anyhow::bail!("refund transaction was accepted before expiry"),
+        Err(e) => e,
+    };
+
+    assert!(
+        format!("{:#}", error).contains("non-final"),
+        "unexpected error: {:#}",
+        error
+    );
+
+    Ok(())
+}
+
 struct E2EActor<T> {
     inner: T,
     wallet: Wallet,
@@ -238,6 +331,7 @@ impl E2EActor<Sender> {
             - self.fund_fee // we pay the miner for the fund transaction
             - self.tumble_amount // we pay the tumble amount
             - self.spend_tx_miner_fee // we pay the miner for the redeem transaction
+            - a2l::ANCHOR_OUTPUT_VALUE // nobody bumps the redeem transaction, its anchor output stays unspent
             - self.tumbler_fee // we pay the tumbler for doing the protocol
     }
 
@@ -245,6 +339,7 @@ impl E2EActor<Sender> {
         self.starting_balance
             - self.fund_fee // we pay the miner for the fund transaction, regardless of the outcome
             - self.spend_tx_miner_fee // we pay the miner for the refund transaction, regardless of the outcome
+            - a2l::ANCHOR_OUTPUT_VALUE // nobody bumps the refund transaction, its anchor output stays unspent
     }
 }
 
@@ -254,12 +349,14 @@ impl E2EActor<puzzle_promise::Tumbler> {
             - self.fund_fee // we pay the miner for the fund transaction
             - self.tumble_amount // we pay the tumble amount
             - self.spend_tx_miner_fee // we pay the miner for the redeem transaction
+            - a2l::ANCHOR_OUTPUT_VALUE // nobody bumps the redeem transaction, its anchor output stays unspent
     }
 
     fn expected_balance_after_no_tumble(&self) -> bitcoin::Amount {
         self.starting_balance
             - self.fund_fee // we pay the miner for the fund transaction, regardless of the outcome
             - self.spend_tx_miner_fee // we pay the miner for the refund transaction, regardless of the outcome
+            - a2l::ANCHOR_OUTPUT_VALUE // nobody bumps the refund transaction, its anchor output stays unspent
     }
 }
 
@@ -319,6 +416,55 @@ impl<'c> BitcoindBlockchain<'c> {
     }
 }
 
+impl BitcoindBlockchain<'_> {
+    fn block_height(&self) -> anyhow::Result<u32> {
+        rpc_command::<u32>(
+            &self.bitcoind_url,
+            ureq::json!({"jsonrpc": "1.0", "method": "getblockcount", "params": [] }),
+        )
+    }
+
+    fn timelocks_in(&self, blocks: u32) -> anyhow::Result<a2l::TimelockPolicy> {
+        let promise_expiry = a2l::Expiry::block_height(self.block_height()? + blocks)?;
+        let timelocks =
+            a2l::TimelockPolicy::from_safety_margin(promise_expiry, TIMELOCK_SAFETY_MARGIN)?;
+
+        Ok(timelocks)
+    }
+}
+
+impl WaitForLocktime for BitcoindBlockchain<'_> {
+    fn wait_for_locktime(self, transaction: &Transaction) -> anyhow::Result<Self> {
+        let height = match a2l::Expiry::from_lock_time(transaction.lock_time) {
+            a2l::Expiry::BlockHeight(height) => height,
+            a2l::Expiry::Timestamp(_) => {
+                anyhow::bail!("waiting for a timestamp locktime is not supported on regtest")
+            }
+        };
+
+        while self.block_height()? < height {
+            mine(&format!("{}/wallet/", &self.bitcoind_url))?;
+        }
+
+        Ok(self)
+    }
+}
+
+/// Refuses to wait for any locktime, hence broadcasts the refund transactions as early as possible.
+struct ImpatientBlockchain<'c>(BitcoindBlockchain<'c>);
+
+impl Transition<bitcoin::Transaction> for ImpatientBlockchain<'_> {
+    fn transition(self, transaction: Transaction, rng: &mut impl Rng) -> anyhow::Result<Self> {
+        Ok(Self(self.0.transition(transaction, rng)?))
+    }
+}
+
+impl WaitForLocktime for ImpatientBlockchain<'_> {
+    fn wait_for_locktime(self, _: &Transaction) -> anyhow::Result<Self> {
+        Ok(self)
+    }
+}
+
 impl Transition<bitcoin::Transaction> for BitcoindBlockchain<'_> {
     fn transition(self, message: Transaction, _: &mut impl Rng) -> anyhow::Result<Self> {
         let hex = &serialize_hex(&message);
@@ -337,11 +483,13 @@ impl Transition<bitcoin::Transaction> for BitcoindBlockchain<'_> {
 
 fn make_puzzle_promise_actors(
     bitcoind_url: &str,
+    timelocks: a2l::TimelockPolicy,
     tumble_amount: bitcoin::Amount,
-    spend_transaction_fee_per_wu: bitcoin::Amount,
-    he_keypair: hsm_cl::KeyPair,
+    spend_transaction_feerate: a2l::FeeRate,
+    class_group: hsm_cl::ClassGroupParams,
+    keyring: Arc<Keyring>,
     he_publickey: hsm_cl::PublicKey,
-    ps_keypair: pointcheval_sanders::KeyPair,
+    ps_publickey: pointcheval_sanders::PublicKey,
 ) -> anyhow::Result<(E2EActor<puzzle_promise::Tumbler>, E2EActor<Receiver>)> {
     let tumbler_wallet = Wallet::new(
         bitcoind_url.to_owned(),
@@ -353,27 +501,49 @@ fn make_puzzle_promise_actors(
     let refund_address = tumbler_wallet.getnewaddress()?;
     let redeem_address = receiver_wallet.getnewaddress()?;
 
-    let spend_tx_miner_fee = a2l::spend_tx_miner_fee(spend_transaction_fee_per_wu);
+    let spend_tx_miner_fee = a2l::spend_tx_miner_fee(
+        spend_transaction_feerate,
+        a2l::P2WSH_SATISFACTION_WEIGHT,
+        &redeem_address.parse()?,
+        &refund_address.parse()?,
+    );
 
     let PartialFundTransaction {
-        inner: partial_fund_transaction,
+        inner: partial_fund_psbt,
         expected_fee: fund_fee,
     } = tumbler_wallet
-        .make_partial_fund_transaction(tumble_amount + spend_tx_miner_fee)
+        .make_partial_fund_transaction(
+            tumble_amount + spend_tx_miner_fee + a2l::ANCHOR_OUTPUT_VALUE,
+        )
         .context("failed to make tumbler fund transaction")?;
 
     let params = puzzle_promise::Params::new(
+        SessionId::random(&mut thread_rng()),
         redeem_address.parse()?,
         refund_address.parse()?,
-        0,
+        timelocks,
         tumble_amount,
-        spend_transaction_fee_per_wu,
-        partial_fund_transaction,
+        EPOCH,
+        spend_transaction_feerate,
+        // Bitcoin Core 0.19.1 does not know about taproot outputs
+        a2l::JointOutputKind::P2wsh,
+        partial_fund_psbt,
     );
 
-    let tumbler =
-        puzzle_promise::Tumbler::new(params.clone(), he_keypair, ps_keypair, &mut thread_rng());
-    let receiver = receiver::Receiver::new(params, &mut thread_rng(), he_publickey);
+    let tumbler = puzzle_promise::Tumbler::new(
+        params.clone(),
+        class_group.clone(),
+        keyring,
+        Arc::new(InMemoryTokenStore::default()),
+        &mut thread_rng(),
+    )?;
+    let receiver = receiver::Receiver::new(
+        params,
+        &mut thread_rng(),
+        class_group,
+        he_publickey,
+        ps_publickey,
+    )?;
 
     let tumbler_starting_balance = tumbler_wallet.get_balance()?;
     let tumbler = E2EActor {
@@ -401,11 +571,12 @@ fn make_puzzle_promise_actors(
 
 fn make_puzzle_solver_actors(
     bitcoind_url: &str,
+    timelocks: a2l::TimelockPolicy,
     tumble_amount: bitcoin::Amount,
-    spend_transaction_fee_per_wu: bitcoin::Amount,
+    spend_transaction_feerate: a2l::FeeRate,
     tumbler_fee: bitcoin::Amount,
-    he_keypair: hsm_cl::KeyPair,
-    ps_keypair: pointcheval_sanders::KeyPair,
+    class_group: hsm_cl::ClassGroupParams,
+    keyring: Arc<Keyring>,
     ps_publickey: pointcheval_sanders::PublicKey,
 ) -> anyhow::Result<(E2EActor<puzzle_solver::Tumbler>, E2EActor<Sender>)> {
     let tumbler_wallet = Wallet::new(
@@ -418,28 +589,43 @@ fn make_puzzle_solver_actors(
     let refund_address = sender_wallet.getnewaddress()?;
     let redeem_address = tumbler_wallet.getnewaddress()?;
 
-    let spend_tx_miner_fee = a2l::spend_tx_miner_fee(spend_transaction_fee_per_wu);
+    let spend_tx_miner_fee = a2l::spend_tx_miner_fee(
+        spend_transaction_feerate,
+        a2l::P2WSH_SATISFACTION_WEIGHT,
+        &redeem_address.parse()?,
+        &refund_address.parse()?,
+    );
 
     let PartialFundTransaction {
-        inner: partial_fund_transaction,
+        inner: partial_fund_psbt,
         expected_fee: fund_fee,
     } = sender_wallet
-        .make_partial_fund_transaction(tumble_amount + tumbler_fee + spend_tx_miner_fee)
+        .make_partial_fund_transaction(
+            tumble_amount + tumbler_fee + spend_tx_miner_fee + a2l::ANCHOR_OUTPUT_VALUE,
+        )
         .context("failed to make sender fund transaction")?;
 
     let params = puzzle_solver::Params::new(
+        SessionId::random(&mut thread_rng()),
         redeem_address.parse()?,
         refund_address.parse()?,
-        0,
+        timelocks,
         tumble_amount,
+        EPOCH,
         tumbler_fee,
-        spend_transaction_fee_per_wu,
-        partial_fund_transaction,
+        spend_transaction_feerate,
+        // Bitcoin Core 0.19.1 does not know about taproot outputs
+        a2l::JointOutputKind::P2wsh,
+        partial_fund_psbt,
     );
 
-    let tumbler =
-        puzzle_solver::Tumbler::new(params.clone(), he_keypair, ps_keypair, &mut thread_rng());
-    let sender = sender::Sender::new(params, ps_publickey, &mut thread_rng());
+    let tumbler = puzzle_solver::Tumbler::new(
+        params.clone(),
+        class_group.clone(),
+        keyring,
+        &mut thread_rng(),
+    )?;
+    let sender = sender::Sender::new(params, class_group, ps_publickey, &mut thread_rng())?;
 
     let tumbler_starting_balance = tumbler_wallet.get_balance()?;
     let sender_starting_balance = sender_wallet.get_balance()?;
@@ -503,11 +689,16 @@ impl Wallet {
         let res = self.fundrawtransaction(transaction_hex)?;
 
         let mut transaction = deserialize::<bitcoin::Transaction>(&Vec::<u8>::from_hex(&res.hex)?)?;
+        let mut psbt = PartiallySignedTransaction::from_unsigned_tx(bitcoin::Transaction {
+            output: vec![transaction.output.remove(res.changepos as usize)],
+            ..transaction
+        })?;
+        for (txin, input) in psbt.global.unsigned_tx.input.iter().zip(&mut psbt.inputs) {
+            input.witness_utxo = Some(self.gettxout(&txin.previous_output)?);
+        }
+
         Ok(PartialFundTransaction {
-            inner: bitcoin::Transaction {
-                output: vec![transaction.output.remove(res.changepos as usize)],
-                ..transaction
-            },
+            inner: psbt,
             expected_fee: bitcoin::Amount::from_btc(res.fee)?,
         })
     }
@@ -528,6 +719,30 @@ impl Wallet {
         Ok(transaction)
     }
 
+    fn gettxout(&self, outpoint: &bitcoin::OutPoint) -> anyhow::Result<bitcoin::TxOut> {
+        #[derive(Deserialize)]
+        struct Response {
+            value: f64,
+            #[serde(rename = "scriptPubKey")]
+            script_pubkey: ScriptPubKey,
+        }
+
+        #[derive(Deserialize)]
+        struct ScriptPubKey {
+            hex: String,
+        }
+
+        let res = rpc_command::<Response>(
+            &self.url,
+            ureq::json!({"jsonrpc": "1.0", "method": "gettxout", "params": [outpoint.txid.to_string(), outpoint.vout] }),
+        )?;
+
+        Ok(bitcoin::TxOut {
+            value: bitcoin::Amount::from_btc(res.value)?.as_sat(),
+            script_pubkey: bitcoin::Script::from(Vec::<u8>::from_hex(&res.script_pubkey.hex)?),
+        })
+    }
+
     fn get_balance(&self) -> anyhow::Result<bitcoin::Amount> {
         let balance = rpc_command::<f64>(
             &self.url,
@@ -588,7 +803,7 @@ fn mine(url: &str) -> anyhow::Result<()> {
 }
 
 struct PartialFundTransaction {
-    inner: bitcoin::Transaction,
+    inner: PartiallySignedTransaction,
     expected_fee: bitcoin::Amount,
 }
 